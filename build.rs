@@ -0,0 +1,43 @@
+//! Embeds the target and the git commit of the build for `--build-info` and the User-Agent.
+use std::{path::Path, process::Command};
+
+fn main() {
+    println!(
+        "cargo:rustc-env=VAULTIFY_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+
+    // builds from a source archive (e.g. nix) have no .git and pass the commit instead
+    println!("cargo:rerun-if-env-changed=VAULTIFY_GIT_SHA");
+    let sha = std::env::var("VAULTIFY_GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=VAULTIFY_GIT_SHA={}", sha);
+}
+
+/// The commit checked out, rebuilding once it changes.
+fn git_sha() -> Option<String> {
+    let head = Path::new(".git/HEAD");
+    if !head.exists() {
+        return None;
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(branch) = std::fs::read_to_string(head)
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref: ")?.trim().to_string()))
+    {
+        let branch = Path::new(".git").join(branch);
+        if branch.exists() {
+            println!("cargo:rerun-if-changed={}", branch.display());
+        }
+    }
+
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
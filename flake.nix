@@ -48,6 +48,9 @@
           cargoLock = {
             lockFile = ./Cargo.lock;
           };
+
+          # the source in the store has no .git for build.rs to read the commit from
+          VAULTIFY_GIT_SHA = self.rev or self.dirtyRev or "unknown";
         };
 
         devShells.default = pkgs.mkShell {
@@ -0,0 +1,50 @@
+//! Version, commit and target of this build, printed by `--build-info` and sent as User-Agent so
+//! vault operators can tell vaultify's requests apart.
+use serde_json::{json, Value};
+
+/// `User-Agent` of all requests, e.g. `vaultify/0.3.1 (x86_64-unknown-linux-musl)`.
+pub const USER_AGENT: &str = concat!(
+    "vaultify/",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("VAULTIFY_TARGET"),
+    ")"
+);
+
+/// Cargo features of the build.
+const FEATURES: &[(&str, bool)] = &[("testing", cfg!(feature = "testing"))];
+
+/// The build as JSON.
+pub fn json() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("VAULTIFY_GIT_SHA"),
+        "target": env!("VAULTIFY_TARGET"),
+        "features": FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_build_info() {
+        let info = json();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!info["git_sha"].as_str().unwrap().is_empty());
+        assert!(info["features"].is_array());
+        assert_eq!(
+            USER_AGENT,
+            format!(
+                "vaultify/{} ({})",
+                info["version"].as_str().unwrap(),
+                info["target"].as_str().unwrap()
+            )
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Encrypted on-disk cache of fetched secrets, used while vault is unreachable.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{aead::LessSafeKey, digest};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto,
+    error::{Error, Result},
+    secrets::{Secret, SecretSpecs},
+};
+
+/// Salt of the key derivation, changing it invalidates all existing caches.
+const KEY_SALT: &[u8] = b"vaultify secret cache v1";
+
+/// Environment variable holding the key of the cache if there is no `--cache-key-file`.
+pub const CACHE_KEY_ENV: &str = "VAULTIFY_CACHE_KEY";
+
+/// Cache of the secrets of a single `.secrets` file and set of vault addresses.
+///
+/// # Remarks:
+///
+/// The secrets are stored as JSON sealed with `crypto::seal` using a key derived from
+/// `key_material`, so the cache is useless without the cache key (or vault token) it was
+/// written with.
+pub struct SecretCache {
+    path: PathBuf,
+    key: LessSafeKey,
+    ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Seconds since the unix epoch the secrets were fetched at.
+    created: u64,
+    /// Values by secret name.
+    secrets: BTreeMap<String, String>,
+    /// kv v2 versions by secret name, for `--metadata-env`.
+    #[serde(default)]
+    versions: BTreeMap<String, u64>,
+}
+
+impl SecretCache {
+    pub fn new(
+        dir: &Path,
+        ttl: Duration,
+        key_material: &[u8],
+        hosts: &[String],
+        specs: &SecretSpecs,
+    ) -> Result<Self> {
+        // the file name only depends on what is fetched, so changed specs never read stale values
+        let id = digest::digest(
+            &digest::SHA256,
+            format!("{:?}|{:?}", hosts, specs).as_bytes(),
+        );
+
+        Ok(Self {
+            path: dir.join(format!("{}.cache", crypto::hex(&id.as_ref()[..16]))),
+            key: crypto::derive_key(KEY_SALT, key_material)?,
+            ttl,
+        })
+    }
+
+    /// Encrypts and writes the secrets, replacing the previous cache atomically.
+    pub async fn store(&self, secrets: &[Secret]) -> Result<()> {
+        let entry = Entry {
+            created: unix_now(),
+            secrets: secrets
+                .iter()
+                .map(|secret| (secret.target.name(), secret.secret.clone()))
+                .collect(),
+            versions: versions(secrets),
+        };
+        let contents = serde_json::to_vec(&entry)
+            .map_err(|err| Error::Conversion(format!("unable to serialize cache: {}", err)))?;
+        let contents = crypto::seal(&self.key, contents)?;
+
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|err| Error::io_at("unable to create cache dir", dir, err))?;
+        let tmp = self.path.with_extension("tmp");
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let write = async {
+            use tokio::io::AsyncWriteExt;
+
+            let mut file = options.open(&tmp).await?;
+            file.write_all(&contents).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &self.path).await
+        };
+        write
+            .await
+            .map_err(|err| Error::io_at("unable to write cache", &self.path, err))
+    }
+
+    /// Reads the cached secrets of all `specs`, failing if the cache is missing, expired or was
+    /// written with a different key.
+    pub async fn load(&self, specs: &SecretSpecs) -> Result<Vec<Secret>> {
+        self.load_at(specs, SystemTime::now()).await
+    }
+
+    /// Like `load`, judging the age of the cache at `now`.
+    async fn load_at(&self, specs: &SecretSpecs, now: SystemTime) -> Result<Vec<Secret>> {
+        let contents = tokio::fs::read(&self.path)
+            .await
+            .map_err(|err| Error::io_at("unable to read cache", &self.path, err))?;
+        let entry = self.decrypt(contents)?;
+
+        let age = Duration::from_secs(unix_secs(now).saturating_sub(entry.created));
+        if age > self.ttl {
+            return Err(Error::NotFound(format!(
+                "cache {:?} expired {:?} ago",
+                self.path,
+                age - self.ttl
+            )));
+        }
+
+        specs
+            .values()
+            .map(|spec| {
+                let secret = entry.secrets.get(&spec.name()).ok_or_else(|| {
+                    Error::NotFound(format!("cache does not contain `{}`", spec.name()))
+                })?;
+                let mut secret = Secret::new(spec.target.clone(), secret.clone());
+                secret.metadata.version = entry.versions.get(&spec.name()).copied();
+                Ok(secret)
+            })
+            .collect()
+    }
+
+    fn decrypt(&self, contents: Vec<u8>) -> Result<Entry> {
+        let plaintext = crypto::open(&self.key, contents)?;
+        serde_json::from_slice(&plaintext).map_err(|err| Error::json("unable to parse cache", err))
+    }
+}
+
+/// The kv v2 versions of `secrets` by name.
+///
+/// # Remarks:
+///
+/// Leases are not kept, the cached values may outlive them.
+pub fn versions(secrets: &[Secret]) -> BTreeMap<String, u64> {
+    secrets
+        .iter()
+        .filter_map(|secret| Some((secret.target.name(), secret.metadata.version?)))
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{SecretSpec, SecretTarget};
+
+    fn specs() -> SecretSpecs {
+        let target = SecretTarget::Env {
+            name: "API_KEY".to_string(),
+        };
+        let spec = SecretSpec::vault(target, "secret", "app", "key");
+        SecretSpecs::from([(spec.name(), spec)])
+    }
+
+    fn secrets() -> Vec<Secret> {
+        let mut secret = Secret::new(
+            SecretTarget::Env {
+                name: "API_KEY".to_string(),
+            },
+            "s3cr3t".to_string(),
+        );
+        secret.metadata.version = Some(7);
+        vec![secret]
+    }
+
+    #[tokio::test]
+    async fn pass_store_and_load() {
+        let dir = std::env::temp_dir().join(format!("vaultify-cache-{}", std::process::id()));
+        let hosts = ["http://127.0.0.1:8200".to_string()];
+        let cache =
+            SecretCache::new(&dir, Duration::from_secs(60), b"token", &hosts, &specs()).unwrap();
+        cache.store(&secrets()).await.unwrap();
+        let loaded = cache.load(&specs()).await.unwrap();
+        assert_eq!(loaded, secrets());
+        assert_eq!(loaded[0].metadata.version, Some(7));
+
+        // the raw file does not contain the secret
+        let raw = std::fs::read(&cache.path).unwrap();
+        assert!(!raw.windows(6).any(|window| window == b"s3cr3t"));
+
+        let other =
+            SecretCache::new(&dir, Duration::from_secs(60), b"other", &hosts, &specs()).unwrap();
+        assert!(other.load(&specs()).await.is_err());
+        let expired = SecretCache::new(&dir, Duration::ZERO, b"token", &hosts, &specs()).unwrap();
+        assert!(expired.load(&specs()).await.is_ok());
+        let later = SystemTime::now() + Duration::from_secs(2);
+        assert!(expired.load_at(&specs(), later).await.is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
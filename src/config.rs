@@ -0,0 +1,305 @@
+//! `vaultify.toml` configuration file holding defaults of command line options.
+//!
+//! Every key is the long name of an option (e.g. `ca-cert` or `ca_cert`), its value is used
+//! unless the option is passed on the command line or via its environment variable.
+//!
+//! `[profile.<name>]` tables hold named profiles selected with `--profile`, whose keys override
+//! the top-level ones.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, ArgMatches};
+use serde::Deserialize;
+
+use crate::{
+    error::{Error, Result},
+    Args,
+};
+
+/// Path of the config file below the XDG config directories.
+const CONFIG_FILE: &str = "vaultify/vaultify.toml";
+
+/// Values of options by key, as written in the file.
+type Options = BTreeMap<String, toml::Value>;
+
+/// Contents of a config file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Config {
+    #[serde(skip)]
+    path: PathBuf,
+    /// The `[profile.<name>]` tables by name.
+    #[serde(default)]
+    profile: BTreeMap<String, Options>,
+    /// The top-level options.
+    #[serde(flatten)]
+    options: Options,
+}
+
+impl Config {
+    /// Returns the options of `profile` followed by the top-level options it does not override,
+    /// by long option name.
+    fn options(&self, profile: Option<&str>) -> Result<BTreeMap<String, &toml::Value>> {
+        let mut options = self.normalized(&self.options)?;
+        if let Some(profile) = profile {
+            let overrides = self.profile.get(profile).ok_or_else(|| {
+                Error::Conversion(format!("unknown profile `{}` in {:?}", profile, self.path))
+            })?;
+            options.extend(self.normalized(overrides)?);
+        }
+        Ok(options)
+    }
+
+    /// Returns `options` by long option name, e.g. `ca-cert` for `ca_cert`.
+    fn normalized<'a>(&self, options: &'a Options) -> Result<BTreeMap<String, &'a toml::Value>> {
+        let mut normalized = BTreeMap::new();
+        for (key, value) in options {
+            if normalized.insert(key.replace('_', "-"), value).is_some() {
+                return Err(Error::Conversion(format!(
+                    "duplicate option `{}` in {:?}",
+                    key, self.path
+                )));
+            }
+        }
+        Ok(normalized)
+    }
+}
+
+/// Parses the command line with the defaults of the config file in `--config`, `VAULTIFY_CONFIG`
+/// or the XDG config directories.
+///
+/// Exits like `Args::parse` if the command line is invalid.
+pub fn parse_args() -> Result<Args> {
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let matches = Args::cli().get_matches_from(&argv);
+    // the helpers of a detached instance and the generated scripts take none of the options
+    if matches!(
+        matches.subcommand_name(),
+        Some("stop" | "status" | "completions" | "docs")
+    ) {
+        return Ok(from_matches(&matches));
+    }
+
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => discover(),
+    };
+    let profile = matches.get_one::<String>("profile").map(String::as_str);
+    let Some(path) = path else {
+        if let Some(profile) = profile {
+            return Err(Error::Conversion(format!(
+                "unknown profile `{}`: no config file found",
+                profile
+            )));
+        }
+        return Ok(from_matches(&matches));
+    };
+    let config = load(&path)?;
+
+    let command = with_defaults(Args::cli(), &config, profile)?;
+    Ok(from_matches(&command.get_matches_from(argv)))
+}
+
+fn from_matches(matches: &ArgMatches) -> Args {
+    Args::from_matches(matches).unwrap_or_else(|err| err.exit())
+}
+
+/// Returns the first config file which exists in `$XDG_CONFIG_HOME` (or `~/.config`) and
+/// `$XDG_CONFIG_DIRS` (or `/etc/xdg`).
+fn discover() -> Option<PathBuf> {
+    let env_dir = |name| std::env::var_os(name).filter(|dir| !dir.is_empty());
+    let config_home = env_dir("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env_dir("HOME").map(|home| Path::new(&home).join(".config")));
+    let config_dirs = env_dir("XDG_CONFIG_DIRS").unwrap_or_else(|| "/etc/xdg".into());
+
+    config_home
+        .into_iter()
+        .chain(std::env::split_paths(&config_dirs))
+        .map(|dir| dir.join(CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Reads and parses the config file at `path`.
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| Error::io_at("unable to read config file", path, err))?;
+    parse(&contents, path).map_err(|err| err.in_file(path))
+}
+
+fn parse(contents: &str, path: &Path) -> Result<Config> {
+    let mut config = toml::from_str::<Config>(contents).map_err(|err| {
+        // points at the line the error starts on, like the errors of the secrets file
+        let start = err.span().map_or(0, |span| span.start);
+        let lc = contents[..start].matches('\n').count();
+        let line = contents.lines().nth(lc).unwrap_or_default().trim();
+        Error::parse(err.message(), lc, line)
+    })?;
+    config.path = path.to_path_buf();
+
+    // also rejects typos in profiles which are not selected
+    let command = Args::cli();
+    for options in std::iter::once(&config.options).chain(config.profile.values()) {
+        if let Some(key) = config
+            .normalized(options)?
+            .into_keys()
+            .find(|key| option(&command, key).is_none())
+        {
+            return Err(Error::Conversion(format!(
+                "unknown option `{}` in {:?}",
+                key, config.path
+            )));
+        }
+    }
+
+    Ok(config)
+}
+
+/// Returns the argument with the long name `key`, unless it selects the config itself.
+fn option<'a>(command: &'a clap::Command, key: &str) -> Option<&'a clap::Arg> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key) && !matches!(key, "config" | "profile"))
+}
+
+/// Makes the options of `config` and its `profile` the defaults of `command`, so they apply
+/// unless the option is passed on the command line or via its environment variable.
+fn with_defaults(
+    mut command: clap::Command,
+    config: &Config,
+    profile: Option<&str>,
+) -> Result<clap::Command> {
+    for (key, value) in config.options(profile)? {
+        let invalid = |err: &str| {
+            Error::Conversion(format!(
+                "invalid option `{}` in {:?}: {}",
+                key, config.path, err
+            ))
+        };
+        let Some(arg) = option(&command, &key) else {
+            return Err(invalid("unknown option"));
+        };
+        let scalar = |value: &toml::Value| match value {
+            toml::Value::String(value) => Ok(value.clone()),
+            toml::Value::Integer(value) => Ok(value.to_string()),
+            toml::Value::Float(value) => Ok(value.to_string()),
+            toml::Value::Boolean(value) => Ok(value.to_string()),
+            _ => Err(invalid("expected a string, number or boolean")),
+        };
+        let defaults = match (arg.get_action(), value) {
+            (ArgAction::SetTrue | ArgAction::SetFalse, toml::Value::Boolean(set)) => {
+                vec![set.to_string()]
+            }
+            (ArgAction::SetTrue | ArgAction::SetFalse, _) => {
+                return Err(invalid("expected `true` or `false`"))
+            }
+            (ArgAction::Append, toml::Value::Array(values)) => {
+                values.iter().map(scalar).collect::<Result<_>>()?
+            }
+            (ArgAction::Set | ArgAction::Append, value) => vec![scalar(value)?],
+            _ => return Err(invalid("unsupported value for this option")),
+        };
+        let id = arg.get_id().clone();
+        command = command.mut_arg(id, |arg| arg.default_values(defaults));
+    }
+
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(argv: &[&str], config: &str) -> Result<Args> {
+        let matches = Args::cli().get_matches_from(argv);
+        let profile = matches.get_one::<String>("profile").map(String::as_str);
+        let command = with_defaults(
+            Args::cli(),
+            &parse(config, Path::new("vaultify.toml"))?,
+            profile,
+        )?;
+        Ok(Args::from_matches(&command.get_matches_from(argv)).unwrap())
+    }
+
+    #[test]
+    fn pass_config() {
+        let config = r#"
+            # shared by all services
+            host = [
+                "https://vault-a:8200",
+                "https://vault-b:8200", # standby
+            ]
+            retries = 5
+            retry_delay_ms = 1_000 # ms
+            tls-server-name = 'vault.internal'
+            clear-env = true
+            require-tls = false
+            secrets-file = "/etc/app/.secrets"
+        "#;
+
+        let args = args(&["vaultify", "--retries", "1", "ls"], config).unwrap();
+        assert_eq!(args.host, ["https://vault-a:8200", "https://vault-b:8200"]);
+        assert_eq!(args.retries, 1);
+        assert_eq!(args.retry_delay_ms, 1000);
+        assert_eq!(args.tls_server_name.as_deref(), Some("vault.internal"));
+        assert!(args.clear_env);
+        assert!(!args.require_tls);
+        assert_eq!(args.secrets_file, PathBuf::from("/etc/app/.secrets"));
+        assert_eq!(args.cmd, ["ls"]);
+    }
+
+    #[test]
+    fn pass_profile() {
+        let config = r#"
+            host = "https://vault:8200"
+            retries = 5
+
+            [profile.staging] # staging cluster
+            host = "https://vault.staging:8200"
+            namespace = "staging"
+            auth-provider = "kubernetes"
+            kubernetes-role = "app"
+            secrets-file = ".secrets.staging"
+        "#;
+
+        let staging = args(&["vaultify", "--profile", "staging", "ls"], config).unwrap();
+        assert_eq!(staging.host, ["https://vault.staging:8200"]);
+        assert_eq!(staging.namespace.as_deref(), Some("staging"));
+        assert_eq!(staging.kubernetes_role.as_deref(), Some("app"));
+        assert_eq!(staging.retries, 5);
+        assert_eq!(staging.secrets_file, PathBuf::from(".secrets.staging"));
+
+        // the options are global, so the defaults also apply after a subcommand
+        let env = args(&["vaultify", "--profile", "staging", "env"], config).unwrap();
+        assert_eq!(env.host, ["https://vault.staging:8200"]);
+        assert!(env.subcommand.is_some());
+
+        let default = args(&["vaultify", "ls"], config).unwrap();
+        assert_eq!(default.host, ["https://vault:8200"]);
+        assert_eq!(default.namespace, None);
+
+        assert!(args(&["vaultify", "--profile", "prod", "ls"], config).is_err());
+    }
+
+    #[test]
+    fn fail_config() {
+        for config in [
+            "unknown = 1",
+            "retries = [1, 2]",
+            "clear-env = 1",
+            "clear-env = \"true\"",
+            "host = \"unterminated",
+            "host = [\"a\",",
+            "[vault.staging]",
+            "[profile.a]\n[profile.a]",
+            "[profile.a]\nprofile = \"b\"",
+            "retries = 1 2",
+            "retries = 1\nretries = 2",
+            "retry-delay-ms = 1\nretry_delay_ms = 2",
+            "retries = 1\n[profile.a]\nretires = 2",
+        ] {
+            assert!(args(&["vaultify", "ls"], config).is_err(), "{config}");
+        }
+    }
+}
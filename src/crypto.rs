@@ -0,0 +1,141 @@
+//! Symmetric encryption of secrets stored on disk.
+use std::num::NonZeroU32;
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest, hkdf, hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::error::{Error, Result};
+
+/// Derives an AES-256-GCM key from `key_material`, `salt` separates keys of different uses.
+pub fn derive_key(salt: &[u8], key_material: &[u8]) -> Result<LessSafeKey> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(key_material);
+    let okm = prk
+        .expand(&[], &AES_256_GCM)
+        .map_err(|_| Error::Execution("unable to derive key".to_string()))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Derives an AES-256-GCM key from a human chosen `passphrase` with PBKDF2-HMAC-SHA256, which
+/// makes guessing it expensive; `salt` has to be random.
+pub fn derive_passphrase_key(
+    salt: &[u8],
+    iterations: NonZeroU32,
+    passphrase: &[u8],
+) -> Result<LessSafeKey> {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase,
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| Error::Execution("unable to derive key".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// `len` random bytes, e.g. a salt.
+pub fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Execution("unable to generate random bytes".to_string()))?;
+    Ok(bytes)
+}
+
+/// Uniformly distributed random number in `[0, 1)`, e.g. to spread timers of many instances.
+pub fn random_fraction() -> Result<f64> {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Execution("unable to generate random number".to_string()))?;
+    // the 53 upper bits fill the mantissa of an f64 exactly
+    Ok((u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+/// Random id of 16 lowercase hex characters, e.g. to correlate the requests of a run.
+pub fn random_id() -> Result<String> {
+    Ok(hex(&random_bytes(8)?))
+}
+
+/// Encrypts `plaintext` with a random nonce into `nonce || ciphertext || tag`.
+pub fn seal(key: &LessSafeKey, mut plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Error::Execution("unable to generate nonce".to_string()))?;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut plaintext,
+    )
+    .map_err(|_| Error::Execution("unable to encrypt".to_string()))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.append(&mut plaintext);
+    Ok(sealed)
+}
+
+/// Decrypts the output of `seal`, failing if it was encrypted with a different key.
+pub fn open(key: &LessSafeKey, mut sealed: Vec<u8>) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::deserialization("encrypted data is truncated"));
+    }
+    let mut ciphertext = sealed.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed)
+        .map_err(|_| Error::deserialization("invalid nonce"))?;
+    let len = key
+        .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .map_err(|_| {
+            Error::deserialization("unable to decrypt, the data was encrypted with a different key")
+        })?
+        .len();
+
+    ciphertext.truncate(len);
+    Ok(ciphertext)
+}
+
+/// SHA-256 digest of `data` as lowercase hex.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+/// HMAC-SHA256 of `data` keyed with `key` as lowercase hex.
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hex(hmac::sign(&key, data).as_ref())
+}
+
+/// Encodes `bytes` as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_seal_open() {
+        let key = derive_key(b"test", b"material").unwrap();
+        let sealed = seal(&key, b"s3cr3t".to_vec()).unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"s3cr3t"));
+        assert_eq!(open(&key, sealed.clone()).unwrap(), b"s3cr3t");
+
+        assert!(open(&derive_key(b"test", b"other").unwrap(), sealed.clone()).is_err());
+        assert!(open(&derive_key(b"other", b"material").unwrap(), sealed).is_err());
+        assert!(open(&key, vec![0; 4]).is_err());
+    }
+
+    #[test]
+    fn pass_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"hello"),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Detaching from the invoking process, managing detached instances via pidfiles and locking out
+//! concurrent instances.
+use std::{
+    fs::File,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    sys::signal::{kill, Signal},
+    unistd::{fork, setsid, ForkResult, Pid},
+};
+
+use crate::error::{Error, Result};
+
+/// Interval in which `stop` checks whether the detached process exited.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval in which `lock` retries taking a lock held by another instance.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exit codes of `status`, as defined by the LSB for init scripts.
+const STATUS_RUNNING: i32 = 0;
+const STATUS_DEAD_WITH_PIDFILE: i32 = 1;
+const STATUS_NOT_RUNNING: i32 = 3;
+
+/// Takes an exclusive lock on `path`, which is created if missing, waiting up to `wait` while
+/// another instance holds it.
+///
+/// # Remarks:
+///
+/// The lock is held until the returned lock is dropped or the process exits. As the file is
+/// opened close-on-exec, executing the command releases it as well.
+pub fn lock(path: &Path, wait: Duration) -> Result<Flock<File>> {
+    let deadline = Instant::now() + wait;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| Error::io_at("unable to open lock file", path, err))?;
+
+    let mut waiting = false;
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => return Ok(lock),
+            Err((unlocked, Errno::EWOULDBLOCK)) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::Execution(format!(
+                        "lock file {} is held by another instance",
+                        path.display()
+                    )));
+                }
+                if !waiting {
+                    tracing::info!(
+                        "lock file {} is held by another instance, waiting up to {:?}",
+                        path.display(),
+                        wait
+                    );
+                    waiting = true;
+                }
+                file = unlocked;
+                std::thread::sleep((deadline - now).min(LOCK_POLL_INTERVAL));
+            }
+            Err((_, err)) => return Err(Error::io_at("unable to lock file", path, err)),
+        }
+    }
+}
+
+/// Forks into the background and returns in the detached process only.
+///
+/// The invoking process writes the pid of the detached process to `pidfile` and exits. The
+/// detached process starts a new session and reads stdin from `/dev/null`; stdout and stderr are
+/// kept, so their redirection is left to the caller.
+///
+/// # Remarks:
+///
+/// Must be called while the process is single threaded, i.e. without a running tokio runtime.
+pub fn detach(pidfile: Option<&Path>) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    crate::process::ensure_single_threaded_process("fork")?;
+
+    // SAFETY: the process is single threaded, so the child can safely continue after the fork.
+    match unsafe { fork() }.map_err(|err| Error::Execution(format!("unable to fork: {}", err)))? {
+        ForkResult::Parent { child } => {
+            if let Some(pidfile) = pidfile {
+                if let Err(err) = write_pidfile(pidfile, child) {
+                    let _ = kill(child, Signal::SIGKILL);
+                    return Err(err);
+                }
+            }
+            tracing::info!("detached with pid {}", child);
+            std::process::exit(0);
+        }
+        ForkResult::Child => {}
+    }
+
+    setsid().map_err(|err| Error::Execution(format!("unable to start new session: {}", err)))?;
+    let devnull = std::fs::File::open("/dev/null")
+        .map_err(|err| Error::io("unable to open /dev/null", err))?;
+    nix::unistd::dup2(devnull.as_raw_fd(), nix::libc::STDIN_FILENO)
+        .map_err(|err| Error::Execution(format!("unable to redirect stdin: {}", err)))?;
+
+    Ok(())
+}
+
+fn write_pidfile(path: &Path, pid: Pid) -> Result<()> {
+    std::fs::write(path, format!("{}\n", pid))
+        .map_err(|err| Error::io_at("unable to write pidfile", path, err))
+}
+
+/// Reads the pid from `path`, returning `None` if the file does not exist.
+fn read_pidfile(path: &Path) -> Result<Option<Pid>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::io_at("unable to read pidfile", path, err)),
+    };
+
+    parse_pid(&contents).map(Some)
+}
+
+fn parse_pid(contents: &str) -> Result<Pid> {
+    match contents.trim().parse::<i32>() {
+        Ok(pid) if pid > 0 => Ok(Pid::from_raw(pid)),
+        _ => Err(Error::Conversion(format!(
+            "invalid pid `{}` in pidfile",
+            contents.trim()
+        ))),
+    }
+}
+
+/// Whether a process with the given pid exists.
+fn is_running(pid: Pid) -> bool {
+    !matches!(kill(pid, None), Err(Errno::ESRCH))
+}
+
+/// Prints whether the process in `pidfile` is running and returns the matching exit code.
+pub fn status(pidfile: &Path) -> Result<i32> {
+    match read_pidfile(pidfile)? {
+        Some(pid) if is_running(pid) => {
+            println!("running (pid {})", pid);
+            Ok(STATUS_RUNNING)
+        }
+        Some(pid) => {
+            println!("not running (stale pidfile with pid {})", pid);
+            Ok(STATUS_DEAD_WITH_PIDFILE)
+        }
+        None => {
+            println!("not running");
+            Ok(STATUS_NOT_RUNNING)
+        }
+    }
+}
+
+/// Sends `signal` to the process in `pidfile`, waits up to `timeout` for it to exit and removes
+/// the pidfile.
+pub fn stop(pidfile: &Path, signal: Signal, timeout: Duration) -> Result<i32> {
+    let pid = read_pidfile(pidfile)?
+        .ok_or_else(|| Error::NotFound(format!("pidfile {:?} does not exist", pidfile)))?;
+
+    match kill(pid, signal) {
+        Ok(()) => {}
+        Err(Errno::ESRCH) => {
+            tracing::warn!("process {} is not running, removing stale pidfile", pid);
+            remove_pidfile(pidfile)?;
+            return Ok(0);
+        }
+        Err(err) => {
+            return Err(Error::Execution(format!(
+                "unable to send {} to {}: {}",
+                signal, pid, err
+            )))
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    while is_running(pid) {
+        if Instant::now() >= deadline {
+            return Err(Error::Execution(format!(
+                "process {} did not exit within {:?}",
+                pid, timeout
+            )));
+        }
+        std::thread::sleep(STOP_POLL_INTERVAL);
+    }
+    remove_pidfile(pidfile)?;
+
+    Ok(0)
+}
+
+fn remove_pidfile(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Error::io_at("unable to remove pidfile", path, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_parse_pid() {
+        assert_eq!(parse_pid("1234\n").unwrap(), Pid::from_raw(1234));
+    }
+
+    #[test]
+    fn fail_parse_pid() {
+        assert!(parse_pid("").is_err());
+        assert!(parse_pid("0").is_err());
+        assert!(parse_pid("-1").is_err());
+        assert!(parse_pid("abc").is_err());
+    }
+
+    #[test]
+    fn pass_lock() {
+        let path = std::env::temp_dir().join(format!("vaultify-lock-{}", std::process::id()));
+        let lock = super::lock(&path, Duration::ZERO).unwrap();
+        let started = Instant::now();
+        assert!(super::lock(&path, Duration::from_millis(250)).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(250));
+
+        drop(lock);
+        assert!(super::lock(&path, Duration::ZERO).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
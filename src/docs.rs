@@ -0,0 +1,126 @@
+//! Reference documentation of the `docs` subcommand, generated from the clap command so it stays
+//! in sync with the options.
+use clap::Command;
+use clap_mangen::Man;
+use roff::{roman, Roff};
+
+use crate::{
+    error::{Error, Result},
+    secrets,
+};
+
+/// Renders the reference as man pages in section 1, the page of `command` followed by one page
+/// per subcommand, e.g. `vaultify-fetch.1`, as file name and contents.
+pub fn man(command: Command) -> Result<Vec<(String, String)>> {
+    let mut command = reference(command);
+    // names the pages of subcommands e.g. `vaultify-fetch`
+    command.build();
+    let man = Man::new(command.clone());
+    let mut page = Vec::new();
+    let rendered = (|| {
+        man.render_title(&mut page)?;
+        man.render_name_section(&mut page)?;
+        man.render_synopsis_section(&mut page)?;
+        man.render_description_section(&mut page)?;
+        man.render_options_section(&mut page)?;
+        man.render_subcommands_section(&mut page)?;
+        // instead of the `EXTRA` section of the long help, which only holds the grammar
+        let mut grammar = Roff::new();
+        grammar.control("SH", ["SECRETS FILE"]).control("nf", []);
+        for line in secrets::GRAMMAR.lines() {
+            grammar.text([roman(line)]);
+        }
+        grammar.control("fi", []).to_writer(&mut page)?;
+        man.render_version_section(&mut page)
+    })();
+    rendered.map_err(|err| Error::io("unable to render man page", err))?;
+    let mut pages = vec![(
+        man.get_filename(),
+        String::from_utf8_lossy(&page).into_owned(),
+    )];
+
+    let source = format!(
+        "{} {}",
+        command.get_name(),
+        command.get_version().unwrap_or_default()
+    );
+    for sub in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let man = Man::new(sub.clone()).source(&source);
+        let mut page = Vec::new();
+        man.render(&mut page)
+            .map_err(|err| Error::io("unable to render man page", err))?;
+        pages.push((
+            man.get_filename(),
+            String::from_utf8_lossy(&page).into_owned(),
+        ));
+    }
+    Ok(pages)
+}
+
+/// Renders the reference as markdown.
+pub fn markdown(command: Command) -> String {
+    clap_markdown::help_markdown_command(&reference(command))
+}
+
+/// Returns `command` without the generated `help` subcommand, where subcommands only list their
+/// own options, as the options shared by all are listed with the command itself.
+fn reference(command: Command) -> Command {
+    let mut command = command.disable_help_subcommand(true);
+    let globals = command
+        .get_arguments()
+        .filter(|arg| arg.is_global_set())
+        .map(|arg| arg.clone().hide(true))
+        .collect::<Vec<_>>();
+    let names = command
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_string())
+        .collect::<Vec<_>>();
+    for name in names {
+        command = command.mut_subcommand(name, |sub| {
+            let inherited = globals
+                .iter()
+                .filter(|arg| !sub.get_arguments().any(|own| own.get_id() == arg.get_id()))
+                .cloned()
+                .collect::<Vec<_>>();
+            sub.args(inherited)
+        });
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+
+    #[test]
+    fn pass_docs() {
+        let pages = man(Args::cli()).unwrap();
+        let (name, page) = &pages[0];
+        assert_eq!(name, "vaultify.1");
+        assert!(page.contains(".TH vaultify 1"));
+        assert!(page.contains("\\fB\\-c\\fR, \\fB\\-\\-shell\\fR \\fI<SHELL>\\fR\n"));
+        assert!(page.contains("\\fBVAULT_ADDR\\fR environment variable"));
+        assert!(page.contains("vaultify\\-fetch(1)"));
+        assert!(page.contains(".SH \"SECRETS FILE\"\n.nf\n"));
+        assert!(!page.contains(".SH EXTRA"));
+
+        // one page per subcommand, with only its own options
+        let (_, fetch) = pages
+            .iter()
+            .find(|(name, _)| name == "vaultify-fetch.1")
+            .unwrap();
+        assert!(fetch.contains("\\fB\\-\\-format\\fR \\fI<FORMAT>\\fR [default: dotenv]"));
+        assert!(!fetch.contains("\\-\\-shell"));
+        assert!(!pages.iter().any(|(name, _)| name == "vaultify-help.1"));
+
+        let markdown = markdown(Args::cli());
+        assert!(markdown.contains("\n## `vaultify fetch`\n"));
+        assert!(markdown.contains("**Usage:** `vaultify fetch [OPTIONS]`"));
+        assert!(markdown.contains(
+            "* `--clear-env` — Clear the environment of the spawned process before spawning\n"
+        ));
+        assert!(markdown.contains("Secrets file:\nEach line maps one source"));
+        assert_eq!(markdown.matches("`--clear-env`").count(), 1);
+    }
+}
@@ -0,0 +1,162 @@
+//! Parsing of dotenv files imported into vault by the `import` subcommand or passed to the command
+//! with `--env-file`.
+use std::path::Path;
+
+use crate::{
+    error::{Error, Result},
+    secrets::is_valid_env_var_name,
+};
+
+/// Shown in parse errors instead of lines which may contain a value.
+const REDACTED: &str = "<redacted>";
+
+/// Loads the `NAME=value` pairs of a dotenv file in their order, `-` reads it from stdin.
+pub async fn load(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        tokio::fs::read_to_string(path).await
+    }
+    .map_err(|err| Error::io_at("unable to read file", path, err))?;
+    parse(&contents).map_err(|err| err.in_file(path))
+}
+
+/// Parses all lines, failing with every invalid line at once.
+///
+/// # Remarks:
+///
+/// Values in double quotes may contain escapes (`\n`, `\r`, `\t`, `\\`, `\"`, `\$`), values in
+/// single quotes are taken literally, and quoted values may span lines. Variables in values are
+/// not expanded. Errors only show the name of a variable, never its value.
+fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::<(String, String)>::new();
+    let mut errors = Vec::new();
+
+    let mut lines = contents.lines().enumerate();
+    while let Some((lc, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let Some((name, raw)) = line.split_once('=') else {
+            errors.push(Error::parse("expected `NAME=value`", lc, REDACTED));
+            continue;
+        };
+        let name = name.trim_end();
+
+        // quoted values continue on the following lines until the closing quote
+        let mut raw = raw.trim_start().to_string();
+        let value = loop {
+            match parse_value(&raw) {
+                Ok(Some(value)) => break Ok(value),
+                Ok(None) => match lines.next() {
+                    Some((_, next)) => {
+                        raw.push('\n');
+                        raw.push_str(next);
+                    }
+                    None => break Err("unterminated quote"),
+                },
+                Err(err) => break Err(err),
+            }
+        };
+
+        let err = match value {
+            Err(err) => err,
+            Ok(_) if !is_valid_env_var_name(name) => "invalid variable name",
+            Ok(_) if pairs.iter().any(|(other, _)| other == name) => "duplicate variable",
+            Ok(value) => {
+                pairs.push((name.to_string(), value));
+                continue;
+            }
+        };
+        errors.push(Error::parse(err, lc, name));
+    }
+
+    match errors.len() {
+        0 => Ok(pairs),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::ParseErrors(errors)),
+    }
+}
+
+/// Unquotes a value, or returns `None` if its quote is not closed yet.
+fn parse_value(raw: &str) -> std::result::Result<Option<String>, &'static str> {
+    let mut chars = raw.chars();
+    let quote = match chars.next() {
+        Some(quote @ ('"' | '\'')) => quote,
+        // unquoted values end at a comment
+        _ => {
+            let end = raw
+                .char_indices()
+                .find(|&(idx, c)| c == '#' && raw[..idx].ends_with(char::is_whitespace))
+                .map_or(raw.len(), |(idx, _)| idx);
+            return Ok(Some(raw[..end].trim_end().to_string()));
+        }
+    };
+
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == quote => {
+                let rest = chars.as_str().trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err("unexpected characters after the closing quote");
+                }
+                return Ok(Some(value));
+            }
+            '\\' if quote == '"' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('\\' | '"' | '$')) => value.push(c),
+                Some(c) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => return Ok(None),
+            },
+            c => value.push(c),
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_parse() {
+        let pairs = parse(
+            "# database\nexport DB_USER=app # owner\nDB_PASS=\"p@ss \\\"quoted\\\"\\n\"\n\nKEY='-----BEGIN KEY-----\nabc\n-----END KEY-----'\nEMPTY=\nURL=http://host/#anchor\n",
+        )
+        .unwrap();
+        let expected = [
+            ("DB_USER", "app"),
+            ("DB_PASS", "p@ss \"quoted\"\n"),
+            ("KEY", "-----BEGIN KEY-----\nabc\n-----END KEY-----"),
+            ("EMPTY", ""),
+            ("URL", "http://host/#anchor"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn fail_parse() {
+        let err = parse("A=1\nA=2\nnot a pair\n1B=x\nC=\"open\nD=4").unwrap_err();
+        let Error::ParseErrors(errors) = err else {
+            panic!("expected all parse errors, got {:?}", err);
+        };
+        let lines = errors
+            .iter()
+            .map(|err| match err {
+                Error::Parse { lc, line, .. } => (*lc, line.as_str()),
+                err => panic!("expected a parse error, got {:?}", err),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(2, "A"), (3, REDACTED), (4, "1B"), (5, "C")]);
+    }
+}
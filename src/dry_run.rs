@@ -0,0 +1,111 @@
+//! `--dry-run`: lists what would be injected into the command without spawning it.
+use std::collections::HashMap;
+
+use crate::secrets::{Secret, SecretSpecs, SecretTarget};
+
+/// How far a dry run goes before printing the secrets.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum DryRun {
+    /// Only parse the secrets file.
+    Parse,
+    /// Also fetch the secrets, to check they resolve and pass their checksums.
+    Fetch,
+}
+
+/// Lists every target with its source and, if fetched, a redacted value.
+pub fn report(specs: &SecretSpecs, secrets: Option<&[Secret]>) -> String {
+    let values = secrets
+        .unwrap_or_default()
+        .iter()
+        .map(|secret| (secret.target.name(), secret.secret.chars().count()))
+        .collect::<HashMap<_, _>>();
+    let rows = specs
+        .values()
+        .map(|spec| {
+            let target = match &spec.target {
+                SecretTarget::Env { name } => format!("env {}", name),
+                SecretTarget::EnvFile { path, name } => {
+                    format!("env {} in {}", name, path.display())
+                }
+                SecretTarget::File { path, mode, .. } => {
+                    format!("file {} (mode {:o})", path.display(), mode)
+                }
+            };
+            let value = match (secrets, values.get(&spec.name())) {
+                (None, _) => "not fetched".to_string(),
+                (Some(_), Some(len)) => format!("[redacted, {} chars]", len),
+                (Some(_), None) => "missing".to_string(),
+            };
+            let mut source = match spec.sha256 {
+                Some(_) => format!("{} !sha256", spec.source()),
+                None => spec.source(),
+            };
+            for encoder in &spec.encoders {
+                source.push_str(" | ");
+                source.push_str(encoder.name());
+            }
+            (target, source, value)
+        })
+        .collect::<Vec<_>>();
+
+    let target_width = rows.iter().map(|row| row.0.len()).max().unwrap_or_default();
+    let source_width = rows.iter().map(|row| row.1.len()).max().unwrap_or_default();
+    rows.into_iter()
+        .map(|(target, source, value)| {
+            format!(
+                "{:<target_width$}  {:<source_width$}  {}\n",
+                target, source, value
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{Provider, SecretSpec};
+
+    #[test]
+    fn pass_report() {
+        let spec = |target, provider, path: &str, secret: &str| {
+            let mount = if provider == Provider::Vault {
+                "secret"
+            } else {
+                ""
+            };
+            SecretSpec {
+                provider,
+                ..SecretSpec::vault(target, mount, path, secret)
+            }
+        };
+        let api_key = SecretTarget::Env {
+            name: "API_KEY".to_string(),
+        };
+        let tls_key = SecretTarget::File {
+            path: "/run/tls.key".into(),
+            mode: 0o600,
+            create: false,
+            owner: None,
+            label: None,
+        };
+        let specs = [
+            spec(api_key.clone(), Provider::Vault, "app", "key"),
+            spec(tls_key, Provider::Sops, "tls.enc.yaml", "key"),
+        ]
+        .into_iter()
+        .map(|spec| (spec.name(), spec))
+        .collect::<SecretSpecs>();
+
+        assert_eq!(
+            report(&specs, None),
+            "env API_KEY                   vault:secret/app#key   not fetched\n\
+             file /run/tls.key (mode 600)  sops:tls.enc.yaml#key  not fetched\n"
+        );
+
+        let secrets = [Secret::new(api_key, "s3cr3t".to_string())];
+        let report = report(&specs, Some(&secrets));
+        assert!(!report.contains("s3cr3t"));
+        assert!(report.contains("[redacted, 6 chars]"));
+        assert!(report.contains("missing"));
+    }
+}
@@ -0,0 +1,95 @@
+//! Encoders applied to a resolved value before it is written to its target or into a template,
+//! selected with `| urlencode`, `| shellquote` or `| jsonescape` after the source.
+use std::fmt::Write;
+
+/// Escapes a value for the context it is embedded into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    /// Percent-encodes all but the unreserved characters of RFC 3986, e.g. for the password of a
+    /// connection string.
+    UrlEncode,
+    /// Quotes the value as a single word of a POSIX shell.
+    ShellQuote,
+    /// Escapes the value as the contents of a JSON string, without the quotes.
+    JsonEscape,
+}
+
+impl Encoder {
+    /// Names of the encoders, as shown in errors.
+    pub const NAMES: &'static str = "urlencode, shellquote or jsonescape";
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "urlencode" => Some(Encoder::UrlEncode),
+            "shellquote" => Some(Encoder::ShellQuote),
+            "jsonescape" => Some(Encoder::JsonEscape),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoder::UrlEncode => "urlencode",
+            Encoder::ShellQuote => "shellquote",
+            Encoder::JsonEscape => "jsonescape",
+        }
+    }
+
+    pub fn encode(&self, value: &str) -> String {
+        match self {
+            Encoder::UrlEncode => url_encode(value),
+            Encoder::ShellQuote => format!("'{}'", value.replace('\'', r#"'\''"#)),
+            Encoder::JsonEscape => {
+                let quoted = serde_json::Value::String(value.to_string()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+        }
+    }
+}
+
+/// Applies the `encoders` in order.
+pub fn encode_all(encoders: &[Encoder], value: &str) -> String {
+    encoders
+        .iter()
+        .fold(value.to_string(), |value, encoder| encoder.encode(&value))
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_encode() {
+        let password = r#"p@ss%w/rd "it's" ü"#;
+        assert_eq!(
+            Encoder::UrlEncode.encode(password),
+            "p%40ss%25w%2Frd%20%22it%27s%22%20%C3%BC"
+        );
+        assert_eq!(
+            Encoder::ShellQuote.encode(password),
+            r#"'p@ss%w/rd "it'\''s" ü'"#
+        );
+        assert_eq!(Encoder::ShellQuote.encode(""), "''");
+        assert_eq!(Encoder::JsonEscape.encode("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+        assert_eq!(
+            encode_all(&[Encoder::UrlEncode, Encoder::ShellQuote], "a b"),
+            "'a%20b'"
+        );
+    }
+}
@@ -1,42 +1,199 @@
 //! Error definitions
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde_json::Value;
 
 /// Library result type
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Library errors
+///
+/// The messages include the message of the source, so the error can be logged on a single line;
+/// the source is still available via `std::error::Error::source`.
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum Error {
-    #[error("IO error: {0}")]
-    IO(String),
+    #[error("IO error: {}", describe_io(.message, .path.as_deref(), .source.as_ref()))]
+    IO {
+        /// What failed, e.g. `unable to read file`.
+        message: String,
+        /// File the operation failed on.
+        path: Option<PathBuf>,
+        #[source]
+        source: Option<Shared<std::io::Error>>,
+    },
     #[error("Element not found: {0}")]
     NotFound(String),
-    #[error("Parse error: {err} (line {lc}: `{line}`)")]
+    #[error("Parse error: {err} (line {lc}{}: `{line}`)", describe_path(.path.as_deref()))]
     Parse {
         err: String,
         lc: usize,
         line: String,
+        /// File the line was read from, if known.
+        path: Option<PathBuf>,
     },
+    /// Several `Parse` errors of the same file, one per line.
+    #[error("{} parse errors:{}", .0.len(), describe_all(.0))]
+    ParseErrors(Vec<Error>),
     #[error("Conversion error: {0}")]
     Conversion(String),
-    #[error("Deserialization error: {0}")]
-    Deserialization(String),
+    #[error("Deserialization error: {message}")]
+    Deserialization {
+        message: String,
+        #[source]
+        source: Option<Shared<serde_json::Error>>,
+    },
     #[error("Max number of retries reached: {source}")]
     MaxRetries {
         #[source]
         source: Box<Error>,
     },
-    #[error("Reqwest error: {0}")]
-    Reqwest(String),
-    #[error("Transient reqwest error: {0}")]
-    ReqwestTransient(String),
+    #[error("Reqwest error: {message}")]
+    Reqwest {
+        message: String,
+        /// URL of the failed request.
+        url: Option<String>,
+        #[source]
+        source: Option<Shared<DynError>>,
+    },
+    #[error("Transient reqwest error: {message}")]
+    ReqwestTransient {
+        message: String,
+        /// URL of the failed request.
+        url: Option<String>,
+        #[source]
+        source: Option<Shared<DynError>>,
+    },
     #[error("HTTP status error ({code}) for {url}: {body}")]
     HttpStatus {
         code: u16,
         url: String,
         body: String,
+        /// Delay requested by the server via the `Retry-After` header.
+        retry_after: Option<std::time::Duration>,
+        /// Id vault assigned to the request, which it includes in some error responses.
+        request_id: Option<String>,
     },
     #[error("Execution error: {0}")]
     Execution(String),
+    /// The fetch did not complete within `--timeout`.
+    #[error("Timeout: {0}")]
+    Timeout(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Spawn error: unable to spawn {command}: {source}")]
+    Spawn {
+        /// The command, or the name of the process in a procfile.
+        command: String,
+        #[source]
+        source: Shared<std::io::Error>,
+    },
+    /// `source` annotated with the secrets it affects and the vault request which failed.
+    #[error("{source}")]
+    Context {
+        /// Names of the affected secrets.
+        secrets: Vec<String>,
+        /// Id vault assigned to the failed request.
+        request_id: Option<String>,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+fn describe_io(
+    message: &str,
+    path: Option<&Path>,
+    source: Option<&Shared<std::io::Error>>,
+) -> String {
+    let mut description = message.to_string();
+    if let Some(path) = path {
+        description = format!("{} {:?}", description, path)
+            .trim_start()
+            .to_string();
+    }
+    if let Some(source) = source {
+        if !description.is_empty() {
+            description.push_str(": ");
+        }
+        description.push_str(&source.to_string());
+    }
+    description
+}
+
+fn describe_all(errors: &[Error]) -> String {
+    errors.iter().map(|err| format!("\n  {}", err)).collect()
+}
+
+fn describe_path(path: Option<&Path>) -> String {
+    path.map(|path| format!(" of {:?}", path))
+        .unwrap_or_default()
+}
+
+/// Source of an error, shared by its clones.
+///
+/// The underlying errors are neither `Clone` nor `PartialEq`, so two sources are equal if they
+/// are the same error.
+#[derive(Debug)]
+pub struct Shared<E: ?Sized>(Arc<E>);
+
+/// Source of errors of different types, e.g. of the reqwest and the unix socket transport.
+pub type DynError = dyn std::error::Error + Send + Sync;
+
+impl Shared<DynError> {
+    pub fn new(err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+impl<E: ?Sized> Clone for Shared<E> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<E: ?Sized> PartialEq for Shared<E> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<E> From<E> for Shared<E> {
+    fn from(err: E) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+impl<E: std::fmt::Display + ?Sized> std::fmt::Display for Shared<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<E: std::error::Error + ?Sized> std::error::Error for Shared<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Format of the error reported when vaultify fails.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// The debug representation of the error.
+    Text,
+    /// One JSON object with `error` (the class), `exit_code`, `message`, `secrets`, `http_status`,
+    /// `url`, `path`, `request_id` and `correlation_id`, the latter five being null if unknown.
+    Json,
+}
+
+/// Secrets and vault request an error was caused by.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorContext {
+    /// Names of the affected secrets.
+    pub secrets: Vec<String>,
+    /// Id vault assigned to the failed request.
+    pub request_id: Option<String>,
 }
 
 impl Error {
@@ -47,29 +204,309 @@ impl Error {
             err: err.to_string(),
             lc: line_index + 1,
             line: line.to_string(),
+            path: None,
+        }
+    }
+
+    /// Shorthand to construct the Error::IO variant for an operation failing with `err`
+    #[inline]
+    pub fn io(message: &str, err: impl Into<std::io::Error>) -> Self {
+        Self::IO {
+            message: message.to_string(),
+            path: None,
+            source: Some(err.into().into()),
+        }
+    }
+
+    /// Shorthand to construct the Error::IO variant for an operation on `path` failing with `err`
+    #[inline]
+    pub fn io_at(message: &str, path: impl AsRef<Path>, err: impl Into<std::io::Error>) -> Self {
+        Self::IO {
+            message: message.to_string(),
+            path: Some(path.as_ref().to_path_buf()),
+            source: Some(err.into().into()),
+        }
+    }
+
+    /// Shorthand to construct the Error::Deserialization variant without source
+    #[inline]
+    pub fn deserialization(message: impl Into<String>) -> Self {
+        Self::Deserialization {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Shorthand to construct the Error::Deserialization variant for JSON which cannot be parsed
+    #[inline]
+    pub fn json(message: &str, err: serde_json::Error) -> Self {
+        Self::Deserialization {
+            message: format!("{}: {}", message, err),
+            source: Some(err.into()),
+        }
+    }
+
+    /// Shorthand to construct the Error::ReqwestTransient variant for a request to `url` which
+    /// failed with `err`
+    #[inline]
+    pub fn transient(
+        message: &str,
+        url: &str,
+        err: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::ReqwestTransient {
+            message: format!("{}: {}", message, err),
+            url: Some(url.to_string()),
+            source: Some(Shared::new(err)),
+        }
+    }
+
+    /// Annotates the error with the names of the secrets it affects.
+    pub fn for_secrets(self, names: &[String]) -> Self {
+        self.with_context(names, None)
+    }
+
+    /// Annotates the error with the id vault assigned to the failed request.
+    pub fn for_request(self, request_id: &str) -> Self {
+        self.with_context(&[], Some(request_id))
+    }
+
+    fn with_context(self, names: &[String], id: Option<&str>) -> Self {
+        let (mut secrets, request_id, source) = match self {
+            Error::Context {
+                secrets,
+                request_id,
+                source,
+            } => (secrets, request_id, source),
+            err => (Vec::new(), None, Box::new(err)),
+        };
+        for name in names {
+            if !secrets.contains(name) {
+                secrets.push(name.clone());
+            }
+        }
+        Error::Context {
+            secrets,
+            request_id: request_id.or_else(|| id.map(ToString::to_string)),
+            source,
         }
     }
+
+    /// Sets the file a parse error occurred in, other errors are returned as they are.
+    pub fn in_file(self, file: &Path) -> Self {
+        match self {
+            Error::Parse { err, lc, line, .. } => Error::Parse {
+                err,
+                lc,
+                line,
+                path: Some(file.to_path_buf()),
+            },
+            Error::ParseErrors(errors) => {
+                Error::ParseErrors(errors.into_iter().map(|err| err.in_file(file)).collect())
+            }
+            err => err,
+        }
+    }
+
+    /// Stable name of the kind of error, e.g. `http_status`.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Error::IO { .. } => "io",
+            Error::NotFound(_) => "not_found",
+            Error::Parse { .. } | Error::ParseErrors(_) => "parse",
+            Error::Conversion(_) => "conversion",
+            Error::Deserialization { .. } => "deserialization",
+            Error::MaxRetries { .. } => "max_retries",
+            Error::Reqwest { .. } => "reqwest",
+            Error::ReqwestTransient { .. } => "reqwest_transient",
+            Error::HttpStatus { .. } => "http_status",
+            Error::Execution(_) => "execution",
+            Error::Timeout(_) => "timeout",
+            Error::PermissionDenied(_) => "permission_denied",
+            Error::Spawn { .. } => "spawn",
+            Error::Context { source, .. } => source.class(),
+        }
+    }
+
+    /// Exit code of vaultify failing with this error:
+    ///
+    /// - 2: the secrets or configuration file cannot be parsed
+    /// - 3: vault rejected the login
+    /// - 4: a secret does not exist
+    /// - 5: the token may not read a secret
+    /// - 6: the command cannot be spawned
+    /// - 7: vault is unreachable, sealed or still failing after all retries
+    /// - 8: the secrets were not fetched within `--timeout`
+    /// - 1: any other error
+    pub fn exit_code(&self) -> i32 {
+        match self.innermost() {
+            Error::Parse { .. } | Error::ParseErrors(_) => 2,
+            Error::HttpStatus { code, url, .. }
+                if (400..500).contains(code) && url.contains("/v1/auth/") =>
+            {
+                3
+            }
+            Error::NotFound(_) => 4,
+            Error::HttpStatus { code: 404, .. } => 4,
+            Error::PermissionDenied(_) | Error::HttpStatus { code: 403, .. } => 5,
+            Error::Spawn { .. } => 6,
+            Error::ReqwestTransient { .. } => 7,
+            Error::HttpStatus { code, .. } if matches!(code, 412 | 429) || *code >= 500 => 7,
+            Error::Timeout(_) => 8,
+            Error::IO { .. }
+            | Error::Conversion(_)
+            | Error::Deserialization { .. }
+            | Error::MaxRetries { .. }
+            | Error::Reqwest { .. }
+            | Error::HttpStatus { .. }
+            | Error::Execution(_)
+            | Error::Context { .. } => 1,
+        }
+    }
+
+    /// HTTP status code of the response which failed, also after retries.
+    pub fn http_status(&self) -> Option<u16> {
+        match self.innermost() {
+            Error::HttpStatus { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// File the error occurred in, e.g. the secrets file with a parse error.
+    pub fn path(&self) -> Option<&Path> {
+        match self.innermost() {
+            Error::IO { path, .. } | Error::Parse { path, .. } => path.as_deref(),
+            Error::ParseErrors(errors) => errors.first()?.path(),
+            _ => None,
+        }
+    }
+
+    /// URL of the request which failed.
+    pub fn url(&self) -> Option<&str> {
+        match self.innermost() {
+            Error::HttpStatus { url, .. } => Some(url),
+            Error::Reqwest { url, .. } | Error::ReqwestTransient { url, .. } => url.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Secrets and vault request which caused this error (or the errors it wraps), as far as
+    /// they were annotated.
+    pub fn context(&self) -> ErrorContext {
+        let mut context = ErrorContext::default();
+        let mut err = self;
+        loop {
+            err = match err {
+                Error::Context {
+                    secrets,
+                    request_id,
+                    source,
+                } => {
+                    for secret in secrets {
+                        if !context.secrets.contains(secret) {
+                            context.secrets.push(secret.clone());
+                        }
+                    }
+                    if context.request_id.is_none() {
+                        context.request_id.clone_from(request_id);
+                    }
+                    source
+                }
+                Error::MaxRetries { source } => source,
+                Error::HttpStatus { request_id, .. } => {
+                    if context.request_id.is_none() {
+                        context.request_id.clone_from(request_id);
+                    }
+                    break;
+                }
+                _ => break,
+            };
+        }
+        context
+    }
+
+    /// The error which was retried until giving up or annotated, or this error itself.
+    fn innermost(&self) -> &Error {
+        match self {
+            Error::MaxRetries { source } | Error::Context { source, .. } => source.innermost(),
+            err => err,
+        }
+    }
+
+    /// Structured representation of the error for `--error-format json`.
+    pub fn to_json(&self) -> Value {
+        let context = self.context();
+        serde_json::json!({
+            "error": self.class(),
+            "exit_code": self.exit_code(),
+            "message": self.to_string(),
+            "secrets": context.secrets,
+            "http_status": self.http_status(),
+            "url": self.url(),
+            "path": self.path(),
+            "request_id": context.request_id,
+            "correlation_id": crate::logging::correlation_id(),
+        })
+    }
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
-        Error::IO(err.to_string())
+        Error::IO {
+            message: String::new(),
+            path: None,
+            source: Some(err.into()),
+        }
     }
 }
 
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
-        if value.is_timeout() || value.is_connect() {
-            return Error::ReqwestTransient(value.to_string());
+        let message = value.to_string();
+        let url = value.url().map(ToString::to_string);
+        if value.is_timeout() || value.is_connect() || is_connection_lost(&value) {
+            return Error::ReqwestTransient {
+                message,
+                url,
+                source: Some(Shared::new(value)),
+            };
         }
 
-        Error::Reqwest(value.to_string())
+        Error::Reqwest {
+            message,
+            url,
+            source: Some(Shared::new(value)),
+        }
+    }
+}
+
+/// Whether the error was caused by the connection being closed or reset by the peer.
+fn is_connection_lost(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        source = err.source();
     }
+
+    false
 }
 
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
-        Error::Deserialization(value.to_string())
+        Error::Deserialization {
+            message: value.to_string(),
+            source: Some(value.into()),
+        }
     }
 }
 
@@ -78,3 +515,88 @@ impl From<std::ffi::NulError> for Error {
         Error::Conversion(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_to_json() {
+        let forbidden = Error::HttpStatus {
+            code: 403,
+            url: "https://vault.example/v1/secret/data/json-test".to_string(),
+            body: r#"{"errors": ["permission denied"], "request_id": "r-1"}"#.to_string(),
+            retry_after: None,
+            request_id: Some("r-1".to_string()),
+        };
+        let err = Error::MaxRetries {
+            source: Box::new(forbidden),
+        }
+        .for_secrets(&["API_KEY".to_string()]);
+
+        let json = err.to_json();
+        assert_eq!(json["error"], "max_retries");
+        assert_eq!(
+            json["message"],
+            "Max number of retries reached: HTTP status error (403) for https://vault.example/v1/secret/data/json-test: {\"errors\": [\"permission denied\"], \"request_id\": \"r-1\"}"
+        );
+        assert_eq!(json["http_status"], 403);
+        assert_eq!(json["secrets"], serde_json::json!(["API_KEY"]));
+        assert_eq!(json["request_id"], "r-1");
+
+        assert_eq!(json["exit_code"], 5);
+
+        let json = Error::Execution("json-test".to_string()).to_json();
+        assert_eq!(json["secrets"], serde_json::json!([]));
+        assert_eq!(json["request_id"], Value::Null);
+    }
+
+    #[test]
+    fn pass_exit_code() {
+        let status = |code, path: &str| Error::HttpStatus {
+            code,
+            url: format!("https://vault.example/v1/{}", path),
+            body: String::new(),
+            retry_after: None,
+            request_id: None,
+        };
+        assert_eq!(Error::parse("invalid", 0, "x").exit_code(), 2);
+        assert_eq!(status(400, "auth/kubernetes/login").exit_code(), 3);
+        assert_eq!(status(404, "secret/data/app").exit_code(), 4);
+        assert_eq!(Error::NotFound("key".to_string()).exit_code(), 4);
+        assert_eq!(status(403, "secret/data/app").exit_code(), 5);
+        let spawn = Error::Spawn {
+            command: "app".to_string(),
+            source: std::io::Error::from(std::io::ErrorKind::NotFound).into(),
+        };
+        assert_eq!(spawn.exit_code(), 6);
+        let exhausted = Error::MaxRetries {
+            source: Box::new(status(503, "secret/data/app")),
+        };
+        assert_eq!(exhausted.exit_code(), 7);
+        let timeout = Error::Timeout("fetching secrets did not complete within 1s".to_string());
+        assert_eq!(timeout.exit_code(), 8);
+        assert_eq!(timeout.class(), "timeout");
+        assert_eq!(Error::io("disk", std::io::ErrorKind::Other).exit_code(), 1);
+    }
+
+    #[test]
+    fn pass_source() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        let err = Error::io_at("unable to read file", "/run/secrets", io);
+        assert_eq!(
+            err.to_string(),
+            "IO error: unable to read file \"/run/secrets\": gone"
+        );
+        assert_eq!(err.path(), Some(Path::new("/run/secrets")));
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "gone");
+        assert_eq!(err.clone(), err);
+
+        let err = Error::parse("invalid", 2, "x").in_file(Path::new(".secrets"));
+        assert_eq!(
+            err.to_string(),
+            "Parse error: invalid (line 3 of \".secrets\": `x`)"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Minimal glob matching for environment variable and secret names.
+
+/// Matches `value` against `pattern`, where `*` matches any sequence of characters and `?` matches
+/// exactly one character.
+pub fn matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    // position of the last `*` in the pattern and the value position it is currently matched to
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut v) = (0, 0);
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    star = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Whether `value` matches any of `patterns`.
+pub fn matches_any<S: AsRef<str>>(patterns: &[S], value: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| matches(pattern.as_ref(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_literal() {
+        assert!(matches("PATH", "PATH"));
+        assert!(!matches("PATH", "PATHS"));
+        assert!(!matches("PATH", "PAT"));
+    }
+
+    #[test]
+    fn pass_wildcards() {
+        assert!(matches("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(matches("AWS_*", "AWS_"));
+        assert!(!matches("AWS_*", "MY_AWS_KEY"));
+        assert!(matches("*_TOKEN", "VAULT_TOKEN"));
+        assert!(matches("*", ""));
+        assert!(matches("L?NG", "LANG"));
+        assert!(!matches("L?NG", "LNG"));
+        assert!(matches("*A*B*", "xxAyyBzz"));
+        assert!(!matches("*A*B", "xxAyyBzz"));
+    }
+
+    #[test]
+    fn pass_matches_any() {
+        assert!(matches_any(&["HOME", "LC_*"], "LC_ALL"));
+        assert!(!matches_any::<&str>(&[], "LC_ALL"));
+    }
+}
@@ -0,0 +1,116 @@
+//! Protection of the secrets held in the memory of vaultify itself.
+//!
+//! All measures degrade gracefully: if they are not permitted, a warning is logged and vaultify
+//! continues without them.
+use nix::sys::{
+    mman::{mlockall, munlockall, MlockAllFlags},
+    resource::{getrlimit, setrlimit, Resource, RLIM_INFINITY},
+};
+
+use crate::sandbox::Rlimit;
+
+/// Sets the soft limit of core dumps to 0, so a crash does not write the secrets to disk.
+///
+/// Returns the original limit, which is restored for the child as the hard limit is kept.
+pub fn disable_core_dumps() -> Option<Rlimit> {
+    let (soft, hard) = match getrlimit(Resource::RLIMIT_CORE) {
+        Ok(limits) => limits,
+        Err(err) => {
+            tracing::warn!("unable to read core dump limit: {}", err);
+            return None;
+        }
+    };
+    if let Err(err) = setrlimit(Resource::RLIMIT_CORE, 0, hard) {
+        tracing::warn!("unable to disable core dumps: {}", err);
+        return None;
+    }
+
+    Some(Rlimit {
+        resource: Resource::RLIMIT_CORE,
+        soft,
+        hard,
+    })
+}
+
+/// Locks all current and future pages of vaultify into memory, so the secret values are locked
+/// as soon as they are allocated and never swapped out.
+///
+/// Returns a guard unlocking the memory when dropped. Locks are not inherited by children and end
+/// with the exec of the command or the exit of vaultify anyway.
+///
+/// # Remarks:
+///
+/// Only done with an unlimited `RLIMIT_MEMLOCK` or `CAP_IPC_LOCK`: within a limit, every
+/// allocation beyond it would fail.
+pub fn lock_memory() -> Option<MemoryLock> {
+    if !may_lock_memory() {
+        tracing::info!(
+            "secrets are not locked in memory without CAP_IPC_LOCK or an unlimited \
+            RLIMIT_MEMLOCK, they may be swapped to disk"
+        );
+        return None;
+    }
+    if let Err(err) = mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
+        tracing::warn!(
+            "unable to lock secrets in memory, they may be swapped to disk: {}",
+            err
+        );
+        return None;
+    }
+
+    Some(MemoryLock(()))
+}
+
+/// Memory locked by `lock_memory`, unlocked when dropped.
+pub struct MemoryLock(());
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        if let Err(err) = munlockall() {
+            tracing::debug!("unable to unlock memory: {}", err);
+        }
+    }
+}
+
+/// Whether locking all memory cannot make allocations fail.
+fn may_lock_memory() -> bool {
+    let unlimited =
+        getrlimit(Resource::RLIMIT_MEMLOCK).is_ok_and(|(soft, _)| soft == RLIM_INFINITY);
+    #[cfg(target_os = "linux")]
+    let unlimited = unlimited || crate::sandbox::has_capability("IPC_LOCK");
+    unlimited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_disable_core_dumps() {
+        let (soft, hard) = getrlimit(Resource::RLIMIT_CORE).unwrap();
+        let original = disable_core_dumps().unwrap();
+        assert_eq!((original.soft, original.hard), (soft, hard));
+        assert_eq!(getrlimit(Resource::RLIMIT_CORE).unwrap(), (0, hard));
+        setrlimit(Resource::RLIMIT_CORE, soft, hard).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pass_lock_memory() {
+        let locked = || {
+            std::fs::read_to_string("/proc/self/status")
+                .unwrap()
+                .lines()
+                .find_map(|line| line.strip_prefix("VmLck:"))
+                .is_some_and(|size| size.trim() != "0 kB")
+        };
+        match lock_memory() {
+            Some(lock) => {
+                assert!(locked());
+                drop(lock);
+                assert!(!locked());
+            }
+            None => assert!(!locked()),
+        }
+    }
+}
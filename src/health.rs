@@ -0,0 +1,218 @@
+//! Health of a resident vaultify for orchestrator probes, served on `--health-addr` in attached
+//! mode.
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    error::Result,
+    http::{self, Response, Server},
+    metrics,
+};
+
+static HEALTH: Mutex<Health> = Mutex::new(Health::new());
+
+/// Why the secrets were fetched again.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fetch {
+    /// Periodic refresh, a restart of the child or a change of the secrets file.
+    Refresh,
+    /// Certificates due for renewal.
+    CertificateRenewal,
+}
+
+struct Health {
+    child_running: bool,
+    restarts: u64,
+    /// Last successful fetch of the secrets.
+    last_refresh: Option<SystemTime>,
+    /// Refreshes failed since the last successful fetch.
+    refresh_failures: u64,
+    last_refresh_error: Option<String>,
+    /// Certificate renewals failed since the last successful fetch.
+    renewal_failures: u64,
+    last_renewal_error: Option<String>,
+}
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            child_running: false,
+            restarts: 0,
+            last_refresh: None,
+            refresh_failures: 0,
+            last_refresh_error: None,
+            renewal_failures: 0,
+            last_renewal_error: None,
+        }
+    }
+
+    fn child_started(&mut self, restart: bool) {
+        self.child_running = true;
+        if restart {
+            self.restarts += 1;
+        }
+    }
+
+    fn fetch_completed(&mut self, fetch: Fetch, error: Option<String>, now: SystemTime) {
+        match (error, fetch) {
+            // all secrets were fetched, including the certificates
+            (None, _) => {
+                self.last_refresh = Some(now);
+                self.refresh_failures = 0;
+                self.last_refresh_error = None;
+                self.renewal_failures = 0;
+                self.last_renewal_error = None;
+            }
+            (Some(error), Fetch::Refresh) => {
+                self.refresh_failures += 1;
+                self.last_refresh_error = Some(error);
+            }
+            (Some(error), Fetch::CertificateRenewal) => {
+                self.renewal_failures += 1;
+                self.last_renewal_error = Some(error);
+            }
+        }
+    }
+
+    /// Whether the child is running with secrets which are current and not expired.
+    fn ready(&self, leases: &BTreeMap<String, SystemTime>, now: SystemTime) -> bool {
+        self.child_running
+            && self.refresh_failures == 0
+            && self.renewal_failures == 0
+            && leases.values().all(|expiry| *expiry > now)
+    }
+}
+
+fn health() -> std::sync::MutexGuard<'static, Health> {
+    HEALTH.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Records that the child was spawned, `restart` if it replaces an earlier one.
+pub fn child_started(restart: bool) {
+    health().child_started(restart);
+}
+
+/// Records that the child exited, e.g. while waiting to restart it.
+pub fn child_exited() {
+    health().child_running = false;
+}
+
+/// Records a fetch of the secrets, failed with `error`.
+pub fn fetch_completed(fetch: Fetch, error: Option<String>) {
+    health().fetch_completed(fetch, error, SystemTime::now());
+}
+
+fn respond(path: &str) -> Response {
+    render(
+        &health(),
+        path,
+        &metrics::lease_expiries(),
+        SystemTime::now(),
+    )
+}
+
+/// Answers `/livez` (vaultify is serving) and `/readyz` (the child is running, the last refresh
+/// and certificate renewal succeeded and no lease expired) with 200 or 503 and the status as
+/// JSON, other paths with 404.
+fn render(
+    health: &Health,
+    path: &str,
+    leases: &BTreeMap<String, SystemTime>,
+    now: SystemTime,
+) -> Response {
+    let healthy = match path.split('?').next().unwrap_or_default() {
+        // the state of the child is left to readiness, restarting vaultify would not fix it
+        "/livez" | "/healthz" => true,
+        "/readyz" => health.ready(leases, now),
+        _ => {
+            return Response {
+                status: 404,
+                content_type: "text/plain",
+                body: "not found; try /livez or /readyz\n".to_string(),
+            }
+        }
+    };
+
+    let leases = leases
+        .iter()
+        .map(|(name, expiry)| (name.clone(), Value::String(format_time(*expiry))))
+        .collect::<Map<_, _>>();
+    let body = json!({
+        "status": if healthy { "ok" } else { "unavailable" },
+        "child_running": health.child_running,
+        "restarts": health.restarts,
+        "last_refresh": health.last_refresh.map(format_time),
+        "refresh_failures": health.refresh_failures,
+        "last_refresh_error": health.last_refresh_error,
+        "renewal_failures": health.renewal_failures,
+        "last_renewal_error": health.last_renewal_error,
+        "leases": leases,
+    });
+    Response {
+        status: if healthy { 200 } else { 503 },
+        content_type: "application/json",
+        body: format!("{}\n", body),
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Listens on `addr` and serves the health endpoints.
+pub async fn serve(addr: SocketAddr) -> Result<Server> {
+    let server = http::serve(addr, "health probes", respond).await?;
+    tracing::info!("serving health probes on http://{}/livez and /readyz", addr);
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_render() {
+        let now = SystemTime::now();
+        let hour = std::time::Duration::from_secs(3600);
+        let mut leases = BTreeMap::from([("token".to_string(), now + hour)]);
+        let mut health = Health::new();
+        let status = |health: &Health, path, leases: &BTreeMap<_, _>| {
+            render(health, path, leases, now).status
+        };
+        assert_eq!(status(&health, "/livez", &leases), 200);
+        assert_eq!(status(&health, "/readyz", &leases), 503);
+
+        health.child_started(false);
+        health.fetch_completed(Fetch::Refresh, Some("vault is sealed".to_string()), now);
+        let response = render(&health, "/readyz", &leases, now);
+        assert_eq!(response.status, 503);
+        let body = serde_json::from_str::<Value>(&response.body).unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["refresh_failures"], 1);
+        assert_eq!(body["last_refresh_error"], "vault is sealed");
+
+        health.fetch_completed(Fetch::Refresh, None, now);
+        let response = render(&health, "/readyz?verbose", &leases, now);
+        assert_eq!(response.status, 200);
+        let body = serde_json::from_str::<Value>(&response.body).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert!(body["last_refresh"].is_string());
+        assert_eq!(status(&health, "/metrics", &leases), 404);
+
+        // a failed certificate renewal or an expired lease is not ready, but still alive
+        health.fetch_completed(Fetch::CertificateRenewal, Some("denied".to_string()), now);
+        assert_eq!(status(&health, "/readyz", &leases), 503);
+        health.fetch_completed(Fetch::Refresh, None, now);
+        leases.insert("pki/issue/web".to_string(), now - hour);
+        assert_eq!(status(&health, "/readyz", &leases), 503);
+        health.child_running = false;
+        assert_eq!(status(&health, "/livez", &leases), 200);
+    }
+}
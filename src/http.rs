@@ -0,0 +1,69 @@
+//! Tiny HTTP server of the metrics and health endpoints of a resident vaultify.
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::error::{Error, Result};
+
+/// Response to a request, rendered by the handler of a `Server`.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+/// Server answering requests with its handler, stopped when dropped.
+pub struct Server {
+    server: JoinHandle<()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Listens on `addr` and answers every request with `handler`, which gets the requested path.
+/// `what` names the endpoints in errors.
+pub async fn serve(addr: SocketAddr, what: &str, handler: fn(&str) -> Response) -> Result<Server> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| Error::io(&format!("unable to listen for {} on {}", what, addr), err))?;
+
+    let server = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(respond(stream, handler));
+        }
+    });
+    Ok(Server { server })
+}
+
+async fn respond(mut stream: TcpStream, handler: fn(&str) -> Response) {
+    // only the path of the request line is used, reading it also avoids resetting the connection
+    let mut request = [0u8; 1024];
+    let read = stream.read(&mut request).await.unwrap_or_default();
+    let request = String::from_utf8_lossy(&request[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let response = handler(path);
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
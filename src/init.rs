@@ -0,0 +1,490 @@
+//! Secrets files created by the `init` subcommand, either interactively by browsing the kv mounts
+//! of vault via LIST in a terminal UI or as skeleton for the variables of an environment.
+use std::io::IsTerminal;
+
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListState, Paragraph},
+    Frame, Terminal,
+};
+
+use crate::{
+    error::{Error, Result},
+    secrets::is_valid_env_var_name,
+    vault::{FetchTokenOpts, VaultClient},
+};
+
+/// Screens of the interactive builder.
+enum Screen {
+    /// The mounts, or the entries of the folder of the location.
+    Browse,
+    /// The keys of the secret at `path` of the mount of the location, and whether they are picked.
+    Keys {
+        path: String,
+        keys: Vec<(String, bool)>,
+    },
+    /// The picked sources and the variables suggested for them, named in turn.
+    Name { sources: Vec<(String, String)> },
+}
+
+/// Whether the builder continues after a key.
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    Continue,
+    Finish,
+}
+
+/// State of the interactive builder.
+struct Builder {
+    mounts: Vec<String>,
+    /// Mount and folder (empty or ending with `/`) shown, `None` shows the mounts.
+    location: Option<(String, String)>,
+    entries: Vec<String>,
+    list: ListState,
+    screen: Screen,
+    /// Name of an entry to open typed while browsing, or the name of a variable.
+    input: String,
+    /// Error or result of the last action.
+    message: String,
+    lines: Vec<String>,
+}
+
+impl Builder {
+    /// Lists the entries of the location.
+    async fn load(&mut self, client: &VaultClient, opts: &FetchTokenOpts) {
+        self.message.clear();
+        self.entries = match &self.location {
+            None => {
+                if self.mounts.is_empty() {
+                    self.message =
+                        "no mounts visible to the token, type the name of a mount".into();
+                }
+                self.mounts
+                    .iter()
+                    .map(|mount| format!("{}/", mount))
+                    .collect()
+            }
+            Some((mount, folder)) => match client.list(mount, folder, false, opts).await {
+                Ok(paths) => paths
+                    .into_iter()
+                    .map(|path| path[folder.len()..].to_string())
+                    .collect(),
+                Err(err) => {
+                    self.message = format!("unable to list: {}", err);
+                    Vec::new()
+                }
+            },
+        };
+        self.list = ListState::default();
+        self.list.select((!self.entries.is_empty()).then_some(0));
+    }
+
+    /// Changes the location to its parent, the mounts being the parent of a mount.
+    fn go_up(&mut self) {
+        self.location = self.location.take().and_then(|(mount, folder)| {
+            let folder = folder.trim_end_matches('/');
+            match folder.rsplit_once('/') {
+                Some((parent, _)) => Some((mount, format!("{}/", parent))),
+                None if !folder.is_empty() => Some((mount, String::new())),
+                None => None,
+            }
+        });
+    }
+
+    async fn handle(
+        &mut self,
+        key: KeyEvent,
+        client: &VaultClient,
+        opts: &FetchTokenOpts,
+    ) -> Result<Step> {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Err(Error::Execution("init aborted".to_string()));
+        }
+
+        match &mut self.screen {
+            Screen::Browse => match key.code {
+                KeyCode::Esc => return Ok(Step::Finish),
+                KeyCode::Up => self.list.select_previous(),
+                KeyCode::Down => self.list.select_next(),
+                KeyCode::Char(c) => self.input.push(c),
+                KeyCode::Backspace if !self.input.is_empty() => {
+                    self.input.pop();
+                }
+                KeyCode::Backspace | KeyCode::Left => {
+                    self.go_up();
+                    self.load(client, opts).await;
+                }
+                KeyCode::Enter => self.open(client, opts).await,
+                _ => {}
+            },
+            Screen::Keys { path, keys } => match key.code {
+                KeyCode::Esc | KeyCode::Left => self.screen = Screen::Browse,
+                KeyCode::Up => self.list.select_previous(),
+                KeyCode::Down => self.list.select_next(),
+                KeyCode::Char(' ') => {
+                    if let Some((_, picked)) =
+                        self.list.selected().and_then(|idx| keys.get_mut(idx))
+                    {
+                        *picked = !*picked;
+                    }
+                }
+                KeyCode::Char('a') => {
+                    let all = keys.iter().all(|(_, picked)| *picked);
+                    keys.iter_mut().for_each(|(_, picked)| *picked = !all);
+                }
+                KeyCode::Enter => {
+                    let mount = self.location.as_ref().map(|(mount, _)| mount.as_str());
+                    let sources = keys
+                        .iter()
+                        .filter(|(_, picked)| *picked)
+                        .map(|(key, _)| {
+                            let source = format!("{}/{}#{}", mount.unwrap_or_default(), path, key);
+                            (source, variable_name(path, key))
+                        })
+                        .collect::<Vec<_>>();
+                    match sources.first() {
+                        Some((_, name)) => {
+                            self.input = name.clone();
+                            self.message.clear();
+                            self.list.select(Some(0));
+                            self.screen = Screen::Name { sources };
+                        }
+                        None => self.message = "pick keys with space first".into(),
+                    }
+                }
+                _ => {}
+            },
+            Screen::Name { sources } => match key.code {
+                KeyCode::Esc => {
+                    self.input.clear();
+                    self.load(client, opts).await;
+                    self.screen = Screen::Browse;
+                }
+                KeyCode::Char(c) => self.input.push(c),
+                KeyCode::Backspace => {
+                    self.input.pop();
+                }
+                KeyCode::Enter if !is_valid_env_var_name(&self.input) => {
+                    self.message = "invalid variable name".into();
+                }
+                KeyCode::Enter => {
+                    let (source, _) = sources.remove(0);
+                    self.lines.push(format!(
+                        "{} | env {}",
+                        source,
+                        std::mem::take(&mut self.input)
+                    ));
+                    self.message.clear();
+                    match sources.first() {
+                        Some((_, name)) => self.input = name.clone(),
+                        None => {
+                            self.load(client, opts).await;
+                            self.screen = Screen::Browse;
+                        }
+                    }
+                }
+                _ => {}
+            },
+        }
+
+        Ok(Step::Continue)
+    }
+
+    /// Opens the typed or the selected entry: a mount, a folder or the keys of a secret.
+    async fn open(&mut self, client: &VaultClient, opts: &FetchTokenOpts) {
+        let entry = match std::mem::take(&mut self.input) {
+            typed if !typed.is_empty() => typed,
+            _ => match self.list.selected().and_then(|idx| self.entries.get(idx)) {
+                Some(entry) => entry.clone(),
+                None => return,
+            },
+        };
+
+        match &mut self.location {
+            None => self.location = Some((entry.trim_end_matches('/').to_string(), String::new())),
+            Some((_, folder)) if entry.ends_with('/') => folder.push_str(&entry),
+            Some((mount, folder)) => {
+                let path = format!("{}{}", folder, entry);
+                match client.keys(mount, &path, opts).await {
+                    Ok(keys) => {
+                        self.message.clear();
+                        self.list.select((!keys.is_empty()).then_some(0));
+                        let keys = keys.into_iter().map(|key| (key, false)).collect();
+                        self.screen = Screen::Keys { path, keys };
+                    }
+                    Err(err) => self.message = format!("unable to read: {}", err),
+                }
+                return;
+            }
+        }
+        self.load(client, opts).await;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, input_area, help_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let location = match &self.location {
+            None => "mounts".to_string(),
+            Some((mount, folder)) => format!("{}/{}", mount, folder),
+        };
+        let (title, items, input, help) = match &self.screen {
+            Screen::Browse => (
+                location,
+                self.entries.clone(),
+                format!("open: {}", self.input),
+                "enter opens the selected or typed entry, left goes up, esc finishes",
+            ),
+            Screen::Keys { path, keys } => (
+                format!(
+                    "{}{}",
+                    location,
+                    path.rsplit('/').next().unwrap_or_default()
+                ),
+                keys.iter()
+                    .map(|(key, picked)| format!("[{}] {}", if *picked { "x" } else { " " }, key))
+                    .collect(),
+                String::new(),
+                "space picks a key, a picks all, enter names their variables, esc goes back",
+            ),
+            Screen::Name { sources } => (
+                "variables".to_string(),
+                sources.iter().map(|(source, _)| source.clone()).collect(),
+                format!("variable for `{}`: {}", sources[0].0, self.input),
+                "enter accepts the name, esc skips the remaining keys",
+            ),
+        };
+
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" {} ", title)))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+        frame.render_widget(Paragraph::new(input), input_area);
+        let status = if self.message.is_empty() {
+            format!("{} lines picked; {}", self.lines.len(), help)
+        } else {
+            self.message.clone()
+        };
+        frame.render_widget(
+            Paragraph::new(Line::styled(status, Modifier::DIM)),
+            help_area,
+        );
+    }
+}
+
+/// Lets the user browse the kv mounts and pick keys of secrets and the variables they are passed
+/// as on `terminal`, reading the keys pressed with `next_key`, and returns the lines of the
+/// secrets file.
+///
+/// # Remarks:
+///
+/// Entries can also be typed by name, e.g. a mount the token cannot list. Only the names of the
+/// keys of kv v2 secrets are read, via their `subkeys`.
+pub async fn interactive<B: Backend>(
+    client: &VaultClient,
+    opts: &FetchTokenOpts,
+    terminal: &mut Terminal<B>,
+    mut next_key: impl FnMut() -> Result<KeyEvent>,
+) -> Result<Vec<String>> {
+    let mounts = client.kv_mounts().await.unwrap_or_else(|err| {
+        tracing::info!("unable to list the mounts: {}", err);
+        Vec::new()
+    });
+    let mut builder = Builder {
+        mounts,
+        location: None,
+        entries: Vec::new(),
+        list: ListState::default(),
+        screen: Screen::Browse,
+        input: String::new(),
+        message: String::new(),
+        lines: Vec::new(),
+    };
+    builder.load(client, opts).await;
+
+    loop {
+        terminal
+            .draw(|frame| builder.draw(frame))
+            .map_err(|err| Error::io("unable to draw the terminal", err))?;
+        if builder.handle(next_key()?, client, opts).await? == Step::Finish {
+            return Ok(builder.lines);
+        }
+    }
+}
+
+/// Runs `interactive` in the alternate screen of the terminal on stderr, as stdout may be
+/// redirected.
+pub async fn interactive_terminal(
+    client: &VaultClient,
+    opts: &FetchTokenOpts,
+) -> Result<Vec<String>> {
+    if !std::io::stderr().is_terminal() {
+        return Err(Error::Execution(
+            "init --interactive requires a terminal".to_string(),
+        ));
+    }
+    let setup = |err| Error::io("unable to set up the terminal", err);
+    terminal::enable_raw_mode().map_err(setup)?;
+    let picked = match execute!(std::io::stderr(), EnterAlternateScreen)
+        .and_then(|()| Terminal::new(CrosstermBackend::new(std::io::stderr())))
+    {
+        Ok(mut terminal) => {
+            let next_key = || loop {
+                match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => return Ok(key),
+                    Ok(_) => continue,
+                    Err(err) => return Err(Error::io("unable to read the terminal", err)),
+                }
+            };
+            interactive(client, opts, &mut terminal, next_key).await
+        }
+        Err(err) => Err(setup(err)),
+    };
+    let _ = execute!(std::io::stderr(), LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+
+    picked
+}
+
+/// Renders a secrets file mapping each of the `names` starting with `prefix` to the key of the
+/// secret `to` named like the variable, as `import` writes them, e.g. `secret/myapp#MYAPP_DB_PASS`.
+pub fn skeleton(names: &[String], prefix: &str, to: &(String, String), source: &str) -> String {
+    let (mount, path) = to;
+    let mut skeleton = format!(
+        "# generated by `vaultify init --from-env` from {}\n\
+         # the keys are suggestions, store the values there (e.g. with `vaultify import`) or adjust\n\
+         # the lines to where they are stored\n",
+        source
+    );
+    for name in names {
+        if !name.starts_with(prefix) || !is_valid_env_var_name(name) {
+            continue;
+        }
+        skeleton.push_str(&format!("{}/{}#{} | env {}\n", mount, path, name, name));
+    }
+
+    skeleton
+}
+
+/// Secret suggested by `skeleton` without `--to`, e.g. `secret/myapp` for the prefix `MYAPP_`.
+pub fn default_path(prefix: &str) -> (String, String) {
+    let app = prefix
+        .trim_matches('_')
+        .to_ascii_lowercase()
+        .replace('_', "-");
+    let app = if app.is_empty() {
+        "app".to_string()
+    } else {
+        app
+    };
+    ("secret".to_string(), app)
+}
+
+/// Suggested variable of a key, e.g. `WEB_DB_PASSWORD` for `db-password` of `apps/web`.
+fn variable_name(path: &str, key: &str) -> String {
+    let secret = path.rsplit('/').next().unwrap_or_default();
+    let name = format!("{}_{}", secret, key)
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect::<String>();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{KvVersion, MockVault};
+    use ratatui::backend::TestBackend;
+    use std::time::Duration;
+
+    #[test]
+    fn pass_skeleton() {
+        let names = ["MYAPP_DB_PASS", "OTHER", "MYAPP_TOKEN"].map(str::to_string);
+        let skeleton = skeleton(&names, "MYAPP_", &default_path("MYAPP_"), "the environment");
+        assert!(skeleton
+            .starts_with("# generated by `vaultify init --from-env` from the environment\n"));
+        assert!(skeleton.ends_with(
+            "\nsecret/myapp#MYAPP_DB_PASS | env MYAPP_DB_PASS\nsecret/myapp#MYAPP_TOKEN | env MYAPP_TOKEN\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn pass_interactive() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put(
+            "secret",
+            "apps/web",
+            serde_json::json!({"db-password": "p", "token": "t"}),
+        );
+        vault.put("secret", "infra", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&crate::vault::Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        let opts = FetchTokenOpts {
+            retries: 0,
+            retry_delay: Duration::ZERO,
+        };
+        client.login(auth, opts).await.unwrap();
+
+        // open the mount, `apps/` by name and `web`, pick both keys renaming the token, go up and
+        // pick the key of `infra`, retrying its invalid variable name
+        let mut keys = vec![KeyCode::Enter];
+        keys.extend("apps/".chars().map(KeyCode::Char));
+        keys.extend([KeyCode::Enter; 2]);
+        keys.extend([KeyCode::Char('a'), KeyCode::Enter, KeyCode::Enter]);
+        keys.extend(std::iter::repeat(KeyCode::Backspace).take("WEB_TOKEN".len()));
+        keys.extend("API_TOKEN".chars().map(KeyCode::Char));
+        keys.extend([KeyCode::Enter, KeyCode::Left, KeyCode::Down, KeyCode::Enter]);
+        keys.extend([KeyCode::Char(' '), KeyCode::Enter]);
+        keys.extend([
+            KeyCode::Char('-'),
+            KeyCode::Enter,
+            KeyCode::Backspace,
+            KeyCode::Enter,
+        ]);
+        keys.push(KeyCode::Esc);
+        let mut keys = keys.into_iter();
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        let lines = interactive(&client, &opts, &mut terminal, || {
+            Ok(KeyEvent::new(keys.next().unwrap(), KeyModifiers::NONE))
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            lines,
+            [
+                "secret/apps/web#db-password | env WEB_DB_PASSWORD",
+                "secret/apps/web#token | env API_TOKEN",
+                "secret/infra#a | env INFRA_A",
+            ]
+        );
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains(" secret/ "));
+        assert!(screen.contains("3 lines picked"));
+
+        // the keys are read without their values
+        let requests = vault.requests();
+        assert!(requests.contains(&"GET /v1/secret/subkeys/apps/web?depth=1".to_string()));
+        assert!(!requests.iter().any(|request| request.contains("/data/")));
+    }
+}
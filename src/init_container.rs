@@ -0,0 +1,189 @@
+//! Init container mode (`--k8s-init`), which hands the secrets over to the app container via a
+//! shared volume instead of running a command.
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::{Error, Result},
+    process::{self, EnvSecret, SecretsFormat},
+    secrets::{Owner, Secret, SecretTarget},
+    Args,
+};
+
+/// Fetches the secrets, writes the file targets and the variables to `--init-dotenv`, hands them
+/// and the directories created for them over to `--init-owner` and returns, so the init container
+/// exits.
+pub async fn run(args: &Args) -> Result<()> {
+    let (_, secrets) = crate::load_secrets(args).await?;
+    let has_env = args.metadata_env
+        || !args.env_file.is_empty()
+        || secrets
+            .iter()
+            .any(|secret| matches!(secret.target, SecretTarget::Env { .. }));
+    if has_env && args.init_dotenv.is_none() {
+        return Err(Error::Execution(
+            "invalid configuration: --k8s-init writes variables only to --init-dotenv".to_string(),
+        ));
+    }
+
+    let created = missing_parents(&secrets);
+    let env_secrets = crate::apply_secrets(args, &secrets)?;
+    let env_vars = crate::load_env_files(&args.env_file).await?;
+    let mut written = secrets
+        .iter()
+        .filter_map(|secret| match &secret.target {
+            SecretTarget::File { path, .. } | SecretTarget::EnvFile { path, .. } => {
+                Some(path.as_path())
+            }
+            SecretTarget::Env { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    written.extend(created.iter().map(PathBuf::as_path));
+    written.sort();
+    written.dedup();
+    if let Some(dotenv) = &args.init_dotenv {
+        let mut vars = env_vars
+            .into_iter()
+            .filter(|(name, _)| !env_secrets.iter().any(|secret| &secret.name == name))
+            .map(|(name, secret)| EnvSecret { name, secret })
+            .collect::<Vec<_>>();
+        vars.extend(env_secrets);
+        let contents = process::format_secrets(&vars, SecretsFormat::Dotenv);
+        crate::write_secret_to_file(dotenv, &contents, 0o600, false)?;
+        written.push(dotenv);
+    }
+
+    if let Some(owner) = args.init_owner {
+        for path in &written {
+            chown(path, owner)?;
+        }
+    }
+    tracing::info!("wrote {} secrets for the app container", secrets.len());
+
+    Ok(())
+}
+
+/// The parent directories of the file targets with `create=true` which do not exist yet, so they
+/// are handed over along with the files once created.
+fn missing_parents(secrets: &[Secret]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for secret in secrets {
+        if let SecretTarget::File {
+            path, create: true, ..
+        } = &secret.target
+        {
+            let missing = path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists());
+            dirs.extend(missing.map(Path::to_path_buf));
+        }
+    }
+
+    dirs
+}
+
+fn chown(path: &Path, owner: Owner) -> Result<()> {
+    std::os::unix::fs::lchown(path, Some(owner.uid), owner.gid).map_err(|err| {
+        Error::io_at(
+            &format!("unable to change the owner to {} of", owner.uid),
+            path,
+            err,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{KvVersion, MockVault};
+
+    fn parse(argv: &[&str]) -> Args {
+        Args::from_matches(&Args::cli().try_get_matches_from(argv).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pass_run() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put(
+            "secret",
+            "app",
+            serde_json::json!({"password": "p", "key": "k"}),
+        );
+        let dir = std::env::temp_dir().join(format!("vaultify-init-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        let key = dir.join("tls/private/key");
+        std::fs::write(
+            &secrets_file,
+            format!(
+                "secret/app#password | env DB_PASS\nsecret/app#key | file {} mode=0640 create=true\n",
+                key.display()
+            ),
+        )
+        .unwrap();
+        let env_file = dir.join("config.env");
+        std::fs::write(&env_file, "PORT=8080\n").unwrap();
+        let dotenv = dir.join("app.env");
+        // only root can hand the files over to another user
+        let metadata = std::fs::metadata(&dir).unwrap();
+        let owner = match metadata.uid() {
+            0 => Owner {
+                uid: 4242,
+                gid: Some(4242),
+            },
+            uid => Owner {
+                uid,
+                gid: Some(metadata.gid()),
+            },
+        };
+        let owner_arg = format!("{}:{}", owner.uid, owner.gid.unwrap());
+
+        let host = vault.addr();
+        let args = parse(&[
+            "vaultify",
+            "--host",
+            &host,
+            "--token",
+            MockVault::TOKEN,
+            "--secrets-file",
+            secrets_file.to_str().unwrap(),
+            "--env-file",
+            env_file.to_str().unwrap(),
+            "--k8s-init",
+            "--init-dotenv",
+            dotenv.to_str().unwrap(),
+            "--init-owner",
+            &owner_arg,
+        ]);
+        args.validate().unwrap();
+        run(&args).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), "k");
+        assert_eq!(
+            std::fs::read_to_string(&dotenv).unwrap(),
+            "PORT=\"8080\"\nDB_PASS=\"p\"\n"
+        );
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&key), 0o640);
+        assert_eq!(mode(&dotenv), 0o600);
+        for path in [&key, &dotenv, &dir.join("tls"), &dir.join("tls/private")] {
+            let metadata = std::fs::metadata(path).unwrap();
+            assert_eq!(metadata.uid(), owner.uid, "{}", path.display());
+            assert_eq!(Some(metadata.gid()), owner.gid, "{}", path.display());
+        }
+        // the directories which existed before are left alone
+        assert_eq!(std::fs::metadata(&dir).unwrap().uid(), metadata.uid());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fail_run_with_command() {
+        let args = parse(&["vaultify", "--k8s-init", "--", "true"]);
+        let err = args.validate().unwrap_err();
+        assert!(err.to_string().contains("--k8s-init"), "{}", err);
+    }
+}
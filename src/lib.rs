@@ -0,0 +1,6 @@
+//! Library target of vaultify, which only exposes the mock vault its tests run against, so
+//! downstream crates can test how they run vaultify without a live vault.
+//!
+//! `testing` is available with the `testing` feature.
+#[cfg(feature = "testing")]
+pub mod testing;
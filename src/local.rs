@@ -0,0 +1,130 @@
+//! Secrets read from the local machine, for development without access to vault.
+use std::{collections::HashMap, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+    error::{Error, Result},
+    provider::{lookup, SecretProvider},
+    secrets::{Secret, SecretSpec, SecretSpecs},
+};
+
+/// Resolves `file:file#key.path` specs from plain JSON files.
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    async fn resolve(&self, spec: &SecretSpec) -> Result<Secret> {
+        let document = read_json(Path::new(&spec.path)).await?;
+        lookup(&document, spec)
+    }
+
+    /// Reads every file only once.
+    async fn resolve_all(&self, specs: &[&SecretSpec]) -> Result<Vec<Secret>> {
+        let mut documents = HashMap::new();
+        let mut secrets = Vec::with_capacity(specs.len());
+        for spec in specs {
+            if !documents.contains_key(spec.path.as_str()) {
+                let document = read_json(Path::new(&spec.path)).await?;
+                documents.insert(spec.path.as_str(), document);
+            }
+            secrets.push(lookup(&documents[spec.path.as_str()], spec)?);
+        }
+
+        Ok(secrets)
+    }
+}
+
+/// Resolves `env:NAME` specs from the environment of vaultify.
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    async fn resolve(&self, spec: &SecretSpec) -> Result<Secret> {
+        let secret = std::env::var(&spec.secret).map_err(|err| {
+            Error::NotFound(format!(
+                "environment variable `{}` cannot be read: {}",
+                spec.secret, err
+            ))
+        })?;
+
+        Ok(Secret::new(spec.target.clone(), secret))
+    }
+}
+
+/// Resolves all specs from the `--fallback-file`, regardless of their provider.
+///
+/// The file is a JSON object mapping the name of each secret, i.e. the environment variable or
+/// `file:<path>` for file targets, to its value.
+pub async fn fallback(path: &Path, specs: &SecretSpecs) -> Result<Vec<Secret>> {
+    let document = read_json(path).await?;
+    specs
+        .values()
+        .map(|spec| fallback_secret(&document, path, spec))
+        .collect()
+}
+
+fn fallback_secret(document: &Value, path: &Path, spec: &SecretSpec) -> Result<Secret> {
+    let name = spec.name();
+    let secret = document
+        .get(&name)
+        .ok_or_else(|| {
+            Error::NotFound(format!(
+                "fallback file {} does not contain `{}`",
+                path.display(),
+                name
+            ))
+        })?
+        .as_str()
+        .ok_or_else(|| {
+            Error::deserialization(format!(
+                "fallback value of `{}` in {} is not a string",
+                name,
+                path.display()
+            ))
+        })?;
+
+    Ok(Secret::new(spec.target.clone(), secret.to_string()))
+}
+
+async fn read_json(path: &Path) -> Result<Value> {
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|err| Error::io_at("unable to read file", path, err))?;
+    serde_json::from_slice(&contents)
+        .map_err(|err| Error::json(&format!("unable to parse {:?}", path), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretTarget;
+
+    #[test]
+    fn pass_fallback_secret() {
+        let document = serde_json::json!({"API_KEY": "dummy", "file:/tmp/key": "pem"});
+        let spec = |target| SecretSpec::vault(target, "secret", "app", "key");
+        let path = Path::new("dev-secrets.json");
+
+        let env = spec(SecretTarget::Env {
+            name: "API_KEY".to_string(),
+        });
+        assert_eq!(
+            fallback_secret(&document, path, &env).unwrap().secret,
+            "dummy"
+        );
+        let file = spec(SecretTarget::File {
+            path: "/tmp/key".into(),
+            mode: 0o600,
+            create: false,
+            owner: None,
+            label: None,
+        });
+        assert_eq!(
+            fallback_secret(&document, path, &file).unwrap().secret,
+            "pem"
+        );
+        let missing = spec(SecretTarget::Env {
+            name: "OTHER".to_string(),
+        });
+        assert!(fallback_secret(&document, path, &missing).is_err());
+    }
+}
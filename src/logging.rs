@@ -0,0 +1,539 @@
+//! Log output of vaultify, as text or one JSON object per line to stderr or a file, or to syslog
+//! or journald, with the values of all fetched secrets redacted.
+//!
+//! vaultify logs via `tracing`, formatted by the `tracing-subscriber` fmt layer; records of
+//! dependencies logging via `log` are forwarded to it as well.
+use std::{
+    fmt,
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    sync::{Mutex, OnceLock, PoisonError, RwLock},
+};
+
+use crate::error::{Error, Result};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, MakeWriter},
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+/// Placeholder logged in place of secret values.
+const REDACTED: &str = "[redacted]";
+
+/// Values shorter than this are not redacted, as they would mask unrelated parts of the output.
+const MIN_REDACTED_LEN: usize = 4;
+
+/// Values redacted from all log output.
+static SECRETS: Secrets = Secrets::new();
+
+/// Id of this run, logged as `correlation_id` field of every record.
+static CORRELATION_ID: OnceLock<String> = OnceLock::new();
+
+/// Sockets of the local syslog daemon on linux, macOS and the BSDs.
+#[cfg(unix)]
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+/// Socket of the native journald protocol.
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Name of vaultify in syslog and journald records.
+const IDENTIFIER: &str = "vaultify";
+
+/// Format of the log output.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line with `ts`, `level`, `target`, `message` and the structured fields
+    /// of the record, e.g. `vault_path`, `request_id`, `correlation_id` and `duration_ms`.
+    Json,
+}
+
+/// Destination of the log output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogOutput {
+    Stderr,
+    /// Appended to the file in the configured format.
+    File(PathBuf),
+    /// Sent to the local syslog daemon with facility `user`, always as text.
+    Syslog,
+    /// Sent to journald with the fields of each record as journal fields.
+    Journald,
+}
+
+/// Installs the logger writing to `output`, filtered by `RUST_LOG` with a default level of warn.
+///
+/// Closing a span logs a `close` record with its fields and the time spent in it (`time.busy`)
+/// and waiting (`time.idle`).
+pub fn init(format: LogFormat, output: &LogOutput) -> Result<()> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::WARN.into())
+        .from_env_lossy();
+    let layer = match output {
+        LogOutput::Stderr => fmt_layer(
+            format,
+            std::io::stderr().is_terminal(),
+            Redacting::new(std::io::stderr, &SECRETS),
+        ),
+        LogOutput::File(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| Error::io_at("unable to open log file", path, err))?;
+            fmt_layer(format, false, Redacting::new(Mutex::new(file), &SECRETS))
+        }
+        LogOutput::Syslog => {
+            let socket = Datagram::connect(&SYSLOG_SOCKETS)?;
+            tracing_subscriber::fmt::layer()
+                .without_time()
+                .with_ansi(false)
+                .with_level(false)
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(Redacting::new(socket, &SECRETS))
+                .boxed()
+        }
+        LogOutput::Journald => JournaldLayer {
+            socket: Datagram::connect(&[JOURNALD_SOCKET])?,
+            secrets: &SECRETS,
+        }
+        .boxed(),
+    };
+
+    if let Err(err) = tracing_subscriber::registry()
+        .with(layer)
+        .with(filter)
+        .try_init()
+    {
+        tracing::warn!("unable to install tracing subscriber: {}", err);
+    }
+    Ok(())
+}
+
+/// The fmt layer writing in `format` to `writer`.
+fn fmt_layer<W>(format: LogFormat, ansi: bool, writer: W) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Sets the id of this run; only the first id set is used.
+///
+/// The returned `run` span carries it as `correlation_id` field of every record logged while it
+/// is entered, i.e. for the rest of the run.
+pub fn set_correlation_id(id: &str) -> span::EnteredSpan {
+    let _ = CORRELATION_ID.set(id.to_string());
+    tracing::info_span!("run", correlation_id = id).entered()
+}
+
+/// Id of this run, if set.
+pub fn correlation_id() -> Option<&'static str> {
+    CORRELATION_ID.get().map(String::as_str)
+}
+
+/// Connected datagram socket writing every record as one datagram.
+#[cfg(unix)]
+struct Datagram(std::os::unix::net::UnixDatagram);
+
+/// Writes every record to syslog as RFC 3164 message of facility `user`, the daemon adds the
+/// timestamp.
+#[cfg(unix)]
+impl<'a> MakeWriter<'a> for Datagram {
+    type Writer = SyslogRecord<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogRecord {
+            socket: self,
+            severity: severity(&Level::INFO),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogRecord {
+            socket: self,
+            severity: severity(meta.level()),
+        }
+    }
+}
+
+#[cfg(unix)]
+struct SyslogRecord<'a> {
+    socket: &'a Datagram,
+    severity: u8,
+}
+
+#[cfg(unix)]
+impl Write for SyslogRecord<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // the fmt layer writes each formatted record at once
+        let mut record = format!(
+            "<{}>{}[{}]: ",
+            8 + self.severity,
+            IDENTIFIER,
+            std::process::id()
+        )
+        .into_bytes();
+        record.extend_from_slice(buf.strip_suffix(b"\n").unwrap_or(buf));
+        self.socket.0.send(&record)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Datagram {
+    /// Connects to the first of `paths` accepting a connection.
+    fn connect(paths: &[&str]) -> Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .map_err(|err| Error::io("unable to create log socket", err))?;
+        let mut last_err = None;
+        for path in paths {
+            match socket.connect(path) {
+                Ok(()) => return Ok(Self(socket)),
+                Err(err) => {
+                    last_err = Some(Error::io_at("unable to connect to log socket", path, err))
+                }
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| Error::Conversion("no log socket to connect to".to_string())))
+    }
+}
+
+/// Redacts `secrets` from all following log output.
+pub fn redact_secrets<'a>(secrets: impl IntoIterator<Item = &'a str>) {
+    SECRETS.insert(secrets);
+}
+
+/// `text` as it would be logged.
+#[cfg(test)]
+pub fn redacted(text: &str) -> String {
+    SECRETS.redact(text)
+}
+
+/// Secret values to replace in log output, longest first so overlapping values are redacted
+/// completely.
+struct Secrets(RwLock<Vec<String>>);
+
+impl Secrets {
+    const fn new() -> Self {
+        Self(RwLock::new(Vec::new()))
+    }
+
+    fn insert<'a>(&self, secrets: impl IntoIterator<Item = &'a str>) {
+        let mut registered = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        for secret in secrets
+            .into_iter()
+            .filter(|secret| secret.len() >= MIN_REDACTED_LEN)
+        {
+            // values are quoted in fields of the text format and in JSON
+            let json = serde_json::Value::from(secret).to_string();
+            let escaped = [
+                secret.to_string(),
+                secret.escape_debug().to_string(),
+                json[1..json.len() - 1].to_string(),
+            ];
+            for secret in escaped {
+                if !registered.contains(&secret) {
+                    registered.push(secret);
+                }
+            }
+        }
+        registered.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+
+    /// Replaces every registered secret value in `text`.
+    fn redact(&self, text: &str) -> String {
+        let secrets = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        secrets.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+}
+
+/// Writer redacting the secrets from each formatted record before passing it on.
+struct Redacting<W> {
+    inner: W,
+    secrets: &'static Secrets,
+}
+
+impl<W> Redacting<W> {
+    fn new(inner: W, secrets: &'static Secrets) -> Self {
+        Self { inner, secrets }
+    }
+}
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for Redacting<W> {
+    type Writer = Redacting<W::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacting::new(self.inner.make_writer(), self.secrets)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Redacting::new(self.inner.make_writer_for(meta), self.secrets)
+    }
+}
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // the fmt layer writes each formatted record at once, so no secret is split across writes
+        let record = self.secrets.redact(&String::from_utf8_lossy(buf));
+        self.inner.write_all(record.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Severity of `level` as defined by syslog, also used as journald `PRIORITY`.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Sends every event to journald in its native protocol, with the fields of the event and of
+/// its spans as uppercase journal fields (e.g. `VAULT_PATH`).
+#[cfg(unix)]
+struct JournaldLayer {
+    socket: Datagram,
+    secrets: &'static Secrets,
+}
+
+#[cfg(unix)]
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            if let Some(span_fields) = span.extensions().get::<Fields>() {
+                fields.0.extend(span_fields.0.iter().cloned());
+            }
+        }
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let entry = journald_record(metadata.level(), metadata.target(), fields, self.secrets);
+        let _ = self.socket.0.send(&entry);
+    }
+}
+
+/// Formats an event in the native journald protocol.
+fn journald_record(level: &Level, target: &str, mut fields: Fields, secrets: &Secrets) -> Vec<u8> {
+    let mut entry = JournalFields(Vec::new());
+    entry.push("PRIORITY", &severity(level).to_string());
+    entry.push("SYSLOG_IDENTIFIER", IDENTIFIER);
+    entry.push("SYSLOG_PID", &std::process::id().to_string());
+    entry.push("TARGET", target);
+    let message = fields.take("message").unwrap_or_default();
+    entry.push("MESSAGE", &secrets.redact(&message));
+    for (name, value) in fields.0 {
+        // journal field names consist of uppercase letters, digits and underscores
+        let name = name
+            .chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9') => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+        if !name.is_empty() {
+            entry.push(name, &secrets.redact(&value));
+        }
+    }
+    entry.0
+}
+
+/// Journal entry in the native protocol.
+struct JournalFields(Vec<u8>);
+
+impl JournalFields {
+    fn push(&mut self, name: &str, value: &str) {
+        self.0.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // values with newlines are sent with their length instead of newline terminated
+            self.0.push(b'\n');
+            self.0
+                .extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            self.0.push(b'=');
+        }
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(b'\n');
+    }
+}
+
+/// Fields of a span or event in the order they were recorded, later values replace earlier ones.
+#[derive(Clone, Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Fields {
+    fn take(&mut self, name: &str) -> Option<String> {
+        let idx = self.0.iter().position(|(known, _)| *known == name)?;
+        Some(self.0.remove(idx).1)
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.retain(|(known, _)| *known != field.name());
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Log output collected in memory.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            let output = self.0.lock().unwrap();
+            String::from_utf8_lossy(&output)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn pass_journald_record() {
+        let secrets = Secrets::new();
+        secrets.insert(["hunter2"]);
+        let fields = Fields(vec![
+            ("vault_path", "secret/data/app".to_string()),
+            ("message", "first\nsecond".to_string()),
+            ("token", "hunter2".to_string()),
+        ]);
+        let entry = journald_record(&Level::WARN, "vaultify::vault", fields, &secrets);
+
+        let mut expected = format!(
+            "PRIORITY=4\nSYSLOG_IDENTIFIER=vaultify\nSYSLOG_PID={}\nTARGET=vaultify::vault\nMESSAGE\n",
+            std::process::id()
+        )
+        .into_bytes();
+        expected.extend_from_slice(&12u64.to_le_bytes());
+        expected
+            .extend_from_slice(b"first\nsecond\nVAULT_PATH=secret/data/app\nTOKEN=[redacted]\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn pass_json_record() {
+        let secrets: &'static Secrets = Box::leak(Box::new(Secrets::new()));
+        secrets.insert(["hunter2", "abc", "quo\"ted"]);
+        let buffer = Buffer::default();
+        let layer = fmt_layer(
+            LogFormat::Json,
+            false,
+            Redacting::new(buffer.clone(), secrets),
+        );
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("fetch_secret", vault_path = "secret/data/app");
+            let _entered = span.enter();
+            tracing::info!(
+                duration_ms = 12u64,
+                token = "hunter2",
+                "fetched hunter2 as abc"
+            );
+            tracing::warn!("value quo\"ted");
+        });
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["message"], "fetched [redacted] as abc");
+        assert_eq!(lines[0]["token"], "[redacted]");
+        assert_eq!(lines[0]["duration_ms"], 12);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(
+            lines[0]["spans"],
+            serde_json::json!([{"name": "fetch_secret", "vault_path": "secret/data/app"}])
+        );
+        assert_eq!(lines[1]["message"], "value [redacted]");
+        assert_eq!(lines[2]["message"], "close");
+        assert_eq!(lines[2]["span"]["name"], "fetch_secret");
+        assert!(lines[2]["time.busy"].is_string());
+    }
+}
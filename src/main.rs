@@ -1,22 +1,74 @@
+// the process handling, the vault agent socket and the secrets memfd rely on unix APIs
+#[cfg(not(unix))]
+compile_error!("vaultify only supports unix systems, e.g. linux and macOS");
+
 use std::{
-    io::Write,
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
     time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use futures::future::{FutureExt, LocalBoxFuture};
 #[cfg(target_os = "linux")]
 use nix::libc::{O_CLOEXEC, O_NOFOLLOW};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde_json::{Map, Value};
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
+mod build_info;
+mod cache;
+mod config;
+mod crypto;
+#[cfg(unix)]
+mod daemon;
+mod docs;
+mod dotenv;
+mod dry_run;
+mod encoder;
 mod error;
+mod glob;
+#[cfg(unix)]
+mod hardening;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod health;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod http;
+mod init;
+mod init_container;
+mod local;
+mod logging;
+mod metadata;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod metrics;
+mod output;
 mod process;
+#[cfg(unix)]
+mod procfile;
+mod provider;
+mod ratelimit;
+mod ready;
+#[cfg(unix)]
+mod sandbox;
 mod secrets;
+mod snapshot;
+mod sops;
+#[cfg(unix)]
+mod spiffe;
+#[cfg(unix)]
+mod supervise;
+#[cfg(test)]
+mod testing;
+mod timing;
 mod vault;
+#[cfg(target_os = "linux")]
+mod watch;
 
 use error::{Error, Result};
-use secrets::SecretTarget;
+use secrets::{Provider, Secret, SecretSpecs, SecretTarget};
 
 const RETRIES_MAX: usize = 20;
 const CONCURRENCY_MAX: usize = 64;
@@ -26,14 +78,281 @@ enum AuthProvider {
     Token,
     Github,
     Kubernetes,
+    /// JWT login with the OIDC token of the GitHub Actions job.
+    GithubActions,
+    /// JWT login with the ID token of the GitLab CI job.
+    Gitlab,
+    /// JWT login with the JWT-SVID of the workload from the SPIFFE Workload API.
+    Spiffe,
+}
+
+/// Action taken when a refresh detects changed secrets.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+enum OnChange {
+    /// Send `--on-change-signal` to the child.
+    Signal,
+    /// Stop the child and spawn it again with the new secrets.
+    Restart,
+    /// Run `--on-change-hook` with the new secrets in its environment.
+    Hook,
+}
+
+/// Subcommands; a command line without one is run as with `run`.
+///
+/// All options are accepted before and after the subcommand.
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Fetch the secrets and run the command with them (the default).
+    Run {
+        /// Command to run after fetching secrets, followed by its arguments.
+        #[arg(
+            value_names = ["CMD", "ARGS"],
+            trailing_var_arg = true,
+            required_unless_present_any = ["procfile", "shell", "dry_run", "k8s_init", "print_token_info", "build_info"],
+            conflicts_with_all = ["procfile", "shell"]
+        )]
+        cmd: Vec<String>,
+    },
+    /// Fetch the secrets and print the variables of env targets instead of running a command.
+    ///
+    /// File targets are written as with `run`.
+    Fetch {
+        /// Output format.
+        #[arg(long, value_enum, default_value = "dotenv")]
+        format: process::SecretsFormat,
+    },
+    /// Check the options, the auth configuration and the secrets file without contacting Vault,
+    /// unless --keys is given.
+    Validate {
+        /// Also log in and check that every key read from a kv v2 secret exists, via the
+        /// `subkeys` endpoint which returns no values.
+        #[arg(long)]
+        keys: bool,
+    },
+    /// Fetch the secrets and render a template.
+    ///
+    /// Every `{{ NAME }}` is replaced with the secret of the env variable NAME, or `file:<path>`
+    /// for file targets.
+    Template {
+        /// Template file, `-` reads it from stdin.
+        template: PathBuf,
+        /// Write the rendered template to this file (mode 0600) instead of stdout.
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+    },
+    /// Fetch the secrets and print `export NAME='value'` lines of env targets.
+    ///
+    /// The output can be evaluated by a shell, e.g. `eval "$(vaultify env)"`. File targets are
+    /// written as with `run`.
+    Env,
+    /// Fetch the secrets and print the environment the command would get, where each variable
+    /// comes from and the values as hashes.
+    ///
+    /// Variables are inherited, injected from a secret or read from an --env-file. File targets
+    /// are written as with `run`.
+    DebugEnv {
+        /// Print the values of inherited variables instead of their hashes.
+        #[arg(long)]
+        show_inherited: bool,
+        /// Key of the HMAC-SHA256 hashes of the values, e.g. to compare them across runs; a
+        /// random key of the run is printed otherwise.
+        #[arg(long, env = "VAULTIFY_HASH_KEY", hide_env_values = true)]
+        hash_key: Option<String>,
+    },
+    /// Write a secret to a kv v2 mount of Vault, keeping the other keys of the secret.
+    ///
+    /// The value is read from stdin (without its trailing newline) unless --value is given. The
+    /// write fails if the secret changed since it was read, or since the version of --cas.
+    Put {
+        /// Secret to write, as `mount/path#key`.
+        #[arg(value_name = "SECRET")]
+        secret: String,
+        /// Value to write instead of reading it from stdin. Visible to other users in the process
+        /// list, prefer stdin for sensitive values.
+        #[arg(long)]
+        value: Option<String>,
+        /// Version the secret must have for the write to succeed, 0 if it must not exist yet.
+        #[arg(long)]
+        cas: Option<u64>,
+    },
+    /// Write the variables of a dotenv file to a kv v2 mount of Vault and print the lines of a
+    /// secrets file referencing them.
+    ///
+    /// All variables are written as keys of a single secret, keeping its other keys. With
+    /// --split, every variable is written as `value` key of its own secret below the path.
+    Import {
+        /// Dotenv file to read, `-` reads it from stdin.
+        #[arg(long)]
+        from: PathBuf,
+        /// Path of the secret to write, as `mount/path`.
+        #[arg(long)]
+        to: String,
+        /// Write one secret per variable, at `<path>/<NAME>`.
+        #[arg(long)]
+        split: bool,
+    },
+    /// List the secrets and folders (ending with `/`) below a folder of a kv mount of Vault.
+    List {
+        /// Folder to list, as `mount/path/` or `mount/` for the whole mount.
+        #[arg(value_name = "FOLDER")]
+        folder: String,
+        /// List all secrets below the folder instead of its direct children.
+        #[arg(long, short = 'r')]
+        recursive: bool,
+    },
+    /// Create the secrets file (--secrets-file) by picking secrets of Vault, or print a skeleton
+    /// of it for the variables of the environment.
+    Init {
+        /// Browse the kv mounts of Vault in the terminal and pick the keys and the variables to
+        /// pass them as.
+        #[arg(
+            long,
+            required_unless_present = "from_env",
+            conflicts_with = "from_env"
+        )]
+        interactive: bool,
+        /// Print a secrets file mapping the variables of the environment, or of a dotenv file, to
+        /// suggested keys of a secret in Vault. Values are never printed.
+        #[arg(long, value_name = "DOTENV", num_args = 0..=1, require_equals = true)]
+        from_env: Option<Option<PathBuf>>,
+        /// Only map the variables starting with this prefix, required for the environment.
+        #[arg(long, requires = "from_env")]
+        prefix: Option<String>,
+        /// Secret the variables are mapped to, as `mount/path`. Defaults to `secret/<prefix>`.
+        #[arg(long, requires = "from_env")]
+        to: Option<String>,
+    },
+    /// Print the completion script of a shell.
+    Completions {
+        #[arg(id = "completion_shell", value_name = "SHELL", value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the reference of all options, subcommands and the secrets file.
+    Docs {
+        /// Print a man page.
+        #[arg(
+            long,
+            conflicts_with = "markdown",
+            required_unless_present = "markdown"
+        )]
+        man: bool,
+        /// Print markdown.
+        #[arg(long)]
+        markdown: bool,
+        /// Write the man pages of vaultify and of each subcommand (e.g. `vaultify-fetch.1`) to
+        /// this directory instead of printing the page of vaultify.
+        #[arg(long, value_name = "DIR", requires = "man")]
+        out_dir: Option<PathBuf>,
+    },
+    /// Stop the detached instance in the pidfile and remove the pidfile.
+    Stop {
+        /// Pidfile written by `--detach --pidfile`.
+        #[arg(long)]
+        pidfile: PathBuf,
+        /// Signal sent to the detached instance.
+        #[arg(long, default_value = "SIGTERM", value_parser = parse_signal_name)]
+        signal: String,
+        /// Time to wait for the detached instance to exit.
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+    /// Check whether the detached instance in the pidfile is running.
+    /// Exits with 0 if it is running, 1 if the pidfile is stale and 3 if there is no pidfile.
+    #[command(verbatim_doc_comment)]
+    Status {
+        /// Pidfile written by `--detach --pidfile`.
+        #[arg(long)]
+        pidfile: PathBuf,
+    },
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    override_usage = "vaultify [OPTIONS] <CMD> [ARGS]...\n       vaultify [OPTIONS] <COMMAND>",
+    after_long_help = format!("Secrets file:\n{}", secrets::GRAMMAR),
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Vault address (in the same format as vault-cli).
-    #[arg(long, env = "VAULT_ADDR", default_value = "http://127.0.0.1:8200")]
-    pub host: String,
+    #[command(subcommand)]
+    pub subcommand: Option<Command>,
+    /// Executable in PATH named like the subcommand, which was run before subcommands existed.
+    #[arg(skip)]
+    pub shadowed_command: Option<PathBuf>,
+    /// State shared by the vault clients of this run, e.g. the leases to revoke on exit.
+    #[arg(skip)]
+    pub vault: vault::Context,
+
+    /// Config file with defaults of these options, keyed by their long name (e.g. `ca-cert`).
+    /// Defaults to vaultify/vaultify.toml in $XDG_CONFIG_HOME (~/.config) or $XDG_CONFIG_DIRS.
+    #[arg(long, env = "VAULTIFY_CONFIG", verbatim_doc_comment)]
+    pub config: Option<PathBuf>,
+    /// Named profile of the config file (a `[profile.<name>]` table) overriding its defaults,
+    /// e.g. to switch between the host, auth and secrets file of clusters.
+    #[arg(long, env = "VAULTIFY_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Vault address (in the same format as vault-cli). A comma separated list of addresses is
+    /// tried in order, failing over to the next one while a vault is unreachable or sealed.
+    #[arg(
+        long,
+        env = "VAULT_ADDR",
+        default_value = "http://127.0.0.1:8200",
+        value_delimiter = ','
+    )]
+    pub host: Vec<String>,
+    /// PEM encoded CA certificate file used to verify the Vault server certificate.
+    #[arg(long, env = "VAULT_CACERT")]
+    pub ca_cert: Option<PathBuf>,
+    /// Directory of PEM encoded CA certificate files used to verify the Vault server certificate.
+    #[arg(long, env = "VAULT_CAPATH")]
+    pub ca_path: Option<PathBuf>,
+    /// PEM encoded client certificate for mutual TLS with the Vault server.
+    #[arg(long, env = "VAULT_CLIENT_CERT", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM encoded private key of --client-cert.
+    #[arg(long, env = "VAULT_CLIENT_KEY", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    /// Disable verification of the Vault server certificate. Insecure, only use for bootstrapping.
+    #[arg(
+        long,
+        env = "VAULT_SKIP_VERIFY",
+        default_value = "false",
+        value_parser = parse_env_bool
+    )]
+    pub tls_skip_verify: bool,
+    /// Refuse to send credentials to Vault over plain `http://` unless it runs on localhost. Set
+    /// to `false` to only warn, e.g. if the connection is protected otherwise.
+    #[arg(
+        long,
+        env = "VAULTIFY_REQUIRE_TLS",
+        value_name = "BOOL",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub require_tls: bool,
+    /// Name sent as SNI and the Vault server certificate is verified for instead of the host of
+    /// --host, e.g. when Vault is only reachable via an IP address. Requests are still sent to
+    /// --host, without a proxy.
+    #[arg(long, env = "VAULT_TLS_SERVER_NAME")]
+    pub tls_server_name: Option<String>,
+    /// Proxy used for all requests to Vault (e.g. http://proxy:3128).
+    /// Defaults to HTTPS_PROXY / HTTP_PROXY, hosts in NO_PROXY are always reached directly.
+    #[arg(long, env = "VAULT_PROXY_ADDR", verbatim_doc_comment)]
+    pub proxy: Option<String>,
+    /// Header sent with every request to Vault (e.g. `X-Tenant: foo`), can be repeated.
+    #[arg(long = "header", value_name = "HEADER", value_parser = vault::parse_header)]
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Id of this run sent as `X-Vaultify-Correlation-Id` header with every request to Vault and
+    /// logged as `correlation_id`, to find the requests of a run in the audit log of Vault, which
+    /// requires the header in sys/config/auditing/request-headers. Defaults to a random id.
+    #[arg(long, env = "VAULTIFY_CORRELATION_ID", verbatim_doc_comment)]
+    pub correlation_id: Option<String>,
+    /// Vault Enterprise namespace of all requests.
+    #[arg(long, env = "VAULT_NAMESPACE")]
+    pub namespace: Option<String>,
     /// Authenticate via Vault access token.
     #[arg(long, env = "VAULT_TOKEN")]
     token: Option<String>,
@@ -63,30 +382,367 @@ struct Args {
         default_value = "kubernetes"
     )]
     kubernetes_auth_backend: String,
+    /// Role to log in with via --auth-provider github-actions or spiffe, defaults to the default
+    /// role of the JWT auth backend.
+    #[arg(long, env = "VAULT_JWT_ROLE")]
+    jwt_role: Option<String>,
+    /// Vault auth backend mount name for the JWT login of GitHub Actions, GitLab CI and SPIFFE.
+    #[arg(long, env = "VAULT_JWT_AUTH_BACKEND", default_value = "jwt")]
+    jwt_auth_backend: String,
+    /// Audience of the OIDC token requested from GitHub Actions, which the role has to accept.
+    /// Defaults to the audience chosen by GitHub, the URL of the repository owner.
+    #[arg(long, env = "VAULT_GITHUB_ACTIONS_AUDIENCE")]
+    github_actions_audience: Option<String>,
+    /// Role of the JWT auth backend to log in with via --auth-provider gitlab.
+    #[arg(long, env = "VAULT_AUTH_ROLE")]
+    gitlab_role: Option<String>,
+    /// Variable holding the ID token of the GitLab CI job, as declared under `id_tokens`.
+    /// Falls back to CI_JOB_JWT_V2 of older GitLab versions if the variable is not set.
+    #[arg(long, default_value = "VAULT_ID_TOKEN", verbatim_doc_comment)]
+    gitlab_id_token_var: String,
+    /// SPIFFE Workload API socket of the agent, e.g. unix:///run/spire/sockets/agent.sock.
+    #[arg(long, env = "SPIFFE_ENDPOINT_SOCKET")]
+    spiffe_endpoint_socket: Option<String>,
+    /// Audience of the JWT-SVID, which the role has to list in its bound_audiences.
+    #[arg(long, env = "VAULT_SPIFFE_AUDIENCE", default_value = "vault")]
+    spiffe_audience: String,
 
+    /// File of the secrets to fetch, one `SOURCE | TARGET` per line (see `--help`).
     #[arg(long, default_value = ".secrets")]
     pub secrets_file: PathBuf,
+    /// age identity file decrypting an encrypted secrets file, e.g. `.secrets.age`, in memory.
+    #[arg(long, env = "VAULTIFY_SECRETS_IDENTITY")]
+    pub secrets_identity: Option<PathBuf>,
 
-    /// Command to run after fetching secrets.
-    #[clap(index = 1)]
-    pub cmd: String,
-    /// Arguments to pass to <CMD>.
-    #[clap(index = 2)]
-    pub args: Vec<String>,
+    /// Command to run after fetching secrets, followed by its arguments.
+    /// Everything after <CMD> is passed to it as is, even if it looks like a vaultify option.
+    /// Commands named like a subcommand have to follow `--` or `run`.
+    #[clap(
+        value_names = ["CMD", "ARGS"],
+        trailing_var_arg = true,
+        required_unless_present_any = ["procfile", "shell", "dry_run", "k8s_init", "print_token_info", "build_info"],
+        conflicts_with = "shell",
+        verbatim_doc_comment
+    )]
+    pub cmd: Vec<String>,
+    /// Run the given command line through the shell (`/bin/sh -c`) instead of <CMD>.
+    #[arg(short = 'c', long, conflicts_with = "procfile")]
+    pub shell: Option<String>,
 
     /// Number of retries per query.
-    #[arg(long, default_value = "3", value_parser = parse_retries)]
+    #[arg(
+        long,
+        env = "VAULT_MAX_RETRIES",
+        default_value = "3",
+        value_parser = parse_retries
+    )]
     pub retries: usize,
     /// Delay between retries (in ms).
     #[arg(long, default_value = "50")]
     pub retry_delay_ms: u64,
+    /// KV mounts of version 1 (comma separated), read without trying the v2 API first. Other
+    /// mounts are looked up once when reading a v2 secret from them fails.
+    #[arg(long, value_delimiter = ',')]
+    pub kv_v1_mounts: Vec<String>,
+    /// Failed attempts in a row (unreachable or sealed after all retries) after which a vault
+    /// address is skipped for --circuit-breaker-cooldown, e.g. on refreshes; 0 never skips it.
+    #[arg(long, default_value = "3")]
+    pub circuit_breaker_threshold: usize,
+    /// How long a vault address is skipped once its circuit breaker opened.
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub circuit_breaker_cooldown: Duration,
+    /// Timeout of a single request to Vault, e.g. `30s` or a number of seconds.
+    #[arg(
+        long,
+        env = "VAULT_CLIENT_TIMEOUT",
+        default_value = "30s",
+        value_parser = parse_timeout
+    )]
+    pub request_timeout: Duration,
+    /// Timeout of each attempt to fetch the secrets of one path (e.g. `5s`), after which the
+    /// attempt is retried, so a hung connection does not hold back the fetch for the whole
+    /// --request-timeout.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub fetch_timeout: Option<Duration>,
+    /// Timeout for connecting to Vault.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    pub connect_timeout: Duration,
+    /// Total time (e.g. `30s`) for the login and fetching all secrets including retries; running
+    /// out of it fails with exit code 8, without failover or fallback values.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
+    /// Wait up to the given time (e.g. `2m`) for Vault to be initialized and unsealed before
+    /// fetching secrets.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub wait_for_vault: Option<Duration>,
+    /// Wait up to the given time (e.g. `10m`) for a sealed Vault to be unsealed instead of failing.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub unseal_timeout: Option<Duration>,
+    /// Do not check via sys/capabilities-self that the token can read all secrets before
+    /// fetching them.
+    #[arg(long, default_value = "false")]
+    pub no_preflight: bool,
+    /// Continue with the secrets which could be fetched if some are missing or may not be read,
+    /// logging a warning for each. Optionally limited to the secrets whose names (variables or
+    /// `file:<path>`) match the comma separated globs, e.g. `--allow-missing='OPTIONAL_*'`.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "*",
+        value_delimiter = ',',
+        value_name = "NAMES"
+    )]
+    pub allow_missing: Vec<String>,
     /// Number of parallel requests to the vault.
     #[arg(long, default_value = "8", value_parser = parse_concurrency)]
     pub concurrency: usize,
+    /// Maximum number of requests per second (e.g. `20` or `0.5`) while fetching secrets,
+    /// including retries.
+    #[arg(long, value_name = "REQ_PER_SEC", value_parser = ratelimit::parse_rate)]
+    pub rate_limit: Option<f64>,
+    /// JSON file mapping secret names (the env variable, or `file:<path>` for file targets) to
+    /// dummy values, used for all secrets if they cannot be fetched, e.g. during local
+    /// development without access to Vault.
+    #[arg(long)]
+    pub fallback_file: Option<PathBuf>,
+    /// Directory of an encrypted cache of the fetched secrets, used on startup while Vault is
+    /// unreachable or sealed.
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// File holding the key the cache is encrypted with; defaults to VAULTIFY_CACHE_KEY, or the
+    /// vault token if neither is set. The cache is disabled without any of them.
+    #[arg(long, requires = "cache_dir")]
+    pub cache_key_file: Option<PathBuf>,
+    /// Maximum age (e.g. `1h`) of cached secrets.
+    #[arg(long, default_value = "24h", value_parser = humantime::parse_duration)]
+    pub cache_ttl: Duration,
+    /// Write the fetched secrets to a snapshot file, which can be used with `--replay`.
+    #[arg(long, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// How values are written to the `--record` snapshot. Encrypted values use a key derived from
+    /// the passphrase in VAULTIFY_SNAPSHOT_KEY, which is required again for replaying.
+    #[arg(long, value_enum, default_value = "redacted")]
+    pub record_values: snapshot::RecordValues,
+    /// Read the secrets from a snapshot written with `--record` instead of fetching them.
+    #[arg(long, conflicts_with = "refresh_interval")]
+    pub replay: Option<PathBuf>,
 
     /// Clear the environment of the spawned process before spawning.
     #[arg(long, default_value = "false")]
     pub clear_env: bool,
+    /// Inherited environment variables kept with --clear-env (comma separated, globs allowed).
+    #[arg(long, value_delimiter = ',', requires = "clear_env")]
+    pub keep_env: Vec<String>,
+    /// Inherited environment variables never passed to the command (comma separated, globs allowed).
+    #[arg(long, value_delimiter = ',')]
+    pub drop_env: Vec<String>,
+    /// Dotenv file of plain `NAME=value` variables passed to the command (repeatable). They are
+    /// set over the inherited environment, later files over earlier ones, and secrets over them.
+    #[arg(long)]
+    pub env_file: Vec<PathBuf>,
+    /// Fail instead of warning if an inherited environment variable already holds a secret value
+    /// in plaintext or would be shadowed by a secret.
+    #[arg(long, default_value = "false")]
+    pub strict_env: bool,
+    /// What happens if a secret is named like an inherited environment variable: refuse to spawn
+    /// the command, keep the inherited value, or replace it with the secret.
+    #[arg(long, value_enum, default_value = "overwrite")]
+    pub on_conflict: process::OnConflict,
+    /// Pass metadata of the secrets to the command: VAULTIFY_SECRETS_HASH, a keyed hash of all
+    /// values which changes when a secret is rotated, <NAME>_VERSION with the kv v2 version of the
+    /// secret of each variable, and <NAME>_LEASE_ID and <NAME>_EXPIRES_AT of leased secrets.
+    #[arg(long, env = "VAULTIFY_METADATA_ENV", default_value = "false")]
+    pub metadata_env: bool,
+    /// Pass the secrets to the command via an inherited file descriptor in the given format
+    /// instead of the environment. The descriptor number is set in VAULTIFY_SECRETS_FD.
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub secrets_fd: Option<process::SecretsFormat>,
+    /// Pass the secrets to the command via stdin in the given format instead of the environment.
+    #[arg(long, value_enum, conflicts_with = "secrets_fd")]
+    pub stdin_format: Option<process::SecretsFormat>,
+    /// Working directory of the spawned process.
+    #[arg(long)]
+    pub chdir: Option<PathBuf>,
+    /// File mode creation mask of the spawned process in octal (e.g. 0027).
+    #[arg(long, value_parser = parse_umask)]
+    pub umask: Option<u32>,
+    /// Niceness increment of the spawned process (-20 to 19).
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    pub nice: Option<i32>,
+    /// Resource limit of the spawned process as NAME=SOFT[:HARD] (e.g. nofile=65536).
+    /// Can be given multiple times.
+    #[cfg(unix)]
+    #[arg(long, value_parser = sandbox::parse_rlimit, verbatim_doc_comment)]
+    pub rlimit: Vec<sandbox::Rlimit>,
+    /// Process group of the child when it runs attached: `inherit` keeps it in the group of
+    /// vaultify, so Ctrl-C and Ctrl-Z from the terminal reach both and vaultify leaves them to the
+    /// child; `own` starts a new group which takes over the terminal, so only the child gets them.
+    #[cfg(unix)]
+    #[arg(long, value_enum, default_value = "inherit")]
+    pub process_group: process::ProcessGroup,
+    /// OOM killer score adjustment of the spawned process (-1000 to 1000).
+    #[cfg(target_os = "linux")]
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+    pub oom_score_adj: Option<i32>,
+    /// Capabilities dropped before spawning the process (comma separated, e.g. CAP_NET_RAW or all).
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_delimiter = ',', value_parser = sandbox::parse_capability)]
+    pub cap_drop: Vec<sandbox::Capability>,
+
+    /// Re-fetch secrets periodically (e.g. `5m`) and keep the child attached.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub refresh_interval: Option<Duration>,
+    /// Action taken when a refresh detects changed secrets.
+    /// Defaults to `restart` with --supervise and `signal` otherwise.
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    pub on_change: Option<OnChange>,
+    /// Signal sent to the child on change when `--on-change signal` is used. Defaults to SIGHUP.
+    #[arg(long, value_parser = parse_signal_name)]
+    pub on_change_signal: Option<String>,
+    /// Command run via `/bin/sh -c` on change when `--on-change hook` is used.
+    #[arg(long)]
+    pub on_change_hook: Option<String>,
+    /// Run all processes of a Procfile (`name: command` per line) instead of <CMD>.
+    /// All processes receive the secrets and are stopped as soon as one of them exits.
+    #[arg(long, verbatim_doc_comment)]
+    pub procfile: Option<PathBuf>,
+    /// Watch the secrets file and reload the secrets when it changes (keeps the child attached).
+    #[arg(long, default_value = "false")]
+    pub watch_secrets_file: bool,
+
+    /// Signal sent to the child when vaultify is asked to terminate while attached.
+    /// Defaults to forwarding the received signal.
+    #[arg(long, value_parser = parse_signal_name, verbatim_doc_comment)]
+    pub stop_signal: Option<String>,
+    /// Time to wait for the child to exit after the stop signal before sending SIGKILL.
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub stop_timeout: Duration,
+
+    /// Keep the child attached and restart it with fresh secrets when it crashes.
+    #[arg(long, default_value = "false")]
+    pub supervise: bool,
+    /// Maximum number of crash restarts in supervise mode before giving up.
+    #[arg(long, default_value = "10")]
+    pub max_restarts: usize,
+    /// Keep the previous version of every file target as `<path>.bak` when it is replaced.
+    #[arg(long, default_value = "false")]
+    pub backup_files: bool,
+    /// Overwrite and remove the file targets (and their backups) when vaultify exits after the
+    /// child in attached mode.
+    #[arg(long, default_value = "false")]
+    pub cleanup_files: bool,
+    /// Only count the crash restarts within this window (e.g. `10m`) towards --max-restarts; a
+    /// child which ran for the whole window also resets the restart backoff, which otherwise
+    /// takes a run of 5 minutes.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub restart_window: Option<Duration>,
+    /// Delay before restarting a crashed child, doubled after every restart.
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    pub restart_backoff: Duration,
+    /// Revoke the leases of the fetched secrets, e.g. dynamic database credentials, via
+    /// `sys/leases/revoke` once the child exits, instead of leaving them valid for their TTL.
+    /// Requires the child to stay attached or --procfile.
+    #[arg(long, default_value = "false")]
+    pub revoke_leases: bool,
+    /// Fraction of the lifetime of `pki:` certificates after which they are issued again while
+    /// the child is attached. Vault tokens and leases are obtained again on each fetch instead.
+    #[arg(long, default_value = "0.7", value_parser = parse_fraction)]
+    pub renew_at_fraction: f64,
+    /// Fraction of the lifetime by which renewals are randomly moved earlier, so instances
+    /// started together do not renew at the same time.
+    #[arg(long, default_value = "0.1", value_parser = parse_fraction)]
+    pub renew_jitter: f64,
+    /// Serve Prometheus metrics (fetch latencies, refresh results, child restarts and lease
+    /// expiry) on this address, e.g. `127.0.0.1:9100`. Requires the child to stay attached.
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Serve health probes on this address, e.g. `0.0.0.0:8081`: `/livez` fails while the child
+    /// is not running, `/readyz` also while refreshing the secrets fails. Requires the child to stay
+    /// attached.
+    #[arg(long)]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Run as Kubernetes init container: write the file targets, e.g. to an emptyDir volume shared
+    /// with the app container, and the variables to --init-dotenv, then exit instead of running a
+    /// command.
+    #[arg(long, default_value = "false")]
+    pub k8s_init: bool,
+    /// Dotenv file the variables are written to (mode 0600) with --k8s-init.
+    #[arg(long, requires = "k8s_init")]
+    pub init_dotenv: Option<PathBuf>,
+    /// Owner of the files written with --k8s-init as UID[:GID], e.g. the user of the app container.
+    #[arg(long, requires = "k8s_init", value_parser = secrets::parse_owner)]
+    pub init_owner: Option<secrets::Owner>,
+
+    /// Fork into the background after fetching the secrets.
+    #[arg(long, default_value = "false")]
+    pub detach: bool,
+    /// Write the pid of the detached process to this file.
+    #[arg(long, requires = "detach")]
+    pub pidfile: Option<PathBuf>,
+    /// Hold an exclusive lock (flock) on this file while fetching and writing the secrets, so
+    /// concurrent invocations (e.g. overlapping cron runs) don't race. The lock is released when
+    /// the command is executed; attached modes such as --supervise hold it until vaultify exits.
+    #[arg(long, env = "VAULTIFY_LOCK_FILE")]
+    pub lock_file: Option<PathBuf>,
+    /// Wait up to this long for --lock-file while another invocation holds it, instead of failing
+    /// right away.
+    #[arg(long, requires = "lock_file", value_parser = humantime::parse_duration)]
+    pub lock_wait: Option<Duration>,
+    /// Create this file once the secrets are injected and the command is spawned.
+    #[arg(long)]
+    pub ready_file: Option<PathBuf>,
+    /// Send READY=1 to the service manager in NOTIFY_SOCKET once the command is spawned.
+    #[arg(long, default_value = "false")]
+    pub sd_notify: bool,
+    /// Print the targets of all secrets with their sources instead of running the command.
+    /// With `--dry-run=fetch` the secrets are fetched as well and printed redacted.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "parse",
+        verbatim_doc_comment
+    )]
+    pub dry_run: Option<dry_run::DryRun>,
+    /// Log in and print the accessor, policies and TTL of the token as JSON instead of running
+    /// the command, to look it up in the audit log or check what it may read.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub print_token_info: bool,
+    /// Print version, git commit, target and enabled features of this build as JSON and exit.
+    #[arg(long)]
+    pub build_info: bool,
+    /// Print how long authentication and fetching each secret path took, slowest first, to stderr
+    /// or with `--timing=<FILE>` to a file.
+    #[arg(long, num_args = 0..=1, require_equals = true, value_name = "FILE")]
+    pub timing: Option<Option<PathBuf>>,
+    /// Format of the log output on stderr. Values of fetched secrets are redacted in both.
+    #[arg(long, value_enum, default_value = "text", env = "VAULTIFY_LOG_FORMAT")]
+    pub log_format: logging::LogFormat,
+    /// Format of the error printed to stderr if vaultify fails, `json` for tools deciding whether
+    /// to retry based on the class of the error, the affected secrets and the HTTP status.
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        env = "VAULTIFY_ERROR_FORMAT"
+    )]
+    pub error_format: error::ErrorFormat,
+    /// Append the log output to this file instead of writing it to stderr, e.g. for --detach.
+    #[arg(long, env = "VAULTIFY_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Send the log output to the local syslog daemon instead of stderr.
+    #[arg(long, default_value = "false", conflicts_with = "log_file")]
+    pub log_syslog: bool,
+    /// Send the log output to journald, with structured fields, instead of stderr.
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["log_file", "log_syslog"]
+    )]
+    pub log_journald: bool,
 }
 
 fn parse_retries(raw: &str) -> std::result::Result<usize, String> {
@@ -120,29 +776,136 @@ fn parse_concurrency(raw: &str) -> std::result::Result<usize, String> {
     Ok(value)
 }
 
+fn parse_fraction(raw: &str) -> std::result::Result<f64, String> {
+    let value = raw
+        .parse::<f64>()
+        .map_err(|e| format!("invalid fraction: {e}"))?;
+    if !(0.0..1.0).contains(&value) {
+        return Err("fraction must be at least 0 and less than 1".to_string());
+    }
+
+    Ok(value)
+}
+
+/// Parses a boolean like the vault cli does for its environment variables, e.g. `1` or `TRUE`,
+/// an empty value is false.
+fn parse_env_bool(raw: &str) -> std::result::Result<bool, String> {
+    match raw.to_ascii_lowercase().as_str() {
+        "1" | "t" | "true" | "y" | "yes" | "on" => Ok(true),
+        "" | "0" | "f" | "false" | "n" | "no" | "off" => Ok(false),
+        _ => Err("expected a boolean, e.g. `true` or `false`".to_string()),
+    }
+}
+
+/// Parses a duration like `30s` or, as the vault cli does for `VAULT_CLIENT_TIMEOUT`, a plain
+/// number of seconds.
+fn parse_timeout(raw: &str) -> std::result::Result<Duration, String> {
+    match raw.parse::<u64>() {
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(_) => humantime::parse_duration(raw).map_err(|e| format!("invalid timeout: {e}")),
+    }
+}
+
+fn parse_umask(raw: &str) -> std::result::Result<u32, String> {
+    let value = u32::from_str_radix(raw, 8).map_err(|e| format!("invalid umask value: {e}"))?;
+    if value > 0o777 {
+        return Err("umask must be less than or equal to 0777".to_string());
+    }
+
+    Ok(value)
+}
+
+fn parse_signal_name(raw: &str) -> std::result::Result<String, String> {
+    #[cfg(unix)]
+    process::parse_signal(raw).map_err(|err| err.to_string())?;
+
+    Ok(raw.to_string())
+}
+
 enum AuthMethod {
-    GitHub { token: String, backend: String },
-    Kubernetes { role: String, backend: String },
+    GitHub {
+        token: String,
+        backend: String,
+    },
+    Kubernetes {
+        role: String,
+        backend: String,
+    },
+    /// JWT login with the ID token of a GitLab CI job.
+    GitLab {
+        jwt: String,
+        role: String,
+        backend: String,
+    },
+    /// JWT login with an OIDC token requested from the runner of a GitHub Actions job.
+    GitHubActions {
+        /// `ACTIONS_ID_TOKEN_REQUEST_URL` of the job.
+        request_url: String,
+        /// `ACTIONS_ID_TOKEN_REQUEST_TOKEN` of the job.
+        request_token: String,
+        audience: Option<String>,
+        role: Option<String>,
+        backend: String,
+    },
+    /// JWT login with the JWT-SVID fetched from the SPIFFE Workload API on each login.
+    #[cfg(unix)]
+    Spiffe {
+        socket: PathBuf,
+        audience: String,
+        role: Option<String>,
+        backend: String,
+    },
     Token(String),
 }
 
+impl AuthMethod {
+    /// The credential passed in, as opposed to one read or requested on login.
+    fn credential(&self) -> Option<&str> {
+        match self {
+            AuthMethod::GitHub { token, .. } | AuthMethod::Token(token) => Some(token),
+            AuthMethod::GitLab { jwt, .. } => Some(jwt),
+            AuthMethod::GitHubActions { request_token, .. } => Some(request_token),
+            AuthMethod::Kubernetes { .. } => None,
+            #[cfg(unix)]
+            AuthMethod::Spiffe { .. } => None,
+        }
+    }
+}
+
 impl Args {
+    /// Command line parser, which accepts all options before and after a subcommand.
+    pub fn cli() -> clap::Command {
+        <Args as CommandFactory>::command().mut_args(|arg| {
+            if arg.is_positional() {
+                arg
+            } else {
+                arg.global(true)
+            }
+        })
+    }
+
+    /// Builds the arguments from a parsed command line, where `run` is the same as no subcommand.
+    pub fn from_matches(matches: &ArgMatches) -> std::result::Result<Self, clap::Error> {
+        let mut args = <Args as FromArgMatches>::from_arg_matches(matches)?;
+        match args.subcommand.take() {
+            Some(Command::Run { cmd }) => args.cmd = cmd,
+            command => args.subcommand = command,
+        }
+        if args.subcommand.is_some() {
+            args.shadowed_command = matches.subcommand_name().and_then(find_executable);
+        }
+        Ok(args)
+    }
+
     pub fn auth_method(&self) -> Result<AuthMethod> {
+        self.auth_method_with(|var| std::env::var(var).ok())
+    }
+
+    /// The auth method of `--auth-provider`, with the variables of the CI job looked up by `env`.
+    fn auth_method_with(&self, env: impl Fn(&str) -> Option<String>) -> Result<AuthMethod> {
+        self.check_other_credentials()?;
         match self.auth_provider {
             AuthProvider::Token => {
-                if self.github_token.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider token cannot be combined with --github-token"
-                            .to_string(),
-                    ));
-                }
-                if self.kubernetes_role.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider token cannot be combined with --kubernetes-role"
-                            .to_string(),
-                    ));
-                }
-
                 let token = self.token.as_ref().ok_or_else(|| {
                     Error::Execution(
                         "invalid auth configuration: --auth-provider token requires --token (or VAULT_TOKEN)"
@@ -152,19 +915,6 @@ impl Args {
                 Ok(AuthMethod::Token(token.clone()))
             }
             AuthProvider::Github => {
-                if self.token.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider github cannot be combined with --token"
-                            .to_string(),
-                    ));
-                }
-                if self.kubernetes_role.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider github cannot be combined with --kubernetes-role"
-                            .to_string(),
-                    ));
-                }
-
                 let token = self.github_token.as_ref().ok_or_else(|| {
                     Error::Execution(
                         "invalid auth configuration: --auth-provider github requires --github-token (or VAULT_GITHUB_TOKEN)"
@@ -177,19 +927,6 @@ impl Args {
                 })
             }
             AuthProvider::Kubernetes => {
-                if self.token.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider kubernetes cannot be combined with --token"
-                            .to_string(),
-                    ));
-                }
-                if self.github_token.is_some() {
-                    return Err(Error::Execution(
-                        "invalid auth configuration: --auth-provider kubernetes cannot be combined with --github-token"
-                            .to_string(),
-                    ));
-                }
-
                 let role = self.kubernetes_role.as_ref().ok_or_else(|| {
                     Error::Execution(
                         "invalid auth configuration: --auth-provider kubernetes requires --kubernetes-role (or VAULT_KUBERNETES_ROLE)"
@@ -201,216 +938,2063 @@ impl Args {
                     backend: self.kubernetes_auth_backend.clone(),
                 })
             }
+            AuthProvider::GithubActions => {
+                let (Some(request_url), Some(request_token)) = (
+                    env("ACTIONS_ID_TOKEN_REQUEST_URL"),
+                    env("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+                ) else {
+                    return Err(Error::Execution(
+                        "invalid auth configuration: --auth-provider github-actions requires ACTIONS_ID_TOKEN_REQUEST_URL and ACTIONS_ID_TOKEN_REQUEST_TOKEN, which GitHub sets for jobs with `permissions: id-token: write`"
+                            .to_string(),
+                    ));
+                };
+                Ok(AuthMethod::GitHubActions {
+                    request_url,
+                    request_token,
+                    audience: self.github_actions_audience.clone(),
+                    role: self.jwt_role.clone(),
+                    backend: self.jwt_auth_backend.clone(),
+                })
+            }
+            AuthProvider::Gitlab => {
+                let role = self.gitlab_role.as_ref().ok_or_else(|| {
+                    Error::Execution(
+                        "invalid auth configuration: --auth-provider gitlab requires --gitlab-role (or VAULT_AUTH_ROLE)"
+                            .to_string(),
+                    )
+                })?;
+                let jwt = [self.gitlab_id_token_var.as_str(), "CI_JOB_JWT_V2"]
+                    .into_iter()
+                    .find_map(|var| env(var).filter(|jwt| !jwt.is_empty()))
+                    .ok_or_else(|| {
+                        Error::Execution(format!(
+                            "invalid auth configuration: --auth-provider gitlab requires the ID token of the job in {}, declared under `id_tokens` of the job",
+                            self.gitlab_id_token_var
+                        ))
+                    })?;
+                Ok(AuthMethod::GitLab {
+                    jwt,
+                    role: role.clone(),
+                    backend: self.jwt_auth_backend.clone(),
+                })
+            }
+            AuthProvider::Spiffe => {
+                let endpoint = self.spiffe_endpoint_socket.as_ref().ok_or_else(|| {
+                    Error::Execution(
+                        "invalid auth configuration: --auth-provider spiffe requires --spiffe-endpoint-socket (or SPIFFE_ENDPOINT_SOCKET)"
+                            .to_string(),
+                    )
+                })?;
+                Ok(AuthMethod::Spiffe {
+                    socket: spiffe::socket_path(endpoint)?,
+                    audience: self.spiffe_audience.clone(),
+                    role: self.jwt_role.clone(),
+                    backend: self.jwt_auth_backend.clone(),
+                })
+            }
         }
     }
-}
 
-struct PreparedSpawn {
-    cmd: String,
-    args: Vec<String>,
-    env_secrets: Vec<process::EnvSecret>,
-    clear_env: bool,
-}
+    /// Fails if the credential of another auth provider than `--auth-provider` is given.
+    fn check_other_credentials(&self) -> Result<()> {
+        let credentials: [(&[AuthProvider], &str, bool); 5] = [
+            (&[AuthProvider::Token], "--token", self.token.is_some()),
+            (
+                &[AuthProvider::Github],
+                "--github-token",
+                self.github_token.is_some(),
+            ),
+            (
+                &[AuthProvider::Kubernetes],
+                "--kubernetes-role",
+                self.kubernetes_role.is_some(),
+            ),
+            (
+                &[AuthProvider::Gitlab],
+                "--gitlab-role",
+                self.gitlab_role.is_some(),
+            ),
+            (
+                &[AuthProvider::GithubActions, AuthProvider::Spiffe],
+                "--jwt-role",
+                self.jwt_role.is_some(),
+            ),
+        ];
+        for (providers, option, given) in credentials {
+            if given && !providers.contains(&self.auth_provider) {
+                let name = self
+                    .auth_provider
+                    .to_possible_value()
+                    .map(|value| value.get_name().to_string())
+                    .unwrap_or_default();
+                return Err(Error::Execution(format!(
+                    "invalid auth configuration: --auth-provider {} cannot be combined with {}",
+                    name, option
+                )));
+            }
+        }
 
-fn main() -> Result<()> {
-    // set default log level to warn
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+        Ok(())
+    }
 
-    let args = Args::parse();
+    /// Whether a credential for the `--auth-provider` is configured, as opposed to e.g. a
+    /// developer machine without access to vault.
+    fn has_credential(&self) -> bool {
+        let env_set = |var: &str| std::env::var_os(var).is_some_and(|value| !value.is_empty());
+        match self.auth_provider {
+            AuthProvider::Token => self.token.is_some(),
+            AuthProvider::Github => self.github_token.is_some(),
+            AuthProvider::Kubernetes => self.kubernetes_role.is_some(),
+            AuthProvider::GithubActions => env_set("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+            AuthProvider::Gitlab => env_set(&self.gitlab_id_token_var) || env_set("CI_JOB_JWT_V2"),
+            AuthProvider::Spiffe => self.spiffe_endpoint_socket.is_some(),
+        }
+    }
 
-    let runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .map_err(|err| Error::Execution(format!("unable to initialize tokio runtime: {}", err)))?;
-    let prepared = runtime.block_on(prepare_spawn(args))?;
-    drop(runtime);
+    /// Validates the options controlling how the child is spawned.
+    pub fn validate(&self) -> Result<()> {
+        if self.k8s_init
+            && (!self.cmd.is_empty()
+                || self.shell.is_some()
+                || self.procfile.is_some()
+                || self.attached()
+                || self.detach)
+        {
+            return Err(Error::Execution(
+                "invalid configuration: --k8s-init exits after writing the secrets and cannot run a command"
+                    .to_string(),
+            ));
+        }
 
-    process::spawn(
-        prepared.cmd,
-        &prepared.args,
-        &prepared.env_secrets,
-        process::SpawnOptions {
-            clear_env: prepared.clear_env,
-        },
-    )?;
+        if self.procfile.is_some() && self.attached() {
+            return Err(Error::Execution(
+                "invalid configuration: --procfile cannot be combined with --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
+        }
 
-    Ok(())
-}
+        if self.revoke_leases && !self.attached() && self.procfile.is_none() {
+            return Err(Error::Execution(
+                "invalid configuration: --revoke-leases requires --procfile, --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
+        }
 
-async fn prepare_spawn(args: Args) -> Result<PreparedSpawn> {
-    // validate auth selection before reading secret specs
-    let auth_method = args.auth_method()?;
+        if self.cleanup_files && !self.attached() && self.procfile.is_none() {
+            return Err(Error::Execution(
+                "invalid configuration: --cleanup-files requires --procfile, --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
+        }
 
-    // read secret spec file
-    let secret_specs = match secrets::load_async(&args.secrets_file).await {
-        Ok(specs) => specs,
-        Err(err) => {
-            println!("Error parsing secrets file: {err}");
-            return Err(err);
+        if self.metrics_addr.is_some() && !self.attached() {
+            return Err(Error::Execution(
+                "invalid configuration: --metrics-addr requires --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
+        }
+        if self.health_addr.is_some() && !self.attached() {
+            return Err(Error::Execution(
+                "invalid configuration: --health-addr requires --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
         }
-    };
 
-    // get / fetch token
-    let opts = vault::FetchTokenOpts {
-        retries: args.retries,
-        retry_delay: Duration::from_millis(args.retry_delay_ms),
-    };
-    let token = match vault::fetch_token(&args.host, auth_method, opts).await {
-        Ok(token) => token,
-        Err(err) => {
-            println!("Error getting vault token: {err}");
-            return Err(err);
+        if self.renew_jitter >= self.renew_at_fraction {
+            return Err(Error::Execution(
+                "invalid renewal configuration: --renew-jitter must be less than --renew-at-fraction"
+                    .to_string(),
+            ));
         }
-    };
 
-    // read secrets
-    let opts = vault::FetchAllOpts {
-        retries: args.retries,
-        retry_delay: Duration::from_millis(args.retry_delay_ms),
-        concurrency: args.concurrency,
-    };
-    let secrets = match vault::fetch_all(&args.host, token.as_deref(), &secret_specs, opts).await {
-        Ok(secrets) => secrets,
-        Err(err) => {
-            println!("Error fetching secrets: {err}");
-            return Err(err);
+        if !self.attached()
+            && (self.on_change.is_some()
+                || self.on_change_signal.is_some()
+                || self.on_change_hook.is_some())
+        {
+            return Err(Error::Execution(
+                "invalid refresh configuration: --on-change, --on-change-signal and --on-change-hook require --refresh-interval, --supervise or --watch-secrets-file"
+                    .to_string(),
+            ));
+        }
+        if self.on_change() == OnChange::Hook && self.on_change_hook.is_none() {
+            return Err(Error::Execution(
+                "invalid refresh configuration: --on-change hook requires --on-change-hook"
+                    .to_string(),
+            ));
+        }
+        if self.on_change_hook.is_some() && self.on_change() != OnChange::Hook {
+            return Err(Error::Execution(
+                "invalid refresh configuration: --on-change-hook requires --on-change hook"
+                    .to_string(),
+            ));
         }
-    };
 
-    let mut env_secrets = Vec::new();
-    for secret in secrets.into_iter() {
-        match secret.target {
-            SecretTarget::Env { name } => env_secrets.push(process::EnvSecret {
-                name,
-                secret: secret.secret,
-            }),
-            SecretTarget::File { path, mode, create } => {
-                write_secret_to_file(&path, &secret.secret, mode, create)?;
+        for host in self
+            .host
+            .iter()
+            .filter(|host| vault::is_plaintext_remote(host))
+        {
+            if self.require_tls {
+                return Err(Error::Execution(format!(
+                    "refusing to send credentials over plain http to `{}`; use https or pass --require-tls false",
+                    host
+                )));
             }
+            tracing::warn!(
+                "credentials are sent over plain http to `{}`, which is not on localhost",
+                host
+            );
         }
+
+        Ok(())
     }
 
-    Ok(PreparedSpawn {
-        cmd: args.cmd,
-        args: args.args,
-        env_secrets,
-        clear_env: args.clear_env,
-    })
-}
+    /// Command and arguments to spawn; present unless `--procfile` is used.
+    pub fn command(&self) -> Result<(&str, Vec<String>)> {
+        if let Some(shell) = &self.shell {
+            return Ok(process::shell_command(shell));
+        }
 
-fn write_secret_to_file(path: &Path, value: &str, mode: u32, create: bool) -> Result<()> {
-    let parent = path.parent().ok_or_else(|| {
-        Error::IO(format!(
-            "unable to resolve parent directory for file target {}",
-            path.display()
-        ))
-    })?;
+        let (cmd, args) = self
+            .cmd
+            .split_first()
+            .ok_or_else(|| Error::Execution("missing command to run".to_string()))?;
+        Ok((cmd, args.to_vec()))
+    }
 
-    ensure_secure_parent_directory(parent, path, create)?;
+    /// Whether vaultify stays resident and keeps the child attached instead of exec'ing it.
+    pub fn attached(&self) -> bool {
+        self.refresh_interval.is_some() || self.supervise || self.watch_secrets_file
+    }
 
-    let path_exists = path.exists();
-    let mut open_opts = std::fs::OpenOptions::new();
-    open_opts.write(true);
+    /// Destination of the log output.
+    pub fn log_output(&self) -> logging::LogOutput {
+        match &self.log_file {
+            Some(path) => logging::LogOutput::File(path.clone()),
+            None if self.log_syslog => logging::LogOutput::Syslog,
+            None if self.log_journald => logging::LogOutput::Journald,
+            None => logging::LogOutput::Stderr,
+        }
+    }
 
-    #[cfg(target_os = "linux")]
-    {
-        open_opts.custom_flags(O_NOFOLLOW | O_CLOEXEC).mode(mode);
+    /// Action taken when a refresh detects changed secrets.
+    pub fn on_change(&self) -> OnChange {
+        match self.on_change {
+            Some(on_change) => on_change,
+            None if self.supervise => OnChange::Restart,
+            None => OnChange::Signal,
+        }
     }
+}
+
+struct PreparedSpawn {
+    secret_specs: SecretSpecs,
+    secrets: Vec<Secret>,
+    env_secrets: Vec<process::EnvSecret>,
+    /// Variables of the `--env-file`s.
+    env_vars: Vec<(String, String)>,
+}
 
-    if !path_exists {
-        open_opts.create_new(true);
+fn main() {
+    let (err, error_format) = match config::parse_args() {
+        Ok(args) => {
+            let error_format = args.error_format;
+            match run(args) {
+                Ok(()) => return,
+                Err(err) => (err, error_format),
+            }
+        }
+        Err(err) => (err, error::ErrorFormat::Text),
+    };
+
+    match error_format {
+        error::ErrorFormat::Text => {
+            eprintln!("Error: {:?}", err);
+            // failed vault requests can be looked up in its audit log
+            if err.url().is_some() {
+                let request_id = err.context().request_id;
+                eprintln!(
+                    "vault request id: {}, correlation id: {}",
+                    request_id.as_deref().unwrap_or("unknown"),
+                    logging::correlation_id().unwrap_or("unknown")
+                );
+            }
+        }
+        error::ErrorFormat::Json => eprintln!("{}", err.to_json()),
     }
+    std::process::exit(err.exit_code());
+}
 
-    let mut file = open_opts
-        .open(path)
-        .map_err(|err| Error::IO(format!("unable to open {}: {}", path.display(), err)))?;
+/// Runs the subcommand or spawns the command of `args`.
+fn run(mut args: Args) -> Result<()> {
+    if args.build_info {
+        println!("{}", serde_json::to_string_pretty(&build_info::json())?);
+        return Ok(());
+    }
+    logging::init(args.log_format, &args.log_output())?;
+    let correlation_id = match &args.correlation_id {
+        Some(id) => id.clone(),
+        None => crypto::random_id()?,
+    };
+    let _run = logging::set_correlation_id(&correlation_id);
+    if let Some(path) = &args.shadowed_command {
+        tracing::warn!(
+            "running the vaultify subcommand, not {:?}; pass the command after `--` or `run` to run it",
+            path
+        );
+    }
 
-    let metadata = file.metadata().map_err(|err| {
-        Error::IO(format!(
-            "unable to read metadata for {}: {}",
-            path.display(),
-            err
-        ))
-    })?;
-    if !metadata.file_type().is_file() {
-        return Err(Error::IO(format!(
-            "refusing to write secret to non-regular file {}",
-            path.display()
-        )));
+    if let Some(command) = &args.subcommand {
+        if let Some(code) = run_helper(command)? {
+            std::process::exit(code);
+        }
     }
+    args.validate()?;
 
-    #[cfg(target_os = "linux")]
-    file.set_permissions(std::fs::Permissions::from_mode(mode))
-        .map_err(|err| {
-            Error::IO(format!(
-                "unable to set file mode {:o} for {}: {}",
-                mode,
-                path.display(),
-                err
-            ))
-        })?;
+    // held for the rest of the run, closing on exec releases it
+    #[cfg(unix)]
+    let _lock = match &args.lock_file {
+        Some(path) => Some(daemon::lock(path, args.lock_wait.unwrap_or_default())?),
+        None => None,
+    };
 
-    file.set_len(0)
-        .map_err(|err| Error::IO(format!("unable to truncate {}: {}", path.display(), err)))?;
+    // a crash must not leave the secrets in a core file, the child gets the original limit back
+    #[cfg(unix)]
+    if let Some(core_limit) = hardening::disable_core_dumps() {
+        if !args
+            .rlimit
+            .iter()
+            .any(|limit| limit.resource == core_limit.resource)
+        {
+            args.rlimit.push(core_limit);
+        }
+    }
+    // nor in swap, the secrets are locked into memory from their allocation on
+    #[cfg(unix)]
+    let _memory_lock = hardening::lock_memory();
 
-    file.write_all(value.as_bytes())
-        .map_err(|err| Error::IO(format!("unable to write {}: {}", path.display(), err)))?;
+    args.host = args.vault.init_client(
+        &args.host,
+        vault::ClientOpts {
+            request_timeout: args.request_timeout,
+            connect_timeout: args.connect_timeout,
+            ca_cert: args.ca_cert.clone(),
+            ca_path: args.ca_path.clone(),
+            client_identity: args.client_cert.clone().zip(args.client_key.clone()),
+            tls_skip_verify: args.tls_skip_verify,
+            tls_server_name: args.tls_server_name.clone(),
+            proxy: args.proxy.clone(),
+            headers: args
+                .headers
+                .iter()
+                .cloned()
+                .chain([vault::correlation_header(&correlation_id)?])
+                .collect(),
+        },
+    )?;
 
-    Ok(())
-}
+    if let Some(mode) = args.dry_run {
+        return runtime()?.block_on(print_dry_run(&args, mode));
+    }
+    if args.revoke_leases {
+        args.vault.track_leases();
+    }
+    args.vault.set_renewal(vault::Renewal {
+        fraction: args.renew_at_fraction,
+        jitter: args.renew_jitter,
+    });
+    args.vault.set_kv_v1_mounts(&args.kv_v1_mounts);
+    args.vault
+        .breakers()
+        .configure(vault::breaker::CircuitBreaker {
+            threshold: args.circuit_breaker_threshold,
+            cooldown: args.circuit_breaker_cooldown,
+        });
+    if args.print_token_info {
+        return runtime()?.block_on(print_token_info(&args));
+    }
+    if let Some(command) = &args.subcommand {
+        return runtime()?.block_on(run_subcommand(&args, command));
+    }
+    if args.k8s_init {
+        return runtime()?.block_on(init_container::run(&args));
+    }
 
-fn ensure_secure_parent_directory(parent: &Path, target_path: &Path, create: bool) -> Result<()> {
-    ensure_parent_directory_exists(parent, target_path, create)?;
-    ensure_parent_has_no_symlink_components(parent, target_path)
-}
+    let prepared = cleanup_on_error(&args, runtime()?.block_on(prepare_spawn(&args)))?;
 
-fn ensure_parent_directory_exists(parent: &Path, target_path: &Path, create: bool) -> Result<()> {
-    if parent.exists() {
-        if parent.is_dir() {
-            return Ok(());
+    // the runtime is dropped before detaching, as forking requires a single threaded process
+    #[cfg(unix)]
+    if args.detach {
+        cleanup_on_error(&args, daemon::detach(args.pidfile.as_deref()))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(procfile) = &args.procfile {
+        let runtime = runtime()?;
+        let code = runtime.block_on(async {
+            let specs = procfile::load_async(procfile).await?;
+            let code = procfile::run(
+                &specs,
+                &prepared.env_secrets,
+                &spawn_options(&args, &prepared.env_vars),
+                stop_options(&args)?,
+                &readiness(&args),
+            )
+            .await;
+            if args.revoke_leases {
+                args.vault.revoke_leases().await;
+            }
+            code
+        });
+        drop(runtime);
+        readiness(&args).clear();
+        if args.cleanup_files {
+            shred_written_files();
         }
+        std::process::exit(code?);
+    }
 
-        return Err(Error::IO(format!(
-            "parent path is not a directory for file target {}",
-            target_path.display()
-        )));
+    #[cfg(unix)]
+    if args.attached() {
+        let runtime = runtime()?;
+        let code = runtime.block_on(async {
+            let code = supervise::run(&args, prepared).await;
+            if args.revoke_leases {
+                args.vault.revoke_leases().await;
+            }
+            code
+        });
+        drop(runtime);
+        readiness(&args).clear();
+        if args.cleanup_files {
+            shred_written_files();
+        }
+        std::process::exit(code?);
     }
 
-    if !create {
-        return Err(Error::IO(format!(
-            "parent directory {} does not exist for file target {} (set create=true to create it)",
-            parent.display(),
-            target_path.display()
-        )));
+    // the command replaces vaultify, so readiness is signaled right before it is spawned
+    let readiness = readiness(&args);
+    readiness.notify()?;
+    let (cmd, cmd_args) = args.command()?;
+    let res = process::spawn(
+        cmd,
+        &cmd_args,
+        &prepared.env_secrets,
+        spawn_options(&args, &prepared.env_vars),
+    );
+    if res.is_err() {
+        readiness.clear();
     }
 
-    std::fs::create_dir_all(parent).map_err(|err| {
-        Error::IO(format!(
-            "unable to create parent directory {} for file target {}: {}",
-            parent.display(),
-            target_path.display(),
-            err
-        ))
-    })
+    res
 }
 
-fn ensure_parent_has_no_symlink_components(parent: &Path, target_path: &Path) -> Result<()> {
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| Error::Execution(format!("unable to initialize tokio runtime: {}", err)))
+}
+
+/// The executable `name` resolves to in PATH.
+fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            let Ok(metadata) = candidate.metadata() else {
+                return false;
+            };
+            use std::os::unix::fs::PermissionsExt;
+            metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+        })
+}
+
+/// Runs a helper subcommand which needs no secrets and returns its exit code, or `None` for the
+/// other subcommands.
+fn run_helper(command: &Command) -> Result<Option<i32>> {
+    match command {
+        Command::Completions { shell } => {
+            clap_complete::generate(*shell, &mut Args::cli(), "vaultify", &mut std::io::stdout());
+            Ok(Some(0))
+        }
+        Command::Docs { man, out_dir, .. } => {
+            if !*man {
+                print!("{}", docs::markdown(Args::cli()));
+            } else if let Some(dir) = out_dir {
+                for (name, page) in docs::man(Args::cli())? {
+                    let path = dir.join(name);
+                    std::fs::write(&path, page)
+                        .map_err(|err| Error::io_at("unable to write man page", &path, err))?;
+                }
+            } else {
+                print!("{}", docs::man(Args::cli())?[0].1);
+            }
+            Ok(Some(0))
+        }
+        Command::Stop {
+            pidfile,
+            signal,
+            timeout,
+        } => daemon::stop(pidfile, process::parse_signal(signal)?, *timeout).map(Some),
+        Command::Status { pidfile } => daemon::status(pidfile).map(Some),
+        Command::Run { .. }
+        | Command::Fetch { .. }
+        | Command::Validate { .. }
+        | Command::Template { .. }
+        | Command::Env
+        | Command::DebugEnv { .. }
+        | Command::Put { .. }
+        | Command::Import { .. }
+        | Command::List { .. }
+        | Command::Init { .. } => Ok(None),
+    }
+}
+
+/// Runs a subcommand which prints the secrets or checks them instead of running a command.
+async fn run_subcommand(args: &Args, command: &Command) -> Result<()> {
+    match command {
+        Command::Fetch { format } => {
+            let prepared = prepare_spawn(args).await?;
+            print!(
+                "{}",
+                process::format_secrets(&prepared.env_secrets, *format)
+            );
+        }
+        Command::Env => {
+            let prepared = prepare_spawn(args).await?;
+            print!("{}", output::shell_exports(&prepared.env_secrets));
+        }
+        Command::DebugEnv {
+            show_inherited,
+            hash_key,
+        } => {
+            let key = output::HashKey::new(hash_key.as_deref())?;
+            // only lists the file targets instead of writing them
+            let (secret_specs, secrets) = load_secrets(args).await?;
+            let env_secrets = env_secrets(args, &secrets)?;
+            let env_vars = load_env_files(&args.env_file).await?;
+            let opts = spawn_options(args, &env_vars);
+            let delivered = if args.secrets_fd.is_some() || args.stdin_format.is_some() {
+                tracing::info!(
+                    "the secrets are passed on a file descriptor or stdin, not the environment"
+                );
+                &[][..]
+            } else {
+                &env_secrets[..]
+            };
+            let env = process::child_env(delivered, &opts);
+            let files = secrets
+                .into_iter()
+                .filter(|secret| !matches!(secret.target, SecretTarget::Env { .. }))
+                .collect::<Vec<_>>();
+            let sources = output::EnvSources {
+                inherited: &process::child_env(&[], &spawn_options(args, &[])),
+                env_files: &env_vars,
+                secrets: delivered,
+                files: &files,
+                specs: &secret_specs,
+            };
+            print!(
+                "{}",
+                output::debug_env(&env, &sources, *show_inherited, &key)
+            );
+        }
+        Command::Template { template, output } => {
+            let contents = if template == Path::new("-") {
+                std::io::read_to_string(std::io::stdin())
+            } else {
+                std::fs::read_to_string(template)
+            }
+            .map_err(|err| Error::io_at("unable to read template", template, err))?;
+            let (_, secrets) = load_secrets(args).await?;
+            let rendered = output::render_template(&contents, &secrets)?;
+            match output {
+                Some(path) => write_secret_to_file(path, &rendered, 0o600, false)?,
+                None => print!("{}", rendered),
+            }
+        }
+        Command::Validate { keys } => {
+            let secret_specs =
+                secrets::load_async(&args.secrets_file, args.secrets_identity.as_deref())
+                    .await
+                    .inspect_err(|err| tracing::error!("error parsing secrets file: {}", err))?;
+            if args.replay.is_none() && provider::uses_vault(&secret_specs) {
+                args.auth_method()?;
+                if *keys {
+                    check_keys(args, &secret_specs).await?;
+                }
+            }
+            println!(
+                "{}: {} secrets ok",
+                args.secrets_file.display(),
+                secret_specs.len()
+            );
+        }
+        Command::Put { secret, value, cas } => {
+            let (mount, path, key) = secrets::parse_vault_secret(secret)?;
+            let value = match value {
+                Some(value) => value.clone(),
+                None => read_value()?,
+            };
+            let entries = Map::from_iter([(key.clone(), Value::String(value))]);
+            let writes = [(path.clone(), entries)];
+            let version = write_secrets(args, &mount, &writes, *cas).await?[0];
+            println!("{}/{}#{}: wrote version {}", mount, path, key, version);
+        }
+        Command::Import { from, to, split } => {
+            let (mount, path) = secrets::parse_vault_path(to)?;
+            let pairs = dotenv::load(from).await?;
+            let (writes, lines) = if *split {
+                let writes = pairs
+                    .iter()
+                    .map(|(name, value)| {
+                        let entries = Map::from_iter([("value".to_string(), value.clone().into())]);
+                        (format!("{}/{}", path, name), entries)
+                    })
+                    .collect::<Vec<_>>();
+                let lines = pairs
+                    .iter()
+                    .map(|(name, _)| format!("{}/{}/{}#value | env {}", mount, path, name, name))
+                    .collect::<Vec<_>>();
+                (writes, lines)
+            } else {
+                let lines = pairs
+                    .iter()
+                    .map(|(name, _)| format!("{}/{}#{} | env {}", mount, path, name, name))
+                    .collect::<Vec<_>>();
+                let entries = pairs
+                    .into_iter()
+                    .map(|(name, value)| (name, Value::String(value)))
+                    .collect();
+                (vec![(path, entries)], lines)
+            };
+            write_secrets(args, &mount, &writes, None).await?;
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Command::List { folder, recursive } => {
+            let folder = folder.strip_prefix("vault:").unwrap_or(folder);
+            let (mount, path) = folder.split_once('/').unwrap_or((folder, ""));
+            if mount.is_empty() {
+                return Err(Error::Conversion(format!(
+                    "invalid folder `{}`: must be in format `mount/path/`",
+                    folder
+                )));
+            }
+            let client = login_failover(args).await?;
+            for path in client
+                .list(mount, path, *recursive, &token_opts(args))
+                .await?
+            {
+                println!("{}/{}", mount, path);
+            }
+        }
+        Command::Init {
+            from_env: Some(from),
+            prefix,
+            to,
+            ..
+        } => {
+            let names = match from {
+                Some(path) => dotenv::load(path)
+                    .await?
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect(),
+                None => {
+                    if prefix.is_none() {
+                        return Err(Error::Execution(
+                            "invalid configuration: --from-env requires --prefix when reading the environment"
+                                .to_string(),
+                        ));
+                    }
+                    let mut names = std::env::vars_os()
+                        .filter_map(|(name, _)| name.into_string().ok())
+                        .collect::<Vec<_>>();
+                    names.sort();
+                    names
+                }
+            };
+            let prefix = prefix.as_deref().unwrap_or_default();
+            let to = match to {
+                Some(to) => secrets::parse_vault_path(to)?,
+                None => init::default_path(prefix),
+            };
+            let source = match from {
+                Some(path) => path.display().to_string(),
+                None => "the environment".to_string(),
+            };
+            print!("{}", init::skeleton(&names, prefix, &to, &source));
+        }
+        Command::Init { .. } => {
+            // fail before browsing rather than after
+            if args.secrets_file.exists() {
+                return Err(Error::Execution(format!(
+                    "secrets file {} already exists, choose another one with --secrets-file",
+                    args.secrets_file.display()
+                )));
+            }
+            let client = login_failover(args).await?;
+            let lines = init::interactive_terminal(&client, &token_opts(args)).await?;
+            let contents = lines
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect::<String>();
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&args.secrets_file)
+                .and_then(|mut file| file.write_all(contents.as_bytes()))
+                .map_err(|err| Error::io_at("unable to write file", &args.secrets_file, err))?;
+            eprintln!(
+                "wrote {} secrets to {}",
+                lines.len(),
+                args.secrets_file.display()
+            );
+        }
+        Command::Run { .. }
+        | Command::Completions { .. }
+        | Command::Docs { .. }
+        | Command::Stop { .. }
+        | Command::Status { .. } => {
+            return Err(Error::Execution(format!(
+                "{:?} does not work with secrets",
+                command
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_options(args: &Args, env_vars: &[(String, String)]) -> process::SpawnOptions {
+    process::SpawnOptions {
+        clear_env: args.clear_env,
+        keep_env: args.keep_env.clone(),
+        drop_env: args.drop_env.clone(),
+        env: env_vars.to_vec(),
+        on_conflict: args.on_conflict,
+        chdir: args.chdir.clone(),
+        umask: args.umask,
+        nice: args.nice,
+        secrets_fd: args.secrets_fd,
+        stdin: args.stdin_format,
+        #[cfg(unix)]
+        rlimits: args.rlimit.clone(),
+        #[cfg(target_os = "linux")]
+        oom_score_adj: args.oom_score_adj,
+        #[cfg(target_os = "linux")]
+        cap_drop: args.cap_drop.clone(),
+        #[cfg(unix)]
+        process_group: args.process_group,
+        // several processes of a procfile cannot share the terminal
+        #[cfg(unix)]
+        foreground: args.process_group == process::ProcessGroup::Own
+            && args.procfile.is_none()
+            && process::owns_terminal(),
+    }
+}
+
+fn readiness(args: &Args) -> ready::Readiness {
+    ready::Readiness {
+        file: args.ready_file.clone(),
+        sd_notify: args.sd_notify,
+    }
+}
+
+#[cfg(unix)]
+fn stop_options(args: &Args) -> Result<process::StopOptions> {
+    Ok(process::StopOptions {
+        signal: args
+            .stop_signal
+            .as_deref()
+            .map(process::parse_signal)
+            .transpose()?,
+        timeout: args.stop_timeout,
+    })
+}
+
+async fn prepare_spawn(args: &Args) -> Result<PreparedSpawn> {
+    let (secret_specs, secrets) = load_secrets(args).await?;
+    let env_vars = load_env_files(&args.env_file).await?;
+    check_inherited_env(args, &secrets, &env_vars)?;
+    let env_secrets = apply_secrets(args, &secrets)?;
+
+    Ok(PreparedSpawn {
+        secret_specs,
+        secrets,
+        env_secrets,
+        env_vars,
+    })
+}
+
+/// Loads the variables of the `--env-file`s, where later files override earlier ones.
+async fn load_env_files(paths: &[PathBuf]) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::<(String, String)>::new();
+    for path in paths {
+        for (name, value) in dotenv::load(path).await? {
+            vars.retain(|(other, _)| other != &name);
+            vars.push((name, value));
+        }
+    }
+
+    Ok(vars)
+}
+
+/// Parses the secrets file and fetches (or replays) the secrets.
+async fn load_secrets(args: &Args) -> Result<(SecretSpecs, Vec<Secret>)> {
+    let started = std::time::Instant::now();
+    if args.timing.is_some() {
+        timing::enable();
+    }
+
+    let loaded = async {
+        // read secret spec file
+        let parse = async {
+            let specs = secrets::load_async(&args.secrets_file, args.secrets_identity.as_deref())
+                .await
+                .inspect_err(|err| tracing::error!("error parsing secrets file: {}", err))?;
+            timing::record(timing::Phase::Parse, started.elapsed(), || {
+                args.secrets_file.display().to_string()
+            });
+            Ok::<_, Error>(specs)
+        };
+        // the login runs while the secrets file is read, decrypted and parsed, and the fetch
+        // starts as soon as both are done
+        let mut parse = std::pin::pin!(parse);
+        let mut login = early_login(args).boxed_local();
+        let mut logged_in = None;
+        let secret_specs = loop {
+            tokio::select! {
+                specs = &mut parse => break specs?,
+                early = &mut login, if logged_in.is_none() => logged_in = Some(early),
+            }
+        };
+        let early_login = match logged_in {
+            Some(early) => futures::future::ready(early).boxed_local(),
+            None => login,
+        };
+
+        let secrets = match &args.replay {
+            Some(path) => {
+                snapshot::replay(path, &secret_specs, snapshot::passphrase().as_deref()).await?
+            }
+            None => resolve_secrets(args, &secret_specs, Some(early_login)).await?,
+        };
+        Ok::<_, Error>((secret_specs, secrets))
+    }
+    .await;
+    let report = match &args.timing {
+        Some(output) => write_timing_report(output.as_deref(), started, loaded.is_err()),
+        None => Ok(()),
+    };
+    let (secret_specs, secrets) = loaded?;
+    report?;
+    if let Some(path) = &args.record {
+        // recorded as resolved, so only if they are the pinned values
+        provider::verify_checksums(&secret_specs, &secrets)?;
+        let passphrase = snapshot::passphrase();
+        snapshot::record(path, &secrets, args.record_values, passphrase.as_deref()).await?;
+    }
+    let secrets = provider::finish(&secret_specs, secrets)?;
+
+    Ok((secret_specs, secrets))
+}
+
+/// Writes the `--timing` report to `output`, or to stderr without a file.
+fn write_timing_report(
+    output: Option<&Path>,
+    started: std::time::Instant,
+    failed: bool,
+) -> Result<()> {
+    let report = timing::take_report(started.elapsed(), failed);
+    match output {
+        Some(path) => std::fs::write(path, report)
+            .map_err(|err| Error::io_at("unable to write timing report", path, err)),
+        None => {
+            eprint!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+/// Prints the secrets which would be injected, fetching them only with `--dry-run=fetch`.
+async fn print_dry_run(args: &Args, mode: dry_run::DryRun) -> Result<()> {
+    let secret_specs =
+        secrets::load_async(&args.secrets_file, args.secrets_identity.as_deref()).await?;
+    let secrets = match (mode, &args.replay) {
+        (dry_run::DryRun::Parse, _) => None,
+        (dry_run::DryRun::Fetch, Some(path)) => {
+            Some(snapshot::replay(path, &secret_specs, snapshot::passphrase().as_deref()).await?)
+        }
+        (dry_run::DryRun::Fetch, None) => Some(resolve_secrets(args, &secret_specs, None).await?),
+    };
+    let secrets = secrets
+        .map(|secrets| provider::finish(&secret_specs, secrets))
+        .transpose()?;
+
+    print!("{}", dry_run::report(&secret_specs, secrets.as_deref()));
+    Ok(())
+}
+
+/// Prints what the token is, looking up a static token which the login did not return.
+async fn print_token_info(args: &Args) -> Result<()> {
+    let client = login_failover(args).await?;
+    let info = match client.token_info() {
+        Some(info) => info.clone(),
+        None => client.lookup_self(&token_opts(args)).await?,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// Login at the first vault address, started while the secrets file is parsed.
+struct EarlyLogin {
+    host: String,
+    login: Result<vault::VaultClient>,
+}
+
+/// The `EarlyLogin`, possibly still in progress.
+type PendingLogin<'a> = LocalBoxFuture<'a, Option<EarlyLogin>>;
+
+/// Logs in at the first available vault address, unless a static token is used or vault has to
+/// be waited for first.
+///
+/// # Remarks:
+///
+/// The secrets are not known yet, so the login is dropped by `resolve_secrets` if none of them is
+/// read from vault. Invalid auth settings are left to be reported by `fetch_secrets_from`.
+async fn early_login(args: &Args) -> Option<EarlyLogin> {
+    if args.replay.is_some() || args.wait_for_vault.is_some() {
+        return None;
+    }
+    let auth_method = args.auth_method().ok()?;
+    if matches!(auth_method, AuthMethod::Token(_)) {
+        return None;
+    }
+    let host = args
+        .vault
+        .breakers()
+        .available(&args.host)
+        .ok()?
+        .first()?
+        .to_string();
+
+    let mut client = vault::VaultClient::new(&args.vault, &host, args.namespace.as_deref());
+    let opts = vault::FetchTokenOpts {
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+    };
+    let login = client.login(auth_method, opts).await.map(|()| client);
+    Some(EarlyLogin { host, login })
+}
+
+/// Fetches the secrets, falling back to the cache or `--fallback-file` if that fails.
+///
+/// The values are returned as resolved, `provider::finish` verifies and encodes them.
+async fn resolve_secrets(
+    args: &Args,
+    secret_specs: &SecretSpecs,
+    early_login: Option<PendingLogin<'_>>,
+) -> Result<Vec<Secret>> {
+    // validate auth selection before contacting vault, unless no secret is read from vault; a
+    // missing credential engages the fallback
+    let uses_vault = provider::uses_vault(secret_specs);
+    let early_login = early_login.filter(|_| uses_vault);
+    if uses_vault {
+        if let Err(err) = args.auth_method() {
+            if args.fallback_file.is_none() || args.has_credential() {
+                return Err(err);
+            }
+            return fallback_secrets(args, secret_specs, err).await;
+        }
+    }
+
+    let fetched = async {
+        if let Some(timeout) = args.wait_for_vault {
+            args.vault.wait_for_health(&args.host, timeout).await?;
+        }
+        fetch_secrets_with(args, secret_specs, early_login).await
+    };
+    match fetched.await {
+        Ok(secrets) => Ok(secrets),
+        Err(err) => fallback_secrets(args, secret_specs, err).await,
+    }
+}
+
+/// Resolves the secrets after fetching them failed with `err`, from the cache or the
+/// `--fallback-file` if vault is unavailable or from the `--fallback-file` if no credential is
+/// configured.
+///
+/// # Remarks:
+///
+/// Any other error, e.g. a denied read or a checksum mismatch, is returned, so a job whose access
+/// was revoked does not quietly run on the fallback values. So is running out of `--timeout`,
+/// which bounds the launch as a whole.
+async fn fallback_secrets(
+    args: &Args,
+    secret_specs: &SecretSpecs,
+    err: Error,
+) -> Result<Vec<Secret>> {
+    if matches!(err, Error::Timeout(_)) {
+        return Err(err);
+    }
+    let unavailable = vault::should_failover(&err);
+    if unavailable {
+        if let Some(cache) = secret_cache(args, secret_specs) {
+            match cache.load(secret_specs).await {
+                Ok(secrets) => {
+                    tracing::warn!("vault is unavailable, using cached secrets: {}", err);
+                    return Ok(secrets);
+                }
+                Err(cache_err) => tracing::warn!("unable to use secret cache: {}", cache_err),
+            }
+        }
+    }
+
+    let Some(path) = &args.fallback_file else {
+        return Err(err);
+    };
+    if !unavailable && args.has_credential() {
+        return Err(err);
+    }
+    tracing::warn!(
+        "unable to fetch secrets, using fallback values from {}: {}",
+        path.display(),
+        err
+    );
+    local::fallback(path, secret_specs).await
+}
+
+/// The cache in `--cache-dir`, encrypted with a key derived from the `--cache-key-file`,
+/// `VAULTIFY_CACHE_KEY` or the static vault token.
+fn secret_cache(args: &Args, secret_specs: &SecretSpecs) -> Option<cache::SecretCache> {
+    let dir = args.cache_dir.as_ref()?;
+    let key_material = match &args.cache_key_file {
+        Some(path) => match std::fs::read(path) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                tracing::warn!(
+                    "secret cache is disabled, unable to read {:?}: {}",
+                    path,
+                    err
+                );
+                return None;
+            }
+        },
+        None => std::env::var(cache::CACHE_KEY_ENV)
+            .ok()
+            .or_else(|| args.token.clone())
+            .map(String::into_bytes),
+    };
+    let Some(key_material) = key_material.filter(|key| !key.is_empty()) else {
+        tracing::warn!(
+            "secret cache is disabled, set --cache-key-file or {} for auth methods other than a token",
+            cache::CACHE_KEY_ENV
+        );
+        return None;
+    };
+
+    cache::SecretCache::new(dir, args.cache_ttl, &key_material, &args.host, secret_specs)
+        .inspect_err(|err| tracing::warn!("secret cache is disabled: {}", err))
+        .ok()
+}
+
+/// Authenticates against vault and fetches all secrets in `secret_specs` within `--timeout`.
+///
+/// The secrets are written to the cache if `--cache-dir` is set.
+async fn fetch_secrets(args: &Args, secret_specs: &SecretSpecs) -> Result<Vec<Secret>> {
+    let secrets = fetch_secrets_with(args, secret_specs, None).await?;
+    provider::finish(secret_specs, secrets)
+}
+
+/// Like `fetch_secrets`, using the `early_login` for its vault address and returning the values
+/// as resolved.
+async fn fetch_secrets_with(
+    args: &Args,
+    secret_specs: &SecretSpecs,
+    early_login: Option<PendingLogin<'_>>,
+) -> Result<Vec<Secret>> {
+    let started = std::time::Instant::now();
+    let fetch = fetch_secrets_unbounded(args, secret_specs, early_login);
+    let fetched = match args.timeout {
+        None => fetch.await,
+        Some(timeout) => match tokio::time::timeout(timeout, fetch).await {
+            Ok(res) => res,
+            Err(_) => {
+                let err = Error::Timeout(format!(
+                    "fetching secrets did not complete within {:?}",
+                    timeout
+                ));
+                tracing::error!("error fetching secrets: {}", err);
+                Err(err)
+            }
+        },
+    };
+    metrics::fetch_completed(started.elapsed(), fetched.is_ok());
+    let secrets = fetched?;
+
+    if let Some(cache) = secret_cache(args, secret_specs) {
+        if let Err(err) = cache.store(&secrets).await {
+            tracing::warn!("unable to update secret cache: {}", err);
+        }
+    }
+
+    Ok(secrets)
+}
+
+async fn fetch_secrets_unbounded(
+    args: &Args,
+    secret_specs: &SecretSpecs,
+    mut early_login: Option<PendingLogin<'_>>,
+) -> Result<Vec<Secret>> {
+    let local = provider::resolve_local(secret_specs, &args.allow_missing).await?;
+    let mut unseal_timeout = args.unseal_timeout;
+    loop {
+        let wait_for_unseal = unseal_timeout.is_some();
+        let early = early_login.take();
+        match fetch_secrets_failover(args, secret_specs, &local, wait_for_unseal, early).await {
+            Err(err) if vault::is_sealed(&err) => {
+                // wait only once, so a vault sealed again right away doesn't block forever
+                let Some(timeout) = unseal_timeout.take() else {
+                    return Err(err);
+                };
+                tracing::warn!(
+                    "vault is sealed, waiting up to {:?} for it to be unsealed",
+                    timeout
+                );
+                args.vault.wait_for_health(&args.host, timeout).await?;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Fetches the secrets from the first vault address which is reachable and unsealed, skipping
+/// those whose circuit breaker is open, along with the `local` secrets of the other providers.
+async fn fetch_secrets_failover(
+    args: &Args,
+    secret_specs: &SecretSpecs,
+    local: &[Secret],
+    wait_for_unseal: bool,
+    mut early_login: Option<PendingLogin<'_>>,
+) -> Result<Vec<Secret>> {
+    let mut hosts = args
+        .vault
+        .breakers()
+        .available(&args.host)?
+        .into_iter()
+        .peekable();
+    while let Some(host) = hosts.next() {
+        let failover = hosts.peek().is_some();
+        let recoverable = |err: &Error| {
+            (failover && vault::should_failover(err)) || (wait_for_unseal && vault::is_sealed(err))
+        };
+        let early = match early_login.take() {
+            Some(pending) => pending.await,
+            None => None,
+        };
+        let login = early
+            .filter(|early| early.host == *host)
+            .map(|early| early.login);
+        let fetched =
+            fetch_secrets_from(args, host, secret_specs, local, &recoverable, login).await;
+        args.vault
+            .breakers()
+            .record(host, fetched.as_ref().map(|_| ()));
+        match fetched {
+            Err(err) if failover && vault::should_failover(&err) => {
+                tracing::warn!(
+                    "vault at `{}` is unavailable, failing over to the next address: {}",
+                    host,
+                    err
+                );
+            }
+            res => return res,
+        }
+    }
+
+    Err(Error::Conversion(
+        "vault address must not be empty".to_string(),
+    ))
+}
+
+/// Fetches the secrets from a single vault address, with the result of an earlier `login` to it
+/// if any, and adds the `local` ones; errors are only reported if they are not `recoverable`, e.g.
+/// by failing over to another address.
+async fn fetch_secrets_from(
+    args: &Args,
+    host: &str,
+    secret_specs: &SecretSpecs,
+    local: &[Secret],
+    recoverable: &dyn Fn(&Error) -> bool,
+    login: Option<Result<vault::VaultClient>>,
+) -> Result<Vec<Secret>> {
+    // get / fetch token, not needed if all secrets are resolved by other providers
+    let expanded = provider::expand(secret_specs);
+    let vault_specs = provider::select(&expanded, Provider::Vault);
+    let client = match login {
+        _ if !provider::uses_vault(secret_specs) => Ok(vault::VaultClient::new(
+            &args.vault,
+            host,
+            args.namespace.as_deref(),
+        )),
+        Some(login) => login,
+        None => {
+            let mut client = vault::VaultClient::new(&args.vault, host, args.namespace.as_deref());
+            let opts = vault::FetchTokenOpts {
+                retries: args.retries,
+                retry_delay: Duration::from_millis(args.retry_delay_ms),
+            };
+            client
+                .login(args.auth_method()?, opts)
+                .await
+                .map(|()| client)
+        }
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            if !recoverable(&err) {
+                tracing::error!("error getting vault token: {}", err);
+            }
+            return Err(err);
+        }
+    };
+
+    // both only need the token, so they run side by side
+    let retry_delay = Duration::from_millis(args.retry_delay_ms);
+    let (mounts, preflight) = tokio::join!(
+        async {
+            if vault_specs.is_empty() {
+                return Ok(());
+            }
+            client
+                .detect_mounts(&vault_specs, args.retries, retry_delay, &args.allow_missing)
+                .await
+        },
+        async {
+            if args.no_preflight || vault_specs.is_empty() {
+                return Ok(());
+            }
+            client
+                .preflight(&vault_specs, args.retries, retry_delay, &args.allow_missing)
+                .await
+        }
+    );
+    if let Err(err) = mounts {
+        if !recoverable(&err) {
+            tracing::error!("error looking up mounts: {}", err);
+        }
+        return Err(err);
+    }
+    if let Err(err) = preflight {
+        if !recoverable(&err) {
+            tracing::error!("error checking capabilities: {}", err);
+        }
+        return Err(err);
+    }
+
+    // read secrets
+    let opts = vault::FetchAllOpts {
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+        concurrency: args.concurrency,
+        rate_limit: args.rate_limit,
+        fetch_timeout: args.fetch_timeout,
+        allow_missing: args.allow_missing.clone(),
+    };
+    let vault = vault::VaultProvider {
+        client: &client,
+        opts,
+    };
+    match provider::resolve(secret_specs, &vault, local, &args.allow_missing).await {
+        Ok(secrets) => Ok(secrets),
+        Err(err) => {
+            if !recoverable(&err) {
+                tracing::error!("error fetching secrets: {}", err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Checks that the keys read from vault exist, reporting the unknown keys of every secret.
+///
+/// # Remarks:
+///
+/// Keys of kv v1 secrets are not checked, as reading them would read their values.
+async fn check_keys(args: &Args, specs: &SecretSpecs) -> Result<()> {
+    let specs = provider::expand(specs);
+    let mut paths = BTreeMap::<(&str, &str), Vec<&secrets::SecretSpec>>::new();
+    for spec in provider::select(&specs, Provider::Vault) {
+        paths
+            .entry((&spec.mount, &spec.path))
+            .or_default()
+            .push(spec);
+    }
+
+    let client = login_failover(args).await?;
+    let mut failed = Vec::new();
+    for ((mount, path), specs) in paths {
+        let names = specs.iter().map(|spec| spec.name()).collect::<Vec<_>>();
+        let existing = match client.subkeys(mount, path, &token_opts(args)).await {
+            Ok(Some(existing)) => existing,
+            Ok(None) => {
+                tracing::warn!(
+                    "not checking the keys of `{}/{}`, kv v1 secrets have no subkeys endpoint",
+                    mount,
+                    path
+                );
+                continue;
+            }
+            Err(err)
+                if names
+                    .iter()
+                    .all(|name| glob::matches_any(&args.allow_missing, name)) =>
+            {
+                tracing::warn!("secret `{}/{}` cannot be read: {}", mount, path, err);
+                continue;
+            }
+            Err(err) => {
+                tracing::error!("secret `{}/{}` cannot be read: {}", mount, path, err);
+                failed.extend(names);
+                continue;
+            }
+        };
+
+        let unknown = specs
+            .iter()
+            .filter(|spec| !existing.contains(&spec.secret))
+            .filter(|spec| !glob::matches_any(&args.allow_missing, &spec.name()))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            tracing::error!(
+                "secret `{}/{}` has no keys {} (keys: {})",
+                mount,
+                path,
+                unknown
+                    .iter()
+                    .map(|spec| format!("`{}`", spec.secret))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                existing.join(", ")
+            );
+            failed.extend(unknown.iter().map(|spec| spec.name()));
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(Error::NotFound(format!(
+        "{} secrets are missing in vault: {}",
+        failed.len(),
+        failed.join(", ")
+    ))
+    .for_secrets(&failed))
+}
+
+/// Reads the value of `put` from stdin, without the newline ending it e.g. with `echo`.
+fn read_value() -> Result<String> {
+    let mut value = std::io::read_to_string(std::io::stdin())
+        .map_err(|err| Error::io("unable to read the value from stdin", err))?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(value)
+}
+
+/// Options of the requests of the subcommands working with vault directly, e.g. `put`.
+fn token_opts(args: &Args) -> vault::FetchTokenOpts {
+    vault::FetchTokenOpts {
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+    }
+}
+
+/// Logs in to the first vault address which is reachable and unsealed, for the subcommands working
+/// with vault directly.
+async fn login_failover(args: &Args) -> Result<vault::VaultClient> {
+    let mut hosts = args
+        .vault
+        .breakers()
+        .available(&args.host)?
+        .into_iter()
+        .peekable();
+    while let Some(host) = hosts.next() {
+        let mut client = vault::VaultClient::new(&args.vault, host, args.namespace.as_deref());
+        let login = client.login(args.auth_method()?, token_opts(args)).await;
+        args.vault
+            .breakers()
+            .record(host, login.as_ref().map(|_| ()));
+        match login {
+            Ok(()) => return Ok(client),
+            Err(err) if hosts.peek().is_some() && vault::should_failover(&err) => {
+                tracing::warn!(
+                    "vault at `{}` is unavailable, failing over to the next address: {}",
+                    host,
+                    err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(Error::Conversion(
+        "vault address must not be empty".to_string(),
+    ))
+}
+
+/// Writes the `entries` of each path of `writes` to `mount` and returns their new versions,
+/// failing over to the next address of `--host` while vault is unavailable.
+///
+/// # Remarks:
+///
+/// A failover writes all paths again, the ones written before are overwritten with the same
+/// entries, creating another version.
+async fn write_secrets(
+    args: &Args,
+    mount: &str,
+    writes: &[(String, Map<String, Value>)],
+    cas: Option<u64>,
+) -> Result<Vec<u64>> {
+    let mut hosts = args
+        .vault
+        .breakers()
+        .available(&args.host)?
+        .into_iter()
+        .peekable();
+    while let Some(host) = hosts.next() {
+        let write = async {
+            let mut client = vault::VaultClient::new(&args.vault, host, args.namespace.as_deref());
+            client.login(args.auth_method()?, token_opts(args)).await?;
+            let mut versions = Vec::with_capacity(writes.len());
+            for (path, entries) in writes {
+                versions.push(
+                    client
+                        .put(mount, path, entries.clone(), cas, &token_opts(args))
+                        .await?,
+                );
+            }
+            Ok(versions)
+        };
+        let versions = write.await;
+        args.vault
+            .breakers()
+            .record(host, versions.as_ref().map(|_| ()));
+        match versions {
+            Ok(versions) => return Ok(versions),
+            Err(err) if hosts.peek().is_some() && vault::should_failover(&err) => {
+                tracing::warn!(
+                    "vault at `{}` is unavailable, failing over to the next address: {}",
+                    host,
+                    err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(Error::Conversion(
+        "vault address must not be empty".to_string(),
+    ))
+}
+
+/// Warns about (or with `--strict-env` fails on) inherited and `--env-file` variables `env_vars`
+/// conflicting with the secrets, and fails on shadowed inherited ones with `--on-conflict error`.
+fn check_inherited_env(
+    args: &Args,
+    secrets: &[Secret],
+    env_vars: &[(String, String)],
+) -> Result<()> {
+    let env = std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect::<Vec<_>>();
+    let secrets = secrets
+        .iter()
+        .map(|secret| (secret.target.name(), secret.secret.as_str()))
+        .collect::<Vec<_>>();
+    let conflicts = process::env_conflicts(&env, &secrets, &spawn_options(args, env_vars));
+    // `--on-conflict` applies to inherited variables, --env-file ones are always overridden
+    let inherited = |var: &String| !env_vars.iter().any(|(name, _)| name == var);
+    if args.strict_env && !conflicts.is_empty() {
+        let conflicts = conflicts
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        return Err(Error::Execution(format!(
+            "refusing to spawn with --strict-env: {}",
+            conflicts.join("; ")
+        )));
+    }
+    let shadowed = conflicts
+        .iter()
+        .filter(
+            |conflict| matches!(conflict, process::EnvConflict::Shadowed { var } if inherited(var)),
+        )
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if args.on_conflict == process::OnConflict::Error && !shadowed.is_empty() {
+        return Err(Error::Execution(format!(
+            "refusing to spawn with --on-conflict error: {}",
+            shadowed.join("; ")
+        )));
+    }
+    for conflict in conflicts {
+        match conflict {
+            process::EnvConflict::Shadowed { var }
+                if args.on_conflict == process::OnConflict::KeepExisting && inherited(&var) =>
+            {
+                tracing::warn!(
+                    "keeping inherited env variable `{}`, the secret of the same name is not passed",
+                    var
+                );
+            }
+            conflict => tracing::warn!("{}", conflict),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes file targets and the dotenv files of sections to disk and returns the secrets destined
+/// for the environment.
+fn apply_secrets(args: &Args, secrets: &[Secret]) -> Result<Vec<process::EnvSecret>> {
+    let env_secrets = env_secrets(args, secrets)?;
+    let mut env_files = BTreeMap::<&Path, Vec<process::EnvSecret>>::new();
+    let mut writes = FileWrites {
+        backup: args.backup_files,
+        ..FileWrites::default()
+    };
+    for secret in secrets.iter() {
+        match &secret.target {
+            SecretTarget::Env { .. } => {}
+            SecretTarget::EnvFile { path, name } => {
+                env_files.entry(path).or_default().push(process::EnvSecret {
+                    name: name.clone(),
+                    secret: secret.secret.clone(),
+                })
+            }
+            SecretTarget::File {
+                path,
+                mode,
+                create,
+                owner,
+                label,
+            } => {
+                let opts = FileOptions {
+                    mode: *mode,
+                    create: *create,
+                    owner: *owner,
+                    label: label.as_deref(),
+                };
+                // either all file targets are updated or none
+                if let Err(err) = writes.write(path, &secret.secret, &opts) {
+                    writes.rollback();
+                    return Err(err);
+                }
+            }
+        }
+    }
+    for (path, vars) in env_files {
+        let contents = process::format_secrets(&vars, process::SecretsFormat::Dotenv);
+        let opts = FileOptions {
+            mode: 0o600,
+            create: false,
+            owner: None,
+            label: None,
+        };
+        if let Err(err) = writes.write(path, &contents, &opts) {
+            writes.rollback();
+            return Err(err);
+        }
+    }
+    writes.commit();
+    logging::redact_secrets(secrets.iter().map(|secret| secret.secret.as_str()));
+
+    Ok(env_secrets)
+}
+
+/// The secrets destined for the environment, followed by the `--metadata-env` variables.
+fn env_secrets(args: &Args, secrets: &[Secret]) -> Result<Vec<process::EnvSecret>> {
+    let mut env_secrets = secrets
+        .iter()
+        .filter_map(|secret| match &secret.target {
+            SecretTarget::Env { name } => Some(process::EnvSecret {
+                name: name.clone(),
+                secret: secret.secret.clone(),
+            }),
+            SecretTarget::EnvFile { .. } | SecretTarget::File { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    if args.metadata_env {
+        env_secrets.extend(metadata::env(secrets)?);
+    }
+
+    Ok(env_secrets)
+}
+
+fn write_secret_to_file(path: &Path, value: &str, mode: u32, create: bool) -> Result<()> {
+    let opts = FileOptions {
+        mode,
+        create,
+        owner: None,
+        label: None,
+    };
+    let mut writes = FileWrites::default();
+    writes.write(path, value, &opts)?;
+    writes.commit();
+
+    Ok(())
+}
+
+/// How a file target is written.
+struct FileOptions<'a> {
+    mode: u32,
+    /// Create the parent directory if it does not exist.
+    create: bool,
+    owner: Option<secrets::Owner>,
+    /// SELinux context of the file.
+    label: Option<&'a str>,
+}
+
+/// Files replaced as a whole: each is written to a temporary file which is renamed over it, so
+/// readers never see a partial file, and the previous versions are kept until `commit`, so all
+/// files can be restored with `rollback` if a later one fails to be written.
+#[derive(Default)]
+struct FileWrites {
+    /// Keep the previous version of each file as `<path>.bak` on commit.
+    backup: bool,
+    /// Written files with the saved previous version, if the file existed.
+    written: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl FileWrites {
+    fn write(&mut self, path: &Path, value: &str, opts: &FileOptions) -> Result<()> {
+        let parent = path.parent().ok_or_else(|| {
+            target_error("unable to resolve parent directory for file target", path)
+        })?;
+        ensure_secure_parent_directory(parent, path, opts.create)?;
+
+        let mut keep_owner = None;
+        let previous = match std::fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_file() => {
+                return Err(target_error(
+                    "refusing to write secret to non-regular file",
+                    path,
+                ))
+            }
+            Ok(metadata) => {
+                // the new file replacing it would be owned by vaultify
+                if opts.owner.is_none() {
+                    use std::os::unix::fs::MetadataExt;
+
+                    keep_owner = Some(secrets::Owner {
+                        uid: metadata.uid(),
+                        gid: Some(metadata.gid()),
+                    });
+                }
+                let saved = sibling_path(path, "prev");
+                let _ = std::fs::remove_file(&saved);
+                std::fs::hard_link(path, &saved)
+                    .or_else(|_| std::fs::copy(path, &saved).map(drop))
+                    .map_err(|err| {
+                        Error::io_at("unable to save the previous version of", path, err)
+                    })?;
+                Some(saved)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(Error::io_at("unable to read metadata for", path, err)),
+        };
+
+        if let Err(err) = replace_file(path, value, opts, keep_owner) {
+            if let Some(saved) = previous {
+                let _ = std::fs::remove_file(saved);
+            }
+            return Err(err);
+        }
+        self.written.push((path.to_path_buf(), previous));
+
+        Ok(())
+    }
+
+    /// Drops the previous versions, or keeps them as `<path>.bak` with `backup`.
+    fn commit(self) {
+        let mut written_files = lock_written_files();
+        for (path, previous) in self.written {
+            written_files.insert(path.clone());
+            let Some(previous) = previous else {
+                continue;
+            };
+            let res = if self.backup {
+                let backup = sibling_path(&path, "bak");
+                written_files.insert(backup.clone());
+                std::fs::rename(&previous, backup)
+            } else {
+                std::fs::remove_file(&previous)
+            };
+            if let Err(err) = res {
+                tracing::warn!(
+                    "unable to clean up the previous version of {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Restores the previous versions of the written files and removes the files created.
+    fn rollback(self) {
+        for (path, previous) in self.written.into_iter().rev() {
+            let res = match previous {
+                Some(previous) => std::fs::rename(previous, &path),
+                None => std::fs::remove_file(&path),
+            };
+            match res {
+                Ok(()) => tracing::info!("restored the previous version of {}", path.display()),
+                Err(err) => tracing::warn!("unable to restore {}: {}", path.display(), err),
+            }
+        }
+    }
+}
+
+/// `<path>.bak`, or a hidden `.<name>.<pid>.<suffix>` next to `path` for other suffixes.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    match suffix {
+        "bak" => path.with_file_name(format!("{}.bak", name)),
+        _ => path.with_file_name(format!(".{}.{}.{}", name, std::process::id(), suffix)),
+    }
+}
+
+/// Writes `value` to a temporary file next to `path` and renames it over `path`, with the owner
+/// `keep_owner` of the replaced file unless `opts` has one.
+///
+/// # Remarks:
+///
+/// A file which is a mount point (e.g. a single file mounted into a container) cannot be
+/// replaced, so it is overwritten in place. So is a file of another owner, which vaultify lacks
+/// the privileges to give to the new file.
+fn replace_file(
+    path: &Path,
+    value: &str,
+    opts: &FileOptions,
+    keep_owner: Option<secrets::Owner>,
+) -> Result<()> {
+    let tmp = sibling_path(path, "tmp");
+    let _ = std::fs::remove_file(&tmp);
+    let file = match create_new_file(&tmp, opts) {
+        Ok(file) => file,
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(err);
+        }
+    };
+    if let Some(owner) = keep_owner {
+        if let Err(err) = std::os::unix::fs::fchown(&file, Some(owner.uid), owner.gid) {
+            drop(file);
+            let _ = std::fs::remove_file(&tmp);
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                tracing::debug!(
+                    "unable to keep the owner {} of {}, overwriting it in place",
+                    owner.uid,
+                    path.display()
+                );
+                return overwrite_file(path, value, opts);
+            }
+            return Err(Error::io_at(
+                &format!("unable to keep the owner {} of", owner.uid),
+                path,
+                err,
+            ));
+        }
+    }
+    if let Err(err) = write_contents(file, &tmp, value) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+
+    match std::fs::rename(&tmp, path) {
+        // the rename itself is only durable once the directory is flushed
+        Ok(()) => sync_parent_directory(path),
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp);
+            #[cfg(unix)]
+            if err.raw_os_error() == Some(nix::errno::Errno::EBUSY as i32) {
+                tracing::debug!(
+                    "{} is a mount point, overwriting it in place",
+                    path.display()
+                );
+                return overwrite_file(path, value, opts);
+            }
+            Err(Error::io_at("unable to replace", path, err))
+        }
+    }
+}
+
+/// Creates the file at `path` with the mode, owner and label of `opts`.
+fn create_new_file(path: &Path, opts: &FileOptions) -> Result<std::fs::File> {
+    let mut open_opts = std::fs::OpenOptions::new();
+    open_opts.write(true).create_new(true);
+    #[cfg(target_os = "linux")]
+    {
+        open_opts
+            .custom_flags(O_NOFOLLOW | O_CLOEXEC)
+            .mode(opts.mode);
+    }
+    let file = open_opts
+        .open(path)
+        .map_err(|err| Error::io_at("unable to open", path, err))?;
+
+    // the umask may have masked bits of the mode
+    set_file_attributes(&file, path, opts)?;
+
+    Ok(file)
+}
+
+/// Writes `value` to the new `file` at `path` and flushes it to disk.
+fn write_contents(mut file: std::fs::File, path: &Path, value: &str) -> Result<()> {
+    file.write_all(value.as_bytes())
+        .map_err(|err| Error::io_at("unable to write", path, err))?;
+    file.sync_all()
+        .map_err(|err| Error::io_at("unable to flush", path, err))
+}
+
+/// Flushes the directory of `path` to disk, so a file renamed into it survives a crash.
+fn sync_parent_directory(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        std::fs::File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|err| Error::io_at("unable to flush the directory of", path, err))?;
+    }
+
+    Ok(())
+}
+
+/// Truncates and writes the existing regular file at `path`.
+#[cfg(unix)]
+fn overwrite_file(path: &Path, value: &str, opts: &FileOptions) -> Result<()> {
+    let mut open_opts = std::fs::OpenOptions::new();
+    open_opts.write(true);
+    #[cfg(target_os = "linux")]
+    {
+        open_opts.custom_flags(O_NOFOLLOW | O_CLOEXEC);
+    }
+    let mut file = open_opts
+        .open(path)
+        .map_err(|err| Error::io_at("unable to open", path, err))?;
+
+    set_file_attributes(&file, path, opts)?;
+
+    file.set_len(0)
+        .map_err(|err| Error::io_at("unable to truncate", path, err))?;
+    file.write_all(value.as_bytes())
+        .map_err(|err| Error::io_at("unable to write", path, err))
+}
+
+/// Files written by vaultify during this run, removed on exit with `--cleanup-files`.
+static WRITTEN_FILES: std::sync::Mutex<BTreeSet<PathBuf>> = std::sync::Mutex::new(BTreeSet::new());
+
+fn lock_written_files() -> std::sync::MutexGuard<'static, BTreeSet<PathBuf>> {
+    WRITTEN_FILES
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Removes the files written so far with `--cleanup-files` if `res` is an error, as the child
+/// which would have been cleaned up after will not run.
+fn cleanup_on_error<T>(args: &Args, res: Result<T>) -> Result<T> {
+    if res.is_err() && args.cleanup_files {
+        shred_written_files();
+    }
+    res
+}
+
+/// Overwrites the files written during this run with zeros and removes them, so the secrets do
+/// not outlive the child.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn shred_written_files() {
+    for path in std::mem::take(&mut *lock_written_files()) {
+        match shred_file(&path) {
+            Ok(()) => tracing::debug!("removed {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!("unable to remove {}: {}", path.display(), err),
+        }
+    }
+}
+
+/// Overwrites the regular file at `path` with zeros and removes it.
+///
+/// # Remarks:
+///
+/// A file which is a mount point is only overwritten, as it cannot be removed.
+fn shred_file(path: &Path) -> std::io::Result<()> {
+    if !std::fs::symlink_metadata(path)?.is_file() {
+        return std::fs::remove_file(path);
+    }
+
+    let mut open_opts = std::fs::OpenOptions::new();
+    open_opts.write(true);
+    #[cfg(target_os = "linux")]
+    {
+        open_opts.custom_flags(O_NOFOLLOW | O_CLOEXEC);
+    }
+    let mut file = open_opts.open(path)?;
+    let len = file.metadata()?.len();
+    std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+    drop(file);
+
+    match std::fs::remove_file(path) {
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(nix::errno::Errno::EBUSY as i32) => Ok(()),
+        res => res,
+    }
+}
+
+/// Applies the mode, owner and SELinux label of `opts` to the open `file` at `path`.
+fn set_file_attributes(file: &std::fs::File, path: &Path, opts: &FileOptions) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    file.set_permissions(std::fs::Permissions::from_mode(opts.mode))
+        .map_err(|err| {
+            Error::io_at(
+                &format!("unable to set file mode {:o} for", opts.mode),
+                path,
+                err,
+            )
+        })?;
+
+    if let Some(owner) = opts.owner {
+        std::os::unix::fs::fchown(file, Some(owner.uid), owner.gid).map_err(|err| {
+            Error::io_at(
+                &format!("unable to change the owner to {} of", owner.uid),
+                path,
+                err,
+            )
+        })?;
+    }
+
+    if let Some(label) = opts.label {
+        #[cfg(target_os = "linux")]
+        set_selinux_label(file, label).map_err(|err| {
+            Error::io_at(
+                &format!("unable to set the SELinux label {} of", label),
+                path,
+                err,
+            )
+        })?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(target_error(
+            &format!(
+                "unable to set the SELinux label {} (only supported on linux) of",
+                label
+            ),
+            path,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sets the `security.selinux` attribute, which holds the SELinux context of a file.
+#[cfg(target_os = "linux")]
+fn set_selinux_label(file: &std::fs::File, label: &str) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the name is a valid c-string and the value is valid for its length.
+    let res = unsafe {
+        nix::libc::fsetxattr(
+            file.as_raw_fd(),
+            c"security.selinux".as_ptr(),
+            label.as_ptr().cast(),
+            label.len(),
+            0,
+        )
+    };
+    if res == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Error about the file target at `path` which is not caused by a failed IO operation.
+fn target_error(message: &str, path: &Path) -> Error {
+    Error::IO {
+        message: message.to_string(),
+        path: Some(path.to_path_buf()),
+        source: None,
+    }
+}
+
+fn ensure_secure_parent_directory(parent: &Path, target_path: &Path, create: bool) -> Result<()> {
+    ensure_parent_directory_exists(parent, target_path, create)?;
+    ensure_parent_has_no_symlink_components(parent, target_path)
+}
+
+fn ensure_parent_directory_exists(parent: &Path, target_path: &Path, create: bool) -> Result<()> {
+    if parent.exists() {
+        if parent.is_dir() {
+            return Ok(());
+        }
+
+        return Err(target_error(
+            "parent path is not a directory for file target",
+            target_path,
+        ));
+    }
+
+    if !create {
+        return Err(target_error(
+            &format!(
+                "parent directory {} does not exist (set create=true to create it) for file target",
+                parent.display()
+            ),
+            target_path,
+        ));
+    }
+
+    std::fs::create_dir_all(parent).map_err(|err| {
+        Error::io_at(
+            &format!(
+                "unable to create parent directory {} for file target",
+                parent.display()
+            ),
+            target_path,
+            err,
+        )
+    })
+}
+
+fn ensure_parent_has_no_symlink_components(parent: &Path, target_path: &Path) -> Result<()> {
     use std::path::Component;
 
     let mut current = if parent.is_absolute() {
         PathBuf::from(std::path::MAIN_SEPARATOR.to_string())
     } else {
-        std::env::current_dir()
-            .map_err(|err| Error::IO(format!("unable to get current directory: {}", err)))?
+        std::env::current_dir().map_err(|err| Error::io("unable to get current directory", err))?
     };
 
     for component in parent.components() {
         match component {
             Component::Prefix(_) => {
-                return Err(Error::IO(format!(
-                    "unsupported path prefix in file target {}",
-                    target_path.display()
-                )))
+                return Err(target_error(
+                    "unsupported path prefix in file target",
+                    target_path,
+                ))
             }
             Component::RootDir | Component::CurDir => continue,
             Component::ParentDir => {
@@ -422,30 +3006,764 @@ fn ensure_parent_has_no_symlink_components(parent: &Path, target_path: &Path) ->
         }
 
         let metadata = std::fs::symlink_metadata(&current).map_err(|err| {
-            Error::IO(format!(
-                "unable to inspect parent directory component {} for file target {}: {}",
-                current.display(),
-                target_path.display(),
-                err
-            ))
+            Error::io_at(
+                &format!(
+                    "unable to inspect parent directory component {} for file target",
+                    current.display()
+                ),
+                target_path,
+                err,
+            )
         })?;
 
         if metadata.file_type().is_symlink() {
-            return Err(Error::IO(format!(
-                "refusing to write secret through symlinked parent component {} for file target {}",
-                current.display(),
-                target_path.display()
-            )));
+            return Err(target_error(
+                &format!(
+                    "refusing to write secret through symlinked parent component {} for file target",
+                    current.display()
+                ),
+                target_path,
+            ));
         }
 
         if !metadata.is_dir() {
-            return Err(Error::IO(format!(
-                "parent path component {} is not a directory for file target {}",
-                current.display(),
-                target_path.display()
-            )));
+            return Err(target_error(
+                &format!(
+                    "parent path component {} is not a directory for file target",
+                    current.display()
+                ),
+                target_path,
+            ));
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{KvVersion, MockVault};
+
+    fn parse(argv: &[&str]) -> std::result::Result<Args, clap::Error> {
+        Args::from_matches(&Args::cli().try_get_matches_from(argv)?)
+    }
+
+    #[test]
+    fn pass_gitlab_auth() {
+        let argv = [
+            "vaultify",
+            "--auth-provider",
+            "gitlab",
+            "--gitlab-id-token-var",
+            "VAULTIFY_TEST_ID_TOKEN",
+            "--",
+            "env",
+        ];
+        let args = parse(&argv).unwrap();
+        let env = |var: &str| (var == "VAULTIFY_TEST_ID_TOKEN").then(|| "eyJ.id.token".to_string());
+        assert!(args.auth_method_with(env).is_err());
+
+        let args =
+            parse(&[&argv[..5], &["--gitlab-role", "deploy", "--", "env"]].concat()).unwrap();
+        assert!(args.auth_method_with(|_| None).is_err());
+        let AuthMethod::GitLab { jwt, role, backend } = args.auth_method_with(env).unwrap() else {
+            panic!("expected gitlab auth");
+        };
+        assert_eq!(
+            (jwt.as_str(), role.as_str(), backend.as_str()),
+            ("eyJ.id.token", "deploy", "jwt")
+        );
+    }
+
+    #[test]
+    fn fail_other_credentials() {
+        for (provider, option, value) in [
+            ("spiffe", "--token", "s.token"),
+            ("gitlab", "--github-token", "ghp"),
+            ("token", "--kubernetes-role", "app"),
+            ("kubernetes", "--gitlab-role", "ci"),
+            ("gitlab", "--jwt-role", "ci"),
+            ("token", "--jwt-role", "ci"),
+        ] {
+            let args = parse(&[
+                "vaultify",
+                "--auth-provider",
+                provider,
+                option,
+                value,
+                "--",
+                "env",
+            ])
+            .unwrap();
+            let Err(err) = args.auth_method_with(|_| None) else {
+                panic!(
+                    "expected {} to be rejected with --auth-provider {}",
+                    option, provider
+                );
+            };
+            assert_eq!(
+                err.to_string(),
+                Error::Execution(format!(
+                    "invalid auth configuration: --auth-provider {} cannot be combined with {}",
+                    provider, option
+                ))
+                .to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn pass_other_credentials() {
+        for (provider, option) in [
+            ("github-actions", "--jwt-role"),
+            ("spiffe", "--jwt-role"),
+            ("gitlab", "--gitlab-role"),
+        ] {
+            let args = parse(&[
+                "vaultify",
+                "--auth-provider",
+                provider,
+                option,
+                "ci",
+                "--",
+                "env",
+            ])
+            .unwrap();
+            assert!(args.check_other_credentials().is_ok(), "{}", provider);
+        }
+    }
+
+    #[test]
+    fn pass_trailing_args() {
+        let args = parse(&[
+            "vaultify",
+            "--retries",
+            "1",
+            "--",
+            "my-server",
+            "--retries",
+            "5",
+            "-v",
+        ])
+        .unwrap();
+        assert_eq!(args.retries, 1);
+        assert_eq!(args.cmd, ["my-server", "--retries", "5", "-v"]);
+
+        let args = parse(&["vaultify", "sh", "-c", "--clear-env", "--", "x"]).unwrap();
+        assert!(!args.clear_env);
+        assert!(args.shell.is_none());
+        assert_eq!(args.cmd, ["sh", "-c", "--clear-env", "--", "x"]);
+    }
+
+    #[test]
+    fn pass_subcommands() {
+        let args = parse(&["vaultify", "run", "--retries", "1", "ls", "--retries", "5"]).unwrap();
+        assert!(args.subcommand.is_none());
+        assert_eq!(args.retries, 1);
+        assert_eq!(args.cmd, ["ls", "--retries", "5"]);
+
+        let args = parse(&["vaultify", "--retries", "1", "fetch", "--format", "json"]).unwrap();
+        assert!(matches!(
+            args.subcommand,
+            Some(Command::Fetch {
+                format: process::SecretsFormat::Json
+            })
+        ));
+        assert_eq!(args.retries, 1);
+        assert!(args.cmd.is_empty());
+
+        let args = parse(&["vaultify", "env", "--clear-env"]).unwrap();
+        assert!(matches!(args.subcommand, Some(Command::Env)));
+        assert!(args.clear_env);
+        // the `env` binary ran before subcommands existed
+        #[cfg(unix)]
+        assert!(args.shadowed_command.is_some());
+        let args = parse(&["vaultify", "validate"]).unwrap();
+        assert!(args.shadowed_command.is_none());
+
+        let args = parse(&["vaultify", "--", "env"]).unwrap();
+        assert!(args.subcommand.is_none());
+        assert_eq!(args.cmd, ["env"]);
+        assert!(args.shadowed_command.is_none());
+
+        assert!(parse(&["vaultify", "run", "--shell", "ls", "ls"]).is_err());
+        assert!(parse(&["vaultify", "run"]).is_err());
+    }
+
+    #[test]
+    fn pass_completions() {
+        let mut script = Vec::new();
+        clap_complete::generate(
+            clap_complete::Shell::Bash,
+            &mut Args::cli(),
+            "vaultify",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("vaultify,debug-env)"));
+        assert!(script.contains("--log-format"));
+    }
+
+    #[test]
+    fn pass_vault_cli_values() {
+        for raw in ["1", "t", "TRUE", "True"] {
+            assert_eq!(parse_env_bool(raw), Ok(true));
+        }
+        for raw in ["", "0", "F", "false"] {
+            assert_eq!(parse_env_bool(raw), Ok(false));
+        }
+        assert!(parse_env_bool("maybe").is_err());
+
+        assert_eq!(parse_timeout("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_timeout("1m30s"), Ok(Duration::from_secs(90)));
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn pass_supervise_without_refresh() {
+        // restarts the crashed child with fresh secrets, but never refreshes a running one
+        let args = parse(&["vaultify", "--supervise", "--", "true"]).unwrap();
+        args.validate().unwrap();
+        assert!(args.attached());
+        assert_eq!(args.on_change(), OnChange::Restart);
+    }
+
+    #[test]
+    fn fail_on_change_without_refresh() {
+        for option in [
+            "--on-change=restart",
+            "--on-change-signal=SIGUSR1",
+            "--on-change-hook=./reload.sh",
+        ] {
+            let args = parse(&["vaultify", option, "--", "true"]).unwrap();
+            let err = args.validate().unwrap_err();
+            assert!(
+                err.to_string().contains("require --refresh-interval"),
+                "{}",
+                err
+            );
+        }
+
+        let args = parse(&[
+            "vaultify",
+            "--refresh-interval",
+            "5m",
+            "--on-change-signal",
+            "SIGUSR1",
+            "--",
+            "true",
+        ])
+        .unwrap();
+        args.validate().unwrap();
+    }
+
+    #[test]
+    fn fail_cleanup_files_exec() {
+        let args = parse(&["vaultify", "--cleanup-files", "--", "true"]).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("--cleanup-files requires"),
+            "{}",
+            err
+        );
+
+        let args = parse(&["vaultify", "--cleanup-files", "--supervise", "--", "true"]).unwrap();
+        args.validate().unwrap();
+    }
+
+    #[test]
+    fn fail_concurrency() {
+        let args = parse(&["vaultify", "--concurrency", "4", "--", "true"]).unwrap();
+        assert_eq!(args.concurrency, 4);
+
+        let too_many = (CONCURRENCY_MAX + 1).to_string();
+        for concurrency in ["0", &too_many, "many"] {
+            let argv = ["vaultify", "--concurrency", concurrency, "--", "true"];
+            assert!(parse(&argv).is_err(), "{}", concurrency);
+        }
+    }
+
+    #[test]
+    fn pass_request_timeouts() {
+        let args = parse(&["vaultify", "--", "true"]).unwrap();
+        assert_eq!(args.request_timeout, Duration::from_secs(30));
+        assert_eq!(args.connect_timeout, Duration::from_secs(5));
+
+        let argv = [
+            "vaultify",
+            "--request-timeout",
+            "10",
+            "--connect-timeout",
+            "500ms",
+            "--",
+            "true",
+        ];
+        let args = parse(&argv).unwrap();
+        assert_eq!(args.request_timeout, Duration::from_secs(10));
+        assert_eq!(args.connect_timeout, Duration::from_millis(500));
+        let args = parse(&["vaultify", "--request-timeout", "2m", "--", "true"]).unwrap();
+        assert_eq!(args.request_timeout, Duration::from_secs(120));
+
+        assert!(parse(&["vaultify", "--request-timeout", "soon", "--", "true"]).is_err());
+        assert!(parse(&["vaultify", "--connect-timeout", "5", "--", "true"]).is_err());
+    }
+
+    #[test]
+    fn pass_wait_for_vault() {
+        let args = parse(&["vaultify", "--wait-for-vault", "2m", "--", "true"]).unwrap();
+        assert_eq!(args.wait_for_vault, Some(Duration::from_secs(120)));
+        assert!(parse(&["vaultify", "--wait-for-vault", "soon", "--", "true"]).is_err());
+    }
+
+    #[test]
+    fn pass_shell() {
+        let args = parse(&["vaultify", "--shell", "exec my-server \"$PORT\""]).unwrap();
+        args.validate().unwrap();
+        assert!(args.cmd.is_empty());
+        let (cmd, cmd_args) = args.command().unwrap();
+        assert_eq!(cmd, "/bin/sh");
+        assert_eq!(cmd_args, ["-c", "exec my-server \"$PORT\""]);
+
+        let args = parse(&["vaultify", "-c", "true"]).unwrap();
+        assert_eq!(args.shell.as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn fail_shell() {
+        assert!(parse(&["vaultify", "--shell", "true", "--procfile", "Procfile"]).is_err());
+        assert!(parse(&["vaultify", "--shell", "true", "--", "ls"]).is_err());
+
+        let args = parse(&["vaultify", "--k8s-init", "--shell", "true"]).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.to_string().contains("--k8s-init"), "{}", err);
+    }
+
+    #[test]
+    fn fail_stdin_format_with_secrets_fd() {
+        let args = parse(&["vaultify", "--stdin-format", "json", "--", "true"]).unwrap();
+        assert_eq!(args.stdin_format, Some(process::SecretsFormat::Json));
+        assert_eq!(spawn_options(&args, &[]).stdin, args.stdin_format);
+
+        let argv = [
+            "vaultify",
+            "--stdin-format",
+            "json",
+            "--secrets-fd",
+            "dotenv",
+            "--",
+            "true",
+        ];
+        assert!(parse(&argv).is_err());
+    }
+
+    #[test]
+    fn fail_unknown_option_before_command() {
+        assert!(parse(&["vaultify", "--unknown", "--", "ls"]).is_err());
+    }
+
+    #[test]
+    fn pass_shred_file() {
+        let dir = std::env::temp_dir().join(format!("vaultify-shred-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, link) = (dir.join("key"), dir.join("link"));
+        std::fs::write(&path, "secret").unwrap();
+        // the link keeps the contents reachable after the file is removed
+        std::fs::hard_link(&path, &link).unwrap();
+
+        shred_file(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(std::fs::read(&link).unwrap(), [0; 6]);
+        let err = shred_file(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pass_file_writes() {
+        let dir = std::env::temp_dir().join(format!("vaultify-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::write(&a, "old").unwrap();
+        let read = |path: &Path| std::fs::read_to_string(path).ok();
+        let opts = FileOptions {
+            mode: 0o600,
+            create: false,
+            owner: None,
+            label: None,
+        };
+
+        // a failed write restores the files written before
+        let mut writes = FileWrites::default();
+        writes.write(&a, "new", &opts).unwrap();
+        writes.write(&b, "new", &opts).unwrap();
+        assert!(writes.write(&dir, "new", &opts).is_err());
+        writes.rollback();
+        assert_eq!(read(&a).as_deref(), Some("old"));
+        assert_eq!(read(&b), None);
+
+        let mut writes = FileWrites {
+            backup: true,
+            ..FileWrites::default()
+        };
+        writes.write(&a, "new", &opts).unwrap();
+        writes.commit();
+        assert_eq!(read(&a).as_deref(), Some("new"));
+        assert_eq!(read(&dir.join("a.bak")).as_deref(), Some("old"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        // a replaced file keeps its owner, unless `owner=` says otherwise
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let owner = |path: &Path| {
+                let metadata = std::fs::metadata(path).unwrap();
+                (metadata.uid(), metadata.gid())
+            };
+            // files written by vaultify itself are created with its own owner
+            let current = owner(&dir.join("a.bak"));
+            let (uid, gid) = match current {
+                (0, _) => (1234, 2345),
+                _ => current,
+            };
+            std::os::unix::fs::chown(&a, Some(uid), Some(gid)).unwrap();
+            let mut writes = FileWrites::default();
+            writes.write(&a, "newer", &opts).unwrap();
+            writes.commit();
+            assert_eq!(read(&a).as_deref(), Some("newer"));
+            assert_eq!(owner(&a), (uid, gid));
+
+            let owned = FileOptions {
+                owner: Some(secrets::Owner {
+                    uid: current.0,
+                    gid: Some(current.1),
+                }),
+                ..opts
+            };
+            let mut writes = FileWrites::default();
+            writes.write(&a, "newest", &owned).unwrap();
+            writes.commit();
+            assert_eq!(owner(&a), current);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Resolves the secrets of `secrets` from `vault` with a fallback file and the options in
+    /// `extra`.
+    async fn resolve_with_fallback(
+        vault: &MockVault,
+        extra: &[&str],
+        secrets: &str,
+    ) -> Result<Vec<Secret>> {
+        static RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "vaultify-fallback-{}-{}",
+            std::process::id(),
+            RUNS.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        std::fs::write(&secrets_file, secrets).unwrap();
+        let fallback_file = dir.join("dev.json");
+        std::fs::write(&fallback_file, r#"{"DB_PASS": "dummy"}"#).unwrap();
+
+        let host = vault.addr();
+        let argv = [
+            &[
+                "vaultify",
+                "--host",
+                &host,
+                "--retries",
+                "0",
+                "--secrets-file",
+                secrets_file.to_str().unwrap(),
+                "--fallback-file",
+                fallback_file.to_str().unwrap(),
+            ],
+            extra,
+            &["--", "true"],
+        ]
+        .concat();
+        let args = parse(&argv).unwrap();
+        let specs = secrets::load(&args.secrets_file).unwrap();
+        let resolved = resolve_secrets(&args, &specs, None)
+            .await
+            .and_then(|secrets| provider::finish(&specs, secrets));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        resolved
+    }
+
+    #[tokio::test]
+    async fn pass_fallback_unavailable() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"password": "p"}));
+        vault.delay("secret", "app", Duration::from_secs(10));
+        let secrets = "secret/app#password | env DB_PASS\n";
+
+        let extra = ["--token", MockVault::TOKEN, "--fetch-timeout", "200ms"];
+        let resolved = resolve_with_fallback(&vault, &extra, secrets)
+            .await
+            .unwrap();
+        assert_eq!(resolved[0].secret, "dummy");
+
+        // running out of the budget of the whole fetch fails instead
+        let extra = ["--token", MockVault::TOKEN, "--timeout", "200ms"];
+        let err = resolve_with_fallback(&vault, &extra, secrets)
+            .await
+            .unwrap_err();
+        assert_eq!(err.class(), "timeout");
+    }
+
+    #[tokio::test]
+    async fn fail_fetch_timeout() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"password": "p"}));
+        vault.put("secret", "db", serde_json::json!({"password": "d"}));
+        vault.delay("secret", "db", Duration::from_secs(10));
+        let dir = std::env::temp_dir().join(format!("vaultify-timeout-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        std::fs::write(&secrets_file, "secret/app#password | env APP_PASS\n").unwrap();
+
+        let host = vault.addr();
+        let args = |timeout: &str| {
+            parse(&[
+                "vaultify",
+                "--host",
+                &host,
+                "--token",
+                MockVault::TOKEN,
+                "--timeout",
+                timeout,
+                "--secrets-file",
+                secrets_file.to_str().unwrap(),
+                "--",
+                "true",
+            ])
+            .unwrap()
+        };
+        let specs = secrets::load(&secrets_file).unwrap();
+        let secrets = fetch_secrets(&args("5s"), &specs).await.unwrap();
+        assert_eq!(secrets[0].secret, "p");
+
+        // the deadline covers the whole fetch, however many retries are left
+        std::fs::write(&secrets_file, "secret/db#password | env DB_PASS\n").unwrap();
+        let specs = secrets::load(&secrets_file).unwrap();
+        let started = std::time::Instant::now();
+        let err = fetch_secrets(&args("200ms"), &specs).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.to_string().contains("did not complete within"),
+            "{}",
+            err
+        );
+        assert_eq!(err.exit_code(), 8);
+        assert!(!vault::should_failover(&err));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_fallback_forbidden() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"password": "p"}));
+        let secrets = "secret/app#password | env DB_PASS\n";
+
+        let err = resolve_with_fallback(&vault, &["--token", "revoked"], secrets)
+            .await
+            .unwrap_err();
+        assert_eq!(err.exit_code(), 5);
+    }
+
+    #[tokio::test]
+    async fn fail_fallback_checksum() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"password": "p"}));
+        let secrets = format!(
+            "secret/app#password !sha256:{} | env DB_PASS\n",
+            "0".repeat(64)
+        );
+
+        let err = resolve_with_fallback(&vault, &["--token", MockVault::TOKEN], &secrets)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("checksum mismatch of secret `DB_PASS`"));
+
+        // values of the fallback file are verified as well
+        let err = resolve_with_fallback(&vault, &[], &secrets)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("checksum mismatch of secret `DB_PASS`"));
+    }
+
+    #[tokio::test]
+    async fn pass_early_login_unused_without_vault_secrets() {
+        let vault = MockVault::start().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("vaultify-early-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        std::fs::write(&secrets_file, "env:HOME | env APP_HOME\n").unwrap();
+
+        let host = vault.addr();
+        let args = parse(&[
+            "vaultify",
+            "--host",
+            &host,
+            "--auth-provider",
+            "github",
+            "--github-token",
+            "gh",
+            "--secrets-file",
+            secrets_file.to_str().unwrap(),
+            "--",
+            "true",
+        ])
+        .unwrap();
+        let (_, secrets) = load_secrets(&args).await.unwrap();
+        assert_eq!(secrets[0].target.name(), "APP_HOME");
+        // the login may have started alongside parsing, but its token is not used
+        assert!(
+            vault
+                .requests()
+                .iter()
+                .all(|r| r == "POST /v1/auth/github/login"),
+            "{:?}",
+            vault.requests()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_timing_report() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        let dir = std::env::temp_dir().join(format!("vaultify-timing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        std::fs::write(&secrets_file, "secret/missing#password | env DB_PASS\n").unwrap();
+        let report = dir.join("timing.txt");
+
+        let host = vault.addr();
+        let timing = format!("--timing={}", report.display());
+        let args = parse(&[
+            "vaultify",
+            "--host",
+            &host,
+            "--token",
+            MockVault::TOKEN,
+            "--secrets-file",
+            secrets_file.to_str().unwrap(),
+            &timing,
+            "--",
+            "true",
+        ])
+        .unwrap();
+        assert!(load_secrets(&args).await.is_err());
+        let report = std::fs::read_to_string(report).unwrap();
+        assert!(report.contains(", failed\n"), "{}", report);
+        assert!(report.contains("parse"), "{}", report);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_failover_local() {
+        let sealed = MockVault::start().await.unwrap();
+        sealed.mount("secret", KvVersion::V2);
+        sealed.seal();
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"password": "p"}));
+        let dir = std::env::temp_dir().join(format!("vaultify-local-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secrets_file = dir.join(".secrets");
+        std::fs::write(
+            &secrets_file,
+            "secret/app#password | env DB_PASS\nenv:PATH | env CHILD_PATH\n",
+        )
+        .unwrap();
+
+        let hosts = format!("{},{}", sealed.addr(), vault.addr());
+        let args = parse(&[
+            "vaultify",
+            "--host",
+            &hosts,
+            "--token",
+            MockVault::TOKEN,
+            "--retries",
+            "0",
+            "--secrets-file",
+            secrets_file.to_str().unwrap(),
+            "--",
+            "true",
+        ])
+        .unwrap();
+        let specs = secrets::load(&args.secrets_file).unwrap();
+        let secrets = fetch_secrets(&args, &specs).await.unwrap();
+        let value = |name: &str| {
+            secrets
+                .iter()
+                .find(|secret| secret.target.name() == name)
+                .map(|secret| secret.secret.clone())
+        };
+        assert!(!sealed.requests().is_empty());
+        assert_eq!(value("DB_PASS").as_deref(), Some("p"));
+        assert_eq!(value("CHILD_PATH"), std::env::var("PATH").ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pass_put_failover() {
+        let sealed = MockVault::start().await.unwrap();
+        sealed.mount("secret", KvVersion::V2);
+        sealed.seal();
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+
+        let hosts = format!("{},{}", sealed.addr(), vault.addr());
+        let args = parse(&[
+            "vaultify",
+            "--host",
+            &hosts,
+            "--token",
+            MockVault::TOKEN,
+            "--retries",
+            "0",
+            "import",
+            "--from",
+            "/dev/null",
+            "--to",
+            "secret/app",
+        ])
+        .unwrap();
+        let writes = [
+            (
+                "app".to_string(),
+                Map::from_iter([("password".to_string(), Value::from("p"))]),
+            ),
+            (
+                "db".to_string(),
+                Map::from_iter([("user".to_string(), Value::from("u"))]),
+            ),
+        ];
+        let versions = write_secrets(&args, "secret", &writes, None).await.unwrap();
+        assert_eq!(versions, [1, 1]);
+        assert!(!sealed.requests().is_empty());
+        assert_eq!(vault.get("secret", "app").unwrap().1["password"], "p");
+        assert_eq!(vault.get("secret", "db").unwrap().1["user"], "u");
+    }
+}
@@ -0,0 +1,186 @@
+//! Metadata of the secrets passed to the command as additional variables with `--metadata-env`.
+use std::{sync::OnceLock, time::SystemTime};
+
+use ring::{hmac, rand::SystemRandom};
+
+use crate::{
+    crypto,
+    error::{Error, Result},
+    process::EnvSecret,
+    secrets::{Secret, SecretTarget},
+};
+
+/// Variable holding the hash of all secret values.
+pub const SECRETS_HASH_VAR: &str = "VAULTIFY_SECRETS_HASH";
+
+/// Variables describing `secrets`: `VAULTIFY_SECRETS_HASH`, `<NAME>_VERSION` of each variable
+/// read from a kv v2 secret, and `<NAME>_LEASE_ID` and `<NAME>_EXPIRES_AT` (RFC 3339, UTC) of
+/// each variable holding a leased secret or a field of a certificate.
+///
+/// # Remarks:
+///
+/// Variables which are already set by a secret are left out, so the secrets file takes
+/// precedence.
+pub fn env(secrets: &[Secret]) -> Result<Vec<EnvSecret>> {
+    let mut vars = vec![EnvSecret {
+        name: SECRETS_HASH_VAR.to_string(),
+        secret: secrets_hash(secrets)?,
+    }];
+    for secret in secrets {
+        let SecretTarget::Env { name } = &secret.target else {
+            continue;
+        };
+        let metadata = &secret.metadata;
+        let companions = [
+            (
+                "VERSION",
+                metadata.version.map(|version| version.to_string()),
+            ),
+            ("LEASE_ID", metadata.lease_id.clone()),
+            ("EXPIRES_AT", metadata.expires_at.map(format_time)),
+        ];
+        for (suffix, value) in companions {
+            if let Some(value) = value {
+                vars.push(EnvSecret {
+                    name: format!("{}_{}", name, suffix),
+                    secret: value,
+                });
+            }
+        }
+    }
+
+    vars.retain(|var| {
+        let taken = secrets
+            .iter()
+            .any(|secret| secret.target.name() == var.name);
+        if taken {
+            tracing::warn!(
+                "not passing metadata variable `{}`, a secret is passed as it",
+                var.name
+            );
+        }
+        !taken
+    });
+    Ok(vars)
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// HMAC-SHA256 (lowercase hex) of the targets and values of all secrets, which is independent of
+/// their order and changes whenever a value does.
+///
+/// # Remarks:
+///
+/// The key is random per vaultify process, so the hash cannot be used to guess low entropy
+/// values offline; it is only comparable between the runs of the command by the same vaultify.
+pub fn secrets_hash(secrets: &[Secret]) -> Result<String> {
+    let mut entries = secrets
+        .iter()
+        .map(|secret| (secret.target.name(), secret.secret.as_str()))
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+
+    // length prefixes keep the boundaries between names and values unambiguous
+    let mut data = Vec::new();
+    for (name, value) in entries {
+        for part in [name.as_bytes(), value.as_bytes()] {
+            data.extend_from_slice(&(part.len() as u64).to_be_bytes());
+            data.extend_from_slice(part);
+        }
+    }
+    let tag = hmac::sign(hash_key()?, &data);
+    Ok(crypto::hex(tag.as_ref()))
+}
+
+fn hash_key() -> Result<&'static hmac::Key> {
+    static KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+    if let Some(key) = KEY.get() {
+        return Ok(key);
+    }
+    let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+        .map_err(|_| Error::Execution("unable to generate the secrets hash key".to_string()))?;
+    Ok(KEY.get_or_init(|| key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(name: &str, value: &str, version: Option<u64>) -> Secret {
+        let mut secret = Secret::new(
+            SecretTarget::Env {
+                name: name.to_string(),
+            },
+            value.to_string(),
+        );
+        secret.metadata.version = version;
+        secret
+    }
+
+    #[test]
+    fn pass_lease_env() {
+        let mut creds = secret("DB_PASS", "p", None);
+        creds.metadata.lease_id = Some("database/creds/app/abc".to_string());
+        creds.metadata.expires_at =
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000));
+        let vars = env(&[creds])
+            .unwrap()
+            .into_iter()
+            .skip(1)
+            .map(|var| (var.name, var.secret))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vars,
+            [
+                (
+                    "DB_PASS_LEASE_ID".to_string(),
+                    "database/creds/app/abc".to_string()
+                ),
+                (
+                    "DB_PASS_EXPIRES_AT".to_string(),
+                    "2023-11-14T22:13:20Z".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pass_env() {
+        let secrets = [
+            secret("DB_PASS", "p", Some(3)),
+            secret("API_KEY", "k", None),
+            secret("TOKEN", "t", Some(1)),
+            secret("TOKEN_VERSION", "pinned", None),
+        ];
+        let vars = env(&secrets)
+            .unwrap()
+            .into_iter()
+            .map(|var| (var.name, var.secret))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vars,
+            [
+                (
+                    SECRETS_HASH_VAR.to_string(),
+                    secrets_hash(&secrets).unwrap()
+                ),
+                ("DB_PASS_VERSION".to_string(), "3".to_string()),
+            ]
+        );
+
+        // the hash depends on the values, not on their order or versions
+        let reordered = [
+            secret("API_KEY", "k", None),
+            secret("DB_PASS", "p", Some(4)),
+        ];
+        let same = [secret("DB_PASS", "p", None), secret("API_KEY", "k", None)];
+        let rotated = [secret("DB_PASS", "q", None), secret("API_KEY", "k", None)];
+        let hash = |secrets: &[Secret]| secrets_hash(secrets).unwrap();
+        assert_eq!(hash(&reordered), hash(&same));
+        assert_ne!(hash(&same), hash(&rotated));
+        assert_eq!(hash(&same).len(), 64);
+    }
+}
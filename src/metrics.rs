@@ -0,0 +1,261 @@
+//! Prometheus metrics of a resident vaultify, served on `--metrics-addr` in attached mode.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::Result,
+    http::{self, Response, Server},
+};
+
+/// Upper bounds (in seconds) of the buckets of `vaultify_fetch_duration_seconds`.
+const FETCH_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+struct Metrics {
+    /// Fetches per bucket of `FETCH_BUCKETS`, not cumulative.
+    fetch_buckets: [u64; FETCH_BUCKETS.len()],
+    fetch_seconds: f64,
+    fetches_ok: u64,
+    fetches_failed: u64,
+    refreshes_ok: u64,
+    refreshes_failed: u64,
+    renewals_ok: u64,
+    renewals_failed: u64,
+    /// Restarts of the child by reason.
+    restarts: BTreeMap<&'static str, u64>,
+    /// Expiry of leases by name, e.g. `token` or the path of a leased secret.
+    leases: BTreeMap<String, SystemTime>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            fetch_buckets: [0; FETCH_BUCKETS.len()],
+            fetch_seconds: 0.0,
+            fetches_ok: 0,
+            fetches_failed: 0,
+            refreshes_ok: 0,
+            refreshes_failed: 0,
+            renewals_ok: 0,
+            renewals_failed: 0,
+            restarts: BTreeMap::new(),
+            leases: BTreeMap::new(),
+        }
+    }
+
+    fn fetch_completed(&mut self, elapsed: Duration, success: bool) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(idx) = FETCH_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.fetch_buckets[idx] += 1;
+        }
+        self.fetch_seconds += secs;
+        if success {
+            self.fetches_ok += 1;
+        } else {
+            self.fetches_failed += 1;
+        }
+    }
+
+    fn refresh_completed(&mut self, success: bool) {
+        if success {
+            self.refreshes_ok += 1;
+        } else {
+            self.refreshes_failed += 1;
+        }
+    }
+
+    fn renewal_completed(&mut self, success: bool) {
+        if success {
+            self.renewals_ok += 1;
+        } else {
+            self.renewals_failed += 1;
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format, with the lease expiries relative to
+    /// `now`.
+    fn render(&self, now: SystemTime) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_fetch_duration_seconds Duration of fetching all secrets.\n# TYPE vaultify_fetch_duration_seconds histogram"
+        );
+        let mut cumulative = 0;
+        for (bound, count) in FETCH_BUCKETS.iter().zip(self.fetch_buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "vaultify_fetch_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let total = self.fetches_ok + self.fetches_failed;
+        let _ = writeln!(
+            out,
+            "vaultify_fetch_duration_seconds_bucket{{le=\"+Inf\"}} {}\nvaultify_fetch_duration_seconds_sum {}\nvaultify_fetch_duration_seconds_count {}",
+            total, self.fetch_seconds, total
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_fetches_total Fetches of all secrets by result.\n# TYPE vaultify_fetches_total counter\nvaultify_fetches_total{{result=\"success\"}} {}\nvaultify_fetches_total{{result=\"failure\"}} {}",
+            self.fetches_ok, self.fetches_failed
+        );
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_refreshes_total Refreshes of the secrets of the running child by result.\n# TYPE vaultify_refreshes_total counter\nvaultify_refreshes_total{{result=\"success\"}} {}\nvaultify_refreshes_total{{result=\"failure\"}} {}",
+            self.refreshes_ok, self.refreshes_failed
+        );
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_renewals_total Renewals of certificates before they expire by result.\n# TYPE vaultify_renewals_total counter\nvaultify_renewals_total{{result=\"success\"}} {}\nvaultify_renewals_total{{result=\"failure\"}} {}",
+            self.renewals_ok, self.renewals_failed
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_child_restarts_total Restarts of the child by reason.\n# TYPE vaultify_child_restarts_total counter"
+        );
+        for reason in ["exit", "secrets_changed"] {
+            let count = self.restarts.get(reason).copied().unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "vaultify_child_restarts_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP vaultify_lease_expiry_seconds Seconds until a lease expires, negative once expired.\n# TYPE vaultify_lease_expiry_seconds gauge"
+        );
+        for (name, expiry) in &self.leases {
+            let secs = match expiry.duration_since(now) {
+                Ok(remaining) => remaining.as_secs_f64(),
+                Err(err) => -err.duration().as_secs_f64(),
+            };
+            let _ = writeln!(
+                out,
+                "vaultify_lease_expiry_seconds{{lease=\"{}\"}} {:.0}",
+                escape_label(name),
+                secs
+            );
+        }
+
+        out
+    }
+}
+
+fn metrics() -> std::sync::MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Records a fetch of all secrets which took `elapsed`.
+pub fn fetch_completed(elapsed: Duration, success: bool) {
+    metrics().fetch_completed(elapsed, success);
+}
+
+/// Records a periodic or file change triggered refresh of the secrets.
+pub fn refresh_completed(success: bool) {
+    metrics().refresh_completed(success);
+}
+
+/// Records a fetch of the secrets to renew certificates before they expire.
+pub fn renewal_completed(success: bool) {
+    metrics().renewal_completed(success);
+}
+
+/// Records a restart of the child, `reason` is either `exit` or `secrets_changed`.
+pub fn child_restarted(reason: &'static str) {
+    *metrics().restarts.entry(reason).or_default() += 1;
+}
+
+/// Records a lease called `name` which expires in `duration`, replacing its previous expiry.
+pub fn lease_granted(name: &str, duration: Duration) {
+    metrics()
+        .leases
+        .insert(name.to_string(), SystemTime::now() + duration);
+}
+
+/// Expiry of the leases by name.
+pub fn lease_expiries() -> BTreeMap<String, SystemTime> {
+    metrics().leases.clone()
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render() -> String {
+    metrics().render(SystemTime::now())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Listens on `addr` and serves the metrics to every HTTP request, e.g. `GET /metrics`.
+pub async fn serve(addr: SocketAddr) -> Result<Server> {
+    let server = http::serve(addr, "metrics", |_| Response {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: render(),
+    })
+    .await?;
+    tracing::info!("serving metrics on http://{}/metrics", addr);
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_render() {
+        // a metrics instance of its own, the global one is shared with the other tests
+        let mut metrics = Metrics::new();
+        metrics.fetch_completed(Duration::from_millis(300), true);
+        metrics.fetch_completed(Duration::from_secs(60), false);
+        metrics.refresh_completed(true);
+        metrics.renewal_completed(true);
+        metrics.renewal_completed(false);
+        metrics.renewal_completed(false);
+        metrics.restarts.insert("exit", 1);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        metrics
+            .leases
+            .insert("secret/app".to_string(), now + Duration::from_secs(3600));
+        metrics
+            .leases
+            .insert("token".to_string(), now - Duration::from_secs(5));
+
+        let rendered = metrics.render(now);
+        for line in [
+            "vaultify_fetch_duration_seconds_bucket{le=\"0.25\"} 0",
+            "vaultify_fetch_duration_seconds_bucket{le=\"0.5\"} 1",
+            "vaultify_fetch_duration_seconds_bucket{le=\"30\"} 1",
+            "vaultify_fetch_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "vaultify_fetch_duration_seconds_count 2",
+            "vaultify_fetches_total{result=\"success\"} 1",
+            "vaultify_fetches_total{result=\"failure\"} 1",
+            "vaultify_refreshes_total{result=\"success\"} 1",
+            "vaultify_refreshes_total{result=\"failure\"} 0",
+            "vaultify_renewals_total{result=\"success\"} 1",
+            "vaultify_renewals_total{result=\"failure\"} 2",
+            "vaultify_child_restarts_total{reason=\"exit\"} 1",
+            "vaultify_child_restarts_total{reason=\"secrets_changed\"} 0",
+            "vaultify_lease_expiry_seconds{lease=\"secret/app\"} 3600",
+            "vaultify_lease_expiry_seconds{lease=\"token\"} -5",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing {line}");
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Output of the `env`, `template` and `debug-env` subcommands, which print the secrets instead of
+//! passing them to a command.
+use crate::{
+    crypto,
+    error::{Error, Result},
+    process::EnvSecret,
+    secrets::{Secret, SecretSpecs},
+};
+
+/// Renders one `export NAME='value'` line per secret, to be evaluated by a POSIX shell.
+pub fn shell_exports(secrets: &[EnvSecret]) -> String {
+    secrets
+        .iter()
+        .map(|secret| {
+            format!(
+                "export {}='{}'\n",
+                secret.name,
+                secret.secret.replace('\'', r"'\''")
+            )
+        })
+        .collect()
+}
+
+/// Replaces every `{{ NAME }}` in `template` with the value of the secret NAME, which is the
+/// variable of an env target or `file:<path>` of a file target.
+pub fn render_template(template: &str, secrets: &[Secret]) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let line_index = template[..template.len() - rest.len() + start]
+            .matches('\n')
+            .count();
+        let line = template.lines().nth(line_index).unwrap_or_default();
+        let (name, after) = rest[start + 2..]
+            .split_once("}}")
+            .ok_or_else(|| Error::parse("unterminated `{{`", line_index, line))?;
+
+        let name = name.trim();
+        let secret = secrets
+            .iter()
+            .find(|secret| secret.target.name() == name)
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "template uses `{}` which is not in the secrets file",
+                    name
+                ))
+            })?;
+        rendered.push_str(&secret.secret);
+        rest = after;
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Where the variables of the environment of the child come from, for `debug-env`.
+pub struct EnvSources<'a> {
+    /// Variables of vaultify's own environment.
+    pub inherited: &'a [(String, String)],
+    /// Variables of the `--env-file`s.
+    pub env_files: &'a [(String, String)],
+    /// Secrets with env targets and the `--metadata-env` variables.
+    pub secrets: &'a [EnvSecret],
+    /// Secrets with file targets or in the dotenv file of a section.
+    pub files: &'a [Secret],
+    pub specs: &'a SecretSpecs,
+}
+
+/// Key the values printed by `debug-env` are hashed with, so a short password or PIN cannot be
+/// looked up from a hash pasted into a ticket.
+pub struct HashKey {
+    key: String,
+    /// Whether the key is printed along with the hashes, which a random key of the run is.
+    printed: bool,
+}
+
+impl HashKey {
+    /// The key of `--hash-key`, or a random key of this run.
+    pub fn new(key: Option<&str>) -> Result<Self> {
+        Ok(match key {
+            Some(key) => Self {
+                key: key.to_string(),
+                printed: false,
+            },
+            None => Self {
+                key: crypto::hex(&crypto::random_bytes(16)?),
+                printed: true,
+            },
+        })
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let hmac = crypto::hmac_sha256_hex(self.key.as_bytes(), value.as_bytes());
+        format!("hmac:{}", &hmac[..16])
+    }
+}
+
+/// Renders one `NAME=hmac:<hash>  # <provenance>` line per variable of the child's `env`, sorted
+/// by name, followed by one line per file target.
+///
+/// # Remarks:
+///
+/// The hash is the first 16 hex digits of the HMAC-SHA256 of the value keyed with `key`, e.g. to
+/// compare with `printf %s "$PASSWORD" | openssl dgst -sha256 -hmac <key>`. A random key is
+/// printed in a comment first. Inherited values are printed as they are with `show_inherited`.
+pub fn debug_env(
+    env: &[(String, String)],
+    sources: &EnvSources,
+    show_inherited: bool,
+    key: &HashKey,
+) -> String {
+    let lookup = |vars: &[(String, String)], name: &str| {
+        vars.iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, value)| value.clone())
+    };
+    let mut env = env.iter().collect::<Vec<_>>();
+    env.sort();
+
+    let mut rendered = String::new();
+    if key.printed {
+        rendered.push_str(&format!("# hmac key of this run: {}\n", key.key));
+    }
+    for (name, value) in env {
+        let inherited = lookup(sources.inherited, name);
+        let secret = sources.secrets.iter().find(|secret| &secret.name == name);
+        let source = || match sources.specs.get(name) {
+            Some(spec) => spec.source(),
+            None => "--metadata-env".to_string(),
+        };
+        let mut provenance = match secret {
+            Some(secret) if &secret.secret == value => format!("injected from {}", source()),
+            // an inherited variable kept with --on-conflict keep-existing
+            Some(_) => format!("inherited, shadows {}", source()),
+            None if lookup(sources.env_files, name).as_ref() == Some(value) => {
+                "from --env-file".to_string()
+            }
+            None => "inherited".to_string(),
+        };
+        let from_inherited = provenance.starts_with("inherited");
+        if !from_inherited && inherited.is_some() {
+            provenance.push_str(", replaces inherited");
+        }
+
+        let shown = if from_inherited && show_inherited {
+            value.clone()
+        } else {
+            key.hash(value)
+        };
+        rendered.push_str(&format!("{}={}  # {}\n", name, shown, provenance));
+    }
+    for secret in sources.files {
+        let name = secret.target.name();
+        let source = match sources.specs.get(&name) {
+            Some(spec) => spec.source(),
+            None => "--metadata-env".to_string(),
+        };
+        rendered.push_str(&format!(
+            "{}={}  # from {}, not written\n",
+            name,
+            key.hash(&secret.secret),
+            source
+        ));
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretTarget;
+
+    #[test]
+    fn pass_render_template() {
+        let secret = |target, value: &str| Secret::new(target, value.to_string());
+        let secrets = [
+            secret(
+                SecretTarget::Env {
+                    name: "DB_USER".to_string(),
+                },
+                "app",
+            ),
+            secret(
+                SecretTarget::File {
+                    path: "/run/db.pass".into(),
+                    mode: 0o600,
+                    create: false,
+                    owner: None,
+                    label: None,
+                },
+                "p@ss",
+            ),
+        ];
+
+        assert_eq!(
+            render_template(
+                "url: postgres://{{DB_USER}}:{{ file:/run/db.pass }}@db/app\n",
+                &secrets
+            )
+            .unwrap(),
+            "url: postgres://app:p@ss@db/app\n"
+        );
+        assert!(render_template("{{ DB_PASS }}", &secrets).is_err());
+        assert!(render_template("a\n{{ DB_USER", &secrets).is_err());
+
+        let exports = shell_exports(&[EnvSecret {
+            name: "DB_PASS".to_string(),
+            secret: "it's".to_string(),
+        }]);
+        assert_eq!(exports, "export DB_PASS='it'\\''s'\n");
+    }
+
+    #[test]
+    fn pass_debug_env() {
+        let var = |name: &str, value: &str| (name.to_string(), value.to_string());
+        let secret = |name: &str, value: &str| EnvSecret {
+            name: name.to_string(),
+            secret: value.to_string(),
+        };
+        let target = SecretTarget::Env {
+            name: "DB_PASS".to_string(),
+        };
+        let file = SecretTarget::File {
+            path: "/run/tls.key".into(),
+            mode: 0o600,
+            create: false,
+            owner: None,
+            label: None,
+        };
+        let specs = SecretSpecs::from([
+            (
+                target.name(),
+                crate::secrets::SecretSpec::vault(target, "secret", "db", "password"),
+            ),
+            (
+                file.name(),
+                crate::secrets::SecretSpec::vault(file.clone(), "secret", "tls", "key"),
+            ),
+        ]);
+        let sources = EnvSources {
+            inherited: &[var("HOME", "/root"), var("DB_PASS", "old")],
+            env_files: &[var("PORT", "8080")],
+            secrets: &[
+                secret("DB_PASS", "new"),
+                secret("VAULTIFY_SECRETS_HASH", "ab"),
+            ],
+            files: &[Secret::new(file, "pem".to_string())],
+            specs: &specs,
+        };
+        let env = [
+            var("PORT", "8080"),
+            var("HOME", "/root"),
+            var("DB_PASS", "new"),
+            var("VAULTIFY_SECRETS_HASH", "ab"),
+        ];
+
+        let key = HashKey::new(Some("key")).unwrap();
+        // printf %s new | openssl dgst -sha256 -hmac key
+        assert_eq!(key.hash("new"), "hmac:dc21a898e0fcbea5");
+        let hash = |value: &str| key.hash(value);
+        assert_eq!(
+            debug_env(&env, &sources, true, &key),
+            format!(
+                "DB_PASS={}  # injected from vault:secret/db#password, replaces inherited\n\
+                HOME=/root  # inherited\n\
+                PORT={}  # from --env-file\n\
+                VAULTIFY_SECRETS_HASH={}  # injected from --metadata-env\n\
+                file:/run/tls.key={}  # from vault:secret/tls#key, not written\n",
+                hash("new"),
+                hash("8080"),
+                hash("ab"),
+                hash("pem")
+            )
+        );
+
+        // the inherited value kept with --on-conflict keep-existing
+        let env = [var("DB_PASS", "old")];
+        let sources = EnvSources {
+            files: &[],
+            ..sources
+        };
+        assert_eq!(
+            debug_env(&env, &sources, false, &key),
+            format!(
+                "DB_PASS={}  # inherited, shadows vault:secret/db#password\n",
+                hash("old")
+            )
+        );
+
+        // a random key is printed, so the hashes can be compared
+        let key = HashKey::new(None).unwrap();
+        let rendered = debug_env(&env, &sources, false, &key);
+        let (header, line) = rendered.split_once('\n').unwrap();
+        let printed = header.strip_prefix("# hmac key of this run: ").unwrap();
+        assert_eq!(
+            line,
+            format!(
+                "DB_PASS={}  # inherited, shadows vault:secret/db#password\n",
+                HashKey::new(Some(printed)).unwrap().hash("old")
+            )
+        );
+        assert_ne!(HashKey::new(None).unwrap().key, key.key);
+    }
+}
@@ -1,14 +1,62 @@
-use std::ffi::{CString, OsStr};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::{ffi::OsStr, path::PathBuf, time::Duration};
+
+use crate::{
+    error::{Error, Result},
+    glob,
+};
+
+/// Environment variable holding the file descriptor with the secrets in `--secrets-fd` mode.
+pub const SECRETS_FD_ENV: &str = "VAULTIFY_SECRETS_FD";
+
+/// Returns the shell and arguments to run `script` with.
+pub fn shell_command(script: &str) -> (&'static str, Vec<String>) {
+    ("/bin/sh", vec!["-c".to_string(), script.to_string()])
+}
 
-use crate::error::{Error, Result};
+/// What happens if a secret passed in the environment is named like an inherited variable.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Refuse to spawn the child.
+    Error,
+    /// Keep the inherited value, the secret is not passed as the variable.
+    KeepExisting,
+    /// Replace the inherited value with the secret.
+    #[default]
+    Overwrite,
+}
+
+/// Process group of a child which runs attached.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum ProcessGroup {
+    /// Stay in the process group of vaultify, so signals from the terminal (Ctrl-C, Ctrl-Z) reach
+    /// both and job control of the shell covers the child.
+    #[default]
+    Inherit,
+    /// Start a process group of its own, which takes over the terminal if vaultify runs in its
+    /// foreground, so only the child receives signals from the terminal.
+    Own,
+}
+
+/// Serialization format of secrets passed to the child outside of its environment.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum SecretsFormat {
+    /// One `NAME="value"` line per secret.
+    Dotenv,
+    /// A single JSON object mapping names to values.
+    Json,
+}
 
 /// Environment variable passed to the spawned command.
+#[derive(Clone)]
 pub struct EnvSecret {
     pub name: String,
     pub secret: String,
 }
 
 /// Additional spawn options for the child process
+#[derive(Clone)]
 pub struct SpawnOptions {
     /// Clear the environment of the spawned process.
     ///
@@ -17,50 +65,210 @@ pub struct SpawnOptions {
     /// If this is set to false, all environment variables of the current process are inherited by
     /// the child process as well.
     pub clear_env: bool,
+    /// Glob patterns of inherited environment variables kept when `clear_env` is set.
+    pub keep_env: Vec<String>,
+    /// Glob patterns of inherited environment variables which are never passed to the child.
+    pub drop_env: Vec<String>,
+    /// Plain variables set over the inherited environment, which the secrets override.
+    pub env: Vec<(String, String)>,
+    /// What happens to inherited variables named like a secret.
+    pub on_conflict: OnConflict,
+    /// Working directory of the spawned process.
+    pub chdir: Option<PathBuf>,
+    /// File mode creation mask of the spawned process.
+    pub umask: Option<u32>,
+    /// Niceness increment of the spawned process.
+    pub nice: Option<i32>,
+    /// Pass the secrets via an inherited file descriptor instead of the environment.
+    ///
+    /// # Remarks:
+    ///
+    /// The number of the file descriptor is passed in `VAULTIFY_SECRETS_FD`, so the values do not
+    /// show up in `/proc/<pid>/environ`.
+    pub secrets_fd: Option<SecretsFormat>,
+    /// Pass the secrets via stdin instead of the environment.
+    pub stdin: Option<SecretsFormat>,
+    /// Resource limits of the spawned process.
+    #[cfg(unix)]
+    pub rlimits: Vec<crate::sandbox::Rlimit>,
+    /// OOM killer score adjustment of the spawned process (-1000 to 1000).
+    #[cfg(target_os = "linux")]
+    pub oom_score_adj: Option<i32>,
+    /// Capabilities dropped from the spawned process.
+    #[cfg(target_os = "linux")]
+    pub cap_drop: Vec<crate::sandbox::Capability>,
+    /// Process group of the spawned process.
+    #[cfg(unix)]
+    pub process_group: ProcessGroup,
+    /// Make the own process group of the spawned process the foreground group of the terminal.
+    #[cfg(unix)]
+    pub foreground: bool,
 }
 
-/// Replaces the current process image with the specified process.
-#[cfg(target_os = "linux")]
-pub fn spawn<S: AsRef<OsStr>>(
-    cmd: S,
-    args: &[String],
-    secrets: &[EnvSecret],
-    opts: SpawnOptions,
-) -> Result<()> {
-    ensure_single_threaded_process()?;
+/// Serializes the secrets in the given format.
+pub fn format_secrets(secrets: &[EnvSecret], format: SecretsFormat) -> String {
+    match format {
+        SecretsFormat::Dotenv => secrets
+            .iter()
+            .map(|secret| format!("{}=\"{}\"\n", secret.name, escape_dotenv(&secret.secret)))
+            .collect(),
+        SecretsFormat::Json => {
+            let map = secrets
+                .iter()
+                .map(|secret| {
+                    (
+                        secret.name.clone(),
+                        serde_json::Value::String(secret.secret.clone()),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::Value::Object(map).to_string()
+        }
+    }
+}
 
-    // convert cmd
-    let c_cmd = CString::new(cmd.as_ref().to_str().ok_or_else(|| {
-        Error::Conversion(format!(
-            "{:?} cannot be convert to a c-string",
-            cmd.as_ref()
-        ))
-    })?)?;
+fn escape_dotenv(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '$' => escaped.push_str("\\$"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
 
-    // convert args
-    let mut c_args = Vec::with_capacity(args.len() + 1);
-    c_args.push(c_cmd.clone());
-    for arg in args.iter() {
-        c_args.push(CString::new(arg.as_str())?);
+    escaped
+}
+
+/// Splits the secrets into the ones passed via the environment and the ones passed via a file
+/// descriptor (`secrets_fd`) or stdin (`stdin`).
+///
+/// Returns the memfd holding the secrets for `secrets_fd`, which has to be kept open until the
+/// child is spawned, and the variables to add to the environment of the child.
+fn deliver_secrets(
+    secrets: &[EnvSecret],
+    opts: &SpawnOptions,
+) -> Result<(Option<std::fs::File>, Vec<EnvSecret>)> {
+    if opts.secrets_fd.is_none() && opts.stdin.is_none() {
+        return Ok((None, secrets.to_vec()));
     }
 
-    // generate env
-    let mut c_env = if !opts.clear_env {
-        // copy over current env to c_env
+    let Some(format) = opts.secrets_fd else {
+        return Ok((None, Vec::new()));
+    };
+
+    let (file, env) = secrets_fd(secrets, format)?;
+    Ok((Some(file), vec![env]))
+}
+
+/// Returns the memfd holding the secrets and the variable naming its file descriptor.
+#[cfg(target_os = "linux")]
+fn secrets_fd(secrets: &[EnvSecret], format: SecretsFormat) -> Result<(std::fs::File, EnvSecret)> {
+    let file = secrets_memfd(secrets, format)?;
+    let env = EnvSecret {
+        name: SECRETS_FD_ENV.to_string(),
+        secret: std::os::fd::AsRawFd::as_raw_fd(&file).to_string(),
+    };
+    Ok((file, env))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn secrets_fd(
+    _secrets: &[EnvSecret],
+    _format: SecretsFormat,
+) -> Result<(std::fs::File, EnvSecret)> {
+    Err(Error::Execution(
+        "passing secrets via file descriptor is only supported on linux".to_string(),
+    ))
+}
+
+/// Writes the secrets into a sealed memfd, which is closed on exec unless passed to `inherit_fd`.
+#[cfg(target_os = "linux")]
+fn secrets_memfd(secrets: &[EnvSecret], format: SecretsFormat) -> Result<std::fs::File> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, SealFlag},
+        sys::memfd::{memfd_create, MemFdCreateFlag},
+    };
+    use std::{
+        io::{Seek, Write},
+        os::fd::AsRawFd,
+    };
+
+    let fd = memfd_create(
+        c"vaultify-secrets",
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )
+    .map_err(|err| Error::Execution(format!("unable to create memfd: {}", err)))?;
+    let mut file = std::fs::File::from(fd);
+    file.write_all(format_secrets(secrets, format).as_bytes())
+        .and_then(|_| file.rewind())
+        .map_err(|err| Error::io("unable to write secrets to memfd", err))?;
+    fcntl(
+        file.as_raw_fd(),
+        FcntlArg::F_ADD_SEALS(
+            SealFlag::F_SEAL_SHRINK
+                | SealFlag::F_SEAL_GROW
+                | SealFlag::F_SEAL_WRITE
+                | SealFlag::F_SEAL_SEAL,
+        ),
+    )
+    .map_err(|err| Error::Execution(format!("unable to seal memfd: {}", err)))?;
+
+    Ok(file)
+}
+
+/// Clears `FD_CLOEXEC` of `file`, so that the command executed next inherits it.
+#[cfg(unix)]
+fn inherit_fd(file: &std::fs::File) -> nix::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+    fcntl(
+        std::os::fd::AsRawFd::as_raw_fd(file),
+        FcntlArg::F_SETFD(FdFlag::empty()),
+    )
+    .map(drop)
+}
+
+/// How an attached child process is asked to stop.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+pub struct StopOptions {
+    /// Signal sent to the child.
+    ///
+    /// # Remarks:
+    ///
+    /// If this is not set, the termination signal received by vaultify is forwarded as is and
+    /// `SIGTERM` is used when vaultify itself decides to stop the child.
+    pub signal: Option<nix::sys::signal::Signal>,
+    /// Time to wait for the child to exit before it is killed with `SIGKILL`.
+    pub timeout: Duration,
+}
+
+/// Builds the environment of the child process from the current environment and the secrets.
+pub fn child_env(secrets: &[EnvSecret], opts: &SpawnOptions) -> Vec<(String, String)> {
+    let mut env = if !opts.clear_env || !opts.keep_env.is_empty() {
+        // copy over current env
         let mut r = Vec::with_capacity(secrets.len());
         for (key, value) in std::env::vars_os() {
             if let Some(key) = key.to_str() {
+                if !inherits_env(key, opts) {
+                    continue;
+                }
+
                 if let Some(value) = value.to_str() {
-                    r.push(CString::new(format!("{}={}", key, value))?);
+                    r.push((key.to_string(), value.to_string()));
                 } else {
-                    log::warn!(
+                    tracing::warn!(
                         "invalid unicode in environment variable {}={:?}",
                         key,
                         value
                     );
                 }
             } else {
-                log::warn!(
+                tracing::warn!(
                     "invalid unicode in environment variable {:?}={:?}",
                     key,
                     value
@@ -73,37 +281,590 @@ pub fn spawn<S: AsRef<OsStr>>(
         Vec::with_capacity(secrets.len())
     };
 
-    // add secrets to env
+    let inherited = match opts.on_conflict {
+        OnConflict::KeepExisting => env.iter().map(|(key, _)| key.clone()).collect(),
+        OnConflict::Error | OnConflict::Overwrite => Vec::new(),
+    };
+
+    // add plain variables and secrets to env
+    for (key, value) in &opts.env {
+        env.retain(|(other, _)| other != key);
+        env.push((key.clone(), value.clone()));
+    }
     for secret in secrets.iter() {
-        let key_prefix = format!("{}=", secret.name);
-        let c_var = CString::new(format!("{}={}", &secret.name, &secret.secret))?;
-        let prev_len = c_env.len();
-        c_env.retain(|e| !e.as_bytes().starts_with(key_prefix.as_bytes()));
-        if c_env.len() != prev_len {
-            log::warn!(
-                "env variable `{}` already exists and will be overwritten",
-                secret.name
-            );
+        // inherited variables of the same name are reported by `env_conflicts`
+        if inherited.contains(&secret.name) {
+            continue;
         }
-        c_env.push(c_var);
+        env.retain(|(key, _)| key != &secret.name);
+        env.push((secret.name.clone(), secret.secret.clone()));
         assert_eq!(
-            c_env
-                .iter()
-                .filter(|e| e.as_bytes().starts_with(key_prefix.as_bytes()))
-                .count(),
+            env.iter().filter(|(key, _)| key == &secret.name).count(),
             1,
             "secret env merge must leave exactly one value per key"
         );
     }
 
-    nix::unistd::execvpe(&c_cmd, &c_args, &c_env)
-        .map_err(|err| Error::Execution(err.to_string()))?;
+    env
+}
+
+/// Secret values shorter than this are not reported as exposed, as values like `true` or `8080`
+/// would match unrelated variables.
+const MIN_EXPOSED_LEN: usize = 4;
+
+/// An inherited or `--env-file` environment variable conflicting with a fetched secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvConflict {
+    /// The variable already holds the value of the secret in plaintext.
+    Exposed { var: String, secret: String },
+    /// The variable is passed to the child but replaced by the secret of the same name.
+    Shadowed { var: String },
+}
+
+impl std::fmt::Display for EnvConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvConflict::Exposed { var, secret } => write!(
+                f,
+                "env variable `{}` already holds the value of secret `{}` in plaintext",
+                var, secret
+            ),
+            EnvConflict::Shadowed { var } => write!(
+                f,
+                "env variable `{}` is shadowed by the secret of the same name",
+                var
+            ),
+        }
+    }
+}
+
+/// Compares the inherited environment `env` and the `--env-file` variables of `opts` with the
+/// fetched secrets, given as name and value.
+///
+/// Env secrets are passed as their variable name, so variables of the same name are reported as
+/// shadowed if the child would inherit them, or gets them from an `--env-file`, and the secrets are
+/// added to its environment. Inherited variables overridden by an `--env-file` are not passed.
+pub fn env_conflicts(
+    env: &[(String, String)],
+    secrets: &[(String, &str)],
+    opts: &SpawnOptions,
+) -> Vec<EnvConflict> {
+    let secrets_in_env = opts.secrets_fd.is_none() && opts.stdin.is_none();
+    let from_env_file = |key: &str| opts.env.iter().any(|(name, _)| name == key);
+    let mut conflicts = Vec::new();
+    let inherited = env.iter().filter(|(key, _)| !from_env_file(key));
+    for (key, value) in inherited.chain(&opts.env) {
+        let exposed = secrets
+            .iter()
+            .find(|(_, secret)| secret.len() >= MIN_EXPOSED_LEN && secret == value);
+        if let Some((name, _)) = exposed {
+            conflicts.push(EnvConflict::Exposed {
+                var: key.clone(),
+                secret: name.clone(),
+            });
+        } else if secrets_in_env
+            && (from_env_file(key) || inherits_env(key, opts))
+            && secrets.iter().any(|(name, _)| name == key)
+        {
+            conflicts.push(EnvConflict::Shadowed { var: key.clone() });
+        }
+    }
+
+    conflicts
+}
+
+/// Maximum length of a single `KEY=value` environment string, including the terminating nul.
+#[cfg(target_os = "linux")]
+const MAX_ENV_VAR_LEN: Option<usize> = Some(32 * 4096); // MAX_ARG_STRLEN
+#[cfg(not(target_os = "linux"))]
+const MAX_ENV_VAR_LEN: Option<usize> = None;
+
+/// Checks that the arguments and environment fit within the limits of the OS, so oversized secrets
+/// fail with a clear error instead of `E2BIG` from `execve`.
+fn check_exec_limits(
+    cmd: &OsStr,
+    args: &[String],
+    env: &[(String, String)],
+    secrets: &[EnvSecret],
+) -> Result<()> {
+    // SAFETY: `sysconf` has no memory safety requirements.
+    let arg_max = usize::try_from(unsafe { nix::libc::sysconf(nix::libc::_SC_ARG_MAX) }).ok();
+
+    check_exec_size(
+        cmd.len(),
+        args,
+        env,
+        secrets,
+        ExecLimits {
+            max_var_len: MAX_ENV_VAR_LEN,
+            arg_max,
+        },
+    )
+}
+
+#[derive(Clone, Copy)]
+struct ExecLimits {
+    /// Maximum length of a single environment string.
+    max_var_len: Option<usize>,
+    /// Maximum total size of arguments and environment, including the pointers to them.
+    arg_max: Option<usize>,
+}
+
+fn check_exec_size(
+    cmd_len: usize,
+    args: &[String],
+    env: &[(String, String)],
+    secrets: &[EnvSecret],
+    limits: ExecLimits,
+) -> Result<()> {
+    const POINTER_SIZE: usize = std::mem::size_of::<usize>();
+
+    let mut total = cmd_len + 1 + POINTER_SIZE;
+    for arg in args {
+        total += arg.len() + 1 + POINTER_SIZE;
+    }
+    for (key, value) in env {
+        let len = key.len() + value.len() + 2;
+        total += len + POINTER_SIZE;
+
+        let Some(max_var_len) = limits.max_var_len else {
+            continue;
+        };
+        if len > max_var_len {
+            let hint = if secrets.iter().any(|secret| &secret.name == key) {
+                "; write the secret to a file instead, e.g. `... | file /dev/shm/secret`"
+            } else {
+                ""
+            };
+            return Err(Error::Execution(format!(
+                "env variable `{}` is {} bytes, exceeding the limit of {} bytes for a single variable{}",
+                key, len, max_var_len, hint
+            )));
+        }
+    }
+
+    match limits.arg_max {
+        Some(arg_max) if total > arg_max => Err(Error::Execution(format!(
+            "arguments and environment of the command are {} bytes, exceeding the limit of {} bytes (ARG_MAX); write large secrets to files instead, e.g. `... | file /dev/shm/secret`",
+            total, arg_max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the inherited environment variable `key` is passed to the child.
+fn inherits_env(key: &str, opts: &SpawnOptions) -> bool {
+    if glob::matches_any(&opts.drop_env, key) {
+        return false;
+    }
+
+    !opts.clear_env || glob::matches_any(&opts.keep_env, key)
+}
+
+/// Replaces the current process image with the specified process.
+#[cfg(target_os = "linux")]
+pub fn spawn<S: AsRef<OsStr>>(
+    cmd: S,
+    args: &[String],
+    secrets: &[EnvSecret],
+    opts: SpawnOptions,
+) -> Result<()> {
+    ensure_single_threaded_process("call execvpe")?;
+    let span = tracing::info_span!("exec", cmd = ?cmd.as_ref());
+    let entered = span.enter();
+
+    // convert cmd
+    let c_cmd = CString::new(cmd.as_ref().to_str().ok_or_else(|| {
+        Error::Conversion(format!(
+            "{:?} cannot be convert to a c-string",
+            cmd.as_ref()
+        ))
+    })?)?;
+
+    // convert args
+    let mut c_args = Vec::with_capacity(args.len() + 1);
+    c_args.push(c_cmd.clone());
+    for arg in args.iter() {
+        c_args.push(CString::new(arg.as_str())?);
+    }
+
+    // generate env
+    let (secrets_file, env_secrets) = deliver_secrets(secrets, &opts)?;
+    if let Some(file) = &secrets_file {
+        inherit_fd(file).map_err(|err| {
+            Error::Execution(format!(
+                "unable to pass the secrets file descriptor: {}",
+                err
+            ))
+        })?;
+    }
+    let env = child_env(&env_secrets, &opts);
+    check_exec_limits(cmd.as_ref(), args, &env, &env_secrets)?;
+    let mut c_env = Vec::new();
+    for (key, value) in env {
+        c_env.push(CString::new(format!("{}={}", key, value))?);
+    }
+
+    if let Some(format) = opts.stdin {
+        use std::os::fd::AsRawFd;
+
+        let stdin = secrets_memfd(secrets, format)?;
+        nix::unistd::dup2(stdin.as_raw_fd(), nix::libc::STDIN_FILENO).map_err(|err| {
+            Error::Execution(format!("unable to redirect stdin to secrets: {}", err))
+        })?;
+    }
+
+    if let Some(dir) = &opts.chdir {
+        std::env::set_current_dir(dir).map_err(|err| {
+            Error::Execution(format!(
+                "unable to change directory to {}: {}",
+                dir.display(),
+                err
+            ))
+        })?;
+    }
+    apply_process_attributes(&opts)
+        .map_err(|err| Error::Execution(format!("unable to set process attributes: {}", err)))?;
+
+    // closed before the process image is replaced, as it would never finish otherwise
+    drop(entered);
+    drop(span);
+    nix::unistd::execvpe(&c_cmd, &c_args, &c_env).map_err(|err| Error::Spawn {
+        command: format!("{:?}", cmd.as_ref()),
+        source: std::io::Error::from(err).into(),
+    })?;
+
+    Ok(())
+}
+
+/// Runs the specified process as a child of the current process and exits with its exit code.
+///
+/// # Remarks:
+///
+/// Used on platforms without `execvpe`. Ctrl-C is delivered to all processes attached to the
+/// console (or the foreground process group), so the current process only ignores it and keeps
+/// waiting for the child to exit.
+#[cfg(not(target_os = "linux"))]
+pub fn spawn<S: AsRef<OsStr>>(
+    cmd: S,
+    args: &[String],
+    secrets: &[EnvSecret],
+    opts: SpawnOptions,
+) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| Error::Execution(format!("unable to initialize tokio runtime: {}", err)))?;
+
+    let status = runtime.block_on(async {
+        let mut child = spawn_attached(cmd.as_ref(), args, secrets, &opts)?;
+
+        loop {
+            tokio::select! {
+                status = child.wait() => {
+                    #[cfg(unix)]
+                    reclaim_terminal(&opts);
+                    return status.map_err(|err| {
+                        Error::Execution(format!("unable to wait for child: {}", err))
+                    });
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("received ctrl-c, waiting for child to exit");
+                }
+            }
+        }
+    })?;
+    drop(runtime);
+
+    std::process::exit(exit_code(status));
+}
+
+/// Builds a command running with the environment and process attributes of `opts`.
+pub fn command<S: AsRef<OsStr>>(
+    cmd: S,
+    args: &[String],
+    secrets: &[EnvSecret],
+    opts: &SpawnOptions,
+) -> Result<tokio::process::Command> {
+    let (secrets_file, secrets) = deliver_secrets(secrets, opts)?;
+
+    let env = child_env(&secrets, opts);
+    check_exec_limits(cmd.as_ref(), args, &env, &secrets)?;
+
+    let mut command = tokio::process::Command::new(cmd.as_ref());
+    command.args(args).env_clear().envs(env);
+    if let Some(dir) = &opts.chdir {
+        command.current_dir(dir);
+    }
+    if opts.stdin.is_some() {
+        command.stdin(std::process::Stdio::piped());
+    }
+
+    if opts.process_group == ProcessGroup::Own {
+        command.process_group(0);
+    }
+    let pre_exec_opts = opts.clone();
+    // SAFETY: `inherit_fd` and `apply_process_attributes` only perform async-signal-safe syscalls.
+    unsafe {
+        command.pre_exec(move || {
+            // only this child inherits the secrets file, which stays open until the command is
+            // dropped
+            if let Some(file) = &secrets_file {
+                inherit_fd(file)?;
+            }
+            apply_process_attributes(&pre_exec_opts)
+        });
+    }
+
+    Ok(command)
+}
+
+/// Spawns a command built by `command` and writes the secrets to its stdin if requested.
+pub fn spawn_command(
+    command: &mut tokio::process::Command,
+    secrets: &[EnvSecret],
+    opts: &SpawnOptions,
+) -> std::io::Result<tokio::process::Child> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = command.spawn()?;
+    if let (Some(format), Some(mut stdin)) = (opts.stdin, child.stdin.take()) {
+        let contents = format_secrets(secrets, format);
+        tokio::spawn(async move {
+            if let Err(err) = stdin.write_all(contents.as_bytes()).await {
+                tracing::warn!("unable to write secrets to stdin of child: {}", err);
+            }
+        });
+    }
+
+    Ok(child)
+}
+
+/// Spawns the specified process as a child of the current process.
+pub fn spawn_attached<S: AsRef<OsStr>>(
+    cmd: S,
+    args: &[String],
+    secrets: &[EnvSecret],
+    opts: &SpawnOptions,
+) -> Result<tokio::process::Child> {
+    let span = tracing::info_span!("exec", cmd = ?cmd.as_ref(), pid = tracing::field::Empty);
+    let _entered = span.enter();
+    let child = spawn_command(
+        &mut command(cmd.as_ref(), args, secrets, opts)?,
+        secrets,
+        opts,
+    )
+    .map_err(|err| Error::Spawn {
+        command: format!("{:?}", cmd.as_ref()),
+        source: err.into(),
+    })?;
+    if let Some(pid) = child.id() {
+        span.record("pid", pid);
+    }
+
+    Ok(child)
+}
+
+/// Applies umask, niceness, resource limits and capabilities to the current process.
+#[cfg(unix)]
+fn apply_process_attributes(opts: &SpawnOptions) -> std::io::Result<()> {
+    // the process group was already set up by `Command::process_group`
+    if opts.foreground {
+        set_foreground(nix::unistd::getpgrp())?;
+    }
+
+    if let Some(umask) = opts.umask {
+        nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(umask as _));
+    }
+
+    if let Some(nice) = opts.nice {
+        // `nice` may legitimately return -1, so errors have to be detected via errno
+        nix::errno::Errno::clear();
+        // SAFETY: `nice` has no memory safety requirements.
+        let res = unsafe { nix::libc::nice(nice) };
+        if res == -1 && nix::errno::Errno::last_raw() != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    crate::sandbox::apply_rlimits(&opts.rlimits)?;
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(value) = opts.oom_score_adj {
+            crate::sandbox::apply_oom_score_adj(value)?;
+        }
+        // dropped last, as the steps above may require capabilities like CAP_SYS_RESOURCE
+        crate::sandbox::drop_capabilities(&opts.cap_drop)?;
+    }
 
     Ok(())
 }
 
+/// Stdin, which is not closed by vaultify, without touching the buffer of `std::io::stdin`.
+#[cfg(unix)]
+fn stdin_fd() -> std::os::fd::BorrowedFd<'static> {
+    // SAFETY: file descriptor 0 is never closed, calls on it fail if it was not open to begin with.
+    unsafe { std::os::fd::BorrowedFd::borrow_raw(nix::libc::STDIN_FILENO) }
+}
+
+/// Whether stdin is a terminal whose foreground process group is the one of vaultify.
+#[cfg(unix)]
+pub fn owns_terminal() -> bool {
+    nix::unistd::tcgetpgrp(stdin_fd()).is_ok_and(|pgrp| pgrp == nix::unistd::getpgrp())
+}
+
+/// Makes `pgrp` the foreground process group of the terminal on stdin.
+///
+/// # Remarks:
+///
+/// `SIGTTOU` is blocked meanwhile, as it would stop a background process changing it. Only
+/// async-signal-safe syscalls are performed, so this can run between fork and exec.
+#[cfg(unix)]
+fn set_foreground(pgrp: nix::unistd::Pid) -> nix::Result<()> {
+    use nix::sys::signal::{pthread_sigmask, SigSet, SigmaskHow, Signal};
+
+    let mut ttou = SigSet::empty();
+    ttou.add(Signal::SIGTTOU);
+    let mut mask = SigSet::empty();
+    pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&ttou), Some(&mut mask))?;
+    let res = nix::unistd::tcsetpgrp(stdin_fd(), pgrp);
+    pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&mask), None)?;
+    res
+}
+
+/// Takes the terminal back from a child which was spawned into the foreground and exited.
+#[cfg(unix)]
+pub fn reclaim_terminal(opts: &SpawnOptions) {
+    if opts.foreground {
+        if let Err(err) = set_foreground(nix::unistd::getpgrp()) {
+            tracing::warn!("unable to take back the terminal from the child: {}", err);
+        }
+    }
+}
+
+/// Whether the child is stopped, e.g. by Ctrl-Z in the terminal it took over.
+///
+/// # Remarks:
+///
+/// Only stops are waited for, so the exit of the child is still reported to `Child::wait`.
+#[cfg(unix)]
+pub fn child_stopped(child: &tokio::process::Child) -> bool {
+    use nix::sys::wait::{waitid, Id, WaitPidFlag, WaitStatus};
+
+    let Some(pid) = child.id() else {
+        return false;
+    };
+    let flags = WaitPidFlag::WSTOPPED | WaitPidFlag::WNOHANG;
+    matches!(
+        waitid(Id::Pid(nix::unistd::Pid::from_raw(pid as i32)), flags),
+        Ok(WaitStatus::Stopped(..))
+    )
+}
+
+/// Suspends vaultify along with its stopped child, which runs in a process group of its own in
+/// the foreground of the terminal, so the shell regains control as for any other job.
+///
+/// # Remarks:
+///
+/// Blocks until vaultify is continued, then gives the terminal back to the child if vaultify is
+/// in the foreground again (`fg`, not `bg`) and continues the child.
+#[cfg(unix)]
+pub fn suspend_with_child(child: &tokio::process::Child, opts: &SpawnOptions) -> Result<()> {
+    use nix::sys::signal::{killpg, raise, Signal};
+
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+    let pgrp = nix::unistd::Pid::from_raw(pid as i32);
+    reclaim_terminal(opts);
+    raise(Signal::SIGTSTP)
+        .map_err(|err| Error::Execution(format!("unable to suspend vaultify: {}", err)))?;
+
+    if owns_terminal() {
+        if let Err(err) = set_foreground(pgrp) {
+            tracing::warn!("unable to give the terminal back to the child: {}", err);
+        }
+    }
+    killpg(pgrp, Signal::SIGCONT)
+        .map_err(|err| Error::Execution(format!("unable to continue child {}: {}", pid, err)))
+}
+
+/// Sends `signal` to the child process if it is still running.
+#[cfg(unix)]
+pub fn signal_child(child: &tokio::process::Child, signal: nix::sys::signal::Signal) -> Result<()> {
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal).map_err(|err| {
+        Error::Execution(format!(
+            "unable to send {} to child {}: {}",
+            signal, pid, err
+        ))
+    })
+}
+
+/// Sends `signal` to the child and kills it if it did not exit within `timeout`.
+#[cfg(unix)]
+pub async fn stop_child(
+    child: &mut tokio::process::Child,
+    signal: nix::sys::signal::Signal,
+    timeout: Duration,
+) -> Result<std::process::ExitStatus> {
+    signal_child(child, signal)?;
+
+    match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => {
+            status.map_err(|err| Error::Execution(format!("unable to wait for child: {}", err)))
+        }
+        Err(_) => {
+            tracing::warn!(
+                "child did not exit within {:?} after {}, sending SIGKILL",
+                timeout,
+                signal
+            );
+            child
+                .kill()
+                .await
+                .map_err(|err| Error::Execution(format!("unable to kill child: {}", err)))?;
+            child
+                .wait()
+                .await
+                .map_err(|err| Error::Execution(format!("unable to wait for child: {}", err)))
+        }
+    }
+}
+
+/// Parses a signal name such as `SIGHUP` or `HUP`.
+#[cfg(unix)]
+pub fn parse_signal(raw: &str) -> Result<nix::sys::signal::Signal> {
+    let name = raw.trim().to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+
+    name.parse::<nix::sys::signal::Signal>()
+        .map_err(|_| Error::Conversion(format!("unknown signal `{}`", raw)))
+}
+
+/// Maps the exit status of a child process to an exit code of the current process.
+pub fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    status.code().unwrap_or(1)
+}
+
 #[cfg(target_os = "linux")]
-fn ensure_single_threaded_process() -> Result<()> {
+pub fn ensure_single_threaded_process(action: &str) -> Result<()> {
     let thread_count = std::fs::read_dir("/proc/self/task")
         .map_err(|err| Error::Execution(format!("unable to read /proc/self/task: {}", err)))?
         .try_fold(0usize, |count, entry| entry.map(|_| count + 1))
@@ -116,10 +877,305 @@ fn ensure_single_threaded_process() -> Result<()> {
 
     if thread_count != 1 {
         return Err(Error::Execution(format!(
-            "refusing to call execvpe with {} running threads; expected exactly 1",
-            thread_count
+            "refusing to {} with {} running threads; expected exactly 1",
+            action, thread_count
         )));
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> Vec<EnvSecret> {
+        vec![
+            EnvSecret {
+                name: "API_KEY".to_string(),
+                secret: "plain".to_string(),
+            },
+            EnvSecret {
+                name: "PASSWORD".to_string(),
+                secret: "a\"b\\c$d\ne".to_string(),
+            },
+        ]
+    }
+
+    /// Options of a child with a cleared environment, which gets the secrets as variables.
+    fn options() -> SpawnOptions {
+        SpawnOptions {
+            clear_env: true,
+            keep_env: Vec::new(),
+            drop_env: Vec::new(),
+            env: Vec::new(),
+            on_conflict: OnConflict::Overwrite,
+            chdir: None,
+            umask: None,
+            nice: None,
+            secrets_fd: None,
+            stdin: None,
+            rlimits: Vec::new(),
+            #[cfg(target_os = "linux")]
+            oom_score_adj: None,
+            #[cfg(target_os = "linux")]
+            cap_drop: Vec::new(),
+            process_group: ProcessGroup::Inherit,
+            foreground: false,
+        }
+    }
+
+    #[test]
+    fn pass_format_dotenv() {
+        assert_eq!(
+            format_secrets(&secrets(), SecretsFormat::Dotenv),
+            "API_KEY=\"plain\"\nPASSWORD=\"a\\\"b\\\\c\\$d\\ne\"\n"
+        );
+    }
+
+    #[test]
+    fn pass_env_conflicts() {
+        let env = [
+            ("LEAKED".to_string(), "s3cr3t".to_string()),
+            ("API_KEY".to_string(), "old".to_string()),
+            ("PORT".to_string(), "80".to_string()),
+        ]
+        .to_vec();
+        let secrets = [
+            ("API_KEY".to_string(), "s3cr3t"),
+            ("file:/tmp/port".to_string(), "80"),
+        ];
+        let opts = SpawnOptions {
+            clear_env: false,
+            ..options()
+        };
+        assert_eq!(
+            env_conflicts(&env, &secrets, &opts),
+            [
+                EnvConflict::Exposed {
+                    var: "LEAKED".to_string(),
+                    secret: "API_KEY".to_string()
+                },
+                EnvConflict::Shadowed {
+                    var: "API_KEY".to_string()
+                },
+            ]
+        );
+
+        // not inherited, so not shadowed
+        let opts = SpawnOptions {
+            clear_env: true,
+            ..opts
+        };
+        assert_eq!(env_conflicts(&env, &secrets, &opts).len(), 1);
+
+        // --env-file variables are passed regardless of --clear-env and override inherited ones
+        let opts = SpawnOptions {
+            env: vec![
+                ("API_KEY".to_string(), "from-file".to_string()),
+                ("TOKEN".to_string(), "s3cr3t".to_string()),
+                ("LEAKED".to_string(), "fine".to_string()),
+            ],
+            ..opts
+        };
+        assert_eq!(
+            env_conflicts(&env, &secrets, &opts),
+            [
+                EnvConflict::Shadowed {
+                    var: "API_KEY".to_string()
+                },
+                EnvConflict::Exposed {
+                    var: "TOKEN".to_string(),
+                    secret: "API_KEY".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pass_child_env() {
+        let opts = SpawnOptions {
+            env: vec![
+                ("API_KEY".to_string(), "from env file".to_string()),
+                ("PORT".to_string(), "8080".to_string()),
+            ],
+            ..options()
+        };
+        let env = child_env(&secrets()[..1], &opts);
+        assert_eq!(
+            env,
+            [
+                ("PORT".to_string(), "8080".to_string()),
+                ("API_KEY".to_string(), "plain".to_string()),
+            ]
+        );
+
+        // inherited variables are kept with keep-existing, plain variables are still replaced
+        let opts = SpawnOptions {
+            keep_env: vec!["PATH".to_string()],
+            on_conflict: OnConflict::KeepExisting,
+            ..opts
+        };
+        let path = EnvSecret {
+            name: "PATH".to_string(),
+            secret: "/secret/bin".to_string(),
+        };
+        let env = child_env(&[secrets().remove(0), path], &opts);
+        assert_eq!(
+            env,
+            [
+                ("PATH".to_string(), std::env::var("PATH").unwrap()),
+                ("PORT".to_string(), "8080".to_string()),
+                ("API_KEY".to_string(), "plain".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fail_check_exec_size() {
+        let secrets = secrets();
+        let env = secrets
+            .iter()
+            .map(|secret| (secret.name.clone(), secret.secret.clone()))
+            .collect::<Vec<_>>();
+        let limits = ExecLimits {
+            max_var_len: Some(32),
+            arg_max: Some(4096),
+        };
+        assert!(check_exec_size(4, &[], &env, &secrets, limits).is_ok());
+
+        let large = vec![("API_KEY".to_string(), "x".repeat(32))];
+        let err = check_exec_size(4, &[], &large, &secrets, limits).unwrap_err();
+        assert!(
+            err.to_string().contains("write the secret to a file"),
+            "{err}"
+        );
+
+        let many = vec![("VAR".to_string(), "x".repeat(16)); 256];
+        assert!(check_exec_size(4, &[], &many, &secrets, limits).is_err());
+    }
+
+    #[test]
+    fn pass_format_json() {
+        let json = format_secrets(&secrets(), SecretsFormat::Json);
+        let value = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(value["API_KEY"], "plain");
+        assert_eq!(value["PASSWORD"], "a\"b\\c$d\ne");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn pass_secrets_fd() {
+        let opts = SpawnOptions {
+            secrets_fd: Some(SecretsFormat::Dotenv),
+            ..options()
+        };
+        let args = [
+            "-c".to_string(),
+            format!("cat /proc/self/fd/${}", SECRETS_FD_ENV),
+        ];
+        let output = command("/bin/sh", &args, &secrets(), &opts)
+            .unwrap()
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format_secrets(&secrets(), SecretsFormat::Dotenv)
+        );
+
+        // other children do not inherit the memfd
+        let (_file, env) = secrets_fd(&secrets(), SecretsFormat::Dotenv).unwrap();
+        let inherited = tokio::process::Command::new("/bin/sh")
+            .args(["-c", &format!("test -e /proc/self/fd/{}", env.secret)])
+            .status()
+            .await
+            .unwrap();
+        assert!(!inherited.success());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pass_stdin_format() {
+        let opts = SpawnOptions {
+            stdin: Some(SecretsFormat::Json),
+            ..options()
+        };
+        let args = ["-c".to_string(), "cat; echo; env".to_string()];
+        let mut command = command("/bin/sh", &args, &secrets(), &opts).unwrap();
+        command.stdout(std::process::Stdio::piped());
+        let output = spawn_command(&mut command, &secrets(), &opts)
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let (json, env) = stdout.split_once('\n').unwrap();
+        assert_eq!(json, format_secrets(&secrets(), SecretsFormat::Json));
+        // the secrets are not passed in the environment as well
+        assert!(!env.contains("API_KEY"), "{}", env);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pass_shell_command() {
+        let opts = options();
+        // the shell expands the secrets passed in its environment
+        let (cmd, args) = shell_command("echo \"$API_KEY\" | tr a-z A-Z && exit 3");
+        let output = command(cmd, &args, &secrets(), &opts)
+            .unwrap()
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "PLAIN\n");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pass_spawn_own_process_group() {
+        let opts = SpawnOptions {
+            // keeps the child running until its stdin is closed
+            stdin: Some(SecretsFormat::Dotenv),
+            process_group: ProcessGroup::Own,
+            ..options()
+        };
+        let args = ["-c".to_string(), "cat >/dev/null".to_string()];
+        let mut child = spawn_attached("/bin/sh", &args, &[], &opts).unwrap();
+        let pid = nix::unistd::Pid::from_raw(child.id().unwrap() as i32);
+        assert_eq!(nix::unistd::getpgid(Some(pid)).unwrap(), pid);
+        assert_ne!(pid, nix::unistd::getpgrp());
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pass_child_stopped() {
+        use nix::sys::signal::{kill, Signal};
+
+        let mut child = tokio::process::Command::new("/bin/sh")
+            .args(["-c", "sleep 10"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = nix::unistd::Pid::from_raw(child.id().unwrap() as i32);
+        assert!(!child_stopped(&child));
+
+        kill(pid, Signal::SIGSTOP).unwrap();
+        let mut stopped = false;
+        for _ in 0..100 {
+            stopped = child_stopped(&child);
+            if stopped {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(stopped);
+
+        // the exit of a stopped child is still reported to `wait`
+        kill(pid, Signal::SIGKILL).unwrap();
+        let status = child.wait().await.unwrap();
+        assert!(!status.success());
+        assert!(!child_stopped(&child));
+    }
+}
@@ -0,0 +1,222 @@
+//! Procfile parser and multi-process runner.
+use std::{
+    io::Write,
+    path::Path,
+    process::{ExitStatus, Stdio},
+};
+
+use nix::sys::signal::Signal;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Child,
+};
+
+use crate::{
+    error::{Error, Result},
+    process::{self, EnvSecret, SpawnOptions, StopOptions},
+    ready::Readiness,
+    supervise::{is_termination, ForwardedSignals},
+};
+
+/// A single process parsed from a Procfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessSpec {
+    /// Name used to prefix the output of the process.
+    pub name: String,
+    /// Command line run via `/bin/sh -c`.
+    pub command: String,
+}
+
+/// Loads the Procfile and parses it
+pub async fn load_async<P: AsRef<Path>>(path: P) -> Result<Vec<ProcessSpec>> {
+    let contents = tokio::fs::read_to_string(path.as_ref())
+        .await
+        .map_err(|err| Error::io_at("unable to read file", path.as_ref(), err))?;
+    parse(&contents).map_err(|err| err.in_file(path.as_ref()))
+}
+
+fn parse(contents: &str) -> Result<Vec<ProcessSpec>> {
+    let mut specs: Vec<ProcessSpec> = Vec::new();
+
+    for (lc, raw_line) in contents.lines().enumerate() {
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, command) = line
+            .split_once(':')
+            .ok_or_else(|| Error::parse("line must be in format `name: command`", lc, line))?;
+        let name = name.trim();
+        let command = command.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error::parse("invalid process name", lc, line));
+        }
+        if command.is_empty() {
+            return Err(Error::parse("command cannot be empty", lc, line));
+        }
+        if specs.iter().any(|spec| spec.name == name) {
+            return Err(Error::parse("duplicate process name", lc, line));
+        }
+
+        specs.push(ProcessSpec {
+            name: name.to_string(),
+            command: command.to_string(),
+        });
+    }
+
+    if specs.is_empty() {
+        return Err(Error::NotFound(
+            "procfile does not contain any processes".to_string(),
+        ));
+    }
+
+    Ok(specs)
+}
+
+/// Runs all processes until the first one exits, then stops the others.
+///
+/// Returns the exit code of the process which exited first.
+pub async fn run(
+    specs: &[ProcessSpec],
+    env_secrets: &[EnvSecret],
+    opts: &SpawnOptions,
+    stop_opts: StopOptions,
+    readiness: &Readiness,
+) -> Result<i32> {
+    let width = specs.iter().map(|spec| spec.name.len()).max().unwrap_or(0);
+    let mut signals = ForwardedSignals::new()?;
+
+    let mut children = Vec::with_capacity(specs.len());
+    let mut outputs = Vec::with_capacity(specs.len() * 2);
+    for spec in specs {
+        let args = ["-c".to_string(), spec.command.clone()];
+        let mut command = process::command("/bin/sh", &args, env_secrets, opts)?;
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = process::spawn_command(&mut command, env_secrets, opts).map_err(|err| {
+            Error::Spawn {
+                command: format!("process `{}`", spec.name),
+                source: err.into(),
+            }
+        })?;
+
+        let prefix = format!("{:width$} | ", spec.name, width = width);
+        if let Some(stdout) = child.stdout.take() {
+            outputs.push(tokio::spawn(forward_output(stdout, prefix.clone(), false)));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            outputs.push(tokio::spawn(forward_output(stderr, prefix, true)));
+        }
+        tracing::info!("started process `{}`", spec.name);
+        children.push((spec.name.as_str(), child));
+    }
+    readiness.notify()?;
+
+    // wait for the first process to exit while forwarding signals to all of them
+    let (name, status, stop_signal) = loop {
+        tokio::select! {
+            (name, status) = wait_any(&mut children) => {
+                break (name, status?, stop_opts.signal.unwrap_or(Signal::SIGTERM));
+            }
+            Some(signal) = signals.recv() => {
+                if is_termination(signal) {
+                    let stop_signal = stop_opts.signal.unwrap_or(signal);
+                    tracing::info!("received {}, stopping all processes with {}", signal, stop_signal);
+                    for (_, child) in children.iter() {
+                        process::signal_child(child, stop_signal)?;
+                    }
+                    let (name, status) = wait_any(&mut children).await;
+                    break (name, status?, stop_signal);
+                }
+
+                tracing::info!("forwarding {} to all processes", signal);
+                for (_, child) in children.iter() {
+                    process::signal_child(child, signal)?;
+                }
+            }
+        }
+    };
+
+    tracing::warn!(
+        "process `{}` exited with {}, stopping all processes",
+        name,
+        status
+    );
+    let stops = children.iter_mut().map(|(name, child)| async move {
+        if let Err(err) = process::stop_child(child, stop_signal, stop_opts.timeout).await {
+            tracing::warn!("unable to stop process `{}`: {}", name, err);
+        }
+    });
+    futures::future::join_all(stops).await;
+    futures::future::join_all(outputs).await;
+
+    Ok(process::exit_code(status))
+}
+
+async fn wait_any<'a>(children: &mut [(&'a str, Child)]) -> (&'a str, Result<ExitStatus>) {
+    let futures = children.iter_mut().map(|(name, child)| {
+        Box::pin(async move {
+            let status = child.wait().await.map_err(|err| {
+                Error::Execution(format!("unable to wait for process `{}`: {}", name, err))
+            });
+            (*name, status)
+        })
+    });
+    futures::future::select_all(futures).await.0
+}
+
+/// Copies the output of a process line by line, prefixed with its name.
+async fn forward_output<R: AsyncRead + Unpin>(reader: R, prefix: String, stderr: bool) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let res = if stderr {
+            writeln!(std::io::stderr().lock(), "{}{}", prefix, line)
+        } else {
+            writeln!(std::io::stdout().lock(), "{}{}", prefix, line)
+        };
+        if res.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_processes() {
+        let specs = parse(
+            r#"
+            # main application
+            web: ./server --port 8080
+            worker:   ./worker --queue default # not a comment
+            "#,
+        )
+        .unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "web");
+        assert_eq!(specs[0].command, "./server --port 8080");
+        assert_eq!(specs[1].command, "./worker --queue default # not a comment");
+    }
+
+    #[test]
+    fn fail_missing_command() {
+        assert!(parse("web:").is_err());
+        assert!(parse("web ./server").is_err());
+    }
+
+    #[test]
+    fn fail_duplicate_name() {
+        assert!(parse("web: a\nweb: b").is_err());
+    }
+
+    #[test]
+    fn fail_empty() {
+        assert!(parse("# nothing here").is_err());
+    }
+}
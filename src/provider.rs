@@ -0,0 +1,417 @@
+//! Backends resolving secret specs to secrets.
+use std::future::Future;
+
+use serde_json::Value;
+
+use crate::{
+    crypto, encoder,
+    error::{Error, Result},
+    glob,
+    local::{EnvProvider, FileProvider},
+    secrets::{Provider, Secret, SecretSpec, SecretSpecs, TemplatePart},
+    sops::SopsProvider,
+};
+
+/// A backend secrets are resolved from.
+pub trait SecretProvider {
+    /// Resolves a single secret.
+    async fn resolve(&self, spec: &SecretSpec) -> Result<Secret>;
+
+    /// Resolves all `specs` in order; providers override this to batch requests.
+    async fn resolve_all(&self, specs: &[&SecretSpec]) -> Result<Vec<Secret>> {
+        let mut secrets = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let secret = self
+                .resolve(spec)
+                .await
+                .map_err(|err| err.for_secrets(&[spec.name()]))?;
+            secrets.push(secret);
+        }
+
+        Ok(secrets)
+    }
+}
+
+/// Returns the specs resolved by `provider`.
+pub fn select(specs: &SecretSpecs, provider: Provider) -> Vec<&SecretSpec> {
+    specs
+        .values()
+        .filter(|spec| spec.provider == provider)
+        .collect()
+}
+
+/// Adds the sources referenced by the `tpl:` and `tplfile:` specs as specs of their own, which are resolved
+/// before the templates are rendered.
+pub fn expand(specs: &SecretSpecs) -> SecretSpecs {
+    let mut expanded = specs.clone();
+    for spec in specs.values() {
+        let Ok(parts) = spec.template_parts() else {
+            continue;
+        };
+        for part in parts {
+            if let TemplatePart::Source(source) = part {
+                expanded.insert(source.name(), source);
+            }
+        }
+    }
+
+    expanded
+}
+
+/// Whether any of the specs is resolved by vault, which requires logging in.
+pub fn uses_vault(specs: &SecretSpecs) -> bool {
+    expand(specs)
+        .values()
+        .any(|spec| matches!(spec.provider, Provider::Vault | Provider::Pki))
+}
+
+/// Resolves every spec with the provider selected by its scheme, the specs of the other
+/// providers than vault being resolved before by `resolve_local` as `local`.
+///
+/// The secrets are grouped by provider, but their order is stable, so results of different
+/// fetches can be compared. Secrets whose names match the `allow_missing` globs are left out if
+/// they are missing; `vault` handles them on its own. Templates are rendered last, from the
+/// sources they reference. The values are returned as resolved, see `finish`.
+pub async fn resolve(
+    specs: &SecretSpecs,
+    vault: &impl SecretProvider,
+    local: &[Secret],
+    allow_missing: &[String],
+) -> Result<Vec<Secret>> {
+    let expanded = expand(specs);
+    let specs = &expanded;
+    let mut secrets = vault.resolve_all(&select(specs, Provider::Vault)).await?;
+    secrets.extend(vault.resolve_all(&select(specs, Provider::Pki)).await?);
+    secrets.extend_from_slice(local);
+    render_templates(specs, secrets, allow_missing)
+}
+
+/// Resolves the specs of the providers other than vault, e.g. decrypting sops files, which do not
+/// depend on the vault address and so are resolved once, even if fetching fails over to another
+/// address or waits for vault to be unsealed.
+pub async fn resolve_local(specs: &SecretSpecs, allow_missing: &[String]) -> Result<Vec<Secret>> {
+    let expanded = expand(specs);
+    let specs = &expanded;
+    let sops = select(specs, Provider::Sops);
+    let mut secrets = resolve_available(&sops, allow_missing, |specs| async move {
+        SopsProvider.resolve_all(&specs).await
+    })
+    .await?;
+    let files = select(specs, Provider::File);
+    secrets.extend(
+        resolve_available(&files, allow_missing, |specs| async move {
+            FileProvider.resolve_all(&specs).await
+        })
+        .await?,
+    );
+    let envs = select(specs, Provider::Env);
+    secrets.extend(
+        resolve_available(&envs, allow_missing, |specs| async move {
+            EnvProvider.resolve_all(&specs).await
+        })
+        .await?,
+    );
+
+    Ok(secrets)
+}
+
+/// Verifies the pinned checksums and applies the encoders of the specs to secrets read from any
+/// source, i.e. vault and the other providers, the cache, the `--fallback-file` or a snapshot.
+pub fn finish(specs: &SecretSpecs, secrets: Vec<Secret>) -> Result<Vec<Secret>> {
+    verify_checksums(specs, &secrets)?;
+
+    Ok(encode(specs, secrets))
+}
+
+/// Replaces the secrets of the sources referenced by the `tpl:` and `tplfile:` specs with the
+/// rendered templates.
+///
+/// A template with a source left out as missing is left out too if its name matches the
+/// `allow_missing` globs.
+fn render_templates(
+    specs: &SecretSpecs,
+    mut secrets: Vec<Secret>,
+    allow_missing: &[String],
+) -> Result<Vec<Secret>> {
+    let mut rendered = Vec::new();
+    let mut sources = Vec::new();
+    let templates = specs
+        .values()
+        .filter(|spec| matches!(spec.provider, Provider::Template | Provider::TemplateFile));
+    for spec in templates {
+        let mut value = String::new();
+        let mut missing = None;
+        for part in spec.template_parts()? {
+            match part {
+                TemplatePart::Literal(text) => value.push_str(&text),
+                TemplatePart::Source(source) => {
+                    let name = source.name();
+                    match secrets.iter().find(|secret| secret.target.name() == name) {
+                        Some(secret) => {
+                            value.push_str(&encoder::encode_all(&source.encoders, &secret.secret))
+                        }
+                        None => missing = Some(source.source()),
+                    }
+                    sources.push(name);
+                }
+            }
+        }
+
+        match missing {
+            None => rendered.push(Secret::new(spec.target.clone(), value)),
+            Some(source) if glob::matches_any(allow_missing, &spec.name()) => {
+                tracing::warn!(
+                    "template `{}` uses the missing `{}`, continuing without it",
+                    spec.name(),
+                    source
+                );
+            }
+            Some(source) => {
+                return Err(Error::NotFound(format!(
+                    "template `{}` uses the missing `{}`",
+                    spec.name(),
+                    source
+                ))
+                .for_secrets(&[spec.name()]));
+            }
+        }
+    }
+
+    secrets.retain(|secret| !sources.contains(&secret.target.name()));
+    secrets.extend(rendered);
+    Ok(secrets)
+}
+
+/// Resolves `specs` with `resolve`, leaving out the secrets which are missing and whose names
+/// match the `allow_missing` globs.
+///
+/// If resolving all of them fails as one is missing, they are resolved again one by one to find
+/// out which.
+pub async fn resolve_available<'a, F, FU>(
+    specs: &[&'a SecretSpec],
+    allow_missing: &[String],
+    resolve: F,
+) -> Result<Vec<Secret>>
+where
+    F: Fn(Vec<&'a SecretSpec>) -> FU,
+    FU: Future<Output = Result<Vec<Secret>>>,
+{
+    let err = match resolve(specs.to_vec()).await {
+        Ok(secrets) => return Ok(secrets),
+        Err(err) => err,
+    };
+    let allowed = |spec: &SecretSpec| glob::matches_any(allow_missing, &spec.name());
+    if !is_missing(&err) || !specs.iter().any(|spec| allowed(spec)) {
+        return Err(err);
+    }
+
+    let mut secrets = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let resolved = match specs.len() {
+            1 => Err(err.clone()),
+            _ => resolve(vec![*spec]).await,
+        };
+        match resolved {
+            Ok(resolved) => secrets.extend(resolved),
+            Err(err) if is_missing(&err) && allowed(spec) => {
+                tracing::warn!(
+                    "secret `{}` is missing, continuing without it: {}",
+                    spec.name(),
+                    err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(secrets)
+}
+
+/// Whether `err` means that a secret does not exist or may not be read, as opposed to e.g. an
+/// unreachable vault.
+pub fn is_missing(err: &Error) -> bool {
+    match err {
+        Error::Context { source, .. } => is_missing(source),
+        Error::NotFound(_) => true,
+        Error::HttpStatus { code, .. } => matches!(code, 403 | 404),
+        Error::IO { .. }
+        | Error::Parse { .. }
+        | Error::ParseErrors(_)
+        | Error::Conversion(_)
+        | Error::Deserialization { .. }
+        | Error::MaxRetries { .. }
+        | Error::Reqwest { .. }
+        | Error::ReqwestTransient { .. }
+        | Error::Execution(_)
+        | Error::Timeout(_)
+        | Error::PermissionDenied(_)
+        | Error::Spawn { .. } => false,
+    }
+}
+
+/// Checks the values against the SHA-256 digests pinned in the specs, so a tampered or rotated
+/// secret aborts instead of being passed on.
+pub fn verify_checksums(specs: &SecretSpecs, secrets: &[Secret]) -> Result<()> {
+    for secret in secrets {
+        let name = secret.target.name();
+        let Some(expected) = specs.get(&name).and_then(|spec| spec.sha256.as_deref()) else {
+            continue;
+        };
+
+        let actual = crypto::sha256_hex(secret.secret.as_bytes());
+        if actual != expected {
+            return Err(Error::Execution(format!(
+                "checksum mismatch of secret `{}`: expected sha256 {}, got {}",
+                name, expected, actual
+            ))
+            .for_secrets(&[name]));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the encoders of the specs to the values of their secrets.
+fn encode(specs: &SecretSpecs, mut secrets: Vec<Secret>) -> Vec<Secret> {
+    for secret in &mut secrets {
+        if let Some(spec) = specs.get(&secret.target.name()) {
+            secret.secret = encoder::encode_all(&spec.encoders, &secret.secret);
+        }
+    }
+
+    secrets
+}
+
+/// Walks the dotted key path of `spec` through the decrypted document.
+///
+/// Numeric segments index into lists, scalar values are converted to strings.
+pub fn lookup(document: &Value, spec: &SecretSpec) -> Result<Secret> {
+    lookup_value(document, spec).map_err(|err| err.for_secrets(&[spec.name()]))
+}
+
+fn lookup_value(document: &Value, spec: &SecretSpec) -> Result<Secret> {
+    let mut value = document;
+    for segment in spec.secret.split('.') {
+        let next = match value {
+            Value::Object(map) => map.get(segment),
+            Value::Array(list) => segment.parse::<usize>().ok().and_then(|idx| list.get(idx)),
+            _ => None,
+        };
+        value = next.ok_or_else(|| {
+            Error::NotFound(format!("{} does not contain {}", spec.path, spec.secret))
+        })?;
+    }
+
+    let secret = match value {
+        Value::String(secret) => secret.clone(),
+        Value::Number(number) => number.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        _ => {
+            return Err(Error::deserialization(format!(
+                "{}#{} cannot be made into a string",
+                spec.path, spec.secret
+            )))
+        }
+    };
+
+    Ok(Secret::new(spec.target.clone(), secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretTarget;
+
+    fn spec(key: &str) -> SecretSpec {
+        let target = SecretTarget::Env {
+            name: "A".to_string(),
+        };
+        SecretSpec {
+            provider: Provider::File,
+            ..SecretSpec::vault(target, "", "dev-secrets.json", key)
+        }
+    }
+
+    #[test]
+    fn pass_lookup() {
+        let document = serde_json::json!({
+            "db": {"password": "hunter2", "port": 5432},
+            "hosts": ["a", "b"],
+        });
+        assert_eq!(
+            lookup(&document, &spec("db.password")).unwrap().secret,
+            "hunter2"
+        );
+        assert_eq!(lookup(&document, &spec("db.port")).unwrap().secret, "5432");
+        assert_eq!(lookup(&document, &spec("hosts.1")).unwrap().secret, "b");
+    }
+
+    #[test]
+    fn fail_lookup() {
+        let document = serde_json::json!({"db": {"password": "hunter2"}});
+        assert!(lookup(&document, &spec("db.user")).is_err());
+        assert!(lookup(&document, &spec("db.password.length")).is_err());
+        assert!(lookup(&document, &spec("db")).is_err());
+    }
+
+    #[test]
+    fn fail_verify_checksums() {
+        let spec = SecretSpec {
+            sha256: Some(crypto::sha256_hex(b"hunter 2")),
+            encoders: vec![crate::encoder::Encoder::UrlEncode],
+            ..spec("db.password")
+        };
+        let specs = SecretSpecs::from([(spec.name(), spec.clone())]);
+        let secret = |value: &str| Secret::new(spec.target.clone(), value.to_string());
+        // the checksum is of the value before it is encoded
+        assert_eq!(
+            finish(&specs, vec![secret("hunter 2")]).unwrap()[0].secret,
+            "hunter%202"
+        );
+        assert!(finish(&specs, vec![secret("rotated")]).is_err());
+    }
+
+    #[test]
+    fn pass_render_templates() {
+        let env = |name: &str| SecretTarget::Env {
+            name: name.to_string(),
+        };
+        let template = SecretSpec {
+            target: env("DATABASE_URL"),
+            provider: Provider::Template,
+            path: "postgres://{{secret/db#user}}:{{file:db.json#pass | urlencode}}@db/app"
+                .to_string(),
+            secret: String::new(),
+            ..spec("")
+        };
+        let specs = expand(&SecretSpecs::from([(template.name(), template.clone())]));
+        assert_eq!(
+            specs.keys().collect::<Vec<_>>(),
+            ["DATABASE_URL", "DATABASE_URL[0]", "DATABASE_URL[1]"]
+        );
+        assert!(uses_vault(&specs));
+
+        let secrets = vec![
+            Secret::new(env("API_KEY"), "k".to_string()),
+            Secret::new(env("DATABASE_URL[0]"), "app".to_string()),
+            Secret::new(env("DATABASE_URL[1]"), "p@ss".to_string()),
+        ];
+        let rendered = render_templates(&specs, secrets.clone(), &[]).unwrap();
+        assert_eq!(
+            rendered,
+            [
+                Secret::new(env("API_KEY"), "k".to_string()),
+                Secret::new(
+                    env("DATABASE_URL"),
+                    "postgres://app:p%40ss@db/app".to_string()
+                ),
+            ]
+        );
+
+        // a missing source fails the template unless it may be missing
+        let partial = secrets[..2].to_vec();
+        assert!(render_templates(&specs, partial.clone(), &[]).is_err());
+        let rendered = render_templates(&specs, partial, &["DATABASE_*".to_string()]).unwrap();
+        assert_eq!(rendered, [Secret::new(env("API_KEY"), "k".to_string())]);
+    }
+}
@@ -0,0 +1,112 @@
+//! Token bucket limiting the rate of requests to vault.
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Limits requests to `rate` per second, allowing bursts of up to one second worth of requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Available tokens, negative if requests are already waiting for tokens.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with a full bucket.
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: burst(rate),
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        let wait = self
+            .bucket
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take(self.rate, Instant::now());
+        if !wait.is_zero() {
+            tracing::debug!("rate limit reached, delaying request by {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Bucket {
+    /// Takes a token and returns how long to wait until it is available.
+    ///
+    /// Tokens are reserved in order, so concurrent requests are delayed one after another.
+    fn take(&mut self, rate: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst(rate));
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Size of the bucket, at least a single token.
+fn burst(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+/// Parses a positive number of requests per second, e.g. `20` or `0.5`.
+pub fn parse_rate(raw: &str) -> std::result::Result<f64, String> {
+    let value = raw
+        .parse::<f64>()
+        .map_err(|e| format!("invalid rate limit value: {e}"))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err("rate limit must be a positive number of requests per second".to_string());
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_take() {
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            tokens: burst(2.0),
+            last: now,
+        };
+        assert_eq!(bucket.take(2.0, now), Duration::ZERO);
+        assert_eq!(bucket.take(2.0, now), Duration::ZERO);
+        assert_eq!(bucket.take(2.0, now), Duration::from_millis(500));
+        assert_eq!(bucket.take(2.0, now), Duration::from_secs(1));
+
+        // refilled, but never beyond the burst size
+        let later = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(2.0, later), Duration::ZERO);
+        assert_eq!(bucket.take(2.0, later), Duration::ZERO);
+        assert_eq!(bucket.take(2.0, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn fail_parse_rate() {
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert_eq!(parse_rate("0.5").unwrap(), 0.5);
+    }
+}
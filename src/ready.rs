@@ -0,0 +1,150 @@
+//! Readiness notifications sent once the secrets are injected and the child is spawned.
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+
+/// Environment variable holding the socket of the service manager, see `sd_notify(3)`.
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// How readiness is signaled to the orchestrator.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    /// File created once the child is spawned.
+    pub file: Option<PathBuf>,
+    /// Send `READY=1` to the socket in `NOTIFY_SOCKET`.
+    pub sd_notify: bool,
+}
+
+impl Readiness {
+    /// Signals that the secrets were injected and the child was spawned.
+    pub fn notify(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            std::fs::write(file, b"")
+                .map_err(|err| Error::io_at("unable to write ready file", file, err))?;
+        }
+        if self.sd_notify {
+            sd_notify("READY=1")?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the ready file after the child exited.
+    pub fn clear(&self) {
+        if let Some(file) = &self.file {
+            if let Err(err) = std::fs::remove_file(file) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("unable to remove ready file {:?}: {}", file, err);
+                }
+            }
+        }
+    }
+}
+
+/// Sends `state` to the service manager; does nothing if `NOTIFY_SOCKET` is not set.
+fn sd_notify(state: &str) -> Result<()> {
+    let Some(path) = std::env::var_os(NOTIFY_SOCKET_ENV) else {
+        tracing::warn!("--sd-notify is set, but {} is not", NOTIFY_SOCKET_ENV);
+        return Ok(());
+    };
+
+    notify_socket(&path, state)
+}
+
+/// Sends `state` to the notify socket at `path`, an abstract socket if it starts with `@`.
+fn notify_socket(path: &std::ffi::OsStr, state: &str) -> Result<()> {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let addr = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(Error::Execution(
+                "abstract notify sockets are only supported on linux".to_string(),
+            ))
+        }
+        None => SocketAddr::from_pathname(path),
+    }
+    .map_err(|err| Error::io_at(&format!("invalid {}", NOTIFY_SOCKET_ENV), path, err))?;
+
+    let socket =
+        UnixDatagram::unbound().map_err(|err| Error::io("unable to create notify socket", err))?;
+    socket
+        .send_to_addr(state.as_bytes(), &addr)
+        .map_err(|err| Error::io("unable to notify service manager", err))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_ready_file() {
+        let file = std::env::temp_dir().join(format!("vaultify-ready-{}", std::process::id()));
+        let readiness = Readiness {
+            file: Some(file.clone()),
+            sd_notify: false,
+        };
+        readiness.notify().unwrap();
+        assert!(file.exists());
+        readiness.clear();
+        assert!(!file.exists());
+        // already removed
+        readiness.clear();
+    }
+
+    #[test]
+    fn fail_ready_file() {
+        let readiness = Readiness {
+            file: Some(PathBuf::from("/nonexistent/vaultify/ready")),
+            sd_notify: false,
+        };
+        let err = readiness.notify().unwrap_err();
+        assert!(
+            err.to_string().contains("unable to write ready file"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn pass_sd_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("vaultify-notify-{}", std::process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let name = format!("vaultify-notify-{}", std::process::id());
+            let addr = SocketAddr::from_abstract_name(&name).unwrap();
+            let socket = UnixDatagram::bind_addr(&addr).unwrap();
+            notify_socket(format!("@{}", name).as_ref(), "READY=1").unwrap();
+            let len = socket.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"READY=1");
+        }
+    }
+
+    #[test]
+    fn fail_sd_notify() {
+        let err = notify_socket("/nonexistent/vaultify/notify".as_ref(), "READY=1").unwrap_err();
+        assert!(
+            err.to_string().contains("unable to notify service manager"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Resource limits and privilege reduction applied to the child before it is executed.
+//!
+//! Everything applied in here runs between `fork` and `exec`, so it has to stick to
+//! async-signal-safe syscalls and must not allocate.
+use nix::sys::resource::{setrlimit, Resource, RLIM_INFINITY};
+
+/// A resource limit set via `setrlimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// Maps the name used by `ulimit` and `prlimit` to the resource.
+fn resource_from_name(name: &str) -> Option<Resource> {
+    let resource = match name {
+        "core" => Resource::RLIMIT_CORE,
+        "cpu" => Resource::RLIMIT_CPU,
+        "data" => Resource::RLIMIT_DATA,
+        "fsize" => Resource::RLIMIT_FSIZE,
+        "nofile" => Resource::RLIMIT_NOFILE,
+        "stack" => Resource::RLIMIT_STACK,
+        #[cfg(target_os = "linux")]
+        "as" => Resource::RLIMIT_AS,
+        #[cfg(target_os = "linux")]
+        "locks" => Resource::RLIMIT_LOCKS,
+        #[cfg(target_os = "linux")]
+        "memlock" => Resource::RLIMIT_MEMLOCK,
+        #[cfg(target_os = "linux")]
+        "msgqueue" => Resource::RLIMIT_MSGQUEUE,
+        #[cfg(target_os = "linux")]
+        "nice" => Resource::RLIMIT_NICE,
+        #[cfg(target_os = "linux")]
+        "nproc" => Resource::RLIMIT_NPROC,
+        #[cfg(target_os = "linux")]
+        "rss" => Resource::RLIMIT_RSS,
+        #[cfg(target_os = "linux")]
+        "rtprio" => Resource::RLIMIT_RTPRIO,
+        #[cfg(target_os = "linux")]
+        "rttime" => Resource::RLIMIT_RTTIME,
+        #[cfg(target_os = "linux")]
+        "sigpending" => Resource::RLIMIT_SIGPENDING,
+        _ => return None,
+    };
+
+    Some(resource)
+}
+
+fn parse_limit(raw: &str) -> std::result::Result<u64, String> {
+    if raw == "unlimited" || raw == "infinity" {
+        return Ok(RLIM_INFINITY);
+    }
+
+    raw.parse::<u64>()
+        .map_err(|e| format!("invalid rlimit value `{}`: {e}", raw))
+}
+
+/// Parses `NAME=LIMIT` or `NAME=SOFT:HARD`, e.g. `nofile=65536` or `core=0:unlimited`.
+pub fn parse_rlimit(raw: &str) -> std::result::Result<Rlimit, String> {
+    let (name, limits) = raw
+        .split_once('=')
+        .ok_or_else(|| "rlimit must be in format `name=soft[:hard]`".to_string())?;
+    let resource = resource_from_name(&name.to_ascii_lowercase())
+        .ok_or_else(|| format!("unknown or unsupported rlimit resource `{}`", name))?;
+    let (soft, hard) = match limits.split_once(':') {
+        Some((soft, hard)) => (parse_limit(soft)?, parse_limit(hard)?),
+        None => {
+            let limit = parse_limit(limits)?;
+            (limit, limit)
+        }
+    };
+    if soft > hard {
+        return Err("rlimit soft limit must not exceed the hard limit".to_string());
+    }
+
+    Ok(Rlimit {
+        resource,
+        soft,
+        hard,
+    })
+}
+
+/// Applies all resource limits to the current process.
+pub fn apply_rlimits(rlimits: &[Rlimit]) -> std::io::Result<()> {
+    for rlimit in rlimits {
+        setrlimit(rlimit.resource, rlimit.soft as _, rlimit.hard as _)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the OOM killer score adjustment of the current process.
+#[cfg(target_os = "linux")]
+pub fn apply_oom_score_adj(value: i32) -> std::io::Result<()> {
+    use nix::libc;
+    use std::io::Write;
+
+    // formatted into a stack buffer, as allocating is not allowed before exec
+    let mut buf = [0u8; 16];
+    let len = {
+        let mut cursor = &mut buf[..];
+        write!(cursor, "{}", value)?;
+        16 - cursor.len()
+    };
+
+    // SAFETY: the path is a valid c-string and `buf` outlives the write.
+    unsafe {
+        let fd = libc::open(
+            c"/proc/self/oom_score_adj".as_ptr(),
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let res = libc::write(fd, buf.as_ptr().cast(), len);
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+        if res < 0 {
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Highest capability number known to vaultify (`CAP_CHECKPOINT_RESTORE`).
+#[cfg(target_os = "linux")]
+const CAP_LAST: u32 = 40;
+
+/// Capability names as used by `capabilities(7)`, indexed by their number.
+#[cfg(target_os = "linux")]
+const CAPABILITIES: [&str; CAP_LAST as usize + 1] = [
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "DAC_READ_SEARCH",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "SETGID",
+    "SETUID",
+    "SETPCAP",
+    "LINUX_IMMUTABLE",
+    "NET_BIND_SERVICE",
+    "NET_BROADCAST",
+    "NET_ADMIN",
+    "NET_RAW",
+    "IPC_LOCK",
+    "IPC_OWNER",
+    "SYS_MODULE",
+    "SYS_RAWIO",
+    "SYS_CHROOT",
+    "SYS_PTRACE",
+    "SYS_PACCT",
+    "SYS_ADMIN",
+    "SYS_BOOT",
+    "SYS_NICE",
+    "SYS_RESOURCE",
+    "SYS_TIME",
+    "SYS_TTY_CONFIG",
+    "MKNOD",
+    "LEASE",
+    "AUDIT_WRITE",
+    "AUDIT_CONTROL",
+    "SETFCAP",
+    "MAC_OVERRIDE",
+    "MAC_ADMIN",
+    "SYSLOG",
+    "WAKE_ALARM",
+    "BLOCK_SUSPEND",
+    "AUDIT_READ",
+    "PERFMON",
+    "BPF",
+    "CHECKPOINT_RESTORE",
+];
+
+/// Capabilities dropped before exec.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// All capabilities.
+    All,
+    /// A single capability by number.
+    Single(u32),
+}
+
+/// Parses a capability name like `CAP_NET_RAW` or `net_raw`, or `all`.
+#[cfg(target_os = "linux")]
+pub fn parse_capability(raw: &str) -> std::result::Result<Capability, String> {
+    let name = raw.to_ascii_uppercase();
+    if name == "ALL" {
+        return Ok(Capability::All);
+    }
+
+    let name = name.strip_prefix("CAP_").unwrap_or(&name);
+    CAPABILITIES
+        .iter()
+        .position(|cap| *cap == name)
+        .map(|cap| Capability::Single(cap as u32))
+        .ok_or_else(|| format!("unknown capability `{}`", raw))
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: nix::libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Whether the capability `name` (as in `CAPABILITIES`) is in the effective set of vaultify.
+#[cfg(target_os = "linux")]
+pub fn has_capability(name: &str) -> bool {
+    let Some(cap) = CAPABILITIES.iter().position(|cap| *cap == name) else {
+        return false;
+    };
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    // SAFETY: `header` and `data` match the layout expected by the v3 capget syscall.
+    let read = unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_capget,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        )
+    } == 0;
+    read && data[cap / 32].effective & (1 << (cap % 32)) != 0
+}
+
+/// Drops the capabilities from the bounding, ambient, effective, permitted and inheritable sets.
+#[cfg(target_os = "linux")]
+pub fn drop_capabilities(caps: &[Capability]) -> std::io::Result<()> {
+    use nix::libc;
+
+    if caps.is_empty() {
+        return Ok(());
+    }
+
+    // 64 bit mask of the capabilities to drop
+    let mut mask = 0u64;
+    for cap in caps {
+        match cap {
+            Capability::All => mask = u64::MAX,
+            Capability::Single(cap) => mask |= 1u64 << cap,
+        }
+    }
+
+    for cap in 0..64u32 {
+        if mask & (1u64 << cap) == 0 {
+            continue;
+        }
+
+        // SAFETY: prctl with integer arguments has no memory safety requirements.
+        unsafe {
+            // EINVAL means the capability is unknown to the kernel
+            let present = libc::prctl(libc::PR_CAPBSET_READ, cap as libc::c_ulong, 0, 0, 0);
+            if present > 0 && libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) != 0
+            {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EPERM) {
+                    return Err(err);
+                }
+                // without CAP_SETPCAP the bounding set cannot be changed; instead prevent the
+                // command from regaining capabilities via setuid or file capabilities
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if present >= 0 {
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_LOWER as libc::c_ulong,
+                    cap as libc::c_ulong,
+                    0,
+                    0,
+                );
+            }
+        }
+    }
+
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    // SAFETY: `header` and `data` match the layout expected by the v3 capget/capset syscalls.
+    unsafe {
+        if libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        for (i, set) in data.iter_mut().enumerate() {
+            let keep = !((mask >> (32 * i)) as u32);
+            set.effective &= keep;
+            set.permitted &= keep;
+            set.inheritable &= keep;
+        }
+        if libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapUserHeader,
+            data.as_ptr(),
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_parse_rlimit() {
+        assert_eq!(
+            parse_rlimit("nofile=65536").unwrap(),
+            Rlimit {
+                resource: Resource::RLIMIT_NOFILE,
+                soft: 65536,
+                hard: 65536
+            }
+        );
+        assert_eq!(
+            parse_rlimit("core=0:unlimited").unwrap(),
+            Rlimit {
+                resource: Resource::RLIMIT_CORE,
+                soft: 0,
+                hard: RLIM_INFINITY
+            }
+        );
+    }
+
+    #[test]
+    fn fail_parse_rlimit() {
+        assert!(parse_rlimit("nofile").is_err());
+        assert!(parse_rlimit("files=10").is_err());
+        assert!(parse_rlimit("nofile=many").is_err());
+        assert!(parse_rlimit("nofile=10:5").is_err());
+        assert!(parse_rlimit("nofile=unlimited:5").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pass_parse_capability() {
+        assert_eq!(parse_capability("all").unwrap(), Capability::All);
+        assert_eq!(
+            parse_capability("CAP_NET_RAW").unwrap(),
+            Capability::Single(13)
+        );
+        assert_eq!(
+            parse_capability("sys_admin").unwrap(),
+            Capability::Single(21)
+        );
+        assert!(parse_capability("CAP_FLY").is_err());
+    }
+}
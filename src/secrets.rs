@@ -1,36 +1,160 @@
 //! .secrets file parser
 use std::{
     collections::BTreeMap,
+    io::Read,
     path::{Path, PathBuf},
 };
 
-use crate::error::{Error, Result};
+use crate::{
+    encoder::Encoder,
+    error::{Error, Result},
+    timing::{self, Phase},
+};
 
 pub type SecretSpecs = BTreeMap<String, SecretSpec>;
 
+/// Grammar of a .secrets file, shown in the long help and the generated reference.
+pub const GRAMMAR: &str = "\
+Each line maps one source to one output target, `#` after whitespace starts a comment:
+
+  SOURCE [!sha256:<hex>] [| ENCODER...] | TARGET
+
+SOURCE is one of
+  [vault:]<mount>/<path>#<key>   key of a Vault KV secret (v2, falling back to v1)
+  sops:<file>#<key.path>         dotted key path in a SOPS encrypted YAML or JSON file
+  file:<file>#<key.path>         dotted key path in a plain JSON file
+  env:<NAME>                     variable in the environment of vaultify
+  pki:<mount>/issue/<role>?common_name=<cn>[&<param>=<value>...]#<field>
+                                 field (certificate, private_key, issuing_ca or ca_chain) of
+                                 a certificate issued by a Vault PKI secrets engine, re-issued
+                                 before it expires while the command runs attached
+  tpl:<text with {{SOURCE}}...>  the text with every `{{SOURCE}}` replaced by the value of
+                                 that source, e.g. tpl:postgres://{{kv/db#user}}@db/app
+  tplfile:<file>                 the template in the file, e.g. a whole .pgpass or kubeconfig,
+                                 relative to the directory of the .secrets file
+
+TARGET is one of
+  env <NAME>                                    environment variable of the command
+  file <path> [mode=0600] [create=true|false]   file with the octal mode, whose parent
+       [owner=UID[:GID]] [label=<context>]      directory is created with create=true, owned
+                                                by the user and group and labeled with the
+                                                SELinux context
+
+`!sha256:<hex>` pins the SHA-256 digest of the value, a different value aborts the launch.
+
+ENCODER escapes the value for where it ends up, also within a template as
+`{{SOURCE | ENCODER}}`; encoders are applied in order:
+  urlencode    percent-encodes all but letters, digits and `-._~`
+  shellquote   quotes the value as a single shell word
+  jsonescape   escapes the value as the contents of a JSON string
+
+A section header groups the lines below it, up to the next header:
+
+  [<name> [prefix=<PREFIX>] [file=<path>]]
+
+The env targets of a section are prefixed with PREFIX (default: the name in upper case with
+`_` for other characters, followed by `_`) and, with file=, written to that dotenv file (mode
+0600) instead of the environment of the command.";
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SecretTarget {
     Env {
         name: String,
     },
+    /// Variable in the dotenv `file=` of a section.
+    EnvFile {
+        path: PathBuf,
+        name: String,
+    },
     File {
         path: PathBuf,
         mode: u32,
         create: bool,
+        owner: Option<Owner>,
+        /// SELinux context of the file.
+        label: Option<String>,
     },
 }
 
+/// Owner of a written file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+/// Parses an owner given as `UID[:GID]`.
+pub fn parse_owner(value: &str) -> std::result::Result<Owner, String> {
+    let (uid, gid) = match value.split_once(':') {
+        Some((uid, gid)) => (uid, Some(gid)),
+        None => (value, None),
+    };
+    let parse = |id: &str| {
+        id.parse::<u32>()
+            .map_err(|_| format!("`{}` is not a numeric id; expected UID[:GID]", id))
+    };
+
+    Ok(Owner {
+        uid: parse(uid)?,
+        gid: gid.map(parse).transpose()?,
+    })
+}
+
+/// Backend a secret is resolved from, selected via a `scheme:` prefix of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    /// `vault:mount/path#secret`, the default without prefix.
+    Vault,
+    /// `sops:file#key.path`, decrypted from a SOPS encrypted YAML or JSON file.
+    Sops,
+    /// `file:file#key.path`, read from a plain JSON file.
+    File,
+    /// `env:NAME`, read from the environment of vaultify itself.
+    Env,
+    /// `pki:mount/issue/role?common_name=cn#field`, issued by a vault PKI secrets engine.
+    Pki,
+    /// `tpl:text`, the text with the values of the `{{source}}` references filled in.
+    Template,
+    /// `tplfile:file`, like `tpl:` with the text read from the file.
+    TemplateFile,
+}
+
+impl Provider {
+    fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "vault" => Some(Provider::Vault),
+            "sops" => Some(Provider::Sops),
+            "file" => Some(Provider::File),
+            "env" => Some(Provider::Env),
+            "pki" => Some(Provider::Pki),
+            "tpl" => Some(Provider::Template),
+            "tplfile" => Some(Provider::TemplateFile),
+            _ => None,
+        }
+    }
+}
+
 /// Spec of a secret parsed from a .secrets file.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SecretSpec {
     /// Where to write the resolved secret.
     pub target: SecretTarget,
-    /// The mount point of the secret in vault.
+    /// The backend the secret is resolved from.
+    pub provider: Provider,
+    /// The mount point of the secret in vault, empty for other providers.
     pub mount: String,
-    /// The path of the secret under the mount point in vault.
+    /// The path of the secret under the mount point in vault, the file of a file based secret or
+    /// template, or the text of a template.
     pub path: String,
-    /// The actual secret key in vault.
+    /// The actual secret key in vault, the dotted key path of a file based secret or the name of
+    /// an environment variable.
     pub secret: String,
+    /// The text of the template file of a `tplfile:` spec, read right after parsing.
+    pub template: Option<String>,
+    /// Expected SHA-256 digest of the value (lowercase hex), pinned via `!sha256:<hex>`.
+    pub sha256: Option<String>,
+    /// Applied in order to the resolved value, after its checksum is verified.
+    pub encoders: Vec<Encoder>,
 }
 
 /// A resolved secret value fetched from vault.
@@ -38,9 +162,41 @@ pub struct SecretSpec {
 pub struct Secret {
     pub target: SecretTarget,
     pub secret: String,
+    /// Metadata of the source, as far as the provider knows it.
+    pub metadata: SecretMetadata,
+}
+
+/// Metadata of a resolved secret, passed to the command with `--metadata-env`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecretMetadata {
+    /// Version of a kv v2 secret.
+    pub version: Option<u64>,
+    /// Lease of a dynamic secret, to renew or revoke it.
+    pub lease_id: Option<String>,
+    /// When the lease of the secret or the issued certificate expires.
+    pub expires_at: Option<std::time::SystemTime>,
+}
+
+/// Secrets are equal if their targets and values are, e.g. a new kv version with the same value
+/// is no change.
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.target == other.target && self.secret == other.secret
+    }
 }
 
+impl Eq for Secret {}
+
 impl Secret {
+    /// A secret without metadata.
+    pub fn new(target: SecretTarget, secret: String) -> Self {
+        Self {
+            target,
+            secret,
+            metadata: SecretMetadata::default(),
+        }
+    }
+
     #[inline]
     fn secret_obfuscated(&self) -> String {
         self.secret.chars().map(|_| '*').collect::<String>()
@@ -52,41 +208,261 @@ impl core::fmt::Debug for Secret {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
-            r#"Secret {{ target: {:?}, secret: "{}" }}"#,
+            r#"Secret {{ target: {:?}, secret: "{}", metadata: {:?} }}"#,
             self.target,
-            self.secret_obfuscated()
+            self.secret_obfuscated(),
+            self.metadata
         )
     }
 }
 
-impl SecretSpec {
-    /// Stable key to identify this secret in logs and maps.
+impl SecretTarget {
+    /// Stable key to identify the secret written to this target in logs and maps.
     pub fn name(&self) -> String {
-        match &self.target {
+        match self {
             SecretTarget::Env { name } => name.clone(),
+            SecretTarget::EnvFile { path, name } => format!("envfile:{}#{}", path.display(), name),
             SecretTarget::File { path, .. } => format!("file:{}", path.display()),
         }
     }
 }
 
+#[cfg(test)]
+impl SecretSpec {
+    /// Spec of `key` of the vault secret `mount/path`, without checksum or encoders.
+    pub fn vault(target: SecretTarget, mount: &str, path: &str, key: &str) -> Self {
+        Self {
+            target,
+            provider: Provider::Vault,
+            mount: mount.to_string(),
+            path: path.to_string(),
+            secret: key.to_string(),
+            template: None,
+            sha256: None,
+            encoders: Vec::new(),
+        }
+    }
+}
+
+impl SecretSpec {
+    /// Stable key to identify this secret in logs and maps.
+    pub fn name(&self) -> String {
+        self.target.name()
+    }
+
+    /// The source as written in the .secrets file, with the provider prefix made explicit.
+    pub fn source(&self) -> String {
+        match self.provider {
+            Provider::Vault => format!("vault:{}/{}#{}", self.mount, self.path, self.secret),
+            Provider::Sops => format!("sops:{}#{}", self.path, self.secret),
+            Provider::File => format!("file:{}#{}", self.path, self.secret),
+            Provider::Env => format!("env:{}", self.secret),
+            Provider::Pki => format!("pki:{}/{}#{}", self.mount, self.path, self.secret),
+            Provider::Template => format!("tpl:{}", self.path),
+            Provider::TemplateFile => format!("tplfile:{}", self.path),
+        }
+    }
+
+    /// The literal text and the sources of a `tpl:` or `tplfile:` spec in order, the sources as
+    /// specs of their own named `<NAME>[<idx>]` which write to nowhere.
+    pub fn template_parts(&self) -> Result<Vec<TemplatePart>> {
+        let name = self.name();
+        let text = match (self.provider, &self.template) {
+            (Provider::TemplateFile, Some(text)) => text,
+            (Provider::TemplateFile, None) => {
+                return Err(Error::Conversion(format!(
+                    "template {} of `{}` was not loaded",
+                    self.path, name
+                )))
+            }
+            _ => &self.path,
+        };
+        let mut sources = 0;
+        let parts = parse_template(text, 0, text)?
+            .into_iter()
+            .map(|part| match part {
+                RawTemplatePart::Literal(text) => TemplatePart::Literal(text.to_string()),
+                RawTemplatePart::Source(provider, mount, path, secret, encoders) => {
+                    sources += 1;
+                    TemplatePart::Source(SecretSpec {
+                        target: SecretTarget::Env {
+                            name: format!("{}[{}]", name, sources - 1),
+                        },
+                        provider,
+                        mount,
+                        path,
+                        secret,
+                        template: None,
+                        sha256: None,
+                        encoders,
+                    })
+                }
+            })
+            .collect();
+        Ok(parts)
+    }
+}
+
+/// Part of the text of a `tpl:` or `tplfile:` spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Literal(String),
+    Source(SecretSpec),
+}
+
 /// Loads the .secrets file and parses it
 #[allow(unused)]
 pub fn load<P: AsRef<Path>>(path: P) -> Result<SecretSpecs> {
-    let contents = std::fs::read_to_string(path.as_ref())
-        .map_err(|err| Error::IO(format!("unable to read file {:?}: {}", path.as_ref(), err)))?;
-    parse(&contents)
+    let contents = std::fs::read(path.as_ref())
+        .map_err(|err| Error::io_at("unable to read file", path.as_ref(), err))?;
+    let contents = plaintext(path.as_ref(), contents)?;
+    let mut specs = parse(&contents).map_err(|err| err.in_file(path.as_ref()))?;
+    for spec in template_files(&mut specs) {
+        let template = template_path(path.as_ref(), &spec.path);
+        let text = std::fs::read_to_string(&template)
+            .map_err(|err| Error::io_at("unable to read template", &template, err))?;
+        check_template_file(&text, &template)?;
+        spec.template = Some(text);
+    }
+
+    Ok(specs)
 }
 
-/// Loads the .secrets file and parses it
-pub async fn load_async<P: AsRef<Path>>(path: P) -> Result<SecretSpecs> {
-    let contents = tokio::fs::read_to_string(path.as_ref())
+/// Loads the .secrets file and parses it, decrypting it with the age `identity` if given.
+pub async fn load_async<P: AsRef<Path>>(path: P, identity: Option<&Path>) -> Result<SecretSpecs> {
+    let contents = tokio::fs::read(path.as_ref())
         .await
-        .map_err(|err| Error::IO(format!("unable to read file {:?}: {}", path.as_ref(), err)))?;
-    parse(&contents)
+        .map_err(|err| Error::io_at("unable to read file", path.as_ref(), err))?;
+    let contents = match identity {
+        Some(identity) => {
+            let identity_contents = tokio::fs::read(identity)
+                .await
+                .map_err(|err| Error::io_at("unable to read age identity", identity, err))?;
+            decrypt(path.as_ref(), &contents, identity, &identity_contents)?
+        }
+        None => plaintext(path.as_ref(), contents)?,
+    };
+    let mut specs = parse(&contents).map_err(|err| err.in_file(path.as_ref()))?;
+    for spec in template_files(&mut specs) {
+        let template = template_path(path.as_ref(), &spec.path);
+        let text = tokio::fs::read_to_string(&template)
+            .await
+            .map_err(|err| Error::io_at("unable to read template", &template, err))?;
+        check_template_file(&text, &template)?;
+        spec.template = Some(text);
+    }
+
+    Ok(specs)
+}
+
+/// The `tplfile:` specs, whose templates are read right after parsing.
+fn template_files(specs: &mut SecretSpecs) -> impl Iterator<Item = &mut SecretSpec> {
+    specs
+        .values_mut()
+        .filter(|spec| spec.provider == Provider::TemplateFile)
+}
+
+/// The path of the template file `template` of a `tplfile:` spec in the secrets file at `path`,
+/// which is relative to the directory of the secrets file.
+fn template_path(path: &Path, template: &str) -> PathBuf {
+    match path.parent() {
+        Some(dir) => dir.join(template),
+        None => PathBuf::from(template),
+    }
+}
+
+/// Checks the syntax of a template file, reporting errors with their line in the template.
+fn check_template_file(text: &str, path: &Path) -> Result<()> {
+    let mut sources = false;
+    for (lc, line) in text.lines().enumerate() {
+        if line.contains("{{") {
+            parse_template(line, lc, line).map_err(|err| err.in_file(path))?;
+            sources = true;
+        }
+    }
+    if !sources {
+        return Err(Error::Conversion(format!(
+            "template {} must reference a source as `{{{{source}}}}`",
+            path.display()
+        )));
+    }
+
+    Ok(())
 }
 
+/// Beginnings of age encrypted files, binary and armored.
+const AGE_HEADERS: [&str; 2] = ["age-encryption.org/", "-----BEGIN AGE ENCRYPTED FILE-----"];
+
+/// The contents of a plaintext .secrets file, rejecting one encrypted with age.
+fn plaintext(path: &Path, contents: Vec<u8>) -> Result<String> {
+    if AGE_HEADERS
+        .iter()
+        .any(|header| contents.starts_with(header.as_bytes()))
+    {
+        return Err(Error::Conversion(format!(
+            "{} is encrypted with age; pass its identity with --secrets-identity",
+            path.display()
+        )));
+    }
+    String::from_utf8(contents).map_err(|err| {
+        Error::io_at(
+            "unable to read file",
+            path,
+            std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        )
+    })
+}
+
+/// Decrypts the `contents` of an age encrypted .secrets file, binary or armored, with the
+/// identities of the age identity file `identity`; the plaintext is only kept in memory.
+fn decrypt(
+    path: &Path,
+    contents: &[u8],
+    identity: &Path,
+    identity_contents: &[u8],
+) -> Result<String> {
+    tracing::debug!("decrypting {} with age", path.display());
+    let started = std::time::Instant::now();
+    let identities = age::IdentityFile::from_buffer(identity_contents)
+        .map_err(|err| err.to_string())
+        .and_then(|file| file.into_identities().map_err(|err| err.to_string()))
+        .map_err(|err| {
+            Error::Conversion(format!(
+                "invalid age identity {}: {}",
+                identity.display(),
+                err
+            ))
+        })?;
+    let mut plaintext = Vec::new();
+    age::Decryptor::new(age::armor::ArmoredReader::new(contents))
+        .and_then(|decryptor| decryptor.decrypt(identities.iter().map(|id| id.as_ref())))
+        .map_err(|err| err.to_string())
+        .and_then(|mut reader| {
+            reader
+                .read_to_end(&mut plaintext)
+                .map_err(|err| err.to_string())
+        })
+        .map_err(|err| {
+            Error::Conversion(format!(
+                "unable to decrypt {} with age: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    timing::record(Phase::Decrypt, started.elapsed(), || {
+        format!("age:{}", path.display())
+    });
+
+    String::from_utf8(plaintext)
+        .map_err(|_| Error::Conversion(format!("decrypted {} is not valid UTF-8", path.display())))
+}
+
+/// Parses all lines, failing with every invalid line at once.
 fn parse(contents: &str) -> Result<SecretSpecs> {
     let mut specs = SecretSpecs::new();
+    let mut errors = Vec::new();
+    let mut sections = Vec::<String>::new();
+    let mut section = None;
 
     for (lc, raw_line) in contents.lines().enumerate() {
         let line = strip_comment(raw_line).trim();
@@ -94,34 +470,298 @@ fn parse(contents: &str) -> Result<SecretSpecs> {
             continue;
         }
 
-        let mut parts = line.split('|');
-        let left = parts
-            .next()
-            .map(str::trim)
-            .ok_or_else(|| Error::parse("missing source", lc, line))?;
-        let right = parts
-            .next()
-            .map(str::trim)
-            .ok_or_else(|| Error::parse("missing output target after `|`", lc, line))?;
-        if parts.next().is_some() {
-            return Err(Error::parse("line must contain exactly one `|`", lc, line));
+        if line.starts_with('[') {
+            match parse_section(line, lc) {
+                Ok(parsed) if sections.contains(&parsed.name) => {
+                    errors.push(Error::parse("duplicate section", lc, line));
+                    section = Some(parsed);
+                }
+                Ok(parsed) => {
+                    sections.push(parsed.name.clone());
+                    section = Some(parsed);
+                }
+                Err(err) => errors.push(err),
+            }
+            continue;
         }
 
-        let (mount, path, secret) = parse_source(left, lc, line)?;
-        let target = parse_target(right, lc, line)?;
-        let spec = SecretSpec {
-            target,
-            mount,
-            path,
-            secret,
+        let spec = match parse_line(line, lc) {
+            Ok(spec) => spec,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let spec = match &section {
+            Some(section) => match section.apply(spec, lc, line) {
+                Ok(spec) => spec,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            },
+            None => spec,
         };
         let key = spec.name();
         if specs.insert(key, spec).is_some() {
-            return Err(Error::parse("duplicate output target", lc, line));
+            errors.push(Error::parse("duplicate output target", lc, line));
         }
     }
 
-    Ok(specs)
+    match errors.len() {
+        0 => Ok(specs),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::ParseErrors(errors)),
+    }
+}
+
+/// `[name]` header of the lines preparing the environment of one component.
+struct Section {
+    name: String,
+    /// Prepended to the variables of the env targets.
+    prefix: String,
+    /// Dotenv file the env targets are written to instead of the environment of the command.
+    file: Option<PathBuf>,
+}
+
+impl Section {
+    /// Moves an env target of `spec` into the section.
+    fn apply(&self, mut spec: SecretSpec, lc: usize, line: &str) -> Result<SecretSpec> {
+        let SecretTarget::Env { name } = spec.target else {
+            return Ok(spec);
+        };
+        let name = format!("{}{}", self.prefix, name);
+        if !is_valid_env_var_name(&name) {
+            return Err(Error::parse(
+                &format!("invalid env var name `{}` with the section prefix", name),
+                lc,
+                line,
+            ));
+        }
+        spec.target = match &self.file {
+            Some(path) => SecretTarget::EnvFile {
+                path: path.clone(),
+                name,
+            },
+            None => SecretTarget::Env { name },
+        };
+
+        Ok(spec)
+    }
+}
+
+/// Parses a `[name prefix=PREFIX file=path]` section header.
+fn parse_section(line: &str, lc: usize) -> Result<Section> {
+    let header = line[1..]
+        .strip_suffix(']')
+        .ok_or_else(|| Error::parse("section header must end with `]`", lc, line))?;
+    let mut tokens = header.split_whitespace();
+    let name = tokens.next().unwrap_or_default();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err(Error::parse(
+            "invalid section name; expected letters, digits, `_`, `-` or `.`",
+            lc,
+            line,
+        ));
+    }
+
+    let mut prefix = None;
+    let mut file = None;
+    for token in tokens {
+        let (key, value) = token
+            .split_once('=')
+            .ok_or_else(|| Error::parse("section options must be key=value", lc, line))?;
+        match key {
+            "prefix" => {
+                if prefix.is_some() {
+                    return Err(Error::parse("duplicate option `prefix`", lc, line));
+                }
+                prefix = Some(value.to_string());
+            }
+            "file" => {
+                if file.is_some() {
+                    return Err(Error::parse("duplicate option `file`", lc, line));
+                }
+                if value.is_empty() {
+                    return Err(Error::parse("section file cannot be empty", lc, line));
+                }
+                file = Some(PathBuf::from(value));
+            }
+            _ => {
+                return Err(Error::parse(
+                    &format!("unknown section option `{}`", key),
+                    lc,
+                    line,
+                ))
+            }
+        }
+    }
+    let prefix = prefix.unwrap_or_else(|| {
+        let mut prefix = name
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+                _ => '_',
+            })
+            .collect::<String>();
+        prefix.push('_');
+        prefix
+    });
+
+    Ok(Section {
+        name: name.to_string(),
+        prefix,
+        file,
+    })
+}
+
+fn parse_line(line: &str, lc: usize) -> Result<SecretSpec> {
+    let mut parts = split_pipes(line);
+    let left = parts[0].trim();
+    let right = match parts.pop() {
+        Some(right) if !parts.is_empty() => right.trim(),
+        _ => return Err(Error::parse("missing output target after `|`", lc, line)),
+    };
+    let encoders = parse_encoders(&parts[1..], lc, line)?;
+
+    let (left, sha256) = parse_checksum(left, lc, line)?;
+    let (provider, source) = parse_provider(left, lc, line)?;
+    let (mount, path, secret) = match provider {
+        Provider::Template => {
+            parse_template(source, lc, line)?;
+            (String::new(), source.to_string(), String::new())
+        }
+        Provider::TemplateFile if source.is_empty() => {
+            return Err(Error::parse(
+                "missing template file after `tplfile:`",
+                lc,
+                line,
+            ));
+        }
+        Provider::TemplateFile => (String::new(), source.to_string(), String::new()),
+        provider => parse_provider_source(provider, source, lc, line)?,
+    };
+    let target = parse_target(right, lc, line)?;
+    Ok(SecretSpec {
+        target,
+        provider,
+        mount,
+        path,
+        secret,
+        template: None,
+        sha256,
+        encoders,
+    })
+}
+
+/// Splits the line at the `|` outside of the `{{source}}` references of a template.
+fn split_pipes(line: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_reference = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '{' if line[idx..].starts_with("{{") => in_reference = true,
+            '}' if line[idx..].starts_with("}}") => in_reference = false,
+            '|' if !in_reference => {
+                parts.push(&line[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&line[start..]);
+
+    parts
+}
+
+fn parse_encoders(names: &[&str], lc: usize, line: &str) -> Result<Vec<Encoder>> {
+    names
+        .iter()
+        .map(|name| {
+            Encoder::from_name(name.trim()).ok_or_else(|| {
+                Error::parse(
+                    &format!(
+                        "unknown encoder `{}`; expected {}",
+                        name.trim(),
+                        Encoder::NAMES
+                    ),
+                    lc,
+                    line,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Parses the source of a provider other than `tpl` into its mount, path and secret.
+fn parse_provider_source(
+    provider: Provider,
+    source: &str,
+    lc: usize,
+    line: &str,
+) -> Result<(String, String, String)> {
+    match provider {
+        Provider::Vault => parse_source(source, lc, line),
+        Provider::Sops | Provider::File => parse_file_source(source, lc, line),
+        Provider::Env => parse_env_source(source, lc, line),
+        Provider::Pki => parse_pki_source(source, lc, line),
+        Provider::Template | Provider::TemplateFile => {
+            Err(Error::parse("templates cannot be nested", lc, line))
+        }
+    }
+}
+
+/// Part of a template with the source parsed into provider, mount, path and secret.
+enum RawTemplatePart<'a> {
+    Literal(&'a str),
+    Source(Provider, String, String, String, Vec<Encoder>),
+}
+
+/// Splits the text of a `tpl:` source into literal text and `{{source}}` references.
+fn parse_template<'a>(
+    template: &'a str,
+    lc: usize,
+    line: &str,
+) -> Result<Vec<RawTemplatePart<'a>>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            parts.push(RawTemplatePart::Literal(&rest[..start]));
+        }
+        let (source, after) = rest[start + 2..]
+            .split_once("}}")
+            .ok_or_else(|| Error::parse("unterminated `{{` in template", lc, line))?;
+        let mut source = source.split('|');
+        let (provider, reference) =
+            parse_provider(source.next().unwrap_or_default().trim(), lc, line)?;
+        let (mount, path, secret) = parse_provider_source(provider, reference, lc, line)?;
+        let encoders = parse_encoders(&source.collect::<Vec<_>>(), lc, line)?;
+        parts.push(RawTemplatePart::Source(
+            provider, mount, path, secret, encoders,
+        ));
+        rest = after;
+    }
+    if !rest.is_empty() {
+        parts.push(RawTemplatePart::Literal(rest));
+    }
+
+    if !parts
+        .iter()
+        .any(|part| matches!(part, RawTemplatePart::Source(..)))
+    {
+        return Err(Error::parse(
+            "template must reference a source as `{{source}}`",
+            lc,
+            line,
+        ));
+    }
+    Ok(parts)
 }
 
 fn strip_comment(line: &str) -> &str {
@@ -141,6 +781,84 @@ fn strip_comment(line: &str) -> &str {
     line
 }
 
+/// Splits a trailing `!sha256:<hex>` checksum of the expected value off the source.
+fn parse_checksum<'a>(source: &'a str, lc: usize, line: &str) -> Result<(&'a str, Option<String>)> {
+    let Some((rest, option)) = source
+        .rsplit_once(char::is_whitespace)
+        .filter(|(_, option)| option.starts_with('!'))
+    else {
+        return Ok((source, None));
+    };
+
+    let digest = option
+        .strip_prefix("!sha256:")
+        .ok_or_else(|| Error::parse("unknown source option; expected `!sha256:<hex>`", lc, line))?;
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::parse(
+            "sha256 checksum must consist of 64 hex characters",
+            lc,
+            line,
+        ));
+    }
+
+    Ok((rest.trim_end(), Some(digest.to_ascii_lowercase())))
+}
+
+/// Splits the `scheme:` prefix selecting the provider off the source.
+///
+/// Only a prefix of lowercase letters in front of the first `/` and `#` is a scheme, so vault
+/// paths containing `:` keep working without prefix.
+fn parse_provider<'a>(source: &'a str, lc: usize, line: &str) -> Result<(Provider, &'a str)> {
+    let scheme = source.split_once(':').and_then(|(scheme, rest)| {
+        (!scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_lowercase()))
+            .then_some((scheme, rest))
+    });
+    let Some((scheme, rest)) = scheme else {
+        return Ok((Provider::Vault, source));
+    };
+
+    let provider = Provider::from_scheme(scheme).ok_or_else(|| {
+        Error::parse(
+            &format!(
+                "unknown secret provider `{}`; expected `vault`, `sops`, `file`, `env`, `pki`, `tpl` or `tplfile`",
+                scheme
+            ),
+            lc,
+            line,
+        )
+    })?;
+    Ok((provider, rest))
+}
+
+/// Parses a `mount/path#secret` reference to a vault secret given on the command line, e.g. to
+/// `put`, into its mount, path and secret.
+pub fn parse_vault_secret(source: &str) -> Result<(String, String, String)> {
+    let source = source.strip_prefix("vault:").unwrap_or(source);
+    parse_source(source, 0, source).map_err(|err| match err {
+        Error::Parse { err, .. } => {
+            Error::Conversion(format!("invalid secret `{}`: {}", source, err))
+        }
+        err => err,
+    })
+}
+
+/// Parses a `mount/path` of a vault secret given on the command line, e.g. to `import`, into its
+/// mount and path.
+pub fn parse_vault_path(source: &str) -> Result<(String, String)> {
+    let source = source.strip_prefix("vault:").unwrap_or(source);
+    source
+        .trim_end_matches('/')
+        .split_once('/')
+        .filter(|(mount, path)| !mount.is_empty() && !path.is_empty() && !path.contains('#'))
+        .map(|(mount, path)| (mount.to_string(), path.to_string()))
+        .ok_or_else(|| {
+            Error::Conversion(format!(
+                "invalid secret path `{}`: must be in format `mount/path`",
+                source
+            ))
+        })
+}
+
 fn parse_source(source: &str, lc: usize, line: &str) -> Result<(String, String, String)> {
     if source.contains('=') {
         return Err(Error::parse(
@@ -170,6 +888,84 @@ fn parse_source(source: &str, lc: usize, line: &str) -> Result<(String, String,
     Ok((mount.to_string(), path.to_string(), secret.to_string()))
 }
 
+/// Parses a `file#key.path` source of a file based provider, the mount is left empty.
+fn parse_file_source(source: &str, lc: usize, line: &str) -> Result<(String, String, String)> {
+    let (file, key) = source
+        .split_once('#')
+        .ok_or_else(|| Error::parse("source must be in format `file#key`", lc, line))?;
+
+    if file.is_empty() {
+        return Err(Error::parse("file cannot be empty", lc, line));
+    }
+    if key.is_empty() || key.split('.').any(str::is_empty) {
+        return Err(Error::parse("key cannot be empty", lc, line));
+    }
+
+    Ok((String::new(), file.to_string(), key.to_string()))
+}
+
+/// Parses the variable name of an `env:NAME` source into the secret, mount and path are empty.
+fn parse_env_source(source: &str, lc: usize, line: &str) -> Result<(String, String, String)> {
+    if !is_valid_env_var_name(source) {
+        return Err(Error::parse("invalid environment variable name", lc, line));
+    }
+
+    Ok((String::new(), String::new(), source.to_string()))
+}
+
+/// Fields of a certificate issued by a PKI secrets engine which can be used as secret.
+const PKI_FIELDS: [&str; 4] = ["certificate", "private_key", "issuing_ca", "ca_chain"];
+
+/// Parses a `mount/issue/role?params#field` source of a certificate into its mount, the path with
+/// the `key=value` parameters of the issue request and the field.
+fn parse_pki_source(source: &str, lc: usize, line: &str) -> Result<(String, String, String)> {
+    let (path_ref, field) = source.split_once('#').ok_or_else(|| {
+        Error::parse(
+            "source must be in format `mount/issue/role?params#field`",
+            lc,
+            line,
+        )
+    })?;
+    let (mount, path) = path_ref
+        .split_once('/')
+        .ok_or_else(|| Error::parse("source must include mount and path", lc, line))?;
+    let (role_path, params) = path.split_once('?').unwrap_or((path, ""));
+
+    if mount.is_empty() {
+        return Err(Error::parse("mount cannot be empty", lc, line));
+    }
+    if !role_path
+        .strip_prefix("issue/")
+        .is_some_and(|role| !role.is_empty() && !role.contains('/'))
+    {
+        return Err(Error::parse(
+            "path must be in format `issue/role`",
+            lc,
+            line,
+        ));
+    }
+    if params
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .any(|param| {
+            !param
+                .split_once('=')
+                .is_some_and(|(key, _)| !key.is_empty())
+        })
+    {
+        return Err(Error::parse("parameters must be key=value", lc, line));
+    }
+    if !PKI_FIELDS.contains(&field) {
+        return Err(Error::parse(
+            "unknown certificate field; expected `certificate`, `private_key`, `issuing_ca` or `ca_chain`",
+            lc,
+            line,
+        ));
+    }
+
+    Ok((mount.to_string(), path.to_string(), field.to_string()))
+}
+
 fn parse_target(target: &str, lc: usize, line: &str) -> Result<SecretTarget> {
     let mut tokens = target.split_whitespace();
     let kind = tokens
@@ -205,6 +1001,8 @@ fn parse_target(target: &str, lc: usize, line: &str) -> Result<SecretTarget> {
 
             let mut mode = 0o600;
             let mut create = false;
+            let mut owner = None;
+            let mut label = None;
             let mut seen_mode = false;
             let mut seen_create = false;
 
@@ -227,6 +1025,22 @@ fn parse_target(target: &str, lc: usize, line: &str) -> Result<SecretTarget> {
                         create = parse_bool(value, lc, line)?;
                         seen_create = true;
                     }
+                    "owner" => {
+                        if owner.is_some() {
+                            return Err(Error::parse("duplicate option `owner`", lc, line));
+                        }
+                        owner =
+                            Some(parse_owner(value).map_err(|err| Error::parse(&err, lc, line))?);
+                    }
+                    "label" => {
+                        if label.is_some() {
+                            return Err(Error::parse("duplicate option `label`", lc, line));
+                        }
+                        if value.is_empty() {
+                            return Err(Error::parse("label cannot be empty", lc, line));
+                        }
+                        label = Some(value.to_string());
+                    }
                     _ => {
                         return Err(Error::parse(
                             &format!("unknown file option `{}`", key),
@@ -241,6 +1055,8 @@ fn parse_target(target: &str, lc: usize, line: &str) -> Result<SecretTarget> {
                 path: PathBuf::from(path),
                 mode,
                 create,
+                owner,
+                label,
             })
         }
         _ => Err(Error::parse(
@@ -277,7 +1093,7 @@ fn parse_bool(value: &str, lc: usize, line: &str) -> Result<bool> {
     }
 }
 
-fn is_valid_env_var_name(name: &str) -> bool {
+pub fn is_valid_env_var_name(name: &str) -> bool {
     let mut chars = name.chars();
     match chars.next() {
         Some(c) if c == '_' || c.is_ascii_alphabetic() => {}
@@ -309,6 +1125,184 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn pass_provider_scheme() {
+        let secrets = parse(
+            r#"
+            vault:secret/app#key | env A
+            secret/app:v2#key | env B
+            "#,
+        )
+        .unwrap();
+        let entry = secrets.get("A").unwrap();
+        assert_eq!(entry.provider, Provider::Vault);
+        assert_eq!(entry.mount, "secret");
+        assert_eq!(entry.path, "app");
+        let entry = secrets.get("B").unwrap();
+        assert_eq!(entry.provider, Provider::Vault);
+        assert_eq!(entry.path, "app:v2");
+    }
+
+    #[test]
+    fn fail_unknown_provider() {
+        assert!(parse("consul:kv/app#key | env A").is_err());
+    }
+
+    #[test]
+    fn pass_pki_source() {
+        let secrets = parse(
+            "pki:pki_int/issue/web?common_name=web.example.com&ttl=72h#certificate | file /run/tls.crt",
+        )
+        .unwrap();
+        let entry = secrets.get("file:/run/tls.crt").unwrap();
+        assert_eq!(entry.provider, Provider::Pki);
+        assert_eq!(entry.mount, "pki_int");
+        assert_eq!(entry.path, "issue/web?common_name=web.example.com&ttl=72h");
+        assert_eq!(entry.secret, "certificate");
+        assert_eq!(
+            entry.source(),
+            "pki:pki_int/issue/web?common_name=web.example.com&ttl=72h#certificate"
+        );
+
+        assert!(parse("pki:pki/sign/web?common_name=a#certificate | env A").is_err());
+        assert!(parse("pki:pki/issue/web?common_name#certificate | env A").is_err());
+        assert!(parse("pki:pki/issue/web?common_name=a#serial | env A").is_err());
+    }
+
+    #[test]
+    fn pass_template_source() {
+        let secrets = parse(
+            "tpl:postgres://{{secret/db#user}}:{{ vault:secret/db#pass }}@{{env:DB_HOST}}:5432/app | env DATABASE_URL",
+        )
+        .unwrap();
+        let entry = secrets.get("DATABASE_URL").unwrap();
+        assert_eq!(entry.provider, Provider::Template);
+        assert_eq!(
+            entry.source(),
+            "tpl:postgres://{{secret/db#user}}:{{ vault:secret/db#pass }}@{{env:DB_HOST}}:5432/app"
+        );
+
+        let parts = entry.template_parts().unwrap();
+        let literals = parts
+            .iter()
+            .filter_map(|part| match part {
+                TemplatePart::Literal(text) => Some(text.as_str()),
+                TemplatePart::Source(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(literals, ["postgres://", ":", "@", ":5432/app"]);
+        let sources = parts
+            .iter()
+            .filter_map(|part| match part {
+                TemplatePart::Source(spec) => Some((spec.name(), spec.source())),
+                TemplatePart::Literal(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                (
+                    "DATABASE_URL[0]".to_string(),
+                    "vault:secret/db#user".to_string()
+                ),
+                (
+                    "DATABASE_URL[1]".to_string(),
+                    "vault:secret/db#pass".to_string()
+                ),
+                ("DATABASE_URL[2]".to_string(), "env:DB_HOST".to_string()),
+            ]
+        );
+
+        assert!(parse("tpl:postgres://db/app | env A").is_err());
+        assert!(parse("tpl:{{secret/db#user | env A").is_err());
+        assert!(parse("tpl:{{secret/db}} | env A").is_err());
+        assert!(parse("tpl:{{tpl:{{secret/db#user}}}} | env A").is_err());
+    }
+
+    #[test]
+    fn pass_encoders() {
+        let secrets = parse(
+            "secret/db#pass | urlencode | shellquote | env DB_PASS\n\
+             tpl:{\"password\": \"{{secret/db#pass|jsonescape}}\"} | file /run/db.json",
+        )
+        .unwrap();
+        assert_eq!(
+            secrets["DB_PASS"].encoders,
+            [Encoder::UrlEncode, Encoder::ShellQuote]
+        );
+        let template = &secrets["file:/run/db.json"];
+        assert!(template.encoders.is_empty());
+        let TemplatePart::Source(source) = &template.template_parts().unwrap()[1] else {
+            panic!("expected a source");
+        };
+        assert_eq!(source.source(), "vault:secret/db#pass");
+        assert_eq!(source.encoders, [Encoder::JsonEscape]);
+
+        assert!(parse("secret/db#pass | base64 | env A").is_err());
+        assert!(parse("secret/db#pass | urlencode").is_err());
+        assert!(parse("tpl:{{secret/db#pass | base64}} | env A").is_err());
+    }
+
+    #[test]
+    fn pass_checksum() {
+        let digest = "2BB80D537B1DA3E38BD30361AA855686BDE0EACD7162FEF6A25FE97BF527A25B";
+        let secrets = parse(&format!(
+            "secret/app#key !sha256:{digest} | env A\nsecret/app#key | env B"
+        ))
+        .unwrap();
+        let entry = secrets.get("A").unwrap();
+        assert_eq!(entry.secret, "key");
+        assert_eq!(
+            entry.sha256.as_deref(),
+            Some(digest.to_lowercase().as_str())
+        );
+        assert_eq!(secrets.get("B").unwrap().sha256, None);
+    }
+
+    #[test]
+    fn fail_checksum() {
+        assert!(parse("secret/app#key !sha256:abc | env A").is_err());
+        assert!(parse("secret/app#key !md5:d41d8cd98f00b204e9800998ecf8427e | env A").is_err());
+    }
+
+    #[test]
+    fn pass_sops_source() {
+        let secrets = parse("sops:secrets.enc.yaml#db.password | env A").unwrap();
+        let entry = secrets.get("A").unwrap();
+        assert_eq!(entry.provider, Provider::Sops);
+        assert_eq!(entry.mount, "");
+        assert_eq!(entry.path, "secrets.enc.yaml");
+        assert_eq!(entry.secret, "db.password");
+
+        assert!(parse("sops:secrets.enc.yaml | env A").is_err());
+        assert!(parse("sops:secrets.enc.yaml#db. | env A").is_err());
+    }
+
+    #[test]
+    fn pass_local_sources() {
+        let secrets = parse(
+            r#"
+            file:dev-secrets.json#db.password | env A
+            env:DEV_API_KEY | env B
+            "#,
+        )
+        .unwrap();
+        let entry = secrets.get("A").unwrap();
+        assert_eq!(entry.provider, Provider::File);
+        assert_eq!(entry.path, "dev-secrets.json");
+        assert_eq!(entry.secret, "db.password");
+        let entry = secrets.get("B").unwrap();
+        assert_eq!(entry.provider, Provider::Env);
+        assert_eq!(entry.secret, "DEV_API_KEY");
+        assert_eq!(entry.source(), "env:DEV_API_KEY");
+        assert_eq!(
+            secrets.get("A").unwrap().source(),
+            "file:dev-secrets.json#db.password"
+        );
+
+        assert!(parse("env:1NVALID | env B").is_err());
+    }
+
     #[test]
     fn pass_file_target_defaults() {
         const SECRET: &str = r#"secret/prod/tls#private_key | file /dev/shm/my-key"#;
@@ -321,26 +1315,56 @@ mod tests {
             SecretTarget::File {
                 ref path,
                 mode: 0o600,
-                create: false
+                create: false,
+                owner: None,
+                label: None,
             } if path == &PathBuf::from("/dev/shm/my-key")
         ));
     }
 
     #[test]
     fn pass_file_target_options() {
-        const SECRET: &str =
-            r#"secret/prod/tls#private_key | file /dev/shm/my-key create=true mode=0640"#;
+        const SECRET: &str = r#"secret/prod/tls#private_key | file /dev/shm/my-key create=true mode=0640 owner=1000:2000 label=system_u:object_r:container_file_t:s0"#;
 
         let secrets = parse(SECRET).unwrap();
         let entry = secrets.get("file:/dev/shm/my-key").unwrap();
-        assert!(matches!(
+        assert_eq!(
             entry.target,
             SecretTarget::File {
+                path: "/dev/shm/my-key".into(),
                 mode: 0o640,
                 create: true,
-                ..
+                owner: Some(Owner {
+                    uid: 1000,
+                    gid: Some(2000)
+                }),
+                label: Some("system_u:object_r:container_file_t:s0".to_string()),
             }
-        ));
+        );
+
+        assert!(parse("a/b#c | file /k owner=app").is_err());
+        assert!(parse("a/b#c | file /k owner=1 owner=2").is_err());
+        assert!(parse("a/b#c | file /k label=").is_err());
+    }
+
+    #[test]
+    fn pass_parse_owner() {
+        assert_eq!(
+            parse_owner("1000"),
+            Ok(Owner {
+                uid: 1000,
+                gid: None
+            })
+        );
+        assert_eq!(
+            parse_owner("1000:2000"),
+            Ok(Owner {
+                uid: 1000,
+                gid: Some(2000)
+            })
+        );
+        assert!(parse_owner("app").is_err());
+        assert!(parse_owner("1000:").is_err());
     }
 
     #[test]
@@ -358,6 +1382,53 @@ mod tests {
         assert!(secrets.contains_key("file:/dev/shm/my-key"));
     }
 
+    #[test]
+    fn pass_sections() {
+        let secrets = parse(
+            r#"
+            kv/shared#token | env TOKEN
+            [service-a]
+            kv/a#db | env DB_URL
+            kv/a#tls | file /run/a.key
+            [worker prefix=] # no prefix
+            kv/b#db | env DB_URL
+            [cron.job prefix=CRON_ file=/run/cron.env]
+            kv/c#db | env DB_URL
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            secrets.keys().collect::<Vec<_>>(),
+            [
+                "DB_URL",
+                "SERVICE_A_DB_URL",
+                "TOKEN",
+                "envfile:/run/cron.env#CRON_DB_URL",
+                "file:/run/a.key"
+            ]
+        );
+        assert_eq!(secrets["DB_URL"].path, "b");
+        assert_eq!(
+            secrets["envfile:/run/cron.env#CRON_DB_URL"].target,
+            SecretTarget::EnvFile {
+                path: PathBuf::from("/run/cron.env"),
+                name: "CRON_DB_URL".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn fail_sections() {
+        assert!(parse("[a\na/b#c | env C").is_err());
+        assert!(parse("[]\na/b#c | env C").is_err());
+        assert!(parse("[a b]\na/b#c | env C").is_err());
+        assert!(parse("[a]\n[a]\na/b#c | env C").is_err());
+        assert!(parse("[a mode=0600]\na/b#c | env C").is_err());
+        assert!(parse("[a prefix=1]\na/b#c | env C").is_err());
+        // the variables of sections without prefix collide
+        assert!(parse("[a prefix=]\na/b#c | env C\n[b prefix=]\na/b#c | env C").is_err());
+    }
+
     #[test]
     fn fail_legacy_format() {
         assert!(parse("BAR_BAZ=foo/bar#baz").is_err());
@@ -390,6 +1461,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fail_all_invalid_lines() {
+        let err = parse(
+            "secret/a#b | env A\nsecret/a#b | env A\nbroken\nsecret/c#d | env B\nsops:#x | env C",
+        )
+        .unwrap_err();
+        let Error::ParseErrors(errors) = err else {
+            panic!("expected all parse errors, got {:?}", err);
+        };
+        let lines = errors
+            .iter()
+            .map(|err| match err {
+                Error::Parse { lc, .. } => *lc,
+                err => panic!("expected a parse error, got {:?}", err),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [2, 3, 5]);
+    }
+
+    #[test]
+    fn pass_vault_secret() {
+        let parsed = parse_vault_secret("vault:kv/apps/web#token").unwrap();
+        assert_eq!(
+            parsed,
+            (
+                "kv".to_string(),
+                "apps/web".to_string(),
+                "token".to_string()
+            )
+        );
+        assert!(matches!(
+            parse_vault_secret("kv/apps/web").unwrap_err(),
+            Error::Conversion(_)
+        ));
+    }
+
     #[test]
     fn pass_load_file() {
         let secrets = load("tests/pass.secrets").unwrap();
@@ -398,7 +1505,93 @@ mod tests {
 
     #[tokio::test]
     async fn pass_load_file_async() {
-        let secrets = load_async("tests/pass.secrets").await.unwrap();
+        let secrets = load_async("tests/pass.secrets", None).await.unwrap();
         assert_eq!(secrets.len(), 4);
     }
+
+    #[tokio::test]
+    async fn pass_load_template_file() {
+        let secrets = load_async("tests/template.secrets", None).await.unwrap();
+        let entry = secrets.get("file:/run/secrets/.pgpass").unwrap();
+        assert_eq!(entry.provider, Provider::TemplateFile);
+        assert_eq!(entry.source(), "tplfile:pgpass.tpl");
+        assert!(entry
+            .template
+            .as_deref()
+            .unwrap()
+            .contains("{{secret/db#user}}"));
+        assert!(entry.secret.is_empty());
+        let sources = entry
+            .template_parts()
+            .unwrap()
+            .into_iter()
+            .filter_map(|part| match part {
+                TemplatePart::Source(spec) => Some(spec.source()),
+                TemplatePart::Literal(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sources, ["vault:secret/db#user", "vault:secret/db#pass"]);
+
+        let err = check_template_file("# pgpass\n{{secret/db#user\n", Path::new("pgpass.tpl"))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("line 2 of \"pgpass.tpl\""),
+            "{}",
+            err
+        );
+        assert!(check_template_file("no sources\n", Path::new("pgpass.tpl")).is_err());
+
+        // relative to the secrets file, not to the working directory
+        assert_eq!(
+            template_path(Path::new("/etc/app/.secrets"), "pgpass.tpl"),
+            Path::new("/etc/app/pgpass.tpl")
+        );
+        assert_eq!(
+            template_path(Path::new("/etc/app/.secrets"), "/srv/pgpass.tpl"),
+            Path::new("/srv/pgpass.tpl")
+        );
+        assert_eq!(
+            template_path(Path::new(".secrets"), "pgpass.tpl"),
+            Path::new("pgpass.tpl")
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_load_encrypted_without_identity() {
+        let err = load_async("tests/encrypted.secrets.age", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--secrets-identity"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn pass_load_encrypted() {
+        let identity = Path::new("tests/encrypted.secrets.key");
+        let secrets = load_async("tests/encrypted.secrets.age", Some(identity))
+            .await
+            .unwrap();
+        assert_eq!(secrets, load("tests/pass.secrets").unwrap());
+    }
+
+    #[tokio::test]
+    async fn fail_load_encrypted_wrong_identity() {
+        let identity = Path::new("tests/wrong.secrets.key");
+        let err = load_async("tests/encrypted.secrets.age", Some(identity))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unable to decrypt"), "{}", err);
+
+        // not an identity file at all
+        let identity = Path::new("tests/pass.secrets");
+        let err = load_async("tests/encrypted.secrets.age", Some(identity))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid age identity"), "{}", err);
+    }
+
+    #[test]
+    fn fail_load_encrypted() {
+        let err = load("tests/encrypted.secrets.age").unwrap_err();
+        assert!(err.to_string().contains("--secrets-identity"), "{}", err);
+    }
 }
@@ -0,0 +1,249 @@
+//! Snapshots of fetched secrets, recorded with `--record` and replayed with `--replay` to run
+//! without access to vault, e.g. in CI.
+use std::{collections::BTreeMap, num::NonZeroU32, path::Path};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::aead::LessSafeKey;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache, crypto,
+    error::{Error, Result},
+    secrets::{Secret, SecretSpecs},
+};
+
+/// Environment variable holding the passphrase of encrypted snapshots.
+pub const SNAPSHOT_KEY_ENV: &str = "VAULTIFY_SNAPSHOT_KEY";
+
+/// PBKDF2 iterations of new snapshots, as recommended by OWASP for HMAC-SHA256.
+const KDF_ITERATIONS: u32 = 600_000;
+
+/// Length of the random salt of each snapshot.
+const SALT_LEN: usize = 16;
+
+/// Value recorded in place of every secret of a redacted snapshot.
+const REDACTED: &str = "redacted";
+
+/// How the values of secrets are written to a snapshot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordValues {
+    /// The values as fetched.
+    Plain,
+    /// Every value is replaced by `redacted`, only the names are recorded.
+    Redacted,
+    /// Each value is encrypted with a key derived from `VAULTIFY_SNAPSHOT_KEY`.
+    Encrypted,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    values: RecordValues,
+    /// Parameters of the key derivation of encrypted snapshots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<Kdf>,
+    /// Values by secret name, base64 encoded if encrypted.
+    secrets: BTreeMap<String, String>,
+    /// kv v2 versions by secret name, for `--metadata-env`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    versions: BTreeMap<String, u64>,
+}
+
+/// PBKDF2-HMAC-SHA256 parameters the key of an encrypted snapshot is derived with.
+#[derive(Serialize, Deserialize)]
+struct Kdf {
+    /// Base64 encoded random salt.
+    salt: String,
+    iterations: NonZeroU32,
+}
+
+impl Kdf {
+    fn key(&self, passphrase: Option<&str>) -> Result<LessSafeKey> {
+        let passphrase = passphrase
+            .filter(|passphrase| !passphrase.is_empty())
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "encrypted snapshots require a passphrase in {}",
+                    SNAPSHOT_KEY_ENV
+                ))
+            })?;
+        let salt = BASE64
+            .decode(&self.salt)
+            .map_err(|err| Error::deserialization(format!("invalid snapshot salt: {}", err)))?;
+
+        crypto::derive_passphrase_key(&salt, self.iterations, passphrase.as_bytes())
+    }
+}
+
+/// The passphrase of encrypted snapshots from `VAULTIFY_SNAPSHOT_KEY`.
+pub fn passphrase() -> Option<String> {
+    std::env::var(SNAPSHOT_KEY_ENV).ok()
+}
+
+/// Writes the secrets to a new snapshot at `path`, encrypting them with a key derived from
+/// `passphrase` if `values` is [`RecordValues::Encrypted`].
+pub async fn record(
+    path: &Path,
+    secrets: &[Secret],
+    values: RecordValues,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    record_with(path, secrets, values, passphrase, KDF_ITERATIONS).await
+}
+
+async fn record_with(
+    path: &Path,
+    secrets: &[Secret],
+    values: RecordValues,
+    passphrase: Option<&str>,
+    iterations: u32,
+) -> Result<()> {
+    let kdf = if values == RecordValues::Encrypted {
+        Some(Kdf {
+            salt: BASE64.encode(crypto::random_bytes(SALT_LEN)?),
+            iterations: NonZeroU32::new(iterations)
+                .ok_or_else(|| Error::Conversion("zero PBKDF2 iterations".to_string()))?,
+        })
+    } else {
+        None
+    };
+    let key = kdf.as_ref().map(|kdf| kdf.key(passphrase)).transpose()?;
+    let recorded = secrets
+        .iter()
+        .map(|secret| {
+            let value = match &key {
+                None if values == RecordValues::Plain => secret.secret.clone(),
+                None => REDACTED.to_string(),
+                Some(key) => BASE64.encode(crypto::seal(key, secret.secret.as_bytes().to_vec())?),
+            };
+            Ok((secret.target.name(), value))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let count = recorded.len();
+    let snapshot = Snapshot {
+        values,
+        kdf,
+        secrets: recorded,
+        versions: cache::versions(secrets),
+    };
+    let contents = serde_json::to_vec_pretty(&snapshot)
+        .map_err(|err| Error::Conversion(format!("unable to serialize snapshot: {}", err)))?;
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let write = async {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = options.open(path).await?;
+        file.write_all(&contents).await?;
+        file.sync_all().await
+    };
+    write
+        .await
+        .map_err(|err| Error::io_at("unable to write snapshot", path, err))?;
+
+    tracing::info!("recorded {} secrets to {:?}", count, path);
+    Ok(())
+}
+
+/// Reads the secrets of all `specs` from the snapshot at `path`, decrypting them with a key
+/// derived from `passphrase` if the snapshot is encrypted.
+pub async fn replay(
+    path: &Path,
+    specs: &SecretSpecs,
+    passphrase: Option<&str>,
+) -> Result<Vec<Secret>> {
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|err| Error::io_at("unable to read snapshot", path, err))?;
+    let snapshot = serde_json::from_slice::<Snapshot>(&contents)
+        .map_err(|err| Error::json(&format!("unable to parse snapshot {:?}", path), err))?;
+    let key = match (snapshot.values, &snapshot.kdf) {
+        (RecordValues::Encrypted, Some(kdf)) => Some(kdf.key(passphrase)?),
+        (RecordValues::Encrypted, None) => {
+            return Err(Error::deserialization(format!(
+                "encrypted snapshot {:?} lacks its key derivation parameters, record it again",
+                path
+            )))
+        }
+        _ => None,
+    };
+
+    specs
+        .values()
+        .map(|spec| {
+            let value = snapshot.secrets.get(&spec.name()).ok_or_else(|| {
+                Error::NotFound(format!(
+                    "snapshot {:?} does not contain `{}`",
+                    path,
+                    spec.name()
+                ))
+            })?;
+            let secret = match &key {
+                None => value.clone(),
+                Some(key) => decrypt(key, value)?,
+            };
+
+            let mut secret = Secret::new(spec.target.clone(), secret);
+            secret.metadata.version = snapshot.versions.get(&spec.name()).copied();
+            Ok(secret)
+        })
+        .collect()
+}
+
+fn decrypt(key: &LessSafeKey, value: &str) -> Result<String> {
+    let sealed = BASE64
+        .decode(value)
+        .map_err(|err| Error::deserialization(format!("invalid encrypted value: {}", err)))?;
+    String::from_utf8(crypto::open(key, sealed)?)
+        .map_err(|err| Error::Conversion(format!("decrypted value is not utf-8: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{SecretSpec, SecretTarget};
+
+    #[tokio::test]
+    async fn pass_record_and_replay() {
+        let target = SecretTarget::Env {
+            name: "API_KEY".to_string(),
+        };
+        let spec = SecretSpec::vault(target.clone(), "secret", "app", "key");
+        let specs = SecretSpecs::from([(spec.name(), spec)]);
+        let mut secret = Secret::new(target, "s3cr3t".to_string());
+        secret.metadata.version = Some(2);
+        let secrets = vec![secret];
+        let path = std::env::temp_dir().join(format!("vaultify-snapshot-{}", std::process::id()));
+
+        let passphrase = Some("passphrase");
+        for (values, expected) in [
+            (RecordValues::Plain, "s3cr3t"),
+            (RecordValues::Redacted, REDACTED),
+            (RecordValues::Encrypted, "s3cr3t"),
+        ] {
+            record_with(&path, &secrets, values, passphrase, 1000)
+                .await
+                .unwrap();
+            let raw = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(raw.contains("s3cr3t"), values == RecordValues::Plain);
+            assert_eq!(raw.contains("salt"), values == RecordValues::Encrypted);
+            let replayed = replay(&path, &specs, passphrase).await.unwrap();
+            assert_eq!(replayed[0].secret, expected);
+            assert_eq!(replayed[0].metadata.version, Some(2));
+        }
+
+        // the salt is random, so the same passphrase yields another key for each snapshot
+        let first = std::fs::read_to_string(&path).unwrap();
+        record_with(&path, &secrets, RecordValues::Encrypted, passphrase, 1000)
+            .await
+            .unwrap();
+        assert_ne!(std::fs::read_to_string(&path).unwrap(), first);
+
+        assert!(replay(&path, &specs, Some("other")).await.is_err());
+        assert!(replay(&path, &specs, None).await.is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
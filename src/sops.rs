@@ -0,0 +1,67 @@
+//! Secrets decrypted from SOPS encrypted files by running the `sops` binary.
+use std::{collections::HashMap, process::Stdio};
+
+use serde_json::Value;
+
+use crate::{
+    error::{Error, Result},
+    provider::{lookup, SecretProvider},
+    secrets::{Secret, SecretSpec},
+    timing::{self, Phase},
+};
+
+/// Resolves `sops:file#key.path` specs.
+///
+/// # Remarks:
+///
+/// Decryption is delegated to `sops`, so age, PGP and KMS keys are configured as usual, e.g. via
+/// `SOPS_AGE_KEY_FILE`. Relative files are resolved against the current working directory.
+pub struct SopsProvider;
+
+impl SecretProvider for SopsProvider {
+    async fn resolve(&self, spec: &SecretSpec) -> Result<Secret> {
+        let document = decrypt(&spec.path).await?;
+        lookup(&document, spec)
+    }
+
+    /// Decrypts every file only once.
+    async fn resolve_all(&self, specs: &[&SecretSpec]) -> Result<Vec<Secret>> {
+        let mut documents = HashMap::new();
+        let mut secrets = Vec::with_capacity(specs.len());
+        for spec in specs {
+            if !documents.contains_key(spec.path.as_str()) {
+                documents.insert(spec.path.as_str(), decrypt(&spec.path).await?);
+            }
+            secrets.push(lookup(&documents[spec.path.as_str()], spec)?);
+        }
+
+        Ok(secrets)
+    }
+}
+
+/// Decrypts `file` into a JSON document, regardless of the format it is stored in.
+async fn decrypt(file: &str) -> Result<Value> {
+    tracing::debug!("decrypting {} with sops", file);
+    let started = std::time::Instant::now();
+    let output = tokio::process::Command::new("sops")
+        .args(["--decrypt", "--output-type", "json"])
+        .arg(file)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|err| Error::Execution(format!("unable to run sops for {}: {}", file, err)))?;
+    timing::record(Phase::Decrypt, started.elapsed(), || {
+        format!("sops:{}", file)
+    });
+    if !output.status.success() {
+        return Err(Error::Execution(format!(
+            "unable to decrypt {} with sops ({}): {}",
+            file,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| Error::json(&format!("unable to parse sops output for {}", file), err))
+}
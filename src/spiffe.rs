@@ -0,0 +1,436 @@
+//! Client of the SPIFFE Workload API, fetching the JWT-SVID of the workload from the agent
+//! (e.g. SPIRE) on its unix domain socket for a JWT login at vault.
+//!
+//! The API is gRPC over HTTP/2; its single call needed here is small enough to encode by hand
+//! instead of pulling in a gRPC stack.
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_TYPE, USER_AGENT},
+    HeaderMap, Request,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+
+use crate::{
+    build_info,
+    error::{Error, Result},
+};
+
+/// Bounds connecting to the agent and fetching the JWT-SVID.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const FETCH_JWT_SVID: &str = "/SpiffeWorkloadAPI/FetchJWTSVID";
+
+/// gRPC status codes the agent answers with while it cannot serve the workload yet, e.g. until
+/// the workload is attested and its registration entry synced.
+const GRPC_PERMISSION_DENIED: u32 = 7;
+const GRPC_UNAVAILABLE: u32 = 14;
+
+/// The path of the socket of `SPIFFE_ENDPOINT_SOCKET`, e.g. `unix:///run/spire/sockets/agent.sock`.
+pub fn socket_path(endpoint: &str) -> Result<PathBuf> {
+    match endpoint
+        .strip_prefix("unix://")
+        .or_else(|| endpoint.strip_prefix("unix:"))
+    {
+        Some(path) if path.starts_with('/') => Ok(PathBuf::from(path)),
+        _ => Err(Error::Conversion(format!(
+            "invalid SPIFFE endpoint socket {:?}: expected an absolute unix:// path",
+            endpoint
+        ))),
+    }
+}
+
+/// Fetches the JWT-SVID of the workload for `audience` from the agent listening on `socket`.
+///
+/// # Remarks:
+///
+/// The agent attests the workload by the process connecting to the socket, so the SVID is of the
+/// default identity of vaultify itself.
+pub async fn fetch_jwt_svid(socket: &Path, audience: &str) -> Result<String> {
+    let url = format!("unix://{}", socket.display());
+    tokio::time::timeout(FETCH_TIMEOUT, fetch(socket, &url, audience))
+        .await
+        .map_err(|_| Error::ReqwestTransient {
+            message: format!(
+                "fetching the JWT-SVID from {} did not complete within {:?}",
+                url, FETCH_TIMEOUT
+            ),
+            url: Some(url.clone()),
+            source: None,
+        })?
+}
+
+async fn fetch(socket: &Path, url: &str, audience: &str) -> Result<String> {
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .map_err(|err| Error::transient("unable to connect to the SPIFFE agent", url, err))?;
+    let (mut sender, conn) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|err| Error::transient("unable to connect to the SPIFFE agent", url, err))?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            tracing::debug!("connection to the SPIFFE agent failed: {}", err);
+        }
+    });
+
+    let request = Request::post(format!("http://localhost{}", FETCH_JWT_SVID))
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("te", "trailers")
+        .header(USER_AGENT, build_info::USER_AGENT)
+        // required by the Workload API, so a proxy cannot forward requests of other workloads
+        .header("workload.spiffe.io", "true")
+        .body(Full::new(Bytes::from(encode_request(audience))))
+        .map_err(|err| Error::Conversion(format!("invalid request to {}: {}", url, err)))?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|err| Error::transient("error requesting the JWT-SVID", url, err))?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|err| Error::transient("error reading the JWT-SVID", url, err))?;
+    if !status.is_success() {
+        return Err(Error::ReqwestTransient {
+            message: format!("the SPIFFE agent at {} answered with {}", url, status),
+            url: Some(url.to_string()),
+            source: None,
+        });
+    }
+
+    // errors come without a body in the headers, the status of a response in the trailers
+    let trailers = body.trailers().cloned().unwrap_or_default();
+    check_status(url, &headers)?;
+    check_status(url, &trailers)?;
+    decode_response(&body.to_bytes())
+}
+
+/// Fails if `headers` carry a gRPC status other than OK.
+fn check_status(url: &str, headers: &HeaderMap) -> Result<()> {
+    let Some(code) = headers
+        .get("grpc-status")
+        .and_then(|code| code.to_str().ok())
+        .and_then(|code| code.parse::<u32>().ok())
+    else {
+        return Ok(());
+    };
+    let message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .unwrap_or_default();
+    let message = format!(
+        "the SPIFFE agent at {} did not issue the JWT-SVID, status {}: {}",
+        url, code, message
+    );
+    match code {
+        0 => Ok(()),
+        GRPC_PERMISSION_DENIED | GRPC_UNAVAILABLE => Err(Error::ReqwestTransient {
+            message,
+            url: Some(url.to_string()),
+            source: None,
+        }),
+        _ => Err(Error::Execution(message)),
+    }
+}
+
+/// The gRPC message of `JWTSVIDRequest { repeated string audience = 1; string spiffe_id = 2; }`.
+fn encode_request(audience: &str) -> Vec<u8> {
+    let mut message = vec![1 << 3 | 2];
+    encode_varint(&mut message, audience.len() as u64);
+    message.extend_from_slice(audience.as_bytes());
+
+    // uncompressed message prefixed with its length
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    frame
+}
+
+/// The first SVID of the gRPC message of `JWTSVIDResponse { repeated JWTSVID svids = 1; }` with
+/// `JWTSVID { string spiffe_id = 1; string svid = 2; ... }`.
+fn decode_response(frame: &[u8]) -> Result<String> {
+    let invalid = |reason: &str| {
+        Error::deserialization(format!(
+            "invalid JWT-SVID response of the SPIFFE agent: {}",
+            reason
+        ))
+    };
+    let message = match frame {
+        [0, length @ ..] if length.len() >= 4 => {
+            let (length, message) = length.split_at(4);
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            message.get(..length).ok_or_else(|| invalid("truncated"))?
+        }
+        [0, ..] | [] => return Err(invalid("truncated")),
+        _ => return Err(invalid("compressed")),
+    };
+
+    let svids = fields(message).map_err(|_| invalid("malformed"))?;
+    let svid = svids
+        .into_iter()
+        .find(|(field, _)| *field == 1)
+        .ok_or_else(|| invalid("no SVID"))?
+        .1;
+    let (spiffe_id, jwt) = fields(svid)
+        .map_err(|_| invalid("malformed"))?
+        .into_iter()
+        .fold((None, None), |(id, jwt), (field, value)| match field {
+            1 => (Some(value), jwt),
+            2 => (id, Some(value)),
+            _ => (id, jwt),
+        });
+    if let Some(spiffe_id) = spiffe_id {
+        tracing::info!(
+            "fetched the JWT-SVID of `{}`",
+            String::from_utf8_lossy(spiffe_id)
+        );
+    }
+    let jwt = jwt.ok_or_else(|| invalid("empty SVID"))?;
+    String::from_utf8(jwt.to_vec()).map_err(|_| invalid("SVID is not UTF-8"))
+}
+
+/// The length-delimited fields of a protobuf `message` by number, skipping the other wire types.
+fn fields(mut message: &[u8]) -> std::result::Result<Vec<(u64, &[u8])>, ()> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = decode_varint(&mut message)?;
+        match key & 0b111 {
+            0 => {
+                decode_varint(&mut message)?;
+            }
+            1 => message = message.get(8..).ok_or(())?,
+            2 => {
+                let length = decode_varint(&mut message)? as usize;
+                let value = message.get(..length).ok_or(())?;
+                message = &message[length..];
+                fields.push((key >> 3, value));
+            }
+            5 => message = message.get(4..).ok_or(())?,
+            _ => return Err(()),
+        }
+    }
+
+    Ok(fields)
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> std::result::Result<u64, ()> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(())?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+
+    Err(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use http_body_util::StreamBody;
+    use hyper::{body::Frame, header::HeaderValue, Response};
+
+    use super::*;
+
+    /// Answer of the test agent to a request for a JWT-SVID.
+    #[derive(Clone)]
+    enum Reply {
+        /// The SVID with the JWT, followed by status OK in the trailers.
+        Svid(&'static str),
+        /// The status in the headers without a message, as gRPC answers most errors.
+        Status(u32, &'static str),
+        /// The status in the trailers after an empty response.
+        TrailerStatus(u32, &'static str),
+    }
+
+    type ReplyBody = StreamBody<
+        futures::stream::Iter<std::vec::IntoIter<std::result::Result<Frame<Bytes>, Infallible>>>,
+    >;
+
+    /// Serves the Workload API on a unix socket in a new directory `name`, answering with
+    /// `replies` in turn and repeating the last one. Returns the socket and the number of requests.
+    async fn start_agent(name: &str, replies: Vec<Reply>) -> (PathBuf, Arc<AtomicUsize>) {
+        let dir =
+            std::env::temp_dir().join(format!("vaultify-spiffe-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("agent.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let count = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (replies, count) = (replies.clone(), count.clone());
+                let service = hyper::service::service_fn(move |request| {
+                    let idx = count.fetch_add(1, Ordering::SeqCst);
+                    respond(request, replies[idx.min(replies.len() - 1)].clone())
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        (socket, requests)
+    }
+
+    async fn respond(
+        request: Request<hyper::body::Incoming>,
+        reply: Reply,
+    ) -> std::result::Result<Response<ReplyBody>, Infallible> {
+        assert_eq!(request.uri().path(), FETCH_JWT_SVID);
+        assert_eq!(request.headers()["workload.spiffe.io"], "true");
+        assert_eq!(request.headers()[CONTENT_TYPE], "application/grpc");
+        let body = request.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, encode_request("vault"));
+
+        let status = |code: u32, message: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("grpc-status", HeaderValue::from(code));
+            headers.insert("grpc-message", HeaderValue::from_static(message));
+            headers
+        };
+        let (headers, frames) = match reply {
+            Reply::Svid(jwt) => (
+                HeaderMap::new(),
+                vec![
+                    Frame::data(Bytes::from(svid_response(jwt))),
+                    Frame::trailers(status(0, "")),
+                ],
+            ),
+            Reply::Status(code, message) => (status(code, message), Vec::new()),
+            Reply::TrailerStatus(code, message) => (
+                HeaderMap::new(),
+                vec![Frame::trailers(status(code, message))],
+            ),
+        };
+        let mut response = Response::new(StreamBody::new(futures::stream::iter(
+            frames.into_iter().map(Ok).collect::<Vec<_>>(),
+        )));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        response.headers_mut().extend(headers);
+        Ok(response)
+    }
+
+    /// `JWTSVIDResponse` with a single JWTSVID of `spiffe://acme/web`, its expiry as a varint
+    /// field.
+    fn svid_response(jwt: &str) -> Vec<u8> {
+        let mut svid = vec![0x0a, 17];
+        svid.extend_from_slice(b"spiffe://acme/web");
+        svid.extend_from_slice(&[0x12, jwt.len() as u8]);
+        svid.extend_from_slice(jwt.as_bytes());
+        svid.extend_from_slice(&[0x20, 0x80, 0x01]);
+        let mut message = vec![0x0a, svid.len() as u8];
+        message.extend(svid);
+        let mut response = vec![0, 0, 0, 0, message.len() as u8];
+        response.extend(message);
+        response
+    }
+
+    #[test]
+    fn pass_encoding() {
+        assert_eq!(
+            socket_path("unix:///run/spire/sockets/agent.sock").unwrap(),
+            Path::new("/run/spire/sockets/agent.sock")
+        );
+        assert!(socket_path("tcp://127.0.0.1:8081").is_err());
+
+        let audience = "a".repeat(200);
+        let request = encode_request(&audience);
+        assert_eq!(request[..8], [0, 0, 0, 0, 203, 0x0a, 0xc8, 0x01]);
+
+        let response = svid_response("ey.j.wt");
+        assert_eq!(decode_response(&response).unwrap(), "ey.j.wt");
+
+        assert!(decode_response(&response[..10]).is_err());
+        assert!(decode_response(&[0, 0, 0, 0, 0]).is_err());
+        assert!(decode_response(&[1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_jwt_svid() {
+        // the agent denies the workload until it is attested, which is retried
+        let (socket, requests) = start_agent(
+            "retry",
+            vec![
+                Reply::Status(GRPC_PERMISSION_DENIED, "no identity issued"),
+                Reply::Svid("ey.j.wt"),
+            ],
+        )
+        .await;
+        let jwt = crate::vault::retry(|| fetch_jwt_svid(&socket, "vault"), 1, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(jwt, "ey.j.wt");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_dir_all(socket.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_fetch_jwt_svid() {
+        let (socket, requests) = start_agent(
+            "denied",
+            vec![Reply::Status(GRPC_PERMISSION_DENIED, "no identity issued")],
+        )
+        .await;
+        let err = crate::vault::retry(|| fetch_jwt_svid(&socket, "vault"), 2, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("status 7: no identity issued"),
+            "{}",
+            err
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        std::fs::remove_dir_all(socket.parent().unwrap()).unwrap();
+
+        // a status in the trailers fails as well, other codes without retries
+        let (socket, requests) = start_agent(
+            "invalid",
+            vec![Reply::TrailerStatus(3, "audience is required")],
+        )
+        .await;
+        let err = crate::vault::retry(|| fetch_jwt_svid(&socket, "vault"), 2, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Execution(_)), "{:?}", err);
+        assert!(
+            err.to_string().contains("status 3: audience is required"),
+            "{}",
+            err
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(socket.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,549 @@
+//! Attached mode: keeps the child running while periodically refreshing its secrets.
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use nix::sys::signal::Signal;
+use tokio::{
+    process::Child,
+    signal::unix::{signal, SignalKind},
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::{
+    error::{Error, Result},
+    health::{self, Fetch},
+    metrics,
+    process::{self, EnvSecret, ProcessGroup, SpawnOptions, StopOptions},
+    secrets::{self, Secret, SecretSpecs},
+    Args, OnChange, PreparedSpawn,
+};
+
+/// Signals received by vaultify which are forwarded to the child.
+const FORWARDED_SIGNALS: [SignalKind; 6] = [
+    SignalKind::hangup(),
+    SignalKind::interrupt(),
+    SignalKind::quit(),
+    SignalKind::terminate(),
+    SignalKind::user_defined1(),
+    SignalKind::user_defined2(),
+];
+
+/// Upper bound for the restart backoff in supervise mode, and the uptime after which a crash
+/// restarts the child after the initial backoff again without --restart-window.
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Minimum delay between attempts to renew certificates, so a failing renewal is retried at
+/// this interval until it succeeds or the certificate expired.
+const CERTIFICATE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Signals the terminal sends to its whole foreground process group.
+const TERMINAL_SIGNALS: [Signal; 2] = [Signal::SIGINT, Signal::SIGQUIT];
+
+/// State of the attached child and the secrets it was started with.
+struct Supervisor<'a> {
+    args: &'a Args,
+    spawn_opts: SpawnOptions,
+    /// The child shares the foreground process group of the terminal with vaultify, so it
+    /// receives signals from the terminal itself.
+    shares_terminal: bool,
+    stop_opts: StopOptions,
+    on_change_signal: Signal,
+    secret_specs: SecretSpecs,
+    secrets: Vec<Secret>,
+    env_secrets: Vec<EnvSecret>,
+    child: Child,
+    /// When the child was last spawned.
+    spawned: Instant,
+}
+
+/// When a crashed child is restarted in supervise mode.
+struct RestartPolicy {
+    max_restarts: usize,
+    /// Only restarts within this window count towards `max_restarts`.
+    window: Option<Duration>,
+    initial_backoff: Duration,
+    backoff: Duration,
+    /// Times of the restarts which count towards `max_restarts`.
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartPolicy {
+    fn new(args: &Args) -> Self {
+        Self {
+            max_restarts: args.max_restarts,
+            window: args.restart_window,
+            initial_backoff: args.restart_backoff,
+            backoff: args.restart_backoff,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Records a crash of the child after it ran for `uptime` and returns the delay before
+    /// restarting it, or `None` if the restarts are exhausted.
+    fn next(&mut self, now: Instant, uptime: Duration) -> Option<Duration> {
+        if let Some(window) = self.window {
+            self.restarts
+                .retain(|restart| now.duration_since(*restart) < window);
+        }
+        // a child which ran for a whole window (or longer than the longest backoff) was not
+        // crash looping
+        if uptime >= self.window.unwrap_or(RESTART_BACKOFF_MAX) {
+            self.backoff = self.initial_backoff;
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return None;
+        }
+
+        self.restarts.push_back(now);
+        let backoff = self.backoff;
+        self.backoff = (self.backoff * 2).min(RESTART_BACKOFF_MAX);
+        Some(backoff)
+    }
+}
+
+/// Spawns the child and supervises it until it exits, returning its exit code.
+pub async fn run(args: &Args, prepared: PreparedSpawn) -> Result<i32> {
+    let spawn_opts = crate::spawn_options(args, &prepared.env_vars);
+    let (cmd, cmd_args) = args.command()?;
+    let _metrics = match args.metrics_addr {
+        Some(addr) => Some(metrics::serve(addr).await?),
+        None => None,
+    };
+    let _health = match args.health_addr {
+        Some(addr) => Some(health::serve(addr).await?),
+        None => None,
+    };
+    health::fetch_completed(health::Fetch::Refresh, None);
+    let shares_terminal =
+        spawn_opts.process_group == ProcessGroup::Inherit && process::owns_terminal();
+    let child = process::spawn_attached(cmd, &cmd_args, &prepared.env_secrets, &spawn_opts)?;
+    health::child_started(false);
+    crate::readiness(args).notify()?;
+    let mut sup = Supervisor {
+        args,
+        spawn_opts,
+        shares_terminal,
+        stop_opts: crate::stop_options(args)?,
+        on_change_signal: match &args.on_change_signal {
+            Some(signal) => process::parse_signal(signal)?,
+            None => Signal::SIGHUP,
+        },
+        secret_specs: prepared.secret_specs,
+        secrets: prepared.secrets,
+        env_secrets: prepared.env_secrets,
+        child,
+        spawned: Instant::now(),
+    };
+
+    let mut signals = ForwardedSignals::new()?;
+    // reports the child being stopped, which `Child::wait` does not
+    let mut child_events = signal(SignalKind::child())
+        .map_err(|err| Error::Execution(format!("unable to install signal handler: {}", err)))?;
+    let mut refresh = args.refresh_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut watcher = watch_secrets_file(args)?;
+
+    let mut restart_policy = RestartPolicy::new(args);
+
+    loop {
+        tokio::select! {
+            status = sup.child.wait() => {
+                let status = status.map_err(|err| {
+                    Error::Execution(format!("unable to wait for child: {}", err))
+                })?;
+                health::child_exited();
+                process::reclaim_terminal(&sup.spawn_opts);
+                let code = process::exit_code(status);
+                if !args.supervise || status.success() {
+                    tracing::info!("child exited with {}", status);
+                    return Ok(code);
+                }
+                let Some(backoff) = restart_policy.next(Instant::now(), sup.spawned.elapsed()) else {
+                    let within = args
+                        .restart_window
+                        .map(|window| format!(" within {:?}", window))
+                        .unwrap_or_default();
+                    tracing::error!(
+                        "child exited with {}, giving up after {} restarts{}",
+                        status,
+                        restart_policy.restarts.len(),
+                        within
+                    );
+                    return Ok(code);
+                };
+
+                metrics::child_restarted("exit");
+                tracing::warn!(
+                    "child exited with {}, restarting in {:?} (restart {}/{})",
+                    status,
+                    backoff,
+                    restart_policy.restarts.len(),
+                    args.max_restarts
+                );
+                let pending = match wait_backoff(&mut signals, backoff).await {
+                    Backoff::Elapsed(pending) => pending,
+                    Backoff::Terminated(signal) => {
+                        tracing::info!("received {} while waiting to restart child", signal);
+                        return Ok(code);
+                    }
+                };
+
+                if let Some(new_secrets) = sup.fetch_changed(Fetch::Refresh).await {
+                    sup.apply(new_secrets)?;
+                }
+                sup.spawn()?;
+                for signal in pending {
+                    tracing::info!("forwarding {} received while waiting to restart to child", signal);
+                    process::signal_child(&sup.child, signal)?;
+                }
+            }
+            Some(signal) = signals.recv() => {
+                if sup.shares_terminal && TERMINAL_SIGNALS.contains(&signal) {
+                    // e.g. Ctrl-C cancelling a query of an interactive client, the child decides
+                    tracing::info!("child received {} from the terminal as well, leaving it to the child", signal);
+                    continue;
+                }
+                if is_termination(signal) {
+                    let stop_signal = sup.stop_opts.signal.unwrap_or(signal);
+                    tracing::info!("received {}, stopping child with {}", signal, stop_signal);
+                    let status = process::stop_child(&mut sup.child, stop_signal, sup.stop_opts.timeout).await?;
+                    process::reclaim_terminal(&sup.spawn_opts);
+                    tracing::info!("child exited with {}", status);
+                    return Ok(process::exit_code(status));
+                }
+
+                tracing::info!("forwarding {} to child", signal);
+                process::signal_child(&sup.child, signal)?;
+            }
+            Some(()) = child_events.recv(), if sup.spawn_opts.foreground => {
+                if process::child_stopped(&sup.child) {
+                    // the child took over the terminal, so the shell only sees vaultify stop
+                    tracing::info!("child was stopped, suspending vaultify as well");
+                    process::suspend_with_child(&sup.child, &sup.spawn_opts)?;
+                    tracing::info!("continued, continuing child");
+                }
+            }
+            _ = tick(&mut refresh) => {
+                if let Some(new_secrets) = sup.fetch_changed(Fetch::Refresh).await {
+                    sup.on_change(new_secrets).await?;
+                }
+            }
+            _ = certificate_renewal(args.vault.next_certificate_renewal()) => {
+                tracing::info!("renewing certificates before they expire");
+                if let Some(new_secrets) = sup.fetch_changed(Fetch::CertificateRenewal).await {
+                    sup.on_change(new_secrets).await?;
+                }
+            }
+            changed = watch(&mut watcher) => {
+                changed?;
+                match secrets::load_async(&args.secrets_file, args.secrets_identity.as_deref()).await {
+                    Ok(specs) => {
+                        tracing::info!("{} changed, reloading secrets", args.secrets_file.display());
+                        sup.secret_specs = specs;
+                        if let Some(new_secrets) = sup.fetch_changed(Fetch::Refresh).await {
+                            sup.on_change(new_secrets).await?;
+                        }
+                    }
+                    Err(err) => tracing::warn!(
+                        "ignoring change of {}, unable to parse it: {}",
+                        args.secrets_file.display(),
+                        err
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl Supervisor<'_> {
+    /// Re-fetches all secrets and returns them if they differ from the current ones.
+    async fn fetch_changed(&self, fetch: Fetch) -> Option<Vec<Secret>> {
+        let fetched = crate::fetch_secrets(self.args, &self.secret_specs).await;
+        match fetch {
+            Fetch::Refresh => metrics::refresh_completed(fetched.is_ok()),
+            Fetch::CertificateRenewal => metrics::renewal_completed(fetched.is_ok()),
+        }
+        health::fetch_completed(fetch, fetched.as_ref().err().map(ToString::to_string));
+        match fetched {
+            Ok(secrets) if secrets != self.secrets => Some(secrets),
+            Ok(_) => {
+                tracing::info!("secrets unchanged after refresh");
+                None
+            }
+            Err(err) => {
+                tracing::warn!("unable to refresh secrets, keeping current values: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Writes file targets and stores the secrets used for the next spawn of the child.
+    fn apply(&mut self, secrets: Vec<Secret>) -> Result<()> {
+        self.env_secrets = crate::apply_secrets(self.args, &secrets)?;
+        self.secrets = secrets;
+
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<()> {
+        let (cmd, cmd_args) = self.args.command()?;
+        self.child = process::spawn_attached(cmd, &cmd_args, &self.env_secrets, &self.spawn_opts)?;
+        self.spawned = Instant::now();
+        health::child_started(true);
+
+        Ok(())
+    }
+
+    /// Applies changed secrets and notifies the child according to `--on-change`.
+    async fn on_change(&mut self, secrets: Vec<Secret>) -> Result<()> {
+        self.apply(secrets)?;
+
+        match self.args.on_change() {
+            OnChange::Signal => {
+                tracing::info!(
+                    "secrets changed, sending {} to child",
+                    self.on_change_signal
+                );
+                process::signal_child(&self.child, self.on_change_signal)?;
+            }
+            OnChange::Restart => {
+                tracing::info!("secrets changed, restarting child");
+                process::stop_child(
+                    &mut self.child,
+                    self.stop_opts.signal.unwrap_or(Signal::SIGTERM),
+                    self.stop_opts.timeout,
+                )
+                .await?;
+                self.spawn()?;
+                metrics::child_restarted("secrets_changed");
+            }
+            OnChange::Hook => {
+                if let Some(hook) = self.args.on_change_hook.as_deref() {
+                    tracing::info!("secrets changed, running on-change hook");
+                    run_hook(hook, &self.env_secrets, &self.spawn_opts).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How the restart backoff ended.
+#[derive(Debug, PartialEq)]
+enum Backoff {
+    /// The backoff elapsed, with the other signals received meanwhile in their order.
+    Elapsed(Vec<Signal>),
+    /// A termination signal cut the backoff short.
+    Terminated(Signal),
+}
+
+/// Waits for the restart `backoff` while there is no child, queueing the signals which would
+/// have been forwarded to it so the restarted child receives them.
+async fn wait_backoff(signals: &mut ForwardedSignals, backoff: Duration) -> Backoff {
+    let sleep = tokio::time::sleep(backoff);
+    tokio::pin!(sleep);
+    let mut pending = Vec::new();
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return Backoff::Elapsed(pending),
+            Some(signal) = signals.recv() => {
+                if is_termination(signal) {
+                    return Backoff::Terminated(signal);
+                }
+                // like pending signals of a process, repeated ones are delivered once
+                if !pending.contains(&signal) {
+                    tracing::info!("received {} while waiting to restart child, forwarding it after the restart", signal);
+                    pending.push(signal);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `signal` asks the supervised process tree to shut down.
+pub fn is_termination(signal: Signal) -> bool {
+    matches!(signal, Signal::SIGINT | Signal::SIGQUIT | Signal::SIGTERM)
+}
+
+/// Runs the on-change hook through `/bin/sh -c`; failures are logged but not fatal.
+async fn run_hook(hook: &str, env_secrets: &[EnvSecret], opts: &SpawnOptions) {
+    let args = ["-c".to_string(), hook.to_string()];
+    let status = match process::command("/bin/sh", &args, env_secrets, opts) {
+        Ok(mut command) => match process::spawn_command(&mut command, env_secrets, opts) {
+            Ok(mut child) => child.wait().await,
+            Err(err) => Err(err),
+        },
+        Err(err) => {
+            tracing::warn!("unable to prepare on-change hook: {}", err);
+            return;
+        }
+    };
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => tracing::warn!("on-change hook exited with {}", status),
+        Err(err) => tracing::warn!("unable to run on-change hook: {}", err),
+    }
+}
+
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until the certificates are due for renewal at `renew_at`, but at least
+/// `CERTIFICATE_RETRY_INTERVAL`.
+async fn certificate_renewal(renew_at: Option<SystemTime>) {
+    match renew_at {
+        Some(renew_at) => {
+            let delay = renew_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .max(CERTIFICATE_RETRY_INTERVAL);
+            tokio::time::sleep(delay).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+type Watcher = crate::watch::FileWatcher;
+#[cfg(not(target_os = "linux"))]
+type Watcher = std::convert::Infallible;
+
+fn watch_secrets_file(args: &Args) -> Result<Option<Watcher>> {
+    if !args.watch_secrets_file {
+        return Ok(None);
+    }
+
+    #[cfg(target_os = "linux")]
+    return crate::watch::FileWatcher::new(&args.secrets_file).map(Some);
+
+    #[cfg(not(target_os = "linux"))]
+    return Err(Error::Execution(
+        "--watch-secrets-file is only supported on linux".to_string(),
+    ));
+}
+
+async fn watch(watcher: &mut Option<Watcher>) -> Result<()> {
+    match watcher {
+        #[cfg(target_os = "linux")]
+        Some(watcher) => watcher.changed().await,
+        #[cfg(not(target_os = "linux"))]
+        Some(watcher) => match *watcher {},
+        None => std::future::pending().await,
+    }
+}
+
+/// Listens for all signals in `FORWARDED_SIGNALS`.
+pub struct ForwardedSignals {
+    streams: Vec<(Signal, tokio::signal::unix::Signal)>,
+}
+
+impl ForwardedSignals {
+    pub fn new() -> Result<Self> {
+        let mut streams = Vec::with_capacity(FORWARDED_SIGNALS.len());
+        for kind in FORWARDED_SIGNALS {
+            let stream = signal(kind).map_err(|err| {
+                Error::Execution(format!("unable to install signal handler: {}", err))
+            })?;
+            let signal = Signal::try_from(kind.as_raw_value())
+                .map_err(|err| Error::Execution(format!("unknown signal: {}", err)))?;
+            streams.push((signal, stream));
+        }
+
+        Ok(Self { streams })
+    }
+
+    pub async fn recv(&mut self) -> Option<Signal> {
+        let futures = self
+            .streams
+            .iter_mut()
+            .map(|(signal, stream)| Box::pin(async move { stream.recv().await.map(|_| *signal) }));
+        futures::future::select_all(futures).await.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_restart_policy() {
+        let mut policy = RestartPolicy {
+            max_restarts: 2,
+            window: Some(Duration::from_secs(60)),
+            initial_backoff: Duration::from_secs(1),
+            backoff: Duration::from_secs(1),
+            restarts: VecDeque::new(),
+        };
+        let start = Instant::now();
+        let crash = Duration::from_secs(1);
+        assert_eq!(policy.next(start, crash), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next(start, crash), Some(Duration::from_secs(2)));
+        assert_eq!(policy.next(start, crash), None);
+
+        // restarts outside of the window no longer count, a long run resets the backoff
+        let later = start + Duration::from_secs(90);
+        assert_eq!(policy.next(later, crash), Some(Duration::from_secs(4)));
+        let stable = Duration::from_secs(60);
+        assert_eq!(policy.next(later, stable), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next(later, stable), None);
+
+        // without a window every restart counts, a run beyond the longest backoff resets it
+        let mut policy = RestartPolicy {
+            max_restarts: 3,
+            window: None,
+            initial_backoff: Duration::from_secs(1),
+            backoff: Duration::from_secs(1),
+            restarts: VecDeque::new(),
+        };
+        assert_eq!(policy.next(start, crash), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next(start, stable), Some(Duration::from_secs(2)));
+        assert_eq!(
+            policy.next(start, RESTART_BACKOFF_MAX),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.next(later, crash), None);
+    }
+
+    #[tokio::test]
+    async fn pass_wait_backoff() {
+        let mut signals = ForwardedSignals::new().unwrap();
+        let backoff = Duration::from_millis(300);
+        let start = Instant::now();
+        let raise = tokio::spawn(async {
+            for signal in [Signal::SIGHUP, Signal::SIGUSR1, Signal::SIGHUP] {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                nix::sys::signal::raise(signal).unwrap();
+            }
+        });
+        let ended = wait_backoff(&mut signals, backoff).await;
+        raise.await.unwrap();
+        assert!(start.elapsed() >= backoff);
+        assert_eq!(
+            ended,
+            Backoff::Elapsed(vec![Signal::SIGHUP, Signal::SIGUSR1])
+        );
+
+        let start = Instant::now();
+        let raise = tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            nix::sys::signal::raise(Signal::SIGTERM).unwrap();
+        });
+        let ended = wait_backoff(&mut signals, Duration::from_secs(10)).await;
+        raise.await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(ended, Backoff::Terminated(Signal::SIGTERM));
+    }
+}
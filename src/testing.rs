@@ -0,0 +1,638 @@
+//! In-process server emulating the parts of the vault API used by vaultify, so tests run without
+//! a live vault.
+//!
+//! Available in tests, and to downstream crates as `vaultify::testing` with the `testing` feature.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Map, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// Version of a kv secrets engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvVersion {
+    V1,
+    V2,
+}
+
+/// Mock vault serving `sys/health`, `sys/capabilities-self`, `sys/internal/ui/mounts`,
+/// `auth/<backend>/login`, `auth/token/lookup-self`, reads and lists of kv v1 and v2 secrets, the subkeys of kv v2 secrets,
+/// check-and-set writes of kv v2 secrets, `sys/leases/revoke` and certificates issued by PKI
+/// mounts, as well as the OIDC token endpoint of a GitHub Actions runner.
+///
+/// # Remarks:
+///
+/// Every login succeeds and returns `MockVault::TOKEN`, all other endpoints except the health
+/// check require it. The server is stopped when the mock is dropped.
+pub struct MockVault {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    mounts: HashMap<String, KvVersion>,
+    /// TTL of the certificates issued by the PKI mounts.
+    pki_mounts: HashMap<String, Duration>,
+    /// Number of certificates issued so far, used for their serial numbers.
+    issued: usize,
+    /// Secrets by `<mount>/<path>`.
+    secrets: HashMap<String, Map<String, Value>>,
+    /// Latest version of the secrets by `<mount>/<path>`.
+    versions: HashMap<String, u64>,
+    /// `<mount>/<path>` of the kv v1 secrets which are leased like dynamic secrets.
+    leased: Vec<String>,
+    /// Ids of the leases which have not been revoked.
+    leases: Vec<String>,
+    /// Number of leases granted so far, used for their ids.
+    lease_count: usize,
+    /// `<METHOD> <path>` of all requests received.
+    requests: Vec<String>,
+    /// Delay of the answers to reads of the secrets by `<mount>/<path>`.
+    delays: HashMap<String, Duration>,
+    /// Whether every request is answered like by a sealed vault.
+    sealed: bool,
+    /// Number of connections accepted so far.
+    connections: usize,
+}
+
+impl State {
+    fn store(&mut self, key: String, data: Map<String, Value>) -> u64 {
+        let version = self.versions.entry(key.clone()).or_default();
+        *version += 1;
+        let version = *version;
+        self.secrets.insert(key, data);
+        version
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+    /// Value of the `Authorization` header.
+    authorization: Option<String>,
+}
+
+impl MockVault {
+    /// Token returned by every login.
+    pub const TOKEN: &'static str = "mock-vault-token";
+    /// Accessor of `TOKEN`, which has the policies `default` and `app`.
+    pub const ACCESSOR: &'static str = "mock-vault-accessor";
+    /// Path of the OIDC token endpoint of a GitHub Actions runner, which answers requests with
+    /// `ACTIONS_REQUEST_TOKEN` with the JWT `oidc:<audience>`.
+    pub const ACTIONS_TOKEN_PATH: &'static str = "/_apis/idtoken";
+    /// Bearer token of the requests for an OIDC token.
+    pub const ACTIONS_REQUEST_TOKEN: &'static str = "mock-actions-token";
+
+    /// Starts the server on a random local port.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, server_state.clone()));
+            }
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            server,
+        })
+    }
+
+    /// Address to pass as `--host`.
+    pub fn addr(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Mounts a kv secrets engine of the given version at `mount`.
+    pub fn mount(&self, mount: &str, version: KvVersion) {
+        self.state().mounts.insert(mount.to_string(), version);
+    }
+
+    /// Mounts a PKI secrets engine at `mount` whose roles issue certificates valid for `ttl`.
+    pub fn mount_pki(&self, mount: &str, ttl: Duration) {
+        self.state().pki_mounts.insert(mount.to_string(), ttl);
+    }
+
+    /// Stores the secret at `path` of `mount` as new version.
+    ///
+    /// # Panics:
+    ///
+    /// If `data` is not a JSON object.
+    pub fn put(&self, mount: &str, path: &str, data: Value) {
+        let Value::Object(data) = data else {
+            panic!("secret data must be an object");
+        };
+        self.state().store(format!("{mount}/{path}"), data);
+    }
+
+    /// Stores a kv v1 secret at `path` of `mount` which gets a new lease on every read, like a
+    /// dynamic secret.
+    ///
+    /// # Panics:
+    ///
+    /// If `data` is not a JSON object.
+    pub fn put_leased(&self, mount: &str, path: &str, data: Value) {
+        self.put(mount, path, data);
+        self.state().leased.push(format!("{mount}/{path}"));
+    }
+
+    /// Delays the answers to reads of the secret at `path` of `mount`, e.g. to emulate a hung
+    /// connection.
+    pub fn delay(&self, mount: &str, path: &str, delay: Duration) {
+        self.state().delays.insert(format!("{mount}/{path}"), delay);
+    }
+
+    /// Answers every further request like a sealed vault, e.g. to test the failover to another
+    /// address.
+    pub fn seal(&self) {
+        self.state().sealed = true;
+    }
+
+    /// Answers requests normally again after `seal`.
+    pub fn unseal(&self) {
+        self.state().sealed = false;
+    }
+
+    /// Returns the ids of the leases which have not been revoked.
+    pub fn leases(&self) -> Vec<String> {
+        self.state().leases.clone()
+    }
+
+    /// Returns the latest version of the secret at `path` of `mount` and its data.
+    pub fn get(&self, mount: &str, path: &str) -> Option<(u64, Value)> {
+        let state = self.state();
+        let key = format!("{mount}/{path}");
+        let data = state.secrets.get(&key)?;
+        Some((state.versions[&key], Value::Object(data.clone())))
+    }
+
+    /// Returns `<METHOD> <path>` of all requests received so far, e.g. `GET /v1/sys/health`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+
+    /// Number of connections accepted so far, which serve requests until the client closes them.
+    pub fn connections(&self) -> usize {
+        self.state().connections
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for MockVault {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Answers the requests of a connection until the client closes it.
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    state
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .connections += 1;
+    let mut stream = BufReader::new(stream);
+    while let Ok(request) = read_request(&mut stream).await {
+        if respond(&mut stream, &state, request).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn respond(
+    stream: &mut BufReader<TcpStream>,
+    state: &Mutex<State>,
+    request: Request,
+) -> std::io::Result<()> {
+    let delay = {
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .requests
+            .push(format!("{} {}", request.method, request.path));
+        let path = request.path.split('?').next().unwrap_or_default();
+        let key = match path.trim_start_matches("/v1/").split_once('/') {
+            Some((mount, rest)) => format!("{mount}/{}", rest.trim_start_matches("data/")),
+            None => String::new(),
+        };
+        state.delays.get(&key).copied()
+    };
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let (status, body) = {
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        route(&mut state, &request)
+    };
+
+    let body = match status {
+        204 => String::new(),
+        _ => body.to_string(),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(response.as_bytes()).await
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> std::io::Result<Request> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut token = None;
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "x-vault-token" => token = Some(value.trim().to_string()),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                "content-length" => content_length = value.trim().parse().unwrap_or_default(),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        token,
+        body,
+        authorization,
+    })
+}
+
+fn route(state: &mut State, request: &Request) -> (u16, Value) {
+    let (path, query) = request
+        .path
+        .split_once('?')
+        .unwrap_or((request.path.as_str(), ""));
+    if path == MockVault::ACTIONS_TOKEN_PATH {
+        let expected = format!("Bearer {}", MockVault::ACTIONS_REQUEST_TOKEN);
+        if request.authorization.as_deref() != Some(expected.as_str()) {
+            return (401, serde_json::json!({"message": "unauthorized"}));
+        }
+        let audience = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("audience="))
+            .unwrap_or_default();
+        return (
+            200,
+            serde_json::json!({"value": format!("oidc:{audience}")}),
+        );
+    }
+    let Some(path) = path.strip_prefix("/v1/") else {
+        return not_found();
+    };
+    if state.sealed {
+        return (503, serde_json::json!({"errors": ["Vault is sealed"]}));
+    }
+
+    if path == "sys/health" {
+        return (
+            200,
+            serde_json::json!({"initialized": true, "sealed": false, "standby": false}),
+        );
+    }
+    if request.method == "POST" && path.starts_with("auth/") && path.ends_with("/login") {
+        return (
+            200,
+            serde_json::json!({
+                "auth": {
+                    "client_token": MockVault::TOKEN,
+                    "accessor": MockVault::ACCESSOR,
+                    "policies": ["default", "app"],
+                    "lease_duration": 3600,
+                    "renewable": true,
+                }
+            }),
+        );
+    }
+    if request.token.as_deref() != Some(MockVault::TOKEN) {
+        return (403, serde_json::json!({"errors": ["permission denied"]}));
+    }
+
+    match (request.method.as_str(), path) {
+        ("GET", "auth/token/lookup-self") => (
+            200,
+            serde_json::json!({
+                "data": {
+                    "accessor": MockVault::ACCESSOR,
+                    "policies": ["default"],
+                    "identity_policies": ["app"],
+                    "ttl": 0,
+                    "renewable": false,
+                }
+            }),
+        ),
+        ("POST", "sys/capabilities-self") => (200, capabilities(state, &request.body)),
+        ("GET", "sys/internal/ui/mounts") => (200, mounts(state)),
+        ("GET", path) if path.starts_with("sys/internal/ui/mounts/") => {
+            mount_info(state, &path["sys/internal/ui/mounts/".len()..])
+        }
+        ("GET", path) if query.split('&').any(|param| param == "list=true") => {
+            list_secrets(state, path)
+        }
+        ("GET", path) => {
+            let (status, mut secret) = read_secret(state, path);
+            if status == 200 && state.leased.iter().any(|leased| leased == path) {
+                state.lease_count += 1;
+                let lease_id = format!("{path}/{}", state.lease_count);
+                secret["lease_id"] = Value::String(lease_id.clone());
+                secret["lease_duration"] = Value::from(60);
+                state.leases.push(lease_id);
+            }
+            (status, secret)
+        }
+        ("PUT", "sys/leases/revoke") => {
+            let lease_id = serde_json::from_slice::<Value>(&request.body)
+                .ok()
+                .and_then(|body| body.get("lease_id")?.as_str().map(str::to_string));
+            match lease_id {
+                Some(lease_id) => {
+                    state.leases.retain(|lease| *lease != lease_id);
+                    (204, Value::Null)
+                }
+                None => (400, serde_json::json!({"errors": ["missing lease_id"]})),
+            }
+        }
+        ("POST", path) if state.pki_mounts.contains_key(mount_of(path)) => {
+            issue_certificate(state, path, &request.body)
+        }
+        ("POST", path) => write_secret(state, path, &request.body),
+        _ => not_found(),
+    }
+}
+
+/// Grants `read` on every path a secret can be read from, like a token with a policy limited to
+/// the stored secrets.
+fn capabilities(state: &State, body: &[u8]) -> Value {
+    let paths = serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|body| body.get("paths").cloned())
+        .and_then(|paths| serde_json::from_value::<Vec<String>>(paths).ok())
+        .unwrap_or_default();
+
+    let caps = paths
+        .into_iter()
+        .map(|path| {
+            let cap = if read_secret(state, &path).0 == 200 {
+                "read"
+            } else {
+                "deny"
+            };
+            (path, serde_json::json!([cap]))
+        })
+        .collect::<Map<_, _>>();
+    let mut response = caps.clone();
+    response.insert("data".to_string(), Value::Object(caps));
+    Value::Object(response)
+}
+
+/// Lists the mounts, like vault does for its ui.
+fn mounts(state: &State) -> Value {
+    let mut secret = state
+        .mounts
+        .keys()
+        .map(|mount| {
+            (
+                format!("{mount}/"),
+                mount_info(state, mount).1["data"].clone(),
+            )
+        })
+        .collect::<Map<_, _>>();
+    for mount in state.pki_mounts.keys() {
+        secret.insert(
+            format!("{mount}/"),
+            serde_json::json!({"path": format!("{mount}/"), "type": "pki", "options": null}),
+        );
+    }
+    serde_json::json!({"data": {"auth": {}, "secret": secret}})
+}
+
+/// Describes the mount `path` is in, like vault does for its ui.
+fn mount_info(state: &State, path: &str) -> (u16, Value) {
+    let mount = path.split('/').next().unwrap_or_default();
+    let version = match state.mounts.get(mount) {
+        Some(KvVersion::V1) => "1",
+        Some(KvVersion::V2) => "2",
+        None => return not_found(),
+    };
+    (
+        200,
+        serde_json::json!({
+            "data": {"path": format!("{mount}/"), "type": "kv", "options": {"version": version}}
+        }),
+    )
+}
+
+/// Writes a kv v2 secret, checking `options.cas` against its latest version if set.
+fn write_secret(state: &mut State, path: &str, body: &[u8]) -> (u16, Value) {
+    let Some((mount, rest)) = path.split_once('/') else {
+        return not_found();
+    };
+    let (Some(KvVersion::V2), Some(rest)) = (state.mounts.get(mount), rest.strip_prefix("data/"))
+    else {
+        return not_found();
+    };
+    let Ok(body) = serde_json::from_slice::<Value>(body) else {
+        return (
+            400,
+            serde_json::json!({"errors": ["failed to parse JSON input"]}),
+        );
+    };
+    let Some(Value::Object(data)) = body.get("data").cloned() else {
+        return (400, serde_json::json!({"errors": ["no data provided"]}));
+    };
+
+    let key = format!("{mount}/{rest}");
+    let current = state.versions.get(&key).copied().unwrap_or(0);
+    if let Some(cas) = body.pointer("/options/cas").and_then(Value::as_u64) {
+        if cas != current {
+            return (
+                400,
+                serde_json::json!({
+                    "errors": ["check-and-set parameter did not match the current version"]
+                }),
+            );
+        }
+    }
+    let version = state.store(key, data);
+    (200, serde_json::json!({"data": {"version": version}}))
+}
+
+fn read_secret(state: &State, path: &str) -> (u16, Value) {
+    let Some((mount, rest)) = path.split_once('/') else {
+        return not_found();
+    };
+    let secret = match state.mounts.get(mount) {
+        Some(KvVersion::V2) if rest.starts_with("subkeys/") => {
+            let key = format!("{mount}/{}", &rest["subkeys/".len()..]);
+            state.secrets.get(&key).map(|data| {
+                let subkeys = data
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = match value {
+                            Value::Object(_) => Value::Object(Map::new()),
+                            _ => Value::Null,
+                        };
+                        (name.clone(), value)
+                    })
+                    .collect::<Map<_, _>>();
+                serde_json::json!({
+                    "data": {"subkeys": subkeys, "metadata": {"version": state.versions[&key]}},
+                })
+            })
+        }
+        Some(KvVersion::V2) => rest
+            .strip_prefix("data/")
+            .map(|rest| format!("{mount}/{rest}"))
+            .and_then(|key| Some((state.secrets.get(&key)?, state.versions[&key])))
+            .map(|(data, version)| {
+                serde_json::json!({
+                    "data": {"data": data, "metadata": {"version": version}},
+                    "lease_duration": 0,
+                    "lease_id": "",
+                    "renewable": false,
+                })
+            }),
+        Some(KvVersion::V1) => state.secrets.get(path).map(|data| {
+            serde_json::json!({
+                "data": data,
+                "lease_duration": 2764800,
+                "lease_id": "",
+                "renewable": false,
+            })
+        }),
+        None => None,
+    };
+
+    match secret {
+        Some(secret) => (200, secret),
+        None => not_found(),
+    }
+}
+
+/// Lists the secrets and folders directly below a folder, via `<mount>/metadata/` for kv v2.
+fn list_secrets(state: &State, path: &str) -> (u16, Value) {
+    let Some((mount, rest)) = path.split_once('/') else {
+        return not_found();
+    };
+    let folder = match state.mounts.get(mount) {
+        Some(KvVersion::V2) => rest.strip_prefix("metadata/"),
+        Some(KvVersion::V1) => Some(rest),
+        None => None,
+    };
+    let Some(folder) = folder else {
+        return not_found();
+    };
+
+    let prefix = format!("{mount}/{folder}");
+    let keys = state
+        .secrets
+        .keys()
+        .filter_map(|key| key.strip_prefix(&prefix))
+        .map(|rest| match rest.find('/') {
+            Some(idx) => &rest[..=idx],
+            None => rest,
+        })
+        .collect::<std::collections::BTreeSet<_>>();
+    if keys.is_empty() {
+        return not_found();
+    }
+    (200, serde_json::json!({"data": {"keys": keys}}))
+}
+
+/// Issues a dummy certificate for `common_name` via `<mount>/issue/<role>`, its fields contain
+/// the serial number to tell certificates apart.
+fn issue_certificate(state: &mut State, path: &str, body: &[u8]) -> (u16, Value) {
+    let ttl = state.pki_mounts[mount_of(path)];
+    if !path[mount_of(path).len()..].starts_with("/issue/") {
+        return not_found();
+    }
+    let common_name = serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|body| body.get("common_name")?.as_str().map(str::to_string));
+    let Some(common_name) = common_name else {
+        return (
+            400,
+            serde_json::json!({"errors": ["the common_name field is required"]}),
+        );
+    };
+
+    state.issued += 1;
+    let serial = state.issued;
+    let expiration = (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (
+        200,
+        serde_json::json!({
+            "data": {
+                "certificate": format!("certificate {serial} for {common_name}"),
+                "private_key": format!("private key {serial}"),
+                "issuing_ca": "issuing ca",
+                "ca_chain": ["issuing ca", "root ca"],
+                "expiration": expiration,
+                "serial_number": serial.to_string(),
+            }
+        }),
+    )
+}
+
+fn mount_of(path: &str) -> &str {
+    path.split('/').next().unwrap_or_default()
+}
+
+fn not_found() -> (u16, Value) {
+    (404, serde_json::json!({"errors": []}))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,147 @@
+//! Startup timing report of `--timing`, breaking down where fetching the secrets spent its time.
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Step of the startup, reported in this order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Parse,
+    Auth,
+    Preflight,
+    Fetch,
+    Decrypt,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Auth => "auth",
+            Phase::Preflight => "preflight",
+            Phase::Fetch => "fetch",
+            Phase::Decrypt => "decrypt",
+        }
+    }
+}
+
+struct Entry {
+    phase: Phase,
+    subject: String,
+    elapsed: Duration,
+}
+
+/// Starts recording timings until the report is taken.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Records that `phase` took `elapsed` for the subject returned by `subject`, e.g. a vault path.
+pub fn record(phase: Phase, elapsed: Duration, subject: impl FnOnce() -> String) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    ENTRIES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Entry {
+            phase,
+            subject: subject(),
+            elapsed,
+        });
+}
+
+/// Stops recording and returns the report of everything recorded so far, noting if fetching the
+/// secrets `failed`.
+pub fn take_report(total: Duration, failed: bool) -> String {
+    ENABLED.store(false, Ordering::Relaxed);
+    let entries = std::mem::take(&mut *ENTRIES.lock().unwrap_or_else(PoisonError::into_inner));
+    format_report(entries, total, failed)
+}
+
+/// Lists the entries by phase, slowest first, and marks the slowest secret.
+///
+/// # Remarks:
+///
+/// Secrets are fetched concurrently, so their durations can add up to more than the total.
+fn format_report(mut entries: Vec<Entry>, total: Duration, failed: bool) -> String {
+    entries.sort_by(|a, b| a.phase.cmp(&b.phase).then(b.elapsed.cmp(&a.elapsed)));
+    let slowest = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| matches!(entry.phase, Phase::Fetch | Phase::Decrypt))
+        .max_by_key(|(_, entry)| entry.elapsed)
+        .map(|(idx, _)| idx);
+
+    let mut report = format!(
+        "startup timing: {:.3}s total{}\n",
+        total.as_secs_f64(),
+        if failed { ", failed" } else { "" }
+    );
+    for (idx, entry) in entries.iter().enumerate() {
+        let _ = writeln!(
+            report,
+            "  {:<9} {:>8.3}s  {}{}",
+            entry.phase.name(),
+            entry.elapsed.as_secs_f64(),
+            entry.subject,
+            if Some(idx) == slowest {
+                "  <- slowest"
+            } else {
+                ""
+            }
+        );
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_format_report() {
+        let entry = |phase, subject: &str, millis| Entry {
+            phase,
+            subject: subject.to_string(),
+            elapsed: Duration::from_millis(millis),
+        };
+        let report = format_report(
+            vec![
+                entry(Phase::Fetch, "secret/fast", 50),
+                entry(Phase::Fetch, "secret/slow", 19300),
+                entry(Phase::Auth, "github login at `https://vault`", 1021),
+            ],
+            Duration::from_millis(20412),
+            false,
+        );
+
+        assert_eq!(
+            report,
+            "startup timing: 20.412s total\n\
+             \x20 auth         1.021s  github login at `https://vault`\n\
+             \x20 fetch       19.300s  secret/slow  <- slowest\n\
+             \x20 fetch        0.050s  secret/fast\n"
+        );
+
+        let report = format_report(
+            vec![entry(Phase::Auth, "token login at `https://vault`", 3)],
+            Duration::from_millis(30000),
+            true,
+        );
+        assert_eq!(
+            report,
+            "startup timing: 30.000s total, failed\n\
+             \x20 auth         0.003s  token login at `https://vault`\n"
+        );
+    }
+}
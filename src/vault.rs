@@ -1,15 +1,52 @@
-use std::{future::Future, sync::OnceLock, time::Duration};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use reqwest::{header::CONTENT_TYPE, Client};
-use serde_json::Value;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Certificate, Client, Identity, Method, NoProxy, Proxy,
+};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tracing::Instrument;
 
 use crate::{
+    build_info, crypto,
     error::{Error, Result},
-    secrets::{Secret, SecretSpec, SecretSpecs},
+    glob, logging, metrics,
+    provider::{self, SecretProvider},
+    ratelimit::RateLimiter,
+    secrets::{Provider, Secret, SecretSpec},
+    timing::{self, Phase},
     AuthMethod,
 };
 
-/// Options passed to `fetch_token`.
+pub mod breaker;
+mod types;
+
+use types::{
+    GitHubActionsTokenResponse, KvListResponse, KvSubkeysResponse, KvV1Response, KvV2Response,
+    KvV2WriteResponse, LoginResponse, PkiIssueResponse, TokenLookupResponse,
+};
+
+/// Header carrying the vault token.
+const VAULT_TOKEN_HEADER: &str = "X-Vault-Token";
+/// Header carrying the replication state of a request on Vault Enterprise.
+const VAULT_INDEX_HEADER: &str = "X-Vault-Index";
+/// Header selecting the namespace of a request on Vault Enterprise.
+const VAULT_NAMESPACE_HEADER: &str = "X-Vault-Namespace";
+
+/// Options passed to `VaultClient::login`.
+#[derive(Clone, Copy)]
 pub struct FetchTokenOpts {
     /// Number of retries per query.
     pub retries: usize,
@@ -17,379 +54,2594 @@ pub struct FetchTokenOpts {
     pub retry_delay: Duration,
 }
 
-/// Fetches the vault token or returns it depending on the `AuthMethod`.
-pub async fn fetch_token(
-    host: &str,
-    auth_method: AuthMethod,
-    opts: FetchTokenOpts,
-) -> Result<Option<String>> {
-    match auth_method {
-        AuthMethod::GitHub { token, backend } => {
-            retry(
-                || async { fetch_token_github(host, &token, &backend).await.map(Some) },
-                opts.retries,
-                opts.retry_delay,
+/// Interval between health checks in `wait_for_health`.
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Client for the vault API at a single address.
+///
+/// Requests are sent via the transport of its `Context`, authenticated with the token obtained by
+/// `login` and scoped to the namespace, if any.
+pub struct VaultClient {
+    /// Address of vault, e.g. `https://vault.example.com:8200`.
+    host: String,
+    /// Vault Enterprise namespace of all requests.
+    namespace: Option<String>,
+    /// Vault token, `None` when requests are sent unauthenticated (e.g. to a Vault Agent).
+    token: Option<String>,
+    /// `X-Vault-Index` returned by the login, sent with all reads so that performance standbys
+    /// only answer once they replicated the token.
+    index: Option<String>,
+    /// Accessor and policies of the token obtained by the login.
+    token_info: Option<TokenInfo>,
+    context: Context,
+}
+
+/// What a token is, without the token itself, to correlate with the audit log of vault.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TokenInfo {
+    pub accessor: String,
+    pub policies: Vec<String>,
+    /// Seconds until the token expires, 0 for tokens without expiry.
+    pub ttl: u64,
+    pub renewable: bool,
+    /// Identity entity of the token, empty if it has none.
+    pub entity_id: String,
+}
+
+/// Token obtained by a login.
+struct Login {
+    token: String,
+    /// `X-Vault-Index` of the login response.
+    index: Option<String>,
+    info: Option<TokenInfo>,
+}
+
+impl VaultClient {
+    /// Creates an unauthenticated client for the vault at `host`, sharing the state of `context`.
+    pub fn new(context: &Context, host: &str, namespace: Option<&str>) -> Self {
+        Self {
+            host: host.to_string(),
+            namespace: namespace.map(str::to_string),
+            token: None,
+            index: None,
+            token_info: None,
+            context: context.clone(),
+        }
+    }
+
+    /// Accessor and policies of the token obtained by the login, `None` for a static token which
+    /// cannot look itself up.
+    pub fn token_info(&self) -> Option<&TokenInfo> {
+        self.token_info.as_ref()
+    }
+
+    /// Looks up accessor and policies of the token via `auth/token/lookup-self`.
+    pub async fn lookup_self(&self, opts: &FetchTokenOpts) -> Result<TokenInfo> {
+        let data = retry(
+            || async {
+                self.send(Method::GET, "auth/token/lookup-self", None)
+                    .await?
+                    .json::<TokenLookupResponse>()
+            },
+            opts.retries,
+            opts.retry_delay,
+        )
+        .await?
+        .data;
+
+        let mut policies = data.policies;
+        policies.extend(data.identity_policies);
+        Ok(TokenInfo {
+            accessor: data.accessor,
+            policies,
+            ttl: data.ttl,
+            renewable: data.renewable,
+            entity_id: data.entity_id,
+        })
+    }
+
+    /// Fetches the vault token or uses it directly depending on the `AuthMethod`.
+    pub async fn login(&mut self, auth_method: AuthMethod, opts: FetchTokenOpts) -> Result<()> {
+        let method = match &auth_method {
+            AuthMethod::GitHub { .. } => Some("github"),
+            AuthMethod::Kubernetes { .. } => Some("kubernetes"),
+            AuthMethod::GitHubActions { .. } => Some("github actions"),
+            AuthMethod::GitLab { .. } => Some("gitlab"),
+            #[cfg(unix)]
+            AuthMethod::Spiffe { .. } => Some("spiffe"),
+            AuthMethod::Token(_) => None,
+        };
+        if let Some(credential) = auth_method.credential() {
+            logging::redact_secrets([credential]);
+        }
+        let started = Instant::now();
+        let span = tracing::info_span!(
+            "login",
+            vault_host = %self.host,
+            attempts = tracing::field::Empty
+        );
+        let login = async {
+            Ok::<_, Error>(match auth_method {
+                AuthMethod::GitHub { token, backend } => {
+                    tracing::info!("fetching token via github from `{}`", self.host);
+                    let body = serde_json::json!({
+                        "token": token,
+                    });
+                    retry(
+                        || self.login_with(&backend, &body),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?
+                }
+                AuthMethod::Kubernetes { role, backend } => {
+                    // read service account jwt
+                    const KUBE_SA_TOKEN: &str =
+                        "/var/run/secrets/kubernetes.io/serviceaccount/token";
+                    let jwt = tokio::fs::read_to_string(KUBE_SA_TOKEN)
+                        .await
+                        .map_err(|err| Error::io_at("unable to read file", KUBE_SA_TOKEN, err))?;
+                    logging::redact_secrets([jwt.trim()]);
+
+                    tracing::info!("fetching token via kubernetes role from `{}`", self.host);
+                    let body = serde_json::json!({
+                        "jwt": jwt,
+                        "role": role,
+                    });
+                    retry(
+                        || self.login_with(&backend, &body),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?
+                }
+                AuthMethod::GitHubActions {
+                    request_url,
+                    request_token,
+                    audience,
+                    role,
+                    backend,
+                } => {
+                    tracing::info!("requesting the OIDC token of the GitHub Actions job");
+                    let jwt = retry(
+                        || github_actions_token(&request_url, &request_token, audience.as_deref()),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?;
+                    logging::redact_secrets([jwt.as_str()]);
+
+                    tracing::info!("fetching token via github actions from `{}`", self.host);
+                    let mut body = serde_json::json!({
+                        "jwt": jwt,
+                    });
+                    if let Some(role) = role {
+                        body["role"] = Value::String(role);
+                    }
+                    retry(
+                        || self.login_with(&backend, &body),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?
+                }
+                AuthMethod::GitLab { jwt, role, backend } => {
+                    tracing::info!("fetching token via gitlab role from `{}`", self.host);
+                    let body = serde_json::json!({
+                        "jwt": jwt,
+                        "role": role,
+                    });
+                    retry(
+                        || self.login_with(&backend, &body),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?
+                }
+                #[cfg(unix)]
+                AuthMethod::Spiffe {
+                    socket,
+                    audience,
+                    role,
+                    backend,
+                } => {
+                    tracing::info!(
+                        "fetching the JWT-SVID from the SPIFFE agent at {:?}",
+                        socket
+                    );
+                    let jwt = retry(
+                        || crate::spiffe::fetch_jwt_svid(&socket, &audience),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?;
+                    logging::redact_secrets([jwt.as_str()]);
+
+                    tracing::info!("fetching token via spiffe from `{}`", self.host);
+                    let mut body = serde_json::json!({
+                        "jwt": jwt,
+                    });
+                    if let Some(role) = role {
+                        body["role"] = Value::String(role);
+                    }
+                    retry(
+                        || self.login_with(&backend, &body),
+                        opts.retries,
+                        opts.retry_delay,
+                    )
+                    .await?
+                }
+                AuthMethod::Token(token) => Login {
+                    token,
+                    index: None,
+                    info: None,
+                },
+            })
+        };
+        let login = login.instrument(span).await?;
+        if let Some(method) = method {
+            timing::record(Phase::Auth, started.elapsed(), || {
+                format!("{} login at `{}`", method, self.host)
+            });
+        }
+
+        self.token = Some(login.token);
+        self.index = login.index;
+        self.token_info = match login.info {
+            Some(info) => Some(info),
+            // a static token is looked up to log what it is as well, if its policy allows it
+            None => match self
+                .lookup_self(&FetchTokenOpts { retries: 0, ..opts })
+                .await
+            {
+                Ok(info) => Some(info),
+                Err(err) => {
+                    tracing::debug!("unable to look up the token at `{}`: {}", self.host, err);
+                    None
+                }
+            },
+        };
+        if let Some(info) = &self.token_info {
+            tracing::info!(
+                accessor = info.accessor.as_str(),
+                "logged in at `{}` with token accessor `{}`, policies [{}], ttl {}s",
+                self.host,
+                info.accessor,
+                info.policies.join(", "),
+                info.ttl
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Logs in at the auth backend mounted at `backend`, returning the token with its
+    /// `X-Vault-Index`.
+    async fn login_with(&self, backend: &str, body: &Value) -> Result<Login> {
+        let started = Instant::now();
+        let response = self
+            .send(Method::POST, &format!("auth/{backend}/login"), Some(body))
+            .await?;
+        let index = response.index.clone();
+        let login = response.json::<LoginResponse>()?;
+        logging::redact_secrets([login.auth.client_token.as_str()]);
+        tracing::debug!(
+            request_id = login.request_id.as_str(),
+            duration_ms = started.elapsed().as_millis() as u64,
+            "obtained token valid for {}s (renewable: {})",
+            login.auth.lease.lease_duration,
+            login.auth.lease.renewable
+        );
+        if login.auth.lease.lease_duration > 0 {
+            metrics::lease_granted(
+                "token",
+                Duration::from_secs(login.auth.lease.lease_duration),
+            );
+        }
+
+        Ok(Login {
+            token: login.auth.client_token,
+            index,
+            info: Some(TokenInfo {
+                accessor: login.auth.accessor,
+                policies: login.auth.policies,
+                ttl: login.auth.lease.lease_duration,
+                renewable: login.auth.lease.renewable,
+                entity_id: login.auth.entity_id,
+            }),
+        })
+    }
+
+    /// Checks via `sys/capabilities-self` that the token can read all secrets before fetching
+    /// them.
+    ///
+    /// Fails with a single error listing every path the token cannot read, either as v2 or v1
+    /// secret. Paths whose secrets all match the `allow_missing` globs are only warned about.
+    ///
+    /// # Remarks:
+    ///
+    /// If the capabilities cannot be queried, e.g. as the policy of the token does not allow it,
+    /// the check is skipped. Only errors which warrant a failover to another vault are returned.
+    pub async fn preflight(
+        &self,
+        secrets: &[&SecretSpec],
+        retries: usize,
+        retry_delay: Duration,
+        allow_missing: &[String],
+    ) -> Result<()> {
+        let paths = group_by_path(secrets);
+        let candidates = paths
+            .iter()
+            .filter_map(|specs| specs.first())
+            .flat_map(|spec| candidate_paths(spec))
+            .collect::<Vec<_>>();
+
+        tracing::info!("checking capabilities of the token at `{}`", self.host);
+        let started = Instant::now();
+        let body = serde_json::json!({
+            "paths": candidates,
+        });
+        let capabilities = retry(
+            || async {
+                self.send(Method::POST, "sys/capabilities-self", Some(&body))
+                    .await?
+                    .json::<Value>()
+            },
+            retries,
+            retry_delay,
+        )
+        .await;
+        timing::record(Phase::Preflight, started.elapsed(), || {
+            format!(
+                "capabilities of {} paths at `{}`",
+                candidates.len(),
+                self.host
             )
+        });
+        let capabilities = match capabilities {
+            Ok(capabilities) => capabilities,
+            Err(err) if should_failover(&err) => return Err(err),
+            Err(err) => {
+                tracing::info!(
+                    "unable to check capabilities of the token, skipping: {}",
+                    err
+                );
+                return Ok(());
+            }
+        };
+
+        let mut missing = Vec::new();
+        for specs in &paths {
+            let Some(spec) = specs.first() else {
+                continue;
+            };
+            let readable = candidate_paths(spec)
+                .iter()
+                .any(|path| can_read(&capabilities, path));
+            if readable {
+                continue;
+            }
+            let path = format!("{}/{} ({})", spec.mount, spec.path, secret_names(specs));
+            if specs
+                .iter()
+                .all(|spec| glob::matches_any(allow_missing, &spec.name()))
+            {
+                tracing::warn!("token is not allowed to read {}, continuing", path);
+            } else {
+                missing.push(path);
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Error::PermissionDenied(format!(
+                "token is not allowed to read {}",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Learns the type and kv version of all mounts via `sys/internal/ui/mounts`, so secrets are
+    /// read with the right API right away.
+    ///
+    /// Fails with a single error listing the mounts of `secrets` which do not exist (or are not
+    /// visible to the token); mounts whose secrets all match the `allow_missing` globs are only
+    /// warned about.
+    ///
+    /// # Remarks:
+    ///
+    /// If the mounts cannot be listed the versions are detected on the first failed read instead.
+    /// Only errors which warrant a failover to another vault are returned. Nothing is requested
+    /// if the versions of all mounts of `secrets` are known for this vault, e.g. on a refresh.
+    pub async fn detect_mounts(
+        &self,
+        secrets: &[&SecretSpec],
+        retries: usize,
+        retry_delay: Duration,
+        allow_missing: &[String],
+    ) -> Result<()> {
+        {
+            let versions = lock(&self.context.0.kv_versions);
+            if secrets
+                .iter()
+                .all(|spec| versions.contains_key(&self.mount_key(&spec.mount)))
+            {
+                return Ok(());
+            }
+        }
+
+        let started = Instant::now();
+        let listing = retry(
+            || async {
+                self.send(Method::GET, "sys/internal/ui/mounts", None)
+                    .await?
+                    .json::<Value>()
+            },
+            retries,
+            retry_delay,
+        )
+        .await;
+        timing::record(Phase::Preflight, started.elapsed(), || {
+            format!("mounts at `{}`", self.host)
+        });
+        let listing = match listing {
+            Ok(listing) => listing,
+            Err(err) if should_failover(&err) => return Err(err),
+            Err(err) => {
+                tracing::info!("unable to list the mounts, detecting them on use: {}", err);
+                return Ok(());
+            }
+        };
+
+        let mounts = listing
+            .pointer("/data/secret")
+            .and_then(Value::as_object)
+            .map(|mounts| {
+                mounts
+                    .iter()
+                    .map(|(path, info)| (path.trim_end_matches('/'), info))
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+        {
+            let mut versions = lock(&self.context.0.kv_versions);
+            for (mount, info) in &mounts {
+                if info.get("type").and_then(Value::as_str) == Some("kv") {
+                    let version = info.pointer("/options/version").and_then(Value::as_str);
+                    versions.insert(self.mount_key(mount), version == Some("2"));
+                }
+            }
+        }
+
+        let mut missing = BTreeMap::<&str, Vec<&SecretSpec>>::new();
+        for spec in secrets {
+            // mounts may span several path segments, e.g. `team/kv`
+            let prefix = format!("{}/", spec.mount);
+            if !mounts
+                .keys()
+                .any(|mount| *mount == spec.mount || mount.starts_with(&prefix))
+            {
+                missing.entry(&spec.mount).or_default().push(spec);
+            }
+        }
+        let mut unknown = Vec::new();
+        for (mount, specs) in missing {
+            let mount = format!("`{}` ({})", mount, secret_names(&specs));
+            if specs
+                .iter()
+                .all(|spec| glob::matches_any(allow_missing, &spec.name()))
+            {
+                tracing::warn!("no such mount {}, continuing", mount);
+            } else {
+                unknown.push(mount);
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(Error::NotFound(format!(
+                "no such mount {} at `{}`; mounts visible to the token: {}",
+                unknown.join(", "),
+                self.host,
+                mounts.keys().copied().collect::<Vec<_>>().join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Fetches a list of secrets from vault with retry and batching.
+    ///
+    /// Secrets stored under the same mount and path are fetched with a single request, so
+    /// identical specs with different targets are read once and receive the same value. Missing
+    /// secrets matching `opts.allow_missing` are left out.
+    pub async fn fetch_all(
+        &self,
+        secrets: &[&SecretSpec],
+        opts: &FetchAllOpts,
+    ) -> Result<Vec<Secret>> {
+        let limiter = opts.rate_limit.map(RateLimiter::new);
+        let limiter = limiter.as_ref();
+
+        // at most `concurrency` paths are in flight, a slow path doesn't hold back the others; the
+        // first error drops the stream, which cancels the fetches still in flight
+        let mut paths = futures::stream::iter(group_by_path(secrets).into_iter().enumerate())
+            .map(|(idx, specs)| async move {
+                let span = tracing::info_span!(
+                    "fetch_secret",
+                    secrets = %secret_names(&specs),
+                    attempts = tracing::field::Empty
+                );
+                let started = Instant::now();
+                let secrets =
+                    provider::resolve_available(&specs, &opts.allow_missing, |specs| async move {
+                        retry(
+                            || self.fetch_path_within(&specs, limiter, opts.fetch_timeout),
+                            opts.retries,
+                            opts.retry_delay,
+                        )
+                        .await
+                        .map_err(|err| {
+                            let names = specs.iter().map(|spec| spec.name()).collect::<Vec<_>>();
+                            err.for_secrets(&names)
+                        })
+                    })
+                    .instrument(span)
+                    .await?;
+                timing::record(Phase::Fetch, started.elapsed(), || {
+                    let path = specs
+                        .first()
+                        .map(|spec| format!("{}/{}", spec.mount, spec.path))
+                        .unwrap_or_default();
+                    format!("{}: {}", path, secret_names(&specs))
+                });
+                Ok::<_, Error>((idx, secrets))
+            })
+            .buffer_unordered(opts.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // restore the order of the specs, so results of different fetches can be compared
+        paths.sort_unstable_by_key(|(idx, _)| *idx);
+        Ok(paths.into_iter().flat_map(|(_, secrets)| secrets).collect())
+    }
+
+    /// Fetches the secrets of one path like `fetch_path`, failing with a transient error once
+    /// `timeout` elapsed.
+    async fn fetch_path_within(
+        &self,
+        secrets: &[&SecretSpec],
+        limiter: Option<&RateLimiter>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Secret>> {
+        let fetch = self.fetch_path(secrets, limiter);
+        let Some(timeout) = timeout else {
+            return fetch.await;
+        };
+
+        tokio::time::timeout(timeout, fetch)
             .await
+            .unwrap_or_else(|_| {
+                let url = secrets
+                    .first()
+                    .map(|spec| format!("{}/v1/{}/{}", self.host, spec.mount, spec.path));
+                Err(Error::ReqwestTransient {
+                    message: format!(
+                        "fetching {} did not complete within {:?}",
+                        secret_names(secrets),
+                        timeout
+                    ),
+                    url,
+                    source: None,
+                })
+            })
+    }
+
+    /// Fetches all secrets stored under one path from vault v2 and fallbacks to vault v1 on error.
+    ///
+    /// # Remarks:
+    ///
+    /// All `secrets` must share the same mount and path. Each request waits for `limiter`, if set.
+    pub async fn fetch_path(
+        &self,
+        secrets: &[&SecretSpec],
+        limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<Secret>> {
+        if secrets
+            .first()
+            .is_some_and(|spec| spec.provider == Provider::Pki)
+        {
+            return self.issue_certificate(secrets, limiter).await;
+        }
+        let names = secret_names(secrets);
+        let mount = secrets.first().map_or("", |spec| spec.mount.as_str());
+
+        // try to fetch a v2 secret, unless the mount is known to be v1
+        if self.known_kv_v2(mount) != Some(false) {
+            match self.fetch_path_v2(secrets, limiter).await {
+                Ok(secrets) => return Ok(secrets),
+                Err(err) if !should_fallback_to_v1(&err) => return Err(err),
+                Err(err) => {
+                    let v2 = match self.known_kv_v2(mount) {
+                        Some(v2) => Some(v2),
+                        None => self.detect_kv_v2(mount).await,
+                    };
+                    match v2 {
+                        // the secret is missing from a v2 mount, a v1 read would only obscure that
+                        Some(true) => return Err(err),
+                        Some(false) => {
+                            tracing::debug!(
+                                "{} is a kv v1 mount, reading {} from it",
+                                mount,
+                                names
+                            );
+                        }
+                        None => tracing::warn!(
+                            "could not fetch v2 secrets {} from vault, trying v1 fallback: {}",
+                            names,
+                            err
+                        ),
+                    }
+                }
+            }
+        }
+
+        // fallback to fetching a v1 secret
+        match self.fetch_path_v1(secrets, limiter).await {
+            Ok(secrets) => Ok(secrets),
+            Err(err) => {
+                tracing::warn!("could not fetch v1 secrets {} from vault: {}", names, err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn fetch_path_v2(
+        &self,
+        secret_specs: &[&SecretSpec],
+        limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<Secret>> {
+        let Some(first) = secret_specs.first() else {
+            return Ok(Vec::new());
+        };
+        let path = format!("{}/data/{}", first.mount, first.path);
+        tracing::info!(
+            vault_path = path.as_str(),
+            "fetching v2 secrets {} from `{}/v1/{}`",
+            secret_names(secret_specs),
+            self.host,
+            path
+        );
+
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
+        let started = Instant::now();
+        let response = self
+            .send(Method::GET, &path, None)
+            .await?
+            .json::<KvV2Response>()?;
+        tracing::debug!(
+            vault_path = path.as_str(),
+            request_id = response.request_id.as_str(),
+            duration_ms = started.elapsed().as_millis() as u64,
+            "fetched v2 secrets {}",
+            secret_names(secret_specs)
+        );
+
+        let version = response.data.metadata.map(|metadata| metadata.version);
+        let mut secrets = extract_secrets(&response.data.data, ".data.data", secret_specs)
+            .map_err(|err| err.for_request(&response.request_id))?;
+        for secret in &mut secrets {
+            secret.metadata.version = version;
         }
-        AuthMethod::Kubernetes { role, backend } => {
-            retry(
+        Ok(secrets)
+    }
+
+    async fn fetch_path_v1(
+        &self,
+        secret_specs: &[&SecretSpec],
+        limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<Secret>> {
+        let Some(first) = secret_specs.first() else {
+            return Ok(Vec::new());
+        };
+        let path = format!("{}/{}", first.mount, first.path);
+        tracing::info!(
+            vault_path = path.as_str(),
+            "fetching v1 secrets {} from `{}/v1/{}`",
+            secret_names(secret_specs),
+            self.host,
+            path
+        );
+
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
+        let started = Instant::now();
+        let response = self
+            .send(Method::GET, &path, None)
+            .await?
+            .json::<KvV1Response>()?;
+        tracing::debug!(
+            vault_path = path.as_str(),
+            request_id = response.request_id.as_str(),
+            duration_ms = started.elapsed().as_millis() as u64,
+            "fetched v1 secrets {}",
+            secret_names(secret_specs)
+        );
+        let mut secrets = extract_secrets(&response.data, ".data", secret_specs)
+            .map_err(|err| err.for_request(&response.request_id))?;
+        if !response.lease.lease_id.is_empty() {
+            let duration = Duration::from_secs(response.lease.lease_duration);
+            metrics::lease_granted(&path, duration);
+            for secret in &mut secrets {
+                secret.metadata.lease_id = Some(response.lease.lease_id.clone());
+                secret.metadata.expires_at = Some(SystemTime::now() + duration);
+            }
+            tracing::debug!(
+                "secrets {} are leased as `{}` for {}s (renewable: {})",
+                secret_names(secret_specs),
+                response.lease.lease_id,
+                response.lease.lease_duration,
+                response.lease.renewable
+            );
+            if let Some(leases) = lock(&self.context.0.leases).as_mut() {
+                leases.push(IssuedLease {
+                    host: self.host.clone(),
+                    namespace: self.namespace.clone(),
+                    token: self.token.clone(),
+                    lease_id: response.lease.lease_id.clone(),
+                });
+            }
+        }
+
+        Ok(secrets)
+    }
+
+    /// Issues the certificate of `pki:` secrets with the parameters in their path, or returns the
+    /// fields of the certificate issued before until it is due for renewal.
+    ///
+    /// # Remarks:
+    ///
+    /// All `secrets` must share the same mount and path. `ca_chain` is joined into one PEM bundle.
+    async fn issue_certificate(
+        &self,
+        secret_specs: &[&SecretSpec],
+        limiter: Option<&RateLimiter>,
+    ) -> Result<Vec<Secret>> {
+        let Some(first) = secret_specs.first() else {
+            return Ok(Vec::new());
+        };
+        let key = format!("{}/{}", first.mount, first.path);
+        let issued = lock(&self.context.0.certificates)
+            .get(&key)
+            .filter(|cert| SystemTime::now() < cert.renew_at)
+            .map(|cert| cert.secrets(secret_specs));
+        if let Some(secrets) = issued {
+            return secrets;
+        }
+
+        let (role_path, params) = first.path.split_once('?').unwrap_or((&first.path, ""));
+        let path = format!("{}/{}", first.mount, role_path);
+        let body = params
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| (key.to_string(), Value::String(value.to_string())))
+            .collect::<Map<_, _>>();
+        tracing::info!(
+            vault_path = path.as_str(),
+            "issuing certificate for {} from `{}/v1/{}`",
+            secret_names(secret_specs),
+            self.host,
+            path
+        );
+
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
+        let response = self
+            .send(Method::POST, &path, Some(&Value::Object(body)))
+            .await?
+            .json::<PkiIssueResponse>()?;
+        let mut fields = response.data.fields;
+        if let Some(Value::Array(chain)) = fields.get("ca_chain") {
+            let chain = chain
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join("\n");
+            fields.insert("ca_chain".to_string(), Value::String(chain));
+        }
+
+        let issued_at = SystemTime::now();
+        let expires_at = UNIX_EPOCH + Duration::from_secs(response.data.expiration);
+        let lifetime = expires_at.duration_since(issued_at).unwrap_or_default();
+        let renew_at = lock(&self.context.0.renewal).at(issued_at, lifetime)?;
+        metrics::lease_granted(&path, lifetime);
+        tracing::debug!(
+            vault_path = path.as_str(),
+            request_id = response.request_id.as_str(),
+            "issued certificate `{}` valid for {}s",
+            response.data.serial_number,
+            lifetime.as_secs()
+        );
+
+        let cert = IssuedCertificate {
+            fields,
+            expires_at,
+            renew_at,
+        };
+        let secrets = cert
+            .secrets(secret_specs)
+            .map_err(|err| err.for_request(&response.request_id))?;
+        lock(&self.context.0.certificates).insert(key, cert);
+        Ok(secrets)
+    }
+
+    /// Sets the `entries` of the kv v2 secret at `path` of `mount`, keeping its other keys, and
+    /// returns the version written.
+    ///
+    /// # Remarks:
+    ///
+    /// The write uses check-and-set with `cas`, by default the version read before merging the
+    /// key, so a concurrent write makes it fail instead of being overwritten. A `cas` of 0 only
+    /// allows creating the secret. Mounts which are not kv v2 are refused when vault tells so.
+    pub async fn put(
+        &self,
+        mount: &str,
+        path: &str,
+        entries: Map<String, Value>,
+        cas: Option<u64>,
+        opts: &FetchTokenOpts,
+    ) -> Result<u64> {
+        // a kv v1 mount would store the payload as secret under `data/<path>`
+        if self.is_kv_v2(mount).await? == Some(false) {
+            return Err(Error::Conversion(format!(
+                "`{}` is not a kv v2 mount, writing is only supported for kv v2",
+                mount
+            )));
+        }
+
+        let data_path = format!("{}/data/{}", mount, path);
+        let current = retry(
+            || async {
+                self.send(Method::GET, &data_path, None)
+                    .await?
+                    .json::<KvV2Response>()
+            },
+            opts.retries,
+            opts.retry_delay,
+        )
+        .await;
+        let (mut data, version) = match current {
+            Ok(response) => (
+                response.data.data,
+                response
+                    .data
+                    .metadata
+                    .map_or(0, |metadata| metadata.version),
+            ),
+            // a deleted secret has no data, but its version still counts for check-and-set
+            Err(Error::HttpStatus {
+                code: 404, body, ..
+            }) => {
+                let version = serde_json::from_str::<Value>(&body)
+                    .ok()
+                    .and_then(|body| body.pointer("/data/metadata/version")?.as_u64());
+                (Map::new(), version.unwrap_or(0))
+            }
+            Err(err) => return Err(err),
+        };
+        let keys = entries
+            .keys()
+            .map(|key| format!("`{}`", key))
+            .collect::<Vec<_>>()
+            .join(", ");
+        data.extend(entries);
+
+        tracing::info!(
+            vault_path = data_path.as_str(),
+            "writing {} to `{}/v1/{}`",
+            keys,
+            self.host,
+            data_path
+        );
+        let body = serde_json::json!({
+            "options": {"cas": cas.unwrap_or(version)},
+            "data": data,
+        });
+        // a retried write which went through before fails the check-and-set instead of repeating
+        let response = retry(
+            || async {
+                self.send(Method::POST, &data_path, Some(&body))
+                    .await?
+                    .json::<KvV2WriteResponse>()
+            },
+            opts.retries,
+            opts.retry_delay,
+        )
+        .await?;
+        tracing::debug!(
+            vault_path = data_path.as_str(),
+            request_id = response.request_id.as_str(),
+            "wrote version {}",
+            response.data.version
+        );
+
+        Ok(response.data.version)
+    }
+
+    /// Lists the secrets and folders (ending with `/`) directly below the folder `path` of `mount`,
+    /// or with `recursive` all secrets below it, as paths relative to the mount.
+    ///
+    /// # Remarks:
+    ///
+    /// Mounts are listed as kv v2 unless vault tells they are kv v1.
+    pub async fn list(
+        &self,
+        mount: &str,
+        path: &str,
+        recursive: bool,
+        opts: &FetchTokenOpts,
+    ) -> Result<Vec<String>> {
+        let v2 = self.is_kv_v2(mount).await? != Some(false);
+        let mut folders = vec![path.trim_matches('/').to_string()];
+        let mut paths = Vec::new();
+        while let Some(folder) = folders.pop() {
+            let prefix = if folder.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", folder)
+            };
+            let list_path = if v2 {
+                format!("{}/metadata/{}?list=true", mount, prefix)
+            } else {
+                format!("{}/{}?list=true", mount, prefix)
+            };
+            tracing::info!("listing `{}/v1/{}`", self.host, list_path);
+            let keys = retry(
                 || async {
-                    fetch_token_kubernetes(host, &role, &backend)
-                        .await
-                        .map(Some)
+                    self.send(Method::GET, &list_path, None)
+                        .await?
+                        .json::<KvListResponse>()
                 },
                 opts.retries,
                 opts.retry_delay,
             )
+            .await?
+            .data
+            .keys;
+
+            for key in keys {
+                let child = format!("{}{}", prefix, key);
+                match child.strip_suffix('/') {
+                    Some(child) if recursive => folders.push(child.to_string()),
+                    _ => paths.push(child),
+                }
+            }
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// Names of the keys of the secret at `path` of `mount`, read via `subkeys` on kv v2 mounts
+    /// so no values are transferred.
+    pub async fn keys(
+        &self,
+        mount: &str,
+        path: &str,
+        opts: &FetchTokenOpts,
+    ) -> Result<Vec<String>> {
+        if let Some(keys) = self.subkeys(mount, path, opts).await? {
+            return Ok(keys);
+        }
+
+        // kv v1 has no such endpoint, so the secret is read
+        let path = format!("{}/{}", mount, path);
+        let data = retry(
+            || async {
+                let response = self.send(Method::GET, &path, None).await?;
+                Ok(response.json::<KvV1Response>()?.data)
+            },
+            opts.retries,
+            opts.retry_delay,
+        )
+        .await?;
+
+        Ok(data.keys().cloned().collect())
+    }
+
+    /// Names of the keys of the kv v2 secret at `path` of `mount`, read via the `subkeys` endpoint
+    /// which returns no values, or `None` if `mount` is a kv v1 mount, which has no such endpoint.
+    pub async fn subkeys(
+        &self,
+        mount: &str,
+        path: &str,
+        opts: &FetchTokenOpts,
+    ) -> Result<Option<Vec<String>>> {
+        let v2 = match self.known_kv_v2(mount) {
+            Some(v2) => Some(v2),
+            None => self.detect_kv_v2(mount).await,
+        };
+        if v2 == Some(false) {
+            return Ok(None);
+        }
+
+        let subkeys_path = format!("{}/subkeys/{}?depth=1", mount, path);
+        tracing::info!("reading the keys of `{}/v1/{}`", self.host, subkeys_path);
+        let subkeys = retry(
+            || async {
+                self.send(Method::GET, &subkeys_path, None)
+                    .await?
+                    .json::<KvSubkeysResponse>()
+            },
+            opts.retries,
+            opts.retry_delay,
+        )
+        .await?
+        .data
+        .subkeys;
+
+        Ok(Some(subkeys.keys().cloned().collect()))
+    }
+
+    /// Names of the kv mounts visible to the token, without trailing `/`.
+    pub async fn kv_mounts(&self) -> Result<Vec<String>> {
+        let response = self
+            .send(Method::GET, "sys/internal/ui/mounts", None)
+            .await?
+            .json::<Value>()?;
+        let mounts = response
+            .pointer("/data/secret")
+            .and_then(Value::as_object)
+            .map(|mounts| {
+                mounts
+                    .iter()
+                    .filter(|(_, mount)| {
+                        let kind = mount.get("type").and_then(Value::as_str);
+                        matches!(kind, Some("kv" | "generic"))
+                    })
+                    .map(|(name, _)| name.trim_end_matches('/').to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(mounts)
+    }
+
+    /// Whether `mount` is a kv v2 secrets engine, `None` if vault does not tell, e.g. as the
+    /// policy of the token does not allow reading the configuration of the mount.
+    async fn is_kv_v2(&self, mount: &str) -> Result<Option<bool>> {
+        let response = self
+            .send(
+                Method::GET,
+                &format!("sys/internal/ui/mounts/{}", mount),
+                None,
+            )
+            .await?
+            .json::<Value>();
+        Ok(response.ok().map(|response| {
+            response
+                .pointer("/data/options/version")
+                .and_then(Value::as_str)
+                == Some("2")
+        }))
+    }
+
+    /// Whether `mount` is a kv v2 secrets engine, as far as configured or detected before.
+    fn known_kv_v2(&self, mount: &str) -> Option<bool> {
+        if lock(&self.context.0.kv_v1_mounts)
+            .iter()
+            .any(|v1| v1 == mount)
+        {
+            return Some(false);
+        }
+        lock(&self.context.0.kv_versions)
+            .get(&self.mount_key(mount))
+            .copied()
+    }
+
+    /// Asks vault whether `mount` is a kv v2 secrets engine and remembers the answer, so later
+    /// reads go to the right API right away.
+    async fn detect_kv_v2(&self, mount: &str) -> Option<bool> {
+        let v2 = self.is_kv_v2(mount).await.ok().flatten()?;
+        lock(&self.context.0.kv_versions).insert(self.mount_key(mount), v2);
+        Some(v2)
+    }
+
+    /// Identifies `mount` across clients of the same vault.
+    fn mount_key(&self, mount: &str) -> String {
+        format!(
+            "{}/{}{}",
+            self.host,
+            self.namespace
+                .as_deref()
+                .map(|namespace| format!("{}/", namespace.trim_matches('/')))
+                .unwrap_or_default(),
+            mount
+        )
+    }
+
+    /// Sends a request to `/v1/<path>` with the token, replication state and namespace.
+    async fn send(&self, method: Method, path: &str, body: Option<&Value>) -> Result<Response> {
+        let url = format!("{}/v1/{}", self.host, path);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (VAULT_TOKEN_HEADER, &self.token),
+            (VAULT_INDEX_HEADER, &self.index),
+            (VAULT_NAMESPACE_HEADER, &self.namespace),
+        ] {
+            if let Some(value) = value {
+                let value = HeaderValue::from_str(value).map_err(|err| {
+                    Error::Conversion(format!("invalid value of header {}: {}", name, err))
+                })?;
+                headers.insert(name, value);
+            }
+        }
+
+        self.context
+            .transport()?
+            .send(method, &url, &headers, body)
+            .await
+    }
+}
+
+/// State shared by the clients of all vault addresses of a run: the transport configured by
+/// `init_client`, the detected kv versions, the issued leases and certificates and the circuit
+/// breakers of the addresses.
+///
+/// Clones share the state, so a clone is passed to every `VaultClient`.
+#[derive(Clone, Default)]
+pub struct Context(Arc<ContextState>);
+
+#[derive(Default)]
+struct ContextState {
+    transport: OnceLock<Transport>,
+    /// Mounts configured with `--kv-v1-mounts`, read via the v1 API on every vault.
+    kv_v1_mounts: Mutex<Vec<String>>,
+    /// Whether mounts (keyed by `VaultClient::mount_key`) are kv v2, detected on failed v2 reads.
+    kv_versions: Mutex<BTreeMap<String, bool>>,
+    /// Leases of the secrets fetched since `track_leases`, revoked by `revoke_leases`.
+    leases: Mutex<Option<Vec<IssuedLease>>>,
+    /// Certificates issued by this run by the mount and path of their secrets.
+    certificates: Mutex<BTreeMap<String, IssuedCertificate>>,
+    renewal: Mutex<Renewal>,
+    breakers: breaker::Breakers,
+}
+
+impl std::fmt::Debug for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context").finish_non_exhaustive()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Context {
+    /// Reads secrets of `mounts` via the kv v1 API without trying v2 first.
+    pub fn set_kv_v1_mounts(&self, mounts: &[String]) {
+        *lock(&self.0.kv_v1_mounts) = mounts
+            .iter()
+            .map(|mount| mount.trim_matches('/').to_string())
+            .collect();
+    }
+
+    /// Records the leases of all secrets fetched from now on, to revoke them with
+    /// `revoke_leases`.
+    pub fn track_leases(&self) {
+        lock(&self.0.leases).get_or_insert_with(Vec::new);
+    }
+
+    /// Revokes the leases of the secrets fetched since `track_leases` via `sys/leases/revoke`,
+    /// e.g. dynamic database credentials once the child using them exited.
+    ///
+    /// # Remarks:
+    ///
+    /// Failures are only logged, the affected leases then expire with their TTL.
+    pub async fn revoke_leases(&self) {
+        let leases = lock(&self.0.leases)
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default();
+        for lease in leases {
+            let client = VaultClient {
+                host: lease.host,
+                namespace: lease.namespace,
+                token: lease.token,
+                index: None,
+                token_info: None,
+                context: self.clone(),
+            };
+            let body = serde_json::json!({ "lease_id": lease.lease_id });
+            let revoked = client
+                .send(Method::PUT, "sys/leases/revoke", Some(&body))
+                .await
+                .and_then(Response::error_for_status);
+            match revoked {
+                Ok(_) => tracing::info!("revoked lease `{}`", lease.lease_id),
+                Err(err) => tracing::warn!("unable to revoke lease `{}`: {}", lease.lease_id, err),
+            }
+        }
+    }
+
+    /// Sets when credentials issued from now on are renewed.
+    pub fn set_renewal(&self, renewal: Renewal) {
+        *lock(&self.0.renewal) = renewal;
+    }
+
+    /// When the first of the certificates issued so far is due for renewal, i.e. fetching the
+    /// secrets issues it again.
+    pub fn next_certificate_renewal(&self) -> Option<SystemTime> {
+        lock(&self.0.certificates)
+            .values()
+            .map(|cert| cert.renew_at)
+            .min()
+    }
+
+    /// The circuit breakers of the vault addresses.
+    pub fn breakers(&self) -> &breaker::Breakers {
+        &self.0.breakers
+    }
+
+    /// Polls `sys/health` until vault is able to serve requests or `timeout` elapsed.
+    ///
+    /// With multiple `hosts`, waits until any of them is healthy.
+    ///
+    /// # Remarks:
+    ///
+    /// Active and standby nodes are considered healthy, as standby nodes forward requests to the
+    /// active node. Uninitialized and sealed nodes as well as connection errors are waited out.
+    pub async fn wait_for_health(&self, hosts: &[String], timeout: Duration) -> Result<()> {
+        tracing::info!(
+            "waiting up to {:?} for {} to become healthy",
+            timeout,
+            hosts.join(", ")
+        );
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let mut states = Vec::with_capacity(hosts.len());
+            for host in hosts {
+                match self.health(host).await {
+                    Ok(()) => return Ok(()),
+                    Err(state) => states.push(format!("`{}` is {}", host, state)),
+                }
+            }
+            let state = states.join(", ");
+
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Err(Error::Execution(format!(
+                    "vault did not become healthy within {:?}, last state: {}",
+                    timeout, state
+                )));
+            }
+            tracing::info!("{}, waiting", state);
+            tokio::time::sleep((deadline - now).min(HEALTH_POLL_INTERVAL)).await;
+        }
+    }
+
+    /// Queries `sys/health` of `host` once and describes why it cannot serve requests.
+    async fn health(&self, host: &str) -> std::result::Result<(), String> {
+        let vault_url = format!("{host}/v1/sys/health");
+        let response = match self.transport() {
+            Ok(transport) => {
+                transport
+                    .send(Method::GET, &vault_url, &HeaderMap::new(), None)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        match response {
+            Ok(response) => match response.status {
+                200 | 429 | 473 => Ok(()),
+                501 => Err("not initialized".to_string()),
+                503 => Err("sealed".to_string()),
+                code => Err(format!("unhealthy (status {})", code)),
+            },
+            Err(err) => Err(format!("unreachable ({})", err)),
+        }
+    }
+}
+
+/// Lease of a secret fetched by this process, with the client state needed to revoke it.
+struct IssuedLease {
+    host: String,
+    namespace: Option<String>,
+    token: Option<String>,
+    lease_id: String,
+}
+
+/// When credentials with a limited lifetime are renewed, set via `--renew-at-fraction` and
+/// `--renew-jitter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Renewal {
+    /// Fraction of the lifetime after which a credential is renewed.
+    pub fraction: f64,
+    /// Fraction of the lifetime by which a renewal is randomly moved earlier.
+    pub jitter: f64,
+}
+
+impl Renewal {
+    /// Random point in time to renew a credential issued at `issued_at` for `lifetime`, between
+    /// `fraction - jitter` and `fraction` of its lifetime.
+    pub fn at(&self, issued_at: SystemTime, lifetime: Duration) -> Result<SystemTime> {
+        let fraction = (self.fraction - self.jitter * crypto::random_fraction()?).max(0.0);
+        Ok(issued_at + lifetime.mul_f64(fraction))
+    }
+}
+
+impl Default for Renewal {
+    fn default() -> Self {
+        Self {
+            fraction: 0.7,
+            jitter: 0.1,
+        }
+    }
+}
+
+/// Fields of a certificate issued by `issue_certificate`, reused until `renew_at`.
+struct IssuedCertificate {
+    fields: Map<String, Value>,
+    expires_at: SystemTime,
+    renew_at: SystemTime,
+}
+
+impl IssuedCertificate {
+    /// The fields of the certificate requested by `secret_specs`.
+    fn secrets(&self, secret_specs: &[&SecretSpec]) -> Result<Vec<Secret>> {
+        let mut secrets = extract_secrets(&self.fields, ".data", secret_specs)?;
+        for secret in &mut secrets {
+            secret.metadata.expires_at = Some(self.expires_at);
+        }
+        Ok(secrets)
+    }
+}
+
+/// Resolves `vault:` and `pki:` secrets with a logged in `VaultClient`.
+pub struct VaultProvider<'a> {
+    pub client: &'a VaultClient,
+    pub opts: FetchAllOpts,
+}
+
+impl SecretProvider for VaultProvider<'_> {
+    async fn resolve(&self, spec: &SecretSpec) -> Result<Secret> {
+        self.client
+            .fetch_path(&[spec], None)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::NotFound(format!("secret `{}` was not fetched", spec.name())))
+    }
+
+    async fn resolve_all(&self, specs: &[&SecretSpec]) -> Result<Vec<Secret>> {
+        self.client.fetch_all(specs, &self.opts).await
+    }
+}
+
+/// Requests the OIDC token of the GitHub Actions job from the runner at `request_url`, optionally
+/// for `audience`.
+///
+/// # Remarks:
+///
+/// The runner is not the vault, so the request does not use the vault client, its headers or
+/// its CA certificates.
+async fn github_actions_token(
+    request_url: &str,
+    request_token: &str,
+    audience: Option<&str>,
+) -> Result<String> {
+    let mut url = reqwest::Url::parse(request_url).map_err(|err| {
+        Error::Conversion(format!(
+            "invalid ACTIONS_ID_TOKEN_REQUEST_URL {:?}: {}",
+            request_url, err
+        ))
+    })?;
+    if let Some(audience) = audience {
+        url.query_pairs_mut().append_pair("audience", audience);
+    }
+
+    let response = Client::builder()
+        .timeout(DEFAULT_REQUEST_TIMEOUT)
+        .user_agent(build_info::USER_AGENT)
+        .build()?
+        .get(url.clone())
+        .bearer_auth(request_token)
+        .send()
+        .await?;
+    let response = Response {
+        url: url.to_string(),
+        status: response.status().as_u16(),
+        retry_after: parse_retry_after(response.headers()),
+        index: None,
+        body: response.text().await?,
+    };
+    Ok(response.json::<GitHubActionsTokenResponse>()?.value)
+}
+
+/// Policy paths of a secret as v2 and v1 secret.
+fn candidate_paths(spec: &SecretSpec) -> [String; 2] {
+    [
+        format!("{}/data/{}", spec.mount, spec.path),
+        format!("{}/{}", spec.mount, spec.path),
+    ]
+}
+
+/// Whether the `sys/capabilities-self` response grants reading `path`.
+fn can_read(capabilities: &Value, path: &str) -> bool {
+    // newer vault versions duplicate the top level fields into `.data`
+    let caps = capabilities
+        .get(path)
+        .or_else(|| capabilities.get("data").and_then(|data| data.get(path)));
+    caps.and_then(Value::as_array).is_some_and(|caps| {
+        caps.iter()
+            .filter_map(Value::as_str)
+            .any(|cap| cap == "read" || cap == "root")
+    })
+}
+
+/// Options passed to `VaultClient::fetch_all`.
+pub struct FetchAllOpts {
+    /// Number of retries per query.
+    pub retries: usize,
+    /// Delay between retries.
+    pub retry_delay: Duration,
+    /// Number of parallel requests to the vault.
+    pub concurrency: usize,
+    /// Maximum number of requests per second, including retries.
+    pub rate_limit: Option<f64>,
+    /// Timeout of each attempt to fetch the secrets of a path.
+    pub fetch_timeout: Option<Duration>,
+    /// Globs of the secret names which are skipped if they are missing or may not be read.
+    pub allow_missing: Vec<String>,
+}
+
+/// Groups the secrets by the mount and path they are stored under in vault.
+fn group_by_path<'a>(secrets: &[&'a SecretSpec]) -> Vec<Vec<&'a SecretSpec>> {
+    let mut paths = BTreeMap::<(&str, &str), Vec<&SecretSpec>>::new();
+    for secret in secrets.iter().copied() {
+        paths
+            .entry((&secret.mount, &secret.path))
+            .or_default()
+            .push(secret);
+    }
+
+    paths.into_values().collect()
+}
+
+/// Extracts the requested keys from the secret data object found at `location` in the response.
+fn extract_secrets(
+    data: &Map<String, Value>,
+    location: &str,
+    secret_specs: &[&SecretSpec],
+) -> Result<Vec<Secret>> {
+    secret_specs
+        .iter()
+        .map(|secret_spec| {
+            let secret_value = data
+                .get(&secret_spec.secret)
+                .ok_or_else(|| {
+                    Error::NotFound(format!(
+                        "vault response does not contain {}.{}",
+                        location, secret_spec.secret
+                    ))
+                })?
+                .as_str()
+                .ok_or_else(|| {
+                    Error::deserialization(
+                        "vault response secret cannot be made into a string or is empty"
+                            .to_string(),
+                    )
+                })?;
+
+            Ok(Secret::new(
+                secret_spec.target.clone(),
+                secret_value.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Comma separated list of the names of the secrets, used in logs.
+fn secret_names(secret_specs: &[&SecretSpec]) -> String {
+    secret_specs
+        .iter()
+        .map(|secret| format!("`{}`", secret.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Upper bound for delays requested via `Retry-After`.
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(60);
+
+/// Runs `op` up to `count + 1` times, recording the number of attempts as `attempts` field of the
+/// current span.
+pub async fn retry<T, F, FU>(op: F, count: usize, delay: Duration) -> Result<T>
+where
+    F: Fn() -> FU,
+    FU: Future<Output = Result<T>>,
+{
+    for attempt in 0..=count {
+        tracing::Span::current().record("attempts", attempt + 1);
+        let retry_delay = match op().await {
+            Ok(result) => return Ok(result),
+            Err(err) => {
+                if !is_retryable_error(&err) {
+                    return Err(err);
+                }
+
+                if attempt == count {
+                    return Err(Error::MaxRetries {
+                        source: Box::new(err),
+                    });
+                }
+
+                let retry_delay = match &err {
+                    Error::HttpStatus {
+                        retry_after: Some(retry_after),
+                        ..
+                    } => (*retry_after).clamp(delay, RETRY_AFTER_MAX.max(delay)),
+                    _ => delay,
+                };
+                tracing::warn!("operation failed, retrying in {:?}: {}", retry_delay, err);
+                retry_delay
+            }
+        };
+
+        tokio::time::sleep(retry_delay).await;
+    }
+
+    unreachable!("retry loop always returns from within the loop")
+}
+
+#[inline]
+fn is_retryable_error(err: &Error) -> bool {
+    // sealed vaults are waited for or failed over from instead
+    if is_sealed(err) {
+        return false;
+    }
+
+    match err {
+        Error::Context { source, .. } => is_retryable_error(source),
+        Error::ReqwestTransient { .. } => true,
+        // 412: a performance standby has not yet replicated the state in `X-Vault-Index`
+        Error::HttpStatus { code, .. } => matches!(code, 412 | 429 | 500 | 502 | 503 | 504),
+        Error::IO { .. }
+        | Error::NotFound(_)
+        | Error::Parse { .. }
+        | Error::ParseErrors(_)
+        | Error::Conversion(_)
+        | Error::Deserialization { .. }
+        | Error::MaxRetries { .. }
+        | Error::Reqwest { .. }
+        | Error::Execution(_)
+        | Error::Timeout(_)
+        | Error::PermissionDenied(_)
+        | Error::Spawn { .. } => false,
+    }
+}
+
+/// Parses the `Retry-After` header in its delay-seconds form; HTTP dates are ignored.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Whether the next vault address should be tried after `err`, i.e. the vault is unreachable or
+/// sealed.
+pub fn should_failover(err: &Error) -> bool {
+    match err {
+        Error::MaxRetries { source } | Error::Context { source, .. } => should_failover(source),
+        Error::ReqwestTransient { .. } => true,
+        Error::HttpStatus { code, .. } => *code == 503,
+        Error::IO { .. }
+        | Error::NotFound(_)
+        | Error::Parse { .. }
+        | Error::ParseErrors(_)
+        | Error::Conversion(_)
+        | Error::Deserialization { .. }
+        | Error::Reqwest { .. }
+        | Error::Execution(_)
+        | Error::Timeout(_)
+        | Error::PermissionDenied(_)
+        | Error::Spawn { .. } => false,
+    }
+}
+
+/// Error messages of vault while it is sealed or has no active node, e.g. during maintenance.
+const SEALED_ERRORS: [&str; 2] = [
+    "Vault is sealed",
+    "local node not active but active cluster node not found",
+];
+
+/// Whether `err` is a response of a sealed vault or a vault without active node.
+pub fn is_sealed(err: &Error) -> bool {
+    match err {
+        Error::MaxRetries { source } | Error::Context { source, .. } => is_sealed(source),
+        Error::HttpStatus { code, body, .. } => {
+            *code == 503 && SEALED_ERRORS.iter().any(|msg| body.contains(msg))
+        }
+        Error::IO { .. }
+        | Error::NotFound(_)
+        | Error::Parse { .. }
+        | Error::ParseErrors(_)
+        | Error::Conversion(_)
+        | Error::Deserialization { .. }
+        | Error::Reqwest { .. }
+        | Error::ReqwestTransient { .. }
+        | Error::Execution(_)
+        | Error::Timeout(_)
+        | Error::PermissionDenied(_)
+        | Error::Spawn { .. } => false,
+    }
+}
+
+/// Parses a header in the format `Name: value`.
+pub fn parse_header(raw: &str) -> std::result::Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = raw
+        .split_once(':')
+        .ok_or_else(|| "header must be in format `Name: value`".to_string())?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("invalid header name `{}`: {e}", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("invalid value of header `{}`: {e}", name))?;
+
+    Ok((name, value))
+}
+
+/// Header sending the correlation `id` of this run with every request, so the requests can be
+/// found in the audit log of vault.
+pub fn correlation_header(id: &str) -> Result<(HeaderName, HeaderValue)> {
+    let value = HeaderValue::from_str(id)
+        .map_err(|err| Error::Conversion(format!("invalid correlation id `{}`: {}", id, err)))?;
+    Ok((HeaderName::from_static("x-vaultify-correlation-id"), value))
+}
+
+/// Reads the `X-Vault-Index` header.
+fn parse_index(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get(VAULT_INDEX_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+#[inline]
+fn should_fallback_to_v1(err: &Error) -> bool {
+    match err {
+        Error::Context { source, .. } => should_fallback_to_v1(source),
+        Error::NotFound(_) | Error::Deserialization { .. } => true,
+        Error::HttpStatus { code, .. } => *code == 400 || *code == 404,
+        Error::IO { .. }
+        | Error::Parse { .. }
+        | Error::ParseErrors(_)
+        | Error::Conversion(_)
+        | Error::MaxRetries { .. }
+        | Error::Reqwest { .. }
+        | Error::ReqwestTransient { .. }
+        | Error::Execution(_)
+        | Error::Timeout(_)
+        | Error::PermissionDenied(_)
+        | Error::Spawn { .. } => false,
+    }
+}
+
+/// Options of the client used for all requests to vault.
+#[derive(Debug)]
+pub struct ClientOpts {
+    /// Timeout of a single request, including connecting and reading the response.
+    pub request_timeout: Duration,
+    /// Timeout for establishing a connection.
+    pub connect_timeout: Duration,
+    /// PEM file with CA certificates trusted in addition to the built-in roots.
+    pub ca_cert: Option<PathBuf>,
+    /// Directory of PEM files with CA certificates trusted in addition to the built-in roots.
+    pub ca_path: Option<PathBuf>,
+    /// PEM files with the client certificate (chain) and its private key for mutual TLS.
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+    /// Disable verification of the server certificate.
+    pub tls_skip_verify: bool,
+    /// Name sent as SNI and the server certificate is verified for instead of the host of the
+    /// address; requests are then sent without a proxy.
+    pub tls_server_name: Option<String>,
+    /// Proxy for all requests, replacing the proxies configured via `HTTP(S)_PROXY`.
+    ///
+    /// # Remarks:
+    ///
+    /// Hosts in `NO_PROXY` are still reached directly.
+    pub proxy: Option<String>,
+    /// Headers sent with every request, e.g. required by a gateway in front of vault.
+    pub headers: HeaderMap,
+}
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_REQUEST_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Default for ClientOpts {
+    fn default() -> Self {
+        Self {
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: DEFAULT_REQUEST_CONNECT_TIMEOUT,
+            ca_cert: None,
+            ca_path: None,
+            client_identity: None,
+            tls_skip_verify: false,
+            tls_server_name: None,
+            proxy: None,
+            headers: HeaderMap::new(),
+        }
+    }
+}
+
+/// Prefix of vault addresses pointing to a unix domain socket, e.g. of a Vault Agent.
+const UNIX_SCHEME: &str = "unix://";
+/// Address used in request urls when connecting via a unix domain socket.
+#[cfg(unix)]
+const UNIX_HOST: &str = "http://localhost";
+
+/// Transport used for all requests to vault.
+enum Transport {
+    Http(Client),
+    /// HTTPS with the certificate verified for, and SNI set to, the `--tls-server-name`, which
+    /// reqwest does not support.
+    ServerName {
+        client: Box<ServerNameClient>,
+        request_timeout: Duration,
+        default_headers: HeaderMap,
+    },
+    #[cfg(unix)]
+    Unix {
+        socket: PathBuf,
+        request_timeout: Duration,
+        connect_timeout: Duration,
+        default_headers: HeaderMap,
+    },
+}
+
+/// Status and body of a response from vault.
+struct Response {
+    url: String,
+    status: u16,
+    body: String,
+    /// Value of the `Retry-After` header.
+    retry_after: Option<Duration>,
+    /// Value of the `X-Vault-Index` header.
+    index: Option<String>,
+}
+
+impl Response {
+    /// Fails with the status of an unsuccessful response.
+    fn error_for_status(self) -> Result<Self> {
+        if !(200..=299).contains(&self.status) {
+            let request_id = serde_json::from_str::<Value>(&self.body)
+                .ok()
+                .and_then(|body| body.get("request_id")?.as_str().map(ToString::to_string))
+                .filter(|id| !id.is_empty());
+            return Err(Error::HttpStatus {
+                code: self.status,
+                url: self.url,
+                body: self.body,
+                retry_after: self.retry_after,
+                request_id,
+            });
+        }
+
+        Ok(self)
+    }
+
+    /// Deserializes the body of a successful response.
+    fn json<T: DeserializeOwned>(self) -> Result<T> {
+        Ok(serde_json::from_str(&self.error_for_status()?.body)?)
+    }
+}
+
+impl Context {
+    /// Configures the transport of all requests to vault.
+    ///
+    /// Returns the addresses to send requests to, which differ from `hosts` if vault is reached
+    /// via a unix domain socket (`unix:///path/to/socket`).
+    ///
+    /// # Remarks:
+    ///
+    /// Has to be called before the first request, otherwise a client with default options is
+    /// used. Unix domain sockets are only supported with a single address.
+    pub fn init_client(&self, hosts: &[String], opts: ClientOpts) -> Result<Vec<String>> {
+        match hosts {
+            [] => Err(Error::Conversion(
+                "vault address must not be empty".to_string(),
+            )),
+            [host] => self.init_client_single(host, opts).map(|host| vec![host]),
+            _ => {
+                if hosts.iter().any(|host| host.starts_with(UNIX_SCHEME)) {
+                    return Err(Error::Conversion(
+                        "unix domain sockets cannot be combined with other vault addresses"
+                            .to_string(),
+                    ));
+                }
+
+                self.set_transport(build_transport(&opts)?)?;
+                Ok(hosts.to_vec())
+            }
+        }
+    }
+
+    fn init_client_single(&self, host: &str, opts: ClientOpts) -> Result<String> {
+        if let Some(socket) = host.strip_prefix(UNIX_SCHEME) {
+            self.set_transport(Transport::Unix {
+                socket: PathBuf::from(socket),
+                request_timeout: opts.request_timeout,
+                connect_timeout: opts.connect_timeout,
+                default_headers: opts.headers,
+            })?;
+            return Ok(UNIX_HOST.to_string());
+        }
+
+        self.set_transport(build_transport(&opts)?)?;
+
+        Ok(host.to_string())
+    }
+
+    fn set_transport(&self, transport: Transport) -> Result<()> {
+        self.0
+            .transport
+            .set(transport)
+            .map_err(|_| Error::Execution("vault client is already initialized".to_string()))
+    }
+
+    /// Returns the transport shared by all requests, so connections (and TLS sessions) are
+    /// pooled and kept alive across requests.
+    fn transport(&self) -> Result<&Transport> {
+        if let Some(transport) = self.0.transport.get() {
+            return Ok(transport);
+        }
+        let transport = Transport::Http(build_client(&ClientOpts::default())?);
+        Ok(self.0.transport.get_or_init(|| transport))
+    }
+}
+
+/// Whether requests to the vault address `host` are sent unencrypted to another machine.
+pub fn is_plaintext_remote(host: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(host) else {
+        return false;
+    };
+    if url.scheme() != "http" {
+        return false;
+    }
+
+    let host = url.host_str().unwrap_or_default();
+    let is_loopback = host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    !is_loopback
+}
+
+/// Client of the `--tls-server-name` transport.
+type ServerNameClient = hyper_util::client::legacy::Client<
+    hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>,
+    http_body_util::Full<hyper::body::Bytes>,
+>;
+
+fn build_transport(opts: &ClientOpts) -> Result<Transport> {
+    match &opts.tls_server_name {
+        Some(server_name) if !opts.tls_skip_verify => {
+            if opts.proxy.is_some() {
+                return Err(Error::Conversion(
+                    "--tls-server-name cannot be combined with a proxy".to_string(),
+                ));
+            }
+            let mut http = hyper_util::client::legacy::connect::HttpConnector::new();
+            http.enforce_http(false);
+            http.set_connect_timeout(Some(opts.connect_timeout));
+            let connector = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config(opts)?)
+                .https_or_http()
+                .with_server_name_resolver(hyper_rustls::FixedServerNameResolver::new(
+                    parse_server_name(server_name)?,
+                ))
+                .enable_all_versions()
+                .wrap_connector(http);
+            let client =
+                hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+                    .build(connector);
+            Ok(Transport::ServerName {
+                client: Box::new(client),
+                request_timeout: opts.request_timeout,
+                default_headers: opts.headers.clone(),
+            })
+        }
+        _ => build_client(opts).map(Transport::Http),
+    }
+}
+
+fn build_client(opts: &ClientOpts) -> Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(opts.request_timeout)
+        .connect_timeout(opts.connect_timeout)
+        .user_agent(build_info::USER_AGENT)
+        .default_headers(opts.headers.clone());
+    if opts.tls_skip_verify {
+        tracing::warn!(
+            "TLS verification of the vault server is DISABLED (--tls-skip-verify), \
+            secrets can be intercepted by anyone on the network path"
+        );
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    for cert in load_ca_certificates(opts)? {
+        let cert = Certificate::from_der(&cert)
+            .map_err(|err| Error::Conversion(format!("invalid CA certificate: {}", err)))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some((cert, key)) = &opts.client_identity {
+        builder = builder.identity(load_identity(cert, key)?);
+    }
+    if let Some(proxy) = &opts.proxy {
+        let proxy = Proxy::all(proxy.as_str())
+            .map_err(|err| Error::Conversion(format!("invalid proxy {:?}: {}", proxy, err)))?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    builder
+        .build()
+        .map_err(|err| Error::Execution(format!("unable to build reqwest client: {}", err)))
+}
+
+/// Parses the `--tls-server-name`, a DNS name or an IP address.
+fn parse_server_name(server_name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(server_name.to_string()).map_err(|err| {
+        Error::Conversion(format!(
+            "invalid tls server name {:?}: {}",
+            server_name, err
+        ))
+    })
+}
+
+/// TLS configuration with the same roots and client identity as the one of reqwest, for the
+/// `--tls-server-name` transport.
+fn tls_config(opts: &ClientOpts) -> Result<ClientConfig> {
+    let invalid = |err: rustls::Error| Error::Conversion(format!("invalid TLS setup: {}", err));
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for cert in load_ca_certificates(opts)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(roots);
+    match &opts.client_identity {
+        Some((cert, key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .and_then(Iterator::collect::<std::result::Result<Vec<_>, _>>)
+                .map_err(|err| {
+                    Error::Conversion(format!("invalid client certificate {:?}: {}", cert, err))
+                })?;
+            let key = PrivateKeyDer::from_pem_file(key).map_err(|err| {
+                Error::Conversion(format!("invalid client key {:?}: {}", key, err))
+            })?;
+            builder.with_client_auth_cert(chain, key).map_err(invalid)
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Loads the certificates of `ca_cert` and of all files in `ca_path`.
+fn load_ca_certificates(opts: &ClientOpts) -> Result<Vec<CertificateDer<'static>>> {
+    let mut certs = Vec::new();
+    if let Some(path) = &opts.ca_cert {
+        let bundle = read_ca_bundle(path)?;
+        if bundle.is_empty() {
+            return Err(Error::NotFound(format!(
+                "no certificates found in {:?}",
+                path
+            )));
+        }
+        certs.extend(bundle);
+    }
+
+    if let Some(dir) = &opts.ca_path {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|err| Error::io_at("unable to read directory", dir, err))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|err| Error::io_at("unable to read directory", dir, err))?
+                .path();
+            if path.is_file() {
+                certs.extend(read_ca_bundle(&path)?);
+            }
+        }
+    }
+
+    Ok(certs)
+}
+
+fn read_ca_bundle(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).map_err(|err| Error::io_at("unable to read file", path, err))?;
+    CertificateDer::pem_slice_iter(&pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| Error::Conversion(format!("invalid certificate in {:?}: {}", path, err)))
+}
+
+/// Loads the client certificate and key used for mutual TLS.
+fn load_identity(cert: &Path, key: &Path) -> Result<Identity> {
+    let mut pem =
+        std::fs::read(cert).map_err(|err| Error::io_at("unable to read file", cert, err))?;
+    pem.push(b'\n');
+    pem.extend(std::fs::read(key).map_err(|err| Error::io_at("unable to read file", key, err))?);
+
+    Identity::from_pem(&pem).map_err(|err| {
+        Error::Conversion(format!(
+            "invalid client certificate {:?} or key {:?}: {}",
+            cert, key, err
+        ))
+    })
+}
+
+impl Transport {
+    /// Sends a request with additional headers and an optional json body.
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: Option<&Value>,
+    ) -> Result<Response> {
+        match self {
+            Transport::Http(client) => {
+                let mut request = client.request(method, url).headers(headers.clone());
+                if let Some(body) = body {
+                    request = request.header(CONTENT_TYPE, "application/json").json(body);
+                }
+                let response = request.send().await?;
+                let status = response.status().as_u16();
+                let retry_after = parse_retry_after(response.headers());
+                let index = parse_index(response.headers());
+                let body = response.text().await?;
+
+                Ok(Response {
+                    url: url.to_string(),
+                    status,
+                    body,
+                    retry_after,
+                    index,
+                })
+            }
+            Transport::ServerName {
+                client,
+                request_timeout,
+                default_headers,
+            } => tokio::time::timeout(*request_timeout, async {
+                let request = hyper_request(
+                    method,
+                    url,
+                    url,
+                    default_headers.iter().chain(headers),
+                    body,
+                )?;
+                let response = client.request(request).await.map_err(|err| {
+                    Error::transient(
+                        &format!("error sending request for url ({})", url),
+                        url,
+                        err,
+                    )
+                })?;
+                hyper_response(url, response).await
+            })
+            .await
+            .map_err(|_| Error::ReqwestTransient {
+                message: format!("request to {} timed out", url),
+                url: Some(url.to_string()),
+                source: None,
+            })?,
+            #[cfg(unix)]
+            Transport::Unix {
+                socket,
+                request_timeout,
+                connect_timeout,
+                default_headers,
+            } => tokio::time::timeout(
+                *request_timeout,
+                send_unix(
+                    socket,
+                    *connect_timeout,
+                    method,
+                    url,
+                    default_headers.iter().chain(headers),
+                    body,
+                ),
+            )
             .await
+            .map_err(|_| Error::ReqwestTransient {
+                message: format!("request to {} via {:?} timed out", url, socket),
+                url: Some(url.to_string()),
+                source: None,
+            })?,
         }
-        AuthMethod::Token(token) => Ok(Some(token)),
     }
 }
 
-/// Fetches a vault token via a GitHub personal access token.
-async fn fetch_token_github(host: &str, pat: &str, backend: &str) -> Result<String> {
-    let vault_url = format!("{host}/v1/auth/{backend}/login");
-    log::info!("fetching token via github from `{}`", vault_url);
+/// Sends a HTTP/1.1 request over the unix domain socket at `socket`.
+#[cfg(unix)]
+async fn send_unix(
+    socket: &Path,
+    connect_timeout: Duration,
+    method: Method,
+    url: &str,
+    headers: impl Iterator<Item = (&HeaderName, &HeaderValue)>,
+    body: Option<&Value>,
+) -> Result<Response> {
+    use hyper::header::HOST;
+    use hyper_util::rt::TokioIo;
+
+    let stream = tokio::time::timeout(connect_timeout, tokio::net::UnixStream::connect(socket))
+        .await
+        .map_err(|_| Error::ReqwestTransient {
+            message: format!("connecting to {:?} timed out", socket),
+            url: Some(url.to_string()),
+            source: None,
+        })?
+        .map_err(|err| Error::transient(&format!("unable to connect to {:?}", socket), url, err))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|err| Error::transient(&format!("unable to connect to {:?}", socket), url, err))?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            tracing::debug!("unix socket connection to vault failed: {}", err);
+        }
+    });
+
+    let uri = url.strip_prefix(UNIX_HOST).unwrap_or(url);
+    let mut request = hyper_request(method, uri, url, headers, body)?;
+    request
+        .headers_mut()
+        .insert(HOST, HeaderValue::from_static("localhost"));
+
+    let response = sender.send_request(request).await.map_err(|err| {
+        Error::transient(
+            &format!("error sending request for url ({})", url),
+            url,
+            err,
+        )
+    })?;
+    hyper_response(url, response).await
+}
+
+/// Builds the request to `uri` (of `url`) for the transports based on hyper, with the user agent
+/// of vaultify unless one of the `headers` replaces it.
+fn hyper_request<'n, 'v>(
+    method: Method,
+    uri: &str,
+    url: &str,
+    headers: impl Iterator<Item = (&'n HeaderName, &'v HeaderValue)>,
+    body: Option<&Value>,
+) -> Result<hyper::Request<http_body_util::Full<hyper::body::Bytes>>> {
+    use http_body_util::Full;
+    use hyper::{body::Bytes, header::USER_AGENT};
 
-    // setup body
-    let body = serde_json::json!({
-        "token": pat,
-    });
+    let mut request = hyper::Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if !request
+        .headers_ref()
+        .is_some_and(|headers| headers.contains_key(USER_AGENT))
+    {
+        request = request.header(USER_AGENT, build_info::USER_AGENT);
+    }
+    let body = match body {
+        Some(body) => {
+            request = request.header(CONTENT_TYPE, "application/json");
+            Full::new(Bytes::from(serde_json::to_vec(body)?))
+        }
+        None => Full::new(Bytes::new()),
+    };
+    request
+        .body(body)
+        .map_err(|err| Error::Conversion(format!("invalid request to {}: {}", url, err)))
+}
 
-    // send request
-    let response = client()
-        .post(vault_url.clone())
-        .header(CONTENT_TYPE, "application/json")
-        .json(&body)
-        .send()
-        .await?;
+/// Reads the response to a request to `url` sent by a transport based on hyper.
+async fn hyper_response(
+    url: &str,
+    response: hyper::Response<hyper::body::Incoming>,
+) -> Result<Response> {
+    use http_body_util::BodyExt;
 
-    // read `.auth.client_token` from response
-    let result = require_success_and_read_text(response, &vault_url).await?;
-    let value = serde_json::from_str::<Value>(&result)?;
-    let data = value
-        .get("auth")
-        .ok_or_else(|| Error::NotFound("vault response does not contain .auth".to_string()))?;
-    let token = data
-        .get("client_token")
-        .ok_or_else(|| {
-            Error::NotFound("vault response does not contain .data.client_token".to_string())
-        })?
-        .as_str()
-        .ok_or_else(|| {
-            Error::Deserialization(
-                "vault response token cannot be made into a string or is empty".to_string(),
-            )
-        })?;
+    let status = response.status().as_u16();
+    let retry_after = parse_retry_after(response.headers());
+    let index = parse_index(response.headers());
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|err| Error::transient(&format!("error reading response of {}", url), url, err))?
+        .to_bytes();
 
-    Ok(token.to_string())
+    Ok(Response {
+        url: url.to_string(),
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+        retry_after,
+        index,
+    })
 }
 
-/// Fetches a vault token via a Kubernetes role.
-async fn fetch_token_kubernetes(host: &str, role: &str, backend: &str) -> Result<String> {
-    // read service account jwt
-    const KUBE_SA_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
-    let jwt = tokio::fs::read_to_string(KUBE_SA_TOKEN)
-        .await
-        .map_err(|err| Error::IO(format!("unable to read file {:?}: {}", KUBE_SA_TOKEN, err)))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{KvVersion, MockVault};
 
-    let vault_url = format!("{host}/v1/auth/{backend}/login");
-    log::info!("fetching token via kubernetes role from `{}`", vault_url);
+    fn transient(message: &str) -> Error {
+        Error::ReqwestTransient {
+            message: message.to_string(),
+            url: None,
+            source: None,
+        }
+    }
 
-    // setup body
-    let body = serde_json::json!({
-        "jwt": jwt,
-        "role": role,
-    });
+    fn request_error(message: &str) -> Error {
+        Error::Reqwest {
+            message: message.to_string(),
+            url: None,
+            source: None,
+        }
+    }
 
-    // send request
-    let response = client()
-        .post(vault_url.clone())
-        .header(CONTENT_TYPE, "application/json")
-        .json(&body)
-        .send()
-        .await?;
+    #[test]
+    fn pass_client_build() {
+        Context::default().transport().unwrap();
+    }
 
-    // read `.auth.client_token` from response
-    let result = require_success_and_read_text(response, &vault_url).await?;
-    let value = serde_json::from_str::<Value>(&result)?;
-    let data = value
-        .get("auth")
-        .ok_or_else(|| Error::NotFound("vault response does not contain .auth".to_string()))?;
-    let token = data
-        .get("client_token")
-        .ok_or_else(|| {
-            Error::NotFound("vault response does not contain .data.client_token".to_string())
-        })?
-        .as_str()
-        .ok_or_else(|| {
-            Error::Deserialization(
-                "vault response token cannot be made into a string or is empty".to_string(),
-            )
-        })?;
+    #[tokio::test]
+    async fn fail_request_timeout() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+        vault.delay("secret", "app", Duration::from_secs(10));
 
-    Ok(token.to_string())
-}
+        let context = Context::default();
+        let opts = ClientOpts {
+            request_timeout: Duration::from_millis(200),
+            ..ClientOpts::default()
+        };
+        let hosts = context.init_client(&[vault.addr()], opts).unwrap();
+        let mut client = VaultClient::new(&context, &hosts[0], None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        let started = std::time::Instant::now();
+        let spec = spec("A", "app", "a");
+        let err = client.fetch_all(&[&spec], &fetch_opts()).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(should_failover(&err), "{}", err);
+    }
 
-/// Options passed to `fetch_all`.
-pub struct FetchAllOpts {
-    /// Number of retries per query.
-    pub retries: usize,
-    /// Delay between retries.
-    pub retry_delay: Duration,
-    /// Number of parallel requests to the vault.
-    pub concurrency: usize,
-}
+    #[tokio::test]
+    async fn fail_connect_timeout() {
+        let context = Context::default();
+        let opts = ClientOpts {
+            connect_timeout: Duration::from_millis(100),
+            ..ClientOpts::default()
+        };
+        // not routable, so connecting hangs unless the network is unreachable right away
+        let hosts = ["http://10.255.255.1:8200".to_string()];
+        let hosts = context.init_client(&hosts, opts).unwrap();
+        let mut client = VaultClient::new(&context, &hosts[0], None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        let started = std::time::Instant::now();
+        let spec = spec("A", "app", "a");
+        let err = client.fetch_all(&[&spec], &fetch_opts()).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(should_failover(&err), "{}", err);
+    }
 
-/// Fetches a list of secrets from vault with retry and batching.
-pub async fn fetch_all(
-    host: &str,
-    token: Option<&str>,
-    secrets: &SecretSpecs,
-    opts: FetchAllOpts,
-) -> Result<Vec<Secret>> {
-    let secrets = secrets.iter().map(|(_k, v)| v).collect::<Vec<_>>();
-    let mut results = Vec::with_capacity(secrets.len());
-    for secrets in secrets.chunks(opts.concurrency) {
-        let res = futures::future::join_all(secrets.iter().map(|s| async {
-            retry(
-                || async { fetch_single(host, token, s).await },
-                opts.retries,
-                opts.retry_delay,
-            )
+    #[tokio::test]
+    async fn pass_wait_for_health() {
+        let vault = MockVault::start().await.unwrap();
+        vault.seal();
+        let hosts = [vault.addr()];
+        let context = Context::default();
+        let (waited, ()) = tokio::join!(
+            context.wait_for_health(&hosts, Duration::from_secs(10)),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                vault.unseal();
+            }
+        );
+        waited.unwrap();
+        let checks = vault
+            .requests()
+            .iter()
+            .filter(|request| *request == "GET /v1/sys/health")
+            .count();
+        assert_eq!(checks, 2);
+    }
+
+    #[tokio::test]
+    async fn fail_wait_for_health() {
+        let vault = MockVault::start().await.unwrap();
+        vault.seal();
+        let hosts = [vault.addr(), "http://127.0.0.1:1".to_string()];
+        let err = Context::default()
+            .wait_for_health(&hosts, Duration::from_millis(100))
             .await
-        }))
-        .await;
-        for r in res.into_iter() {
-            results.push(r?);
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("did not become healthy"), "{}", message);
+        assert!(message.contains("is sealed"), "{}", message);
+        assert!(message.contains("is unreachable"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn pass_client_reuses_connection() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+        vault.put("secret", "db", serde_json::json!({"password": "hunter2"}));
+
+        // the requests of all clients of a context share the pooled connection
+        let context = Context::default();
+        for (name, path, key) in [("A", "app", "a"), ("B", "db", "password")] {
+            let mut client = VaultClient::new(&context, &vault.addr(), None);
+            let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+            client.login(auth, token_opts()).await.unwrap();
+            let spec = spec(name, path, key);
+            client.fetch_all(&[&spec], &fetch_opts()).await.unwrap();
         }
+        assert!(vault.requests().len() > 1);
+        assert_eq!(vault.connections(), 1);
     }
 
-    Ok(results)
-}
+    fn spec(name: &str, path: &str, secret: &str) -> SecretSpec {
+        let target = crate::secrets::SecretTarget::Env {
+            name: name.to_string(),
+        };
+        SecretSpec::vault(target, "secret", path, secret)
+    }
 
-/// Fetches a single secret from vault v2 and fallbacks to vault v1 on error.
-pub async fn fetch_single(host: &str, token: Option<&str>, secret: &SecretSpec) -> Result<Secret> {
-    // try to fetch a v2 secret
-    match fetch_single_v2(host, token, secret).await {
-        Ok(secret) => return Ok(secret),
-        Err(err) => {
-            if !should_fallback_to_v1(&err) {
-                return Err(err);
-            }
+    #[test]
+    fn pass_group_by_path() {
+        let (a, b, c) = (
+            spec("A", "app", "a"),
+            spec("B", "db", "password"),
+            spec("C", "app", "c"),
+        );
+        let paths = group_by_path(&[&a, &b, &c])
+            .into_iter()
+            .map(|specs| specs.iter().map(|s| s.name()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![vec!["A", "C"], vec!["B"]]);
+    }
 
-            log::warn!(
-                "could not fetch v2 secret `{}` from vault, trying v1 fallback: {}",
-                secret.name(),
-                err
-            );
-        }
-    };
+    #[test]
+    fn pass_can_read() {
+        let capabilities = serde_json::json!({
+            "secret/data/app": ["read", "list"],
+            "secret/app": ["deny"],
+            "data": {"secret/data/admin": ["root"]},
+        });
+        assert!(can_read(&capabilities, "secret/data/app"));
+        assert!(can_read(&capabilities, "secret/data/admin"));
+        assert!(!can_read(&capabilities, "secret/app"));
+        assert!(!can_read(&capabilities, "secret/data/db"));
+    }
 
-    // fallback to fetching a v1 secret
-    match fetch_single_v1(host, token, secret).await {
-        Ok(secret) => Ok(secret),
-        Err(err) => {
-            log::warn!(
-                "could not fetch v1 secret `{}` from vault: {}",
-                secret.name(),
-                err
-            );
-            Err(err)
-        }
-    }
-}
-
-async fn fetch_single_v2(
-    host: &str,
-    vault_token: Option<&str>,
-    secret_spec: &SecretSpec,
-) -> Result<Secret> {
-    let vault_url = format!(
-        "{}/v1/{}/data/{}",
-        host, secret_spec.mount, secret_spec.path
-    );
-    let secret_name = secret_spec.name();
-    log::info!("fetching v2 secret `{}` from `{}`", secret_name, vault_url);
-
-    let mut client = client().get(vault_url.clone());
-    if let Some(vault_token) = vault_token {
-        client = client.header("X-Vault-Token", vault_token)
-    }
-    let response = client.send().await?;
-    let result = require_success_and_read_text(response, &vault_url).await?;
-
-    // parse json blob dynamically
-    let value = serde_json::from_str::<Value>(&result)?;
-    let data = value
-        .get("data")
-        .ok_or_else(|| Error::NotFound("vault response does not contain .data".to_string()))?;
-    let data = data
-        .get("data")
-        .ok_or_else(|| Error::NotFound("vault response does not contain .data.data".to_string()))?;
-    let secret_value = data
-        .get(&secret_spec.secret)
-        .ok_or_else(|| {
-            Error::NotFound(format!(
-                "vault response does not contain .data.data.{}",
-                secret_spec.secret
-            ))
-        })?
-        .as_str()
-        .ok_or_else(|| {
-            Error::Deserialization(
-                "vault response secret cannot be made into a string or is empty".to_string(),
-            )
-        })?;
+    #[test]
+    fn pass_fan_out_identical_specs() {
+        let (a, b) = (spec("A", "app", "a"), spec("B", "app", "a"));
+        let paths = group_by_path(&[&a, &b]);
+        assert_eq!(paths.len(), 1);
 
-    Ok(Secret {
-        target: secret_spec.target.clone(),
-        secret: secret_value.to_string(),
-    })
-}
+        let data = serde_json::json!({"a": "1"});
+        let secrets = extract_secrets(data.as_object().unwrap(), ".data", &paths[0]).unwrap();
+        assert_eq!(secrets.len(), 2);
+        assert!(secrets.iter().all(|secret| secret.secret == "1"));
+    }
 
-async fn fetch_single_v1(
-    host: &str,
-    vault_token: Option<&str>,
-    secret_spec: &SecretSpec,
-) -> Result<Secret> {
-    let vault_url = format!("{}/v1/{}/{}", host, secret_spec.mount, secret_spec.path);
-    let secret_name = secret_spec.name();
-    log::info!("fetching v1 secret `{}` from `{}`", secret_name, vault_url);
-
-    let mut client = client().get(vault_url.clone());
-    if let Some(vault_token) = vault_token {
-        client = client.header("X-Vault-Token", vault_token)
-    }
-    let response = client.send().await?;
-    let result = require_success_and_read_text(response, &vault_url).await?;
-
-    // parse json blob dynamically
-    let value = serde_json::from_str::<Value>(&result)?;
-    let data = value
-        .get("data")
-        .ok_or_else(|| Error::NotFound("vault response does not contain .data".to_string()))?;
-    let secret_value = data
-        .get(&secret_spec.secret)
-        .ok_or_else(|| {
-            Error::NotFound(format!(
-                "vault response does not contain .data.{}",
-                secret_spec.secret
-            ))
-        })?
-        .as_str()
-        .ok_or_else(|| {
-            Error::Deserialization(
-                "vault response secret cannot be made into a string or is empty".to_string(),
-            )
-        })?;
+    #[test]
+    fn pass_extract_secrets() {
+        let (a, c) = (spec("A", "app", "a"), spec("C", "app", "c"));
+        let specs = [&a, &c];
+        let data = serde_json::json!({"a": "1", "b": "2", "c": "3"});
+        let secrets = extract_secrets(data.as_object().unwrap(), ".data", &specs).unwrap();
+        assert_eq!(secrets[0].secret, "1");
+        assert_eq!(secrets[1].secret, "3");
 
-    Ok(Secret {
-        target: secret_spec.target.clone(),
-        secret: secret_value.to_string(),
-    })
-}
+        let data = serde_json::json!({"a": "1"});
+        assert!(matches!(
+            extract_secrets(data.as_object().unwrap(), ".data", &specs),
+            Err(Error::NotFound(_))
+        ));
+    }
 
-async fn retry<T, F, FU>(op: F, count: usize, delay: Duration) -> Result<T>
-where
-    F: Fn() -> FU,
-    FU: Future<Output = Result<T>>,
-{
-    for attempt in 0..=count {
-        match op().await {
-            Ok(result) => return Ok(result),
-            Err(err) => {
-                if !is_retryable_error(&err) {
-                    return Err(err);
-                }
+    #[tokio::test]
+    async fn pass_tls_server_name() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-                if attempt == count {
-                    return Err(Error::MaxRetries {
-                        source: Box::new(err),
-                    });
-                }
+        // a certificate for `vault.internal` served at 127.0.0.1
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca.self_signed(&ca_key).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["vault.internal".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.der().clone()],
+            PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+        )
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}/v1/sys/health", listener.local_addr().unwrap());
+        let server_names = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&server_names);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let server_name = stream.get_ref().1.server_name().map(str::to_string);
+                received.lock().unwrap().push(server_name);
+                let _ = stream.read(&mut [0; 1024]).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await;
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        let ca_cert = std::env::temp_dir().join(format!("vaultify-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_cert, ca.pem()).unwrap();
+        let opts = |server_name: Option<&str>| ClientOpts {
+            ca_cert: Some(ca_cert.clone()),
+            tls_server_name: server_name.map(str::to_string),
+            ..ClientOpts::default()
+        };
 
-                log::warn!("operation failed, retrying: {}", err);
+        let send = |server_name: Option<&str>| {
+            let transport = build_transport(&opts(server_name));
+            let url = url.clone();
+            async move {
+                transport?
+                    .send(Method::GET, &url, &HeaderMap::new(), None)
+                    .await
             }
+        };
+
+        // the address is left as is, the server name is sent as SNI and verified
+        let response = send(Some("vault.internal")).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            *server_names.lock().unwrap(),
+            [Some("vault.internal".to_string())]
+        );
+        for server_name in [None, Some("other.internal")] {
+            assert!(send(server_name).await.is_err());
         }
 
-        tokio::time::sleep(delay).await;
+        let proxied = ClientOpts {
+            proxy: Some("http://proxy:3128".to_string()),
+            ..opts(Some("vault.internal"))
+        };
+        assert!(build_transport(&proxied).is_err());
+        std::fs::remove_file(ca_cert).unwrap();
     }
 
-    unreachable!("retry loop always returns from within the loop")
-}
+    fn http_status(code: u16) -> Error {
+        Error::HttpStatus {
+            code,
+            url: "https://vault.example/v1/secret/data/a".to_string(),
+            body: String::new(),
+            retry_after: None,
+            request_id: None,
+        }
+    }
 
-async fn require_success_and_read_text(
-    response: reqwest::Response,
-    vault_url: &str,
-) -> Result<String> {
-    let status = response.status();
-    let result = response.text().await?;
-    if !status.is_success() {
-        return Err(Error::HttpStatus {
-            code: status.as_u16(),
-            url: vault_url.to_string(),
-            body: result,
-        });
+    #[test]
+    fn pass_is_retryable_error() {
+        for code in [412, 429, 500, 502, 503, 504] {
+            assert!(is_retryable_error(&http_status(code)), "{}", code);
+        }
+        assert!(is_retryable_error(&transient("connection reset")));
     }
 
-    Ok(result)
-}
+    #[test]
+    fn pass_should_failover() {
+        assert!(should_failover(&http_status(503)));
+        assert!(should_failover(&Error::MaxRetries {
+            source: Box::new(transient("connection refused")),
+        }));
+        assert!(!should_failover(&http_status(403)));
+        assert!(!should_failover(&Error::NotFound(".data".to_string())));
+    }
 
-#[inline]
-fn is_retryable_error(err: &Error) -> bool {
-    match err {
-        Error::ReqwestTransient(_) => true,
-        Error::HttpStatus { code, .. } => *code == 429 || (500..=599).contains(code),
-        Error::IO(_)
-        | Error::NotFound(_)
-        | Error::Parse { .. }
-        | Error::Conversion(_)
-        | Error::Deserialization(_)
-        | Error::MaxRetries { .. }
-        | Error::Reqwest(_)
-        | Error::Execution(_) => false,
+    #[test]
+    fn pass_parse_header() {
+        let (name, value) = parse_header("X-Forwarded-Client: foo").unwrap();
+        assert_eq!(name, "x-forwarded-client");
+        assert_eq!(value, "foo");
+
+        let (_, value) = parse_header("X-Tenant:a:b").unwrap();
+        assert_eq!(value, "a:b");
     }
-}
 
-#[inline]
-fn should_fallback_to_v1(err: &Error) -> bool {
-    match err {
-        Error::NotFound(_) | Error::Deserialization(_) => true,
-        Error::HttpStatus { code, .. } => *code == 400 || *code == 404,
-        Error::IO(_)
-        | Error::Parse { .. }
-        | Error::Conversion(_)
-        | Error::MaxRetries { .. }
-        | Error::Reqwest(_)
-        | Error::ReqwestTransient(_)
-        | Error::Execution(_) => false,
+    #[test]
+    fn fail_parse_header() {
+        assert!(parse_header("X-Tenant").is_err());
+        assert!(parse_header(": foo").is_err());
+        assert!(parse_header("X Tenant: foo").is_err());
+        assert!(parse_header("X-Tenant: foo\nbar").is_err());
     }
-}
 
-fn client() -> &'static Client {
-    const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
-    const DEFAULT_REQUEST_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    #[test]
+    fn pass_correlation_header() {
+        let id = crypto::random_id().unwrap();
+        assert_eq!(id.len(), 16);
+        assert_ne!(id, crypto::random_id().unwrap());
+        let (name, value) = correlation_header(&id).unwrap();
+        assert_eq!(name, "x-vaultify-correlation-id");
+        assert_eq!(value, id.as_str());
 
-    static CLIENT: OnceLock<Client> = OnceLock::new();
-    CLIENT.get_or_init(|| {
-        Client::builder()
-            .timeout(DEFAULT_REQUEST_TIMEOUT)
-            .connect_timeout(DEFAULT_REQUEST_CONNECT_TIMEOUT)
-            .build()
-            .expect("unable to build reqwest client")
-    })
-}
+        assert!(correlation_header("run\n1").is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn pass_is_sealed() {
+        let sealed = Error::HttpStatus {
+            code: 503,
+            url: "http://127.0.0.1:8200/v1/secret/data/app".to_string(),
+            body: r#"{"errors":["Vault is sealed"]}"#.to_string(),
+            retry_after: None,
+            request_id: None,
+        };
+        assert!(is_sealed(&sealed));
+        assert!(!is_retryable_error(&sealed));
+        assert!(should_failover(&sealed));
+        assert!(!is_sealed(&http_status(503)));
+        assert!(!is_sealed(&transient("timeout")));
+    }
 
     #[test]
-    fn pass_client_build() {
-        let _ = client();
+    fn fail_is_retryable_error() {
+        for code in [400, 401, 403, 404, 405, 501] {
+            assert!(!is_retryable_error(&http_status(code)), "{}", code);
+        }
+        assert!(!is_retryable_error(&request_error("builder")));
+    }
+
+    #[test]
+    fn pass_parse_retry_after() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
     }
 
     #[test]
@@ -397,38 +2649,688 @@ mod tests {
         assert!(should_fallback_to_v1(&Error::NotFound(
             "missing data".to_string()
         )));
-        assert!(should_fallback_to_v1(&Error::Deserialization(
-            "invalid v2 response shape".to_string()
+        assert!(should_fallback_to_v1(&Error::deserialization(
+            "invalid v2 response shape"
         )));
         assert!(should_fallback_to_v1(&Error::HttpStatus {
             code: 404,
             url: "https://vault.example/v1/secret/data/a".to_string(),
             body: "not found".to_string(),
+            retry_after: None,
+            request_id: None,
         }));
         assert!(should_fallback_to_v1(&Error::HttpStatus {
             code: 400,
             url: "https://vault.example/v1/secret/data/a".to_string(),
             body: "bad request".to_string(),
+            retry_after: None,
+            request_id: None,
         }));
     }
 
     #[test]
     fn pass_should_not_fallback_to_v1_for_auth_or_transport_errors() {
-        assert!(!should_fallback_to_v1(&Error::Reqwest(
-            "connection timeout".to_string()
-        )));
-        assert!(!should_fallback_to_v1(&Error::ReqwestTransient(
-            "connection timeout".to_string()
-        )));
+        assert!(!should_fallback_to_v1(&request_error("connection timeout")));
+        assert!(!should_fallback_to_v1(&transient("connection timeout")));
         assert!(!should_fallback_to_v1(&Error::HttpStatus {
             code: 403,
             url: "https://vault.example/v1/secret/data/a".to_string(),
             body: "forbidden".to_string(),
+            retry_after: None,
+            request_id: None,
         }));
         assert!(!should_fallback_to_v1(&Error::HttpStatus {
             code: 500,
             url: "https://vault.example/v1/secret/data/a".to_string(),
             body: "internal error".to_string(),
+            retry_after: None,
+            request_id: None,
+        }));
+    }
+
+    #[test]
+    fn pass_is_plaintext_remote() {
+        assert!(is_plaintext_remote("http://vault.example.com:8200"));
+        assert!(is_plaintext_remote("http://10.0.0.1:8200"));
+        assert!(!is_plaintext_remote("https://vault.example.com:8200"));
+        assert!(!is_plaintext_remote("http://127.0.0.1:8200"));
+        assert!(!is_plaintext_remote("http://127.0.1.1:8200"));
+        assert!(!is_plaintext_remote("http://[::1]:8200"));
+        assert!(!is_plaintext_remote("http://LOCALHOST:8200"));
+        assert!(!is_plaintext_remote("unix:///run/vault-agent.sock"));
+    }
+
+    fn token_opts() -> FetchTokenOpts {
+        FetchTokenOpts {
+            retries: 0,
+            retry_delay: Duration::ZERO,
+        }
+    }
+
+    fn fetch_opts() -> FetchAllOpts {
+        FetchAllOpts {
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            concurrency: 2,
+            rate_limit: None,
+            fetch_timeout: None,
+            allow_missing: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_kv2() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1", "c": "3"}));
+        vault.put("secret", "db", serde_json::json!({"password": "hunter2"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::GitHub {
+            token: "gh".to_string(),
+            backend: "github".to_string(),
+        };
+        client.login(auth, token_opts()).await.unwrap();
+
+        let (a, b, c) = (
+            spec("A", "app", "a"),
+            spec("B", "db", "password"),
+            spec("C", "app", "c"),
+        );
+        let specs = [&a, &b, &c];
+        client
+            .preflight(&specs, 0, Duration::ZERO, &[])
+            .await
+            .unwrap();
+        let secrets = client.fetch_all(&specs, &fetch_opts()).await.unwrap();
+        let values = secrets
+            .iter()
+            .map(|s| s.secret.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(values, ["1", "3", "hunter2"]);
+
+        // a single read per path
+        let reads = vault.requests();
+        assert_eq!(reads.iter().filter(|r| r.starts_with("GET ")).count(), 2);
+        assert_eq!(reads[0], "POST /v1/auth/github/login");
+    }
+
+    #[tokio::test]
+    async fn pass_login_github_actions() {
+        let vault = MockVault::start().await.unwrap();
+        let request_url = format!(
+            "{}{}?api-version=2.0",
+            vault.addr(),
+            MockVault::ACTIONS_TOKEN_PATH
+        );
+        let auth = |request_token: &str| crate::AuthMethod::GitHubActions {
+            request_url: request_url.clone(),
+            request_token: request_token.to_string(),
+            audience: Some("https://vault.example.com".to_string()),
+            role: Some("ci".to_string()),
+            backend: "jwt-github".to_string(),
+        };
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        client
+            .login(auth(MockVault::ACTIONS_REQUEST_TOKEN), token_opts())
+            .await
+            .unwrap();
+        assert_eq!(client.token.as_deref(), Some(MockVault::TOKEN));
+        // the request token, the OIDC token and the vault token never show up in log output
+        assert_eq!(
+            logging::redacted(&format!(
+                "{} oidc:https%3A%2F%2Fvault.example.com {}",
+                MockVault::ACTIONS_REQUEST_TOKEN,
+                MockVault::TOKEN
+            )),
+            "[redacted] [redacted] [redacted]"
+        );
+        assert_eq!(
+            vault.requests(),
+            [
+                "GET /_apis/idtoken?api-version=2.0&audience=https%3A%2F%2Fvault.example.com",
+                "POST /v1/auth/jwt-github/login"
+            ]
+        );
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let err = client
+            .login(auth("expired"), token_opts())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HttpStatus { code: 401, .. }));
+    }
+
+    #[tokio::test]
+    async fn pass_token_info() {
+        let vault = MockVault::start().await.unwrap();
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::GitHub {
+            token: "gh".to_string(),
+            backend: "github".to_string(),
+        };
+        client.login(auth, token_opts()).await.unwrap();
+        let info = TokenInfo {
+            accessor: MockVault::ACCESSOR.to_string(),
+            policies: vec!["default".to_string(), "app".to_string()],
+            ttl: 3600,
+            renewable: true,
+            entity_id: String::new(),
+        };
+        assert_eq!(client.token_info(), Some(&info));
+
+        // a static token is looked up, with the policies of its entity
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        let info = TokenInfo {
+            ttl: 0,
+            renewable: false,
+            ..info
+        };
+        assert_eq!(client.token_info(), Some(&info));
+        assert_eq!(client.lookup_self(&token_opts()).await.unwrap(), info);
+
+        // which is not required to log in
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token("revoked".to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        assert_eq!(client.token_info(), None);
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_kv1_fallback() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V1);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let a = spec("A", "app", "a");
+        let secrets = client.fetch_all(&[&a], &fetch_opts()).await.unwrap();
+        assert_eq!(secrets[0].secret, "1");
+        assert_eq!(
+            vault.requests(),
+            [
+                "GET /v1/auth/token/lookup-self",
+                "GET /v1/secret/data/app",
+                "GET /v1/sys/internal/ui/mounts/secret",
+                "GET /v1/secret/app"
+            ]
+        );
+
+        // the version of the mount is remembered
+        client.fetch_all(&[&a], &fetch_opts()).await.unwrap();
+        assert_eq!(vault.requests()[4..], ["GET /v1/secret/app"]);
+    }
+
+    #[tokio::test]
+    async fn pass_detect_mounts() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V1);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let a = spec("A", "app", "a");
+        let typo = SecretSpec {
+            mount: "secrte".to_string(),
+            ..spec("B", "app", "b")
+        };
+        let err = client
+            .detect_mounts(&[&a, &typo], 0, Duration::ZERO, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Element not found: no such mount `secrte` (`B`) at `{}`; mounts visible to the token: secret",
+                vault.addr()
+            )
+        );
+        client
+            .detect_mounts(&[&a, &typo], 0, Duration::ZERO, &["B".to_string()])
+            .await
+            .unwrap();
+        // the version of the mount is known by now, so it is not listed again
+        client
+            .detect_mounts(&[&a], 0, Duration::ZERO, &[])
+            .await
+            .unwrap();
+
+        // the v1 mount is read without trying v2 first
+        let secrets = client.fetch_all(&[&a], &fetch_opts()).await.unwrap();
+        assert_eq!(secrets[0].secret, "1");
+        assert_eq!(vault.requests().last().unwrap(), "GET /v1/secret/app");
+        assert_eq!(vault.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_kv1_mounts() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("old-kv", KvVersion::V1);
+        vault.put("old-kv", "app", serde_json::json!({"a": "1"}));
+        vault.mount("secret", KvVersion::V2);
+
+        let context = Context::default();
+        let mut client = VaultClient::new(&context, &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        context.set_kv_v1_mounts(&["old-kv/".to_string()]);
+        let a = SecretSpec {
+            mount: "old-kv".to_string(),
+            ..spec("A", "app", "a")
+        };
+        let secrets = client.fetch_all(&[&a], &fetch_opts()).await.unwrap();
+        assert_eq!(secrets[0].secret, "1");
+
+        // a secret missing from a v2 mount is not looked up via v1
+        let b = spec("B", "missing", "b");
+        assert!(client.fetch_all(&[&b], &fetch_opts()).await.is_err());
+        assert_eq!(
+            vault.requests(),
+            [
+                "GET /v1/auth/token/lookup-self",
+                "GET /v1/old-kv/app",
+                "GET /v1/secret/data/missing",
+                "GET /v1/sys/internal/ui/mounts/secret"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_fetch_forbidden() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token("invalid".to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let a = spec("A", "app", "a");
+        let err = client.fetch_all(&[&a], &fetch_opts()).await.unwrap_err();
+        assert_eq!(err.http_status(), Some(403), "{err:?}");
+        assert_eq!(err.context().secrets, ["A"]);
+
+        // missing secrets are reported by the preflight check
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        let b = spec("B", "db", "password");
+        assert!(client
+            .preflight(&[&a, &b], 0, Duration::ZERO, &[])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_timeout() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+        vault.put("secret", "slow", serde_json::json!({"b": "2"}));
+        vault.delay("secret", "slow", Duration::from_secs(30));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let started = Instant::now();
+        let (a, b) = (spec("A", "app", "a"), spec("B", "slow", "b"));
+        let opts = FetchAllOpts {
+            retries: 1,
+            fetch_timeout: Some(Duration::from_millis(100)),
+            ..fetch_opts()
+        };
+        let err = client.fetch_all(&[&a, &b], &opts).await.unwrap_err();
+        assert!(should_failover(&err));
+        assert!(err
+            .to_string()
+            .contains("fetching `B` did not complete within 100ms"));
+        let slow_reads = vault
+            .requests()
+            .iter()
+            .filter(|request| request.ends_with("/slow"))
+            .count();
+        assert_eq!(slow_reads, 2);
+
+        // a fatal error of another path cancels the hung fetch
+        let c = spec("C", "missing", "c");
+        assert!(client.fetch_all(&[&b, &c], &fetch_opts()).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_concurrency() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "slow", serde_json::json!({"a": "1"}));
+        vault.put("secret", "fast", serde_json::json!({"b": "2"}));
+        vault.put("secret", "late", serde_json::json!({"c": "3"}));
+        vault.delay("secret", "slow", Duration::from_millis(300));
+        vault.delay("secret", "late", Duration::from_millis(300));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+        let (a, b, c) = (
+            spec("A", "slow", "a"),
+            spec("B", "fast", "b"),
+            spec("C", "late", "c"),
+        );
+
+        // the fast path frees its slot right away, so both slow paths are in flight together
+        let started = Instant::now();
+        let secrets = client
+            .fetch_all(&[&a, &b, &c], &fetch_opts())
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(550));
+
+        // one at a time, in the same order however the fetches completed
+        let started = Instant::now();
+        let opts = FetchAllOpts {
+            concurrency: 1,
+            ..fetch_opts()
+        };
+        let sequential = client.fetch_all(&[&a, &b, &c], &opts).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        let values = |secrets: &[Secret]| {
+            secrets
+                .iter()
+                .map(|secret| (secret.target.name(), secret.secret.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(&secrets), values(&sequential));
+        assert_eq!(secrets.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn pass_fetch_allow_missing() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let (a, b, c) = (
+            spec("A", "app", "a"),
+            spec("OPTIONAL_B", "app", "b"),
+            spec("OPTIONAL_C", "db", "password"),
+        );
+        let specs = [&a, &b, &c];
+        let opts = FetchAllOpts {
+            allow_missing: vec!["OPTIONAL_*".to_string()],
+            ..fetch_opts()
+        };
+        client
+            .preflight(&specs, 0, Duration::ZERO, &opts.allow_missing)
+            .await
+            .unwrap();
+        let secrets = client.fetch_all(&specs, &opts).await.unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].secret, "1");
+
+        // secrets not matching the globs are still required
+        let d = spec("D", "app", "d");
+        assert!(client.fetch_all(&[&a, &d], &opts).await.is_err());
+    }
+
+    fn entry(key: &str, value: &str) -> Map<String, Value> {
+        Map::from_iter([(key.to_string(), Value::String(value.to_string()))])
+    }
+
+    #[tokio::test]
+    async fn pass_put() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let opts = token_opts();
+        let version = client
+            .put("secret", "app", entry("b", "2"), None, &opts)
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(
+            vault.get("secret", "app"),
+            Some((2, serde_json::json!({"a": "1", "b": "2"})))
+        );
+
+        // new secrets are created with check-and-set 0
+        let version = client
+            .put("secret", "db", entry("password", "p"), None, &opts)
+            .await
+            .unwrap();
+        assert_eq!(version, 1);
+    }
+
+    #[tokio::test]
+    async fn fail_put() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.mount("legacy", KvVersion::V1);
+        vault.put("secret", "app", serde_json::json!({"a": "1"}));
+        vault.put("secret", "app", serde_json::json!({"a": "2"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let opts = token_opts();
+        let err = client
+            .put("secret", "app", entry("a", "3"), Some(1), &opts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HttpStatus { code: 400, .. }));
+        assert!(client
+            .put("secret", "app", entry("b", "3"), Some(0), &opts)
+            .await
+            .is_err());
+        assert!(matches!(
+            client
+                .put("legacy", "app", entry("a", "3"), None, &opts)
+                .await
+                .unwrap_err(),
+            Error::Conversion(_)
+        ));
+        assert_eq!(
+            vault.get("secret", "app"),
+            Some((2, serde_json::json!({"a": "2"})))
+        );
+    }
+
+    #[tokio::test]
+    async fn pass_list() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.mount("legacy", KvVersion::V1);
+        for path in ["apps/web", "apps/db/main", "apps/db/replica", "infra"] {
+            vault.put("secret", path, serde_json::json!({"a": "1"}));
+        }
+        vault.put("legacy", "apps/web", serde_json::json!({"a": "1"}));
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let opts = token_opts();
+        let paths = client.list("secret", "apps/", false, &opts).await.unwrap();
+        assert_eq!(paths, ["apps/db/", "apps/web"]);
+        let paths = client.list("secret", "", true, &opts).await.unwrap();
+        assert_eq!(
+            paths,
+            ["apps/db/main", "apps/db/replica", "apps/web", "infra"]
+        );
+        let paths = client.list("legacy", "apps", false, &opts).await.unwrap();
+        assert_eq!(paths, ["apps/web"]);
+        assert!(client
+            .list("secret", "nothere/", false, &opts)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn pass_subkeys() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("secret", KvVersion::V2);
+        vault.mount("legacy", KvVersion::V1);
+        vault.put(
+            "secret",
+            "apps/web",
+            serde_json::json!({"user": "app", "tls": {"cert": "pem"}}),
+        );
+
+        let mut client = VaultClient::new(&Context::default(), &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let opts = token_opts();
+        let keys = client.subkeys("secret", "apps/web", &opts).await.unwrap();
+        assert_eq!(keys.unwrap(), ["tls", "user"]);
+        assert!(client.subkeys("secret", "apps/db", &opts).await.is_err());
+        assert_eq!(
+            client.subkeys("legacy", "apps/web", &opts).await.unwrap(),
+            None
+        );
+        // the values are never read
+        assert!(!vault
+            .requests()
+            .iter()
+            .any(|request| request.contains("/data/")));
+    }
+
+    #[test]
+    fn pass_renewal() {
+        let issued_at = UNIX_EPOCH;
+        let lifetime = Duration::from_secs(1000);
+        let fixed = Renewal {
+            fraction: 0.5,
+            jitter: 0.0,
+        };
+        assert_eq!(
+            fixed.at(issued_at, lifetime).unwrap(),
+            issued_at + lifetime / 2
+        );
+
+        let jittered = Renewal {
+            fraction: 0.7,
+            jitter: 0.2,
+        };
+        let renewals = (0..100)
+            .map(|_| jittered.at(issued_at, lifetime).unwrap())
+            .collect::<Vec<_>>();
+        assert!(renewals.iter().all(|renew_at| {
+            (issued_at + Duration::from_secs(500)..=issued_at + Duration::from_secs(700))
+                .contains(renew_at)
         }));
+        assert!(renewals.iter().any(|renew_at| *renew_at != renewals[0]));
+    }
+
+    #[tokio::test]
+    async fn pass_issue_certificate() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount_pki("pki", Duration::from_secs(3600));
+        vault.mount_pki("pki-expired", Duration::ZERO);
+
+        let context = Context::default();
+        let mut client = VaultClient::new(&context, &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        let pki = |name: &str, mount: &str, field: &str| SecretSpec {
+            provider: Provider::Pki,
+            mount: mount.to_string(),
+            ..spec(name, "issue/web?common_name=web.example.com&ttl=1h", field)
+        };
+        let (cert, key, chain) = (
+            pki("TLS_CERT", "pki", "certificate"),
+            pki("TLS_KEY", "pki", "private_key"),
+            pki("TLS_CHAIN", "pki", "ca_chain"),
+        );
+        let secrets = client
+            .fetch_path(&[&cert, &key, &chain], None)
+            .await
+            .unwrap();
+        let values = secrets
+            .iter()
+            .map(|s| s.secret.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                "certificate 1 for web.example.com",
+                "private key 1",
+                "issuing ca\nroot ca"
+            ]
+        );
+
+        // the certificate is reused until 60-70% of its lifetime passed
+        let again = client.fetch_path(&[&cert], None).await.unwrap();
+        assert_eq!(again[0].secret, "certificate 1 for web.example.com");
+        let renew_in = context
+            .next_certificate_renewal()
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(renew_in > Duration::from_secs(2150) && renew_in <= Duration::from_secs(2520));
+
+        let expired = pki("TLS_CERT", "pki-expired", "certificate");
+        for serial in [2, 3] {
+            let secrets = client.fetch_path(&[&expired], None).await.unwrap();
+            assert_eq!(
+                secrets[0].secret,
+                format!("certificate {serial} for web.example.com")
+            );
+        }
+        let issued = vault
+            .requests()
+            .into_iter()
+            .filter(|request| request.contains("/issue/"))
+            .count();
+        assert_eq!(issued, 3);
+    }
+
+    #[tokio::test]
+    async fn pass_revoke_leases() {
+        let vault = MockVault::start().await.unwrap();
+        vault.mount("database", KvVersion::V1);
+        vault.put_leased(
+            "database",
+            "creds/app",
+            serde_json::json!({"password": "p"}),
+        );
+
+        let context = Context::default();
+        let mut client = VaultClient::new(&context, &vault.addr(), None);
+        let auth = crate::AuthMethod::Token(MockVault::TOKEN.to_string());
+        client.login(auth, token_opts()).await.unwrap();
+
+        context.track_leases();
+        let mut password = spec("DB_PASSWORD", "creds/app", "password");
+        password.mount = "database".to_string();
+        let secrets = client.fetch_path_v1(&[&password], None).await.unwrap();
+        assert_eq!(vault.leases(), ["database/creds/app/1"]);
+        let metadata = &secrets[0].metadata;
+        assert_eq!(metadata.lease_id.as_deref(), Some("database/creds/app/1"));
+        assert!(metadata
+            .expires_at
+            .is_some_and(|expires_at| expires_at > SystemTime::now()));
+
+        context.revoke_leases().await;
+        assert!(vault.leases().is_empty());
     }
 }
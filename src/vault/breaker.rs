@@ -0,0 +1,164 @@
+//! Circuit breaker per vault address: an address which failed repeatedly is skipped for a
+//! cool-down period, so retries do not add load to a recovering cluster and requests fail over to
+//! the next address right away.
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+/// When the circuit of an address opens.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    /// Consecutive failures after which the address is skipped, 0 never skips it.
+    pub threshold: usize,
+    /// How long the address is skipped before it is tried again.
+    pub cooldown: Duration,
+}
+
+/// Failures of an address since it last succeeded.
+struct Circuit {
+    failures: usize,
+    open_until: Option<Instant>,
+    /// The last failure, returned instead of contacting the address while the circuit is open.
+    error: Error,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The circuits of the vault addresses of a run, held by the `vault::Context`.
+#[derive(Default)]
+pub struct Breakers {
+    config: Mutex<CircuitBreaker>,
+    circuits: Mutex<BTreeMap<String, Circuit>>,
+}
+
+impl Breakers {
+    /// Sets when the circuits of addresses open.
+    pub fn configure(&self, config: CircuitBreaker) {
+        *self.config.lock().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    fn circuits(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Circuit>> {
+        self.circuits.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The `hosts` to try in order, leaving out those whose circuit is open.
+    ///
+    /// # Remarks:
+    ///
+    /// Once the cool-down of an address elapsed it is tried again; another failure opens its
+    /// circuit right away. If all circuits are open, the last failure of the first address is
+    /// returned.
+    pub fn available<'a>(&self, hosts: &'a [String]) -> Result<Vec<&'a String>> {
+        let circuits = self.circuits();
+        let now = Instant::now();
+        let is_open = |host: &String| {
+            circuits
+                .get(host)
+                .and_then(|circuit| circuit.open_until)
+                .is_some_and(|open_until| open_until > now)
+        };
+
+        let available = hosts
+            .iter()
+            .filter(|host| !is_open(host))
+            .collect::<Vec<_>>();
+        for host in hosts.iter().filter(|host| is_open(host)) {
+            tracing::info!("skipping vault at `{}`, its circuit breaker is open", host);
+        }
+        match hosts.first() {
+            Some(first) if available.is_empty() => Err(circuits[first].error.clone()),
+            _ => Ok(available),
+        }
+    }
+
+    /// Records the result of contacting `host`; only failures to reach it or a sealed vault count.
+    pub fn record(&self, host: &str, result: std::result::Result<(), &Error>) {
+        let config = *self.config.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut circuits = self.circuits();
+        let err = match result {
+            Ok(()) => {
+                circuits.remove(host);
+                return;
+            }
+            Err(err) if config.threshold > 0 && super::should_failover(err) => err,
+            Err(_) => return,
+        };
+
+        let circuit = circuits
+            .entry(host.to_string())
+            .and_modify(|circuit| {
+                circuit.failures += 1;
+                circuit.error = err.clone();
+            })
+            .or_insert_with(|| Circuit {
+                failures: 1,
+                open_until: None,
+                error: err.clone(),
+            });
+        if circuit.failures >= config.threshold {
+            tracing::warn!(
+                "vault at `{}` failed {} times in a row, skipping it for {:?}",
+                host,
+                circuit.failures,
+                config.cooldown
+            );
+            circuit.open_until = Some(Instant::now() + config.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unreachable() -> Error {
+        Error::ReqwestTransient {
+            message: "connection refused".to_string(),
+            url: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn pass_circuit_breaker() {
+        let breakers = Breakers::default();
+        breakers.configure(CircuitBreaker {
+            threshold: 2,
+            cooldown: Duration::from_secs(60),
+        });
+        let hosts = [
+            "https://a.breaker".to_string(),
+            "https://b.breaker".to_string(),
+        ];
+        let err = unreachable();
+
+        // denied requests do not count
+        breakers.record(
+            &hosts[0],
+            Err(&Error::PermissionDenied("denied".to_string())),
+        );
+        breakers.record(&hosts[0], Err(&err));
+        assert_eq!(breakers.available(&hosts).unwrap(), [&hosts[0], &hosts[1]]);
+        breakers.record(&hosts[0], Err(&err));
+        assert_eq!(breakers.available(&hosts).unwrap(), [&hosts[1]]);
+
+        // with all circuits open the last failure is returned without contacting vault
+        breakers.record(&hosts[1], Err(&err));
+        breakers.record(&hosts[1], Err(&err));
+        assert_eq!(breakers.available(&hosts).unwrap_err(), err);
+
+        breakers.record(&hosts[1], Ok(()));
+        assert_eq!(breakers.available(&hosts).unwrap(), [&hosts[1]]);
+    }
+}
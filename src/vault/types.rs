@@ -0,0 +1,201 @@
+//! Typed responses of the vault API.
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Lease of a response, see https://developer.hashicorp.com/vault/docs/concepts/lease.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct LeaseInfo {
+    /// Id used to renew or revoke the lease, empty for responses without lease (e.g. kv secrets).
+    #[serde(default)]
+    pub lease_id: String,
+    /// Seconds until the lease expires.
+    #[serde(default)]
+    pub lease_duration: u64,
+    /// Whether the lease can be renewed.
+    #[serde(default)]
+    pub renewable: bool,
+}
+
+/// Response of `auth/<backend>/login`.
+#[derive(Debug, Deserialize)]
+pub struct LoginResponse {
+    /// Id vault assigned to the request, to correlate with its audit log.
+    #[serde(default)]
+    pub request_id: String,
+    pub auth: LoginAuth,
+}
+
+/// `.auth` of a login response.
+#[derive(Debug, Deserialize)]
+pub struct LoginAuth {
+    pub client_token: String,
+    #[serde(default)]
+    pub accessor: String,
+    /// Policies of the token, including those of its identity entity.
+    #[serde(default)]
+    pub policies: Vec<String>,
+    #[serde(default)]
+    pub entity_id: String,
+    #[serde(flatten)]
+    pub lease: LeaseInfo,
+}
+
+/// Response of `auth/token/lookup-self`.
+#[derive(Debug, Deserialize)]
+pub struct TokenLookupResponse {
+    pub data: TokenLookupData,
+}
+
+/// `.data` of a token lookup response.
+#[derive(Debug, Deserialize)]
+pub struct TokenLookupData {
+    #[serde(default)]
+    pub accessor: String,
+    #[serde(default)]
+    pub policies: Vec<String>,
+    /// Policies of the identity entity of the token, which `policies` does not include.
+    #[serde(default)]
+    pub identity_policies: Vec<String>,
+    /// Seconds until the token expires, 0 for tokens without expiry.
+    #[serde(default)]
+    pub ttl: u64,
+    #[serde(default)]
+    pub renewable: bool,
+    #[serde(default)]
+    pub entity_id: String,
+}
+
+/// Response of the OIDC token endpoint of a GitHub Actions runner (`ACTIONS_ID_TOKEN_REQUEST_URL`).
+#[derive(Debug, Deserialize)]
+pub struct GitHubActionsTokenResponse {
+    /// The signed JWT.
+    pub value: String,
+}
+
+/// Response of reading a kv v2 secret via `<mount>/data/<path>`.
+#[derive(Debug, Deserialize)]
+pub struct KvV2Response {
+    /// Id vault assigned to the request, to correlate with its audit log.
+    #[serde(default)]
+    pub request_id: String,
+    pub data: KvV2Data,
+}
+
+/// `.data` of a kv v2 response.
+#[derive(Debug, Deserialize)]
+pub struct KvV2Data {
+    /// Keys and values of the secret.
+    pub data: Map<String, Value>,
+    /// Metadata of the version read.
+    #[serde(default)]
+    pub metadata: Option<KvV2Metadata>,
+}
+
+/// Metadata of a kv v2 secret version.
+#[derive(Debug, Deserialize)]
+pub struct KvV2Metadata {
+    /// Version of the secret, starting at 1.
+    pub version: u64,
+}
+
+/// Response of listing a kv v1 path or kv v2 `<mount>/metadata/<path>`.
+#[derive(Debug, Deserialize)]
+pub struct KvListResponse {
+    pub data: KvListData,
+}
+
+/// `.data` of a list response.
+#[derive(Debug, Deserialize)]
+pub struct KvListData {
+    /// Names of the secrets and, ending with `/`, folders directly below the path.
+    pub keys: Vec<String>,
+}
+
+/// Response of reading the keys of a kv v2 secret via `<mount>/subkeys/<path>`.
+#[derive(Debug, Deserialize)]
+pub struct KvSubkeysResponse {
+    pub data: KvSubkeysData,
+}
+
+/// `.data` of a subkeys response.
+#[derive(Debug, Deserialize)]
+pub struct KvSubkeysData {
+    /// Keys of the secret, with `null` for values which are not objects.
+    pub subkeys: Map<String, Value>,
+}
+
+/// Response of writing a kv v2 secret via `<mount>/data/<path>`.
+#[derive(Debug, Deserialize)]
+pub struct KvV2WriteResponse {
+    /// Id vault assigned to the request, to correlate with its audit log.
+    #[serde(default)]
+    pub request_id: String,
+    /// Metadata of the written version.
+    pub data: KvV2Metadata,
+}
+
+/// Response of reading a kv v1 secret via `<mount>/<path>`.
+#[derive(Debug, Deserialize)]
+pub struct KvV1Response {
+    /// Id vault assigned to the request, to correlate with its audit log.
+    #[serde(default)]
+    pub request_id: String,
+    #[serde(flatten)]
+    pub lease: LeaseInfo,
+    /// Keys and values of the secret.
+    pub data: Map<String, Value>,
+}
+
+/// Response of issuing a certificate via `<mount>/issue/<role>` of a PKI secrets engine.
+#[derive(Debug, Deserialize)]
+pub struct PkiIssueResponse {
+    /// Id vault assigned to the request, to correlate with its audit log.
+    #[serde(default)]
+    pub request_id: String,
+    pub data: PkiIssueData,
+}
+
+/// `.data` of an issue response.
+#[derive(Debug, Deserialize)]
+pub struct PkiIssueData {
+    /// Unix time in seconds the certificate expires at.
+    pub expiration: u64,
+    /// Serial number of the certificate, e.g. to revoke it.
+    #[serde(default)]
+    pub serial_number: String,
+    /// The PEM encoded certificate, private key, issuing CA and CA chain.
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_login_response() {
+        let response = serde_json::from_str::<LoginResponse>(
+            r#"{"request_id": "9a1c", "auth": {"client_token": "t0k", "accessor": "acc", "lease_duration": 3600, "renewable": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(response.request_id, "9a1c");
+        assert_eq!(response.auth.client_token, "t0k");
+        assert_eq!(response.auth.accessor, "acc");
+        assert!(response.auth.policies.is_empty());
+        assert_eq!(
+            response.auth.lease,
+            LeaseInfo {
+                lease_id: String::new(),
+                lease_duration: 3600,
+                renewable: true
+            }
+        );
+    }
+
+    #[test]
+    fn fail_kv_v2_response() {
+        // kv v1 responses do not nest the secret in `.data.data`
+        assert!(serde_json::from_str::<KvV2Response>(r#"{"data": {"key": "value"}}"#).is_err());
+        assert!(serde_json::from_str::<KvV2Response>(r#"{"data": {"data": null}}"#).is_err());
+    }
+}
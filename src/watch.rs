@@ -0,0 +1,104 @@
+//! inotify based watcher for the .secrets file.
+use std::{
+    os::fd::{AsFd, AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use tokio::io::unix::AsyncFd;
+
+use crate::error::{Error, Result};
+
+/// Time to wait for related events (e.g. an editor writing a temp file and renaming it) to settle.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watches a file for content changes.
+///
+/// # Remarks:
+///
+/// The parent directory is watched instead of the file itself, so that atomic replacements via
+/// rename, such as the `..data` symlink swap of Kubernetes ConfigMap volumes, are detected as well.
+pub struct FileWatcher {
+    fd: AsyncFd<InotifyFd>,
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+}
+
+struct InotifyFd(Inotify);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .map_err(|err| Error::io("unable to initialize inotify", err))?;
+        inotify
+            .add_watch(
+                parent,
+                AddWatchFlags::IN_CLOSE_WRITE
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE
+                    | AddWatchFlags::IN_MODIFY,
+            )
+            .map_err(|err| Error::io_at("unable to watch", parent, err))?;
+        let fd = AsyncFd::new(InotifyFd(inotify))
+            .map_err(|err| Error::io("unable to register inotify fd", err))?;
+
+        Ok(Self {
+            fd,
+            path: path.to_path_buf(),
+            contents: std::fs::read(path).ok(),
+        })
+    }
+
+    /// Waits until the contents of the watched file changed.
+    pub async fn changed(&mut self) -> Result<()> {
+        loop {
+            self.wait_for_events().await?;
+            tokio::time::sleep(DEBOUNCE).await;
+            self.drain_events()?;
+
+            let contents = tokio::fs::read(&self.path).await.ok();
+            if contents.is_some() && contents != self.contents {
+                self.contents = contents;
+                return Ok(());
+            }
+        }
+    }
+
+    async fn wait_for_events(&self) -> Result<()> {
+        loop {
+            let mut guard = self
+                .fd
+                .readable()
+                .await
+                .map_err(|err| Error::io("unable to poll inotify fd", err))?;
+            match self.fd.get_ref().0.read_events() {
+                Ok(events) if !events.is_empty() => return Ok(()),
+                Ok(_) | Err(nix::errno::Errno::EAGAIN) => guard.clear_ready(),
+                Err(err) => return Err(Error::io("unable to read inotify events", err)),
+            }
+        }
+    }
+
+    fn drain_events(&self) -> Result<()> {
+        loop {
+            match self.fd.get_ref().0.read_events() {
+                Ok(events) if !events.is_empty() => continue,
+                Ok(_) | Err(nix::errno::Errno::EAGAIN) => return Ok(()),
+                Err(err) => return Err(Error::io("unable to read inotify events", err)),
+            }
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Runs the vaultify binary against the mock vault of the library target, as a downstream crate
+//! would.
+#![cfg(feature = "testing")]
+
+use vaultify::testing::{KvVersion, MockVault};
+
+#[tokio::test]
+async fn pass_mock_vault() {
+    let vault = MockVault::start().await.unwrap();
+    vault.mount("secret", KvVersion::V2);
+    vault.put("secret", "app", serde_json::json!({"password": "p4ss"}));
+    let secrets_file = std::env::temp_dir().join(format!("vaultify-mock-{}", std::process::id()));
+    std::fs::write(&secrets_file, "secret/app#password | env DB_PASS\n").unwrap();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_vaultify"))
+        .args(["--host", &vault.addr(), "--token", MockVault::TOKEN])
+        .arg("--secrets-file")
+        .arg(&secrets_file)
+        .arg("fetch")
+        .output()
+        .await
+        .unwrap();
+    std::fs::remove_file(secrets_file).unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "DB_PASS=\"p4ss\"\n"
+    );
+    assert!(vault
+        .requests()
+        .contains(&"GET /v1/secret/data/app".to_string()));
+}
@@ -0,0 +1,2 @@
+# hostname:port:database:username:password
+db:5432:app:{{secret/db#user}}:{{ secret/db#pass }}